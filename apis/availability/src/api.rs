@@ -3,13 +3,22 @@
 
 use crate::{
     data_source::AvailabilityDataSource,
-    query_data::{BlockQueryData, BlockSummaryQueryData, RecordQueryData, StateQueryData},
+    query_data::{
+        BlockQueryData, BlockSummaryQueryData, RecordQueryData, StateQueryData, ValidatorQueryData,
+    },
 };
 use ark_serialize::CanonicalSerialize;
 use clap::Args;
 use derive_more::From;
-use espresso_core::state::{ElaboratedBlockCommitment, TransactionCommitment, ValidatorState};
-use futures::FutureExt;
+use espresso_core::genesis::GenesisNote;
+use espresso_core::state::{
+    ElaboratedBlockCommitment, EspressoTransaction, TransactionCommitment, ValidatorState,
+};
+use futures::{
+    future::{ready, Future},
+    stream::{self, Stream},
+    FutureExt, StreamExt, TryFutureExt,
+};
 use hotshot_types::data::QuorumCertificate;
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, Snafu};
@@ -20,7 +29,7 @@ use tide_disco::{
     RequestError, RequestParams, StatusCode,
 };
 
-#[derive(Args, Default)]
+#[derive(Clone, Args, Default)]
 pub struct Options {
     #[arg(long = "availability-api-path", env = "ESPRESSO_AVAILABILITY_API_PATH")]
     pub api_path: Option<PathBuf>,
@@ -91,6 +100,10 @@ pub enum Error {
     MissingState {
         block_id: u64,
     },
+
+    #[from(ignore)]
+    #[snafu(display("block 0 does not contain a genesis transaction"))]
+    InvalidGenesisBlock,
 }
 
 impl Error {
@@ -105,6 +118,7 @@ impl Error {
             Self::InvalidRecordId { .. } => StatusCode::BadRequest,
             Self::MissingBlock { .. } => StatusCode::NotFound,
             Self::MissingState { .. } => StatusCode::NotFound,
+            Self::InvalidGenesisBlock => StatusCode::InternalServerError,
         }
     }
 }
@@ -159,6 +173,37 @@ where
     Ok(summaries)
 }
 
+/// Lazily fetch `count` items with consecutive IDs, starting from `first`.
+///
+/// Each item is fetched only when the consumer asks for it, so a long range can be sent to a
+/// client without holding the whole range in memory. The stream ends after the first error, such
+/// as a request for a block past the end of the chain.
+fn stream_range<T, F, Fut>(first: u64, count: u64, fetch: F) -> impl Stream<Item = Result<T, Error>>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    stream::iter(first..first.saturating_add(count))
+        .then(fetch)
+        .scan(false, |failed, res| {
+            if *failed {
+                return ready(None);
+            }
+            *failed = res.is_err();
+            ready(Some(res))
+        })
+}
+
+fn get_genesis<State>(state: State) -> Result<GenesisNote, Error>
+where
+    State: AvailabilityDataSource,
+{
+    match get_block(state, 0)?.raw_block.block.0.into_iter().next() {
+        Some(EspressoTransaction::Genesis(genesis)) => Ok(genesis),
+        _ => Err(Error::InvalidGenesisBlock),
+    }
+}
+
 fn get_state<State>(state: State, block_id: u64) -> Result<StateQueryData, Error>
 where
     State: AvailabilityDataSource,
@@ -241,6 +286,21 @@ where
             }
             .boxed()
         })?
+        .get("getgenesis", |_req, state| {
+            async move { get_genesis(state) }.boxed()
+        })?
+        .get("validators", |_req, state| {
+            async move {
+                // The stake table is fixed at genesis, so the genesis block determines the current
+                // validator set.
+                Ok(get_genesis(state)?
+                    .stake_table
+                    .into_iter()
+                    .map(|(staking_key, stake)| ValidatorQueryData { staking_key, stake })
+                    .collect::<Vec<_>>())
+            }
+            .boxed()
+        })?
         .get("getstate", |req, state| {
             async move {
                 let id = block_index(&req, state)?;
@@ -318,6 +378,17 @@ where
             }
             .boxed()
         })?
+        .stream("streamblocks", |req, state| {
+            async move {
+                let first = req.integer_param("first")?;
+                let count = req.integer_param("count")?;
+                Ok(stream_range(first, count, move |id| {
+                    state.read(move |state| async move { get_block(state, id) }.boxed())
+                }))
+            }
+            .try_flatten_stream()
+            .boxed()
+        })?
         .get("getblocksummary", |req, state| {
             async move {
                 let block_id = req.integer_param("block_id")?;
@@ -368,3 +439,42 @@ where
         })?;
     Ok(api)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_stream_range() {
+        let fetched = AtomicU64::new(0);
+        let stream = stream_range(2, 1000, |id| {
+            fetched.fetch_add(1, Ordering::SeqCst);
+            // Pretend the chain ends after block 9.
+            ready(if id < 10 {
+                Ok(id)
+            } else {
+                Err(Error::InvalidBlockId { block_id: id })
+            })
+        });
+        futures::pin_mut!(stream);
+
+        // Items are fetched one at a time, as they are consumed, rather than up front.
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 2);
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 3);
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+        // The rest of the range is cut short by the first error.
+        let rest = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(rest.len(), 7);
+        assert!(rest[..6].iter().all(Result::is_ok));
+        assert!(matches!(
+            rest[6],
+            Err(Error::InvalidBlockId { block_id: 10 })
+        ));
+        assert_eq!(fetched.load(Ordering::SeqCst), 9);
+    }
+}
@@ -2,11 +2,14 @@
 // This file is part of the Espresso library.
 
 use ark_serialize::*;
-use espresso_core::state::{
-    state_comm::LedgerStateCommitment, ElaboratedBlock, ElaboratedBlockCommitment,
-    ElaboratedTransaction, TransactionCommitment, ValidatorState,
+use espresso_core::{
+    state::{
+        state_comm::LedgerStateCommitment, ElaboratedBlock, ElaboratedBlockCommitment,
+        ElaboratedTransaction, TransactionCommitment, ValidatorState,
+    },
+    StakingKey,
 };
-use jf_cap::structs::RecordCommitment;
+use jf_cap::structs::{Amount, RecordCommitment};
 use jf_utils::tagged_blob;
 use serde::{Deserialize, Serialize};
 
@@ -92,3 +95,9 @@ pub struct BlockSummaryQueryData {
     pub block_hash: ElaboratedBlockCommitment,
     pub block_id: u64,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ValidatorQueryData {
+    pub staking_key: StakingKey,
+    pub stake: Amount,
+}
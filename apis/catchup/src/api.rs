@@ -8,31 +8,88 @@ use futures::{stream::iter, FutureExt, StreamExt, TryFutureExt};
 use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 use tide_disco::{
     api::{Api, ApiError},
     method::ReadState,
     RequestError, StatusCode,
 };
 
-#[derive(Args, Default)]
+#[derive(Clone, Args, Default)]
 pub struct Options {
     #[arg(long = "catchup-api-path", env = "ESPRESSO_CATCHUP_API_PATH")]
     pub api_path: Option<PathBuf>,
+
+    /// Maximum number of simultaneous event subscriptions.
+    ///
+    /// Subscriptions beyond this limit are refused. If not provided, there is no limit.
+    #[arg(
+        long = "catchup-max-connections",
+        env = "ESPRESSO_CATCHUP_MAX_CONNECTIONS"
+    )]
+    pub max_connections: Option<usize>,
 }
 
 #[derive(Clone, Debug, From, Snafu, Deserialize, Serialize)]
 pub enum Error {
-    Request { source: RequestError },
+    Request {
+        source: RequestError,
+    },
+
+    #[from(ignore)]
+    #[snafu(display("too many event subscriptions, the maximum is {}", max))]
+    TooManyConnections {
+        max: usize,
+    },
 }
 
 impl Error {
     pub fn status(&self) -> StatusCode {
         match self {
             Self::Request { .. } => StatusCode::BadRequest,
+            Self::TooManyConnections { .. } => StatusCode::TooManyRequests,
+        }
+    }
+}
+
+/// A limit on the number of simultaneous event subscriptions.
+#[derive(Clone, Debug)]
+struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            open: Default::default(),
+            max,
+        }
+    }
+
+    /// Reserve a slot for a new connection, which is released when the returned guard is dropped.
+    fn acquire(&self) -> Result<ConnectionGuard, Error> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        // Create the guard before checking the limit, so the slot is released if we fail.
+        let guard = ConnectionGuard(self.open.clone());
+        match self.max {
+            Some(max) if open >= max => Err(Error::TooManyConnections { max }),
+            _ => Ok(guard),
         }
     }
 }
 
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 pub fn define_api<State>(options: &Options) -> Result<Api<State, Error>, ApiError>
 where
     State: 'static + Send + Sync + ReadState,
@@ -49,6 +106,7 @@ where
             Api::<State, Error>::new(toml)?
         }
     };
+    let connections = ConnectionLimit::new(options.max_connections);
     api.with_version(env!("CARGO_PKG_VERSION").parse().unwrap())
         .get("get_events_since", |req, state| {
             async move {
@@ -65,8 +123,11 @@ where
             }
             .boxed()
         })?
-        .stream("subscribe_for_events", |req, state| {
+        .stream("subscribe_for_events", move |req, state| {
+            let connections = connections.clone();
             async move {
+                // Hold the slot until the subscription stream is dropped.
+                let guard = connections.acquire()?;
                 let mut first = req.integer_param("first")?;
                 let (prefix, receiver) = state
                     .read(|state| {
@@ -86,11 +147,14 @@ where
                 first += prefix.len();
                 Ok(iter(prefix)
                     .map(Ok)
-                    .chain(receiver.filter_map(move |(i, e)| async move {
-                        if i >= first {
-                            Some(Ok(e))
-                        } else {
-                            None
+                    .chain(receiver.filter_map(move |(i, e)| {
+                        let _guard = &guard;
+                        async move {
+                            if i >= first {
+                                Some(Ok(e))
+                            } else {
+                                None
+                            }
                         }
                     })))
             }
@@ -99,3 +163,30 @@ where
         })?;
     Ok(api)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.acquire().unwrap();
+        let _second = limit.acquire().unwrap();
+        assert!(matches!(
+            limit.acquire(),
+            Err(Error::TooManyConnections { max: 2 })
+        ));
+
+        // Closing a connection frees a slot for a new one.
+        drop(first);
+        let _third = limit.acquire().unwrap();
+        assert!(limit.acquire().is_err());
+
+        // Without a maximum, any number of connections is allowed.
+        let unlimited = ConnectionLimit::new(None);
+        let _guards = (0..100)
+            .map(|_| unlimited.acquire().unwrap())
+            .collect::<Vec<_>>();
+    }
+}
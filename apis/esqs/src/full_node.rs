@@ -31,7 +31,7 @@ use std::fmt::Display;
 use std::io;
 use tide_disco::{http::Url, App};
 
-#[derive(Args)]
+#[derive(Clone, Args)]
 pub struct Options {
     #[arg(short, long, env = "ESPRESSO_ESQS_PORT")]
     pub port: u16,
@@ -65,7 +65,7 @@ impl Options {
     }
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Subcommand)]
 pub enum Command {
     Esqs(Options),
 }
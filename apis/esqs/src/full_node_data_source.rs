@@ -20,7 +20,7 @@ use espresso_catchup_api::data_source::{CatchUpDataSource, UpdateCatchUpData};
 use espresso_core::ledger::EspressoLedger;
 use espresso_core::state::{
     ElaboratedBlockCommitment, ElaboratedTransaction, SetMerkleProof, SetMerkleTree,
-    TransactionCommitment, ValidatorState,
+    TransactionCommitment, ValidationError, ValidatorState,
 };
 use espresso_metastate_api::{
     api as metastate,
@@ -590,6 +590,10 @@ impl ValidatorDataSource for QueryData {
         self.consensus.submit(txn).await
     }
 
+    async fn validate(&mut self, txn: &ElaboratedTransaction) -> Result<(), ValidationError> {
+        self.consensus.validate(txn).await
+    }
+
     async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error> {
         self.consensus.next_event().await
     }
@@ -793,6 +797,35 @@ impl QueryData {
         })
     }
 
+    /// Load the genesis block persisted at `store_path`, if there is one.
+    ///
+    /// This reads only the first block, without building the indices or caches of [QueryData], so
+    /// that a node can check its persisted state before starting consensus.
+    pub fn load_genesis_block(
+        store_path: &Path,
+    ) -> Result<Option<BlockQueryData>, PersistenceError> {
+        let key_tag = "query_data_store";
+        let blocks_tag = format!("{}_blocks", key_tag);
+        let states_tag = format!("{}_states", key_tag);
+        let qcerts_tag = format!("{}_qcerts", key_tag);
+        let events_tag = format!("{}_events", key_tag);
+        let status_tag = format!("{}_status", key_tag);
+        let mut loader = AtomicStoreLoader::load(store_path, key_tag)?;
+        let block_storage: AppendLog<BincodeLoadStore<Option<BlockQueryData>>> =
+            AppendLog::load(&mut loader, Default::default(), &blocks_tag, 1024)?;
+        let _state_storage: AppendLog<BincodeLoadStore<Option<StateQueryData>>> =
+            AppendLog::load(&mut loader, Default::default(), &states_tag, 1024)?;
+        let _qcert_storage: AppendLog<BincodeLoadStore<Option<QuorumCertificate<ValidatorState>>>> =
+            AppendLog::load(&mut loader, Default::default(), &qcerts_tag, 1024)?;
+        let _event_storage: AppendLog<BincodeLoadStore<Option<LedgerEvent<EspressoLedger>>>> =
+            AppendLog::load(&mut loader, Default::default(), &events_tag, 1024)?;
+        let _status_storage: RollingLog<BincodeLoadStore<ValidatorStatus>> =
+            RollingLog::load(&mut loader, Default::default(), &status_tag, 1024)?;
+        let _query_storage = AtomicStore::open(loader)?;
+        let genesis = block_storage.iter().next().transpose()?.flatten();
+        Ok(genesis)
+    }
+
     pub fn commit_all(&mut self) {
         if let Err(e) = self.block_storage.commit_version() {
             warn!("Failed to commit block storage: Error {}", e);
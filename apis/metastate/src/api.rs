@@ -15,7 +15,7 @@ use tide_disco::{
     RequestError, StatusCode,
 };
 
-#[derive(Args, Default)]
+#[derive(Clone, Args, Default)]
 pub struct Options {
     #[arg(long = "metastate-api-path", env = "ESPRESSO_METASTATE_API_PATH")]
     pub api_path: Option<PathBuf>,
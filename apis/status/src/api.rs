@@ -14,7 +14,7 @@ use tide_disco::{
     RequestError, StatusCode,
 };
 
-#[derive(Args, Default)]
+#[derive(Clone, Args, Default)]
 pub struct Options {
     #[arg(long = "status-api-path", env = "ESPRESSO_STATUS_API_PATH")]
     pub api_path: Option<PathBuf>,
@@ -14,12 +14,19 @@ use tide_disco::{
     RequestError, StatusCode,
 };
 
-#[derive(Args, Default)]
+#[derive(Clone, Args, Default)]
 pub struct Options {
     #[arg(long = "validator-api-path", env = "ESPRESSO_VALIDATOR_API_PATH")]
     pub api_path: Option<PathBuf>,
 }
 
+/// The result of checking a transaction with the `validate` endpoint.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ValidationResult {
+    Valid,
+    Invalid { reason: String },
+}
+
 #[derive(Clone, Debug, From, Snafu, Deserialize, Serialize)]
 pub enum Error {
     Request {
@@ -66,6 +73,18 @@ where
                 })
             }
             .boxed()
+        })?
+        .post("validate", |req, state| {
+            async move {
+                let txn = req.body_auto()?;
+                Ok(match state.validate(&txn).await {
+                    Ok(()) => ValidationResult::Valid,
+                    Err(err) => ValidationResult::Invalid {
+                        reason: err.to_string(),
+                    },
+                })
+            }
+            .boxed()
         })?;
     Ok(api)
 }
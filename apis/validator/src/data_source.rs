@@ -2,7 +2,7 @@
 // This file is part of the Espresso library.
 
 use async_trait::async_trait;
-use espresso_core::state::{ElaboratedTransaction, ValidatorState};
+use espresso_core::state::{ElaboratedTransaction, ValidationError, ValidatorState};
 use futures::stream::{unfold, BoxStream, StreamExt};
 use hotshot::{
     traits::NodeImplementation,
@@ -18,6 +18,8 @@ pub type ConsensusEvent = EventType<ValidatorState>;
 pub trait ValidatorDataSource {
     type Error: Error + Debug;
     async fn submit(&mut self, txn: ElaboratedTransaction) -> Result<(), Self::Error>;
+    /// Check whether `txn` would be accepted on top of the current state, without submitting it.
+    async fn validate(&mut self, txn: &ElaboratedTransaction) -> Result<(), ValidationError>;
     async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error>;
 
     fn into_stream(self) -> BoxStream<'static, ConsensusEvent>
@@ -45,6 +47,10 @@ where
         self.submit_transaction(txn).await
     }
 
+    async fn validate(&mut self, txn: &ElaboratedTransaction) -> Result<(), ValidationError> {
+        self.get_state().await.validate_transaction(txn)
+    }
+
     async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error> {
         self.next_event().await.map(|e| e.event)
     }
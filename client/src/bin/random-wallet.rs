@@ -29,7 +29,7 @@ use clap::Parser;
 use derive_more::Deref;
 use espresso_client::{ledger_state::TransactionUID, network::NetworkBackend, RecordAmount};
 use espresso_core::{ledger::EspressoLedger, universal_params::UNIVERSAL_PARAM};
-use faucet_types::FaucetError;
+use faucet_types::faucet_client::FaucetClient;
 use human_bytes::human_bytes;
 use jf_cap::{
     keys::{FreezerPubKey, UserKeyPair, UserPubKey},
@@ -203,13 +203,9 @@ async fn get_peers(url: &Url) -> Result<Vec<UserPubKey>, AddressBookError> {
 
 async fn get_native_from_faucet(keystore: &mut Keystore, pub_key: &UserPubKey, url: &Url) {
     // Request native asset for the keystore.
+    let faucet = FaucetClient::new(url.join("api/").unwrap());
     loop {
-        match surf_disco::post::<(), FaucetError>(url.join("api/request_fee_assets").unwrap())
-            .body_binary(pub_key)
-            .unwrap()
-            .send()
-            .await
-        {
+        match faucet.request_fee_assets(pub_key).await {
             Ok(_) => break,
             Err(err) if err.status() == StatusCode::TooManyRequests => {
                 tracing::warn!(
@@ -41,20 +41,44 @@ pub struct NetworkBackend<'a> {
     validator_client: Client<ApiError>,
 }
 
+/// The default timeout for requests to the EsQS, address book, and validator.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 impl<'a> NetworkBackend<'a> {
     pub async fn new(
         univ_param: &'a UniversalParam,
         query_url: Url,
         address_book_url: Url,
         validator_url: Url,
+    ) -> Result<NetworkBackend<'a>, KeystoreError<EspressoLedger>> {
+        Self::with_timeout(
+            univ_param,
+            query_url,
+            address_book_url,
+            validator_url,
+            DEFAULT_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Create a backend whose requests fail if they do not complete within `timeout`.
+    ///
+    /// This also bounds how long we wait for the EsQS to become available when the backend is
+    /// created.
+    pub async fn with_timeout(
+        univ_param: &'a UniversalParam,
+        query_url: Url,
+        address_book_url: Url,
+        validator_url: Url,
+        timeout: Duration,
     ) -> Result<NetworkBackend<'a>, KeystoreError<EspressoLedger>> {
         let backend = Self {
-            query_client: Self::client(query_url),
-            address_book_client: Self::client(address_book_url),
-            validator_client: Self::client(validator_url),
+            query_client: Self::client(query_url, timeout),
+            address_book_client: Self::client(address_book_url, timeout),
+            validator_client: Self::client(validator_url, timeout),
             univ_param,
         };
-        backend.wait_for_esqs().await?;
+        backend.wait_for_esqs(timeout).await?;
         Ok(backend)
     }
 
@@ -89,8 +113,7 @@ impl<'a> NetworkBackend<'a> {
             })
     }
 
-    async fn wait_for_esqs(&self) -> Result<(), KeystoreError<EspressoLedger>> {
-        let timeout = Duration::from_secs(300);
+    async fn wait_for_esqs(&self, timeout: Duration) -> Result<(), KeystoreError<EspressoLedger>> {
         if self.query_client.connect(Some(timeout)).await {
             Ok(())
         } else {
@@ -100,10 +123,8 @@ impl<'a> NetworkBackend<'a> {
         }
     }
 
-    fn client<E: surf_disco::Error>(url: Url) -> Client<E> {
-        Client::builder(url)
-            .set_timeout(Some(Duration::from_secs(5 * 60)))
-            .build()
+    fn client<E: surf_disco::Error>(url: Url, timeout: Duration) -> Client<E> {
+        Client::builder(url).set_timeout(Some(timeout)).build()
     }
 }
 
@@ -1,7 +1,7 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
-use crate::stake_table::StakingKey;
+use crate::stake_table::{StakingKey, StakingPrivKey};
 use crate::{
     state::{ArcSer, ChainVariables},
     universal_params::MERKLE_HEIGHT,
@@ -13,13 +13,133 @@ use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, Read, Serializatio
 use async_std::sync::Arc;
 use commit::{Commitment, Committable, RawCommitmentBuilder};
 use espresso_macros::ser_test;
-use jf_cap::structs::Amount;
+use hotshot_types::traits::signature_key::SignatureKey;
+use jf_cap::keys::UserPubKey;
+use jf_cap::structs::{Amount, AssetCode};
 use jf_cap::{
-    structs::{RecordCommitment, RecordOpening},
-    MerkleTree,
+    structs::{AssetDefinition, FreezeFlag, RecordCommitment, RecordOpening},
+    MerkleTree, NodeValue,
 };
+use rand::{CryptoRng, RngCore};
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
 use serde::{Deserialize, Serialize};
+use snafu::Snafu;
 use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use tagged_base64::TaggedBase64;
+
+/// Errors in genesis notes.
+#[derive(Debug, Snafu, Serialize, Deserialize)]
+#[snafu(visibility(pub(crate)))]
+pub enum GenesisError {
+    /// A genesis commitment string which could not be parsed.
+    InvalidCommitment { reason: String },
+    /// A stake table entry whose key is malformed or is the identity key.
+    InvalidStakingKey { key: StakingKey, reason: String },
+    /// A stake table entry whose key uses a different scheme than the one declared by the chain.
+    #[snafu(display(
+        "staking key uses scheme {}, but the chain uses scheme {}",
+        actual,
+        expected
+    ))]
+    StakingKeySchemeMismatch {
+        key: StakingKey,
+        expected: u16,
+        actual: u16,
+    },
+    /// A genesis note for a different chain than the one expected.
+    #[snafu(display("genesis is for chain {}, expected chain {}", actual, expected))]
+    ChainIdMismatch { expected: u16, actual: u16 },
+    /// A record Merkle tree root which does not match the faucet records of the genesis note.
+    RecordRootMismatch {
+        expected: NodeValue,
+        actual: NodeValue,
+    },
+    /// The total amount of an asset created by the faucet records overflows an [Amount].
+    #[snafu(display("total genesis supply of asset {} overflows", asset))]
+    SupplyOverflow { asset: AssetCode },
+    /// Bytes which are not a genesis note in the [GenesisNote::to_wire] format.
+    #[snafu(display("invalid genesis wire format: {}", reason))]
+    InvalidWireFormat { reason: String },
+    /// The sum of the stakes of a key appearing in two merged stake tables overflows an [Amount].
+    StakeOverflow { key: StakingKey },
+    /// A key appearing in two merged stake tables, which the merge policy does not allow.
+    StakeConflict {
+        key: StakingKey,
+        left: Amount,
+        right: Amount,
+    },
+}
+
+/// How [GenesisNote::merge_stake_tables] handles a key which appears in both tables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StakeMergePolicy {
+    /// The key's stake is the sum of its stakes in both tables.
+    #[default]
+    Sum,
+    /// The key must have the same stake in both tables, which is its stake in the merged table.
+    ///
+    /// This suits parties who each list a shared validator, where a mismatch is a mistake.
+    RequireEqual,
+    /// Each key may appear in only one table.
+    Reject,
+}
+
+/// Versions of the scheme used to commit to a [GenesisNote].
+///
+/// Changing the scheme changes the commitment, and with it the hash of the genesis block, so
+/// existing deployments can keep the version they were created with and migrate deliberately.
+/// Encodings of a [GenesisNote] which predate versions, and so lack one, use [V1](Self::V1), the
+/// scheme those notes were committed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GenesisCommitmentVersion {
+    /// Commits to the chain variables and faucet records only.
+    ///
+    /// This is the scheme used by deployments created before the stake table was committed to.
+    V1,
+    /// Commits to the chain variables, faucet records and stake table.
+    #[default]
+    V2,
+}
+
+impl GenesisCommitmentVersion {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
+impl Display for GenesisCommitmentVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "v{}", self.to_byte())
+    }
+}
+
+impl FromStr for GenesisCommitmentVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(Self::V1),
+            "v2" | "2" => Ok(Self::V2),
+            _ => Err(format!(
+                "unknown genesis commitment version {} (expected v1 or v2)",
+                s
+            )),
+        }
+    }
+}
 
 /// Genesis transaction
 ///
@@ -29,29 +149,105 @@ use std::collections::BTreeMap;
 /// setting the chain variables to `chain` and seeding the record set with commitments to
 /// `faucet_records`.
 #[ser_test(arbitrary)]
-#[derive(
-    Clone,
-    Debug,
-    CanonicalSerialize,
-    CanonicalDeserialize,
-    Serialize,
-    Deserialize,
-    Hash,
-    PartialEq,
-    Eq,
-)]
+#[derive(Clone, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct GenesisNote {
     pub chain: ChainVariables,
     pub faucet_records: ArcSer<Vec<RecordOpening>>,
     pub stake_table: BTreeMap<StakingKey, Amount>,
+    /// The scheme used to commit to this note.
+    ///
+    /// This is the last serde field, and defaults to [GenesisCommitmentVersion::V1] when it is
+    /// missing, as it is from notes serialized before versions existed. It is not part of the
+    /// canonical serialization, which must stay as it was for stored blocks to deserialize.
+    /// Instead, the wire format and genesis transactions
+    /// ([EspressoTransaction](crate::state::EspressoTransaction)) carry it alongside.
+    #[serde(default = "legacy_commitment_version")]
+    pub commitment_version: GenesisCommitmentVersion,
+}
+
+fn legacy_commitment_version() -> GenesisCommitmentVersion {
+    GenesisCommitmentVersion::V1
+}
+
+/// The serde form of a [GenesisNote] from before notes had commitment versions.
+///
+/// Genesis transactions committed with [GenesisCommitmentVersion::V1] are serialized in this form,
+/// so that they encode exactly as they did when they were stored.
+#[derive(Serialize)]
+#[serde(rename = "GenesisNote")]
+pub(crate) struct LegacyGenesisNoteRef<'a> {
+    chain: &'a ChainVariables,
+    faucet_records: &'a ArcSer<Vec<RecordOpening>>,
+    stake_table: &'a BTreeMap<StakingKey, Amount>,
+}
+
+impl<'a> From<&'a GenesisNote> for LegacyGenesisNoteRef<'a> {
+    fn from(note: &'a GenesisNote) -> Self {
+        Self {
+            chain: &note.chain,
+            faucet_records: &note.faucet_records,
+            stake_table: &note.stake_table,
+        }
+    }
+}
+
+/// The owned counterpart of [LegacyGenesisNoteRef], for deserializing.
+#[derive(Deserialize)]
+#[serde(rename = "GenesisNote")]
+pub(crate) struct LegacyGenesisNote {
+    chain: ChainVariables,
+    faucet_records: ArcSer<Vec<RecordOpening>>,
+    stake_table: BTreeMap<StakingKey, Amount>,
+}
+
+impl From<LegacyGenesisNote> for GenesisNote {
+    fn from(note: LegacyGenesisNote) -> Self {
+        Self {
+            chain: note.chain,
+            faucet_records: note.faucet_records,
+            stake_table: note.stake_table,
+            commitment_version: legacy_commitment_version(),
+        }
+    }
+}
+
+impl CanonicalSerialize for GenesisNote {
+    fn serialize<W: Write>(&self, mut writer: W) -> Result<(), SerializationError> {
+        self.chain.serialize(&mut writer)?;
+        self.faucet_records.serialize(&mut writer)?;
+        self.stake_table.serialize(&mut writer)
+    }
+
+    fn serialized_size(&self) -> usize {
+        self.chain.serialized_size()
+            + self.faucet_records.serialized_size()
+            + self.stake_table.serialized_size()
+    }
+}
+
+impl CanonicalDeserialize for GenesisNote {
+    fn deserialize<R: Read>(mut reader: R) -> Result<Self, SerializationError> {
+        Ok(Self {
+            chain: CanonicalDeserialize::deserialize(&mut reader)?,
+            faucet_records: CanonicalDeserialize::deserialize(&mut reader)?,
+            stake_table: CanonicalDeserialize::deserialize(&mut reader)?,
+            commitment_version: Default::default(),
+        })
+    }
 }
 
 impl Committable for GenesisNote {
     fn commit(&self) -> Commitment<Self> {
-        RawCommitmentBuilder::new("GenesisNote")
+        let builder = RawCommitmentBuilder::new("GenesisNote")
             .field("chain", self.chain.commit())
-            .var_size_bytes(&canonical::serialize(&self.faucet_records).unwrap())
-            .finalize()
+            .var_size_bytes(&canonical::serialize(&self.faucet_records).unwrap());
+        match self.commitment_version {
+            GenesisCommitmentVersion::V1 => builder,
+            GenesisCommitmentVersion::V2 => {
+                builder.var_size_bytes(&canonical::serialize(&self.stake_table).unwrap())
+            }
+        }
+        .finalize()
     }
 }
 
@@ -71,11 +267,27 @@ impl<'a> Arbitrary<'a> for GenesisNote {
                     Ok((key, Amount::from(amt)))
                 })
                 .collect::<Result<_, _>>()?,
+            commitment_version: Default::default(),
         })
     }
 }
 
 impl GenesisNote {
+    /// The tag of a genesis commitment in tagged base64.
+    pub const COMMITMENT_TAG: &'static str = "GENS";
+
+    /// The magic bytes at the start of a genesis note in wire format.
+    pub const WIRE_MAGIC: [u8; 4] = *b"ESGN";
+
+    /// The version of the wire format written by [GenesisNote::to_wire].
+    ///
+    /// Version 1 carries only the canonical serialization of the note. Version 2 adds the
+    /// commitment version of the note, and version 3 adds the staking key scheme of the chain.
+    pub const WIRE_VERSION: u8 = 3;
+
+    /// The length of the wire format header: magic, version byte and length prefix.
+    const WIRE_HEADER_LEN: usize = 4 + 1 + 8;
+
     pub fn new(
         chain: ChainVariables,
         faucet_records: Arc<Vec<RecordOpening>>,
@@ -85,8 +297,132 @@ impl GenesisNote {
             chain,
             faucet_records: faucet_records.into(),
             stake_table,
+            commitment_version: Default::default(),
         }
     }
+
+    /// Use commitment scheme `version` for this note.
+    ///
+    /// Notes use the latest scheme by default. Deployments created with an older scheme must keep
+    /// using it, or their genesis block will change.
+    pub fn with_commitment_version(mut self, version: GenesisCommitmentVersion) -> Self {
+        self.commitment_version = version;
+        self
+    }
+
+    /// Create a faucet record to include in a genesis note.
+    ///
+    /// The record is owned by `owner` and is unfrozen, so that the owner can spend it right away.
+    /// The blinding factor of the record is drawn from `rng`, so the resulting record (and its
+    /// commitment) is deterministic given the state of `rng`.
+    pub fn faucet_record<R: CryptoRng + RngCore>(
+        rng: &mut R,
+        owner: &UserPubKey,
+        asset: AssetDefinition,
+        amount: Amount,
+    ) -> RecordOpening {
+        RecordOpening::new(rng, amount, asset, owner.clone(), FreezeFlag::Unfrozen)
+    }
+
+    /// Create faucet records for `grants`, with blinding factors derived from `seed`.
+    ///
+    /// The records depend only on `seed` and `grants`, so parties to a genesis ceremony who agree
+    /// on both can each build the genesis note independently and compare commitments.
+    pub fn seeded_faucet_records(
+        seed: [u8; 32],
+        grants: impl IntoIterator<Item = (UserPubKey, AssetDefinition, Amount)>,
+    ) -> Vec<RecordOpening> {
+        let mut rng = ChaChaRng::from_seed(seed);
+        grants
+            .into_iter()
+            .map(|(owner, asset, amount)| Self::faucet_record(&mut rng, &owner, asset, amount))
+            .collect()
+    }
+
+    /// Whether this note neither creates any records nor registers any validators.
+    pub fn is_empty(&self) -> bool {
+        self.faucet_records.is_empty() && self.stake_table.is_empty()
+    }
+
+    /// The size of this note in its canonical serialization.
+    pub fn serialized_size(&self) -> usize {
+        CanonicalSerialize::serialized_size(self)
+    }
+
+    /// The commitment to this note, as a tagged base64 string.
+    ///
+    /// This is a stable, compact way of referring to a genesis note, for example in configuration
+    /// files. It can be parsed with [GenesisNote::parse_commitment].
+    pub fn commitment_hex(&self) -> String {
+        let bytes = canonical::serialize(&self.commit()).unwrap();
+        TaggedBase64::new(Self::COMMITMENT_TAG, &bytes)
+            .unwrap()
+            .to_string()
+    }
+
+    /// Parse a commitment formatted by [GenesisNote::commitment_hex].
+    pub fn parse_commitment(s: &str) -> Result<Commitment<Self>, GenesisError> {
+        let tb64 = TaggedBase64::parse(s).map_err(|err| GenesisError::InvalidCommitment {
+            reason: err.to_string(),
+        })?;
+        if tb64.tag() != Self::COMMITMENT_TAG {
+            return Err(GenesisError::InvalidCommitment {
+                reason: format!("expected tag {}, got {}", Self::COMMITMENT_TAG, tb64.tag()),
+            });
+        }
+        canonical::deserialize(&tb64.value()).map_err(|err| GenesisError::InvalidCommitment {
+            reason: err.to_string(),
+        })
+    }
+
+    /// Check that every key in the stake table is a well-formed, non-identity key of the chain's
+    /// staking key scheme.
+    ///
+    /// Keys in the table are distinct by construction, but their encodings can still be malformed
+    /// (for example, if the note was built from untrusted input). A well-formed key round trips
+    /// through its byte encoding. The identity key is rejected because it is the public key of the
+    /// zero private key, which anyone can sign with. Keys must also use the staking key scheme
+    /// declared by the chain.
+    pub fn validate_stake_table(&self) -> Result<(), GenesisError> {
+        let identity = StakingKey::from_private(&StakingPrivKey::default());
+        for key in self.stake_table.keys() {
+            if key.scheme() != self.chain.staking_key_scheme {
+                return Err(GenesisError::StakingKeySchemeMismatch {
+                    key: key.clone(),
+                    expected: self.chain.staking_key_scheme,
+                    actual: key.scheme(),
+                });
+            }
+            if StakingKey::from_bytes(&key.to_bytes()).as_ref() != Some(key) {
+                return Err(GenesisError::InvalidStakingKey {
+                    key: key.clone(),
+                    reason: "key does not round trip through its encoding".into(),
+                });
+            }
+            if *key == identity {
+                return Err(GenesisError::InvalidStakingKey {
+                    key: key.clone(),
+                    reason: "key is the identity".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that this note initializes the chain with ID `expected`.
+    ///
+    /// Nodes should refuse to start from a genesis for a different chain, since they would never
+    /// agree with the rest of the network.
+    pub fn check_chain_id(&self, expected: u16) -> Result<(), GenesisError> {
+        if self.chain.chain_id != expected {
+            return Err(GenesisError::ChainIdMismatch {
+                expected,
+                actual: self.chain.chain_id,
+            });
+        }
+        Ok(())
+    }
+
     pub fn output_len(&self) -> usize {
         self.faucet_records.len()
     }
@@ -109,4 +445,718 @@ impl GenesisNote {
         }
         records
     }
+
+    /// The total amount of each asset created by the faucet records of this note.
+    pub fn supply_by_asset(&self) -> Result<BTreeMap<AssetCode, Amount>, GenesisError> {
+        let mut supply = BTreeMap::<AssetCode, u128>::new();
+        for record in self.faucet_records.iter() {
+            let asset = record.asset_def.code;
+            let total = supply.entry(asset).or_default();
+            *total = total
+                .checked_add(u128::from(record.amount))
+                .ok_or(GenesisError::SupplyOverflow { asset })?;
+        }
+        Ok(supply
+            .into_iter()
+            .map(|(asset, total)| (asset, Amount::from(total)))
+            .collect())
+    }
+
+    /// Merge the stake tables `a` and `b`, for genesis notes composed by multiple parties.
+    ///
+    /// Keys which appear in only one table keep their stake. Keys which appear in both are
+    /// resolved according to `policy`.
+    pub fn merge_stake_tables(
+        a: BTreeMap<StakingKey, Amount>,
+        b: BTreeMap<StakingKey, Amount>,
+        policy: StakeMergePolicy,
+    ) -> Result<BTreeMap<StakingKey, Amount>, GenesisError> {
+        let mut merged = a;
+        for (key, right) in b {
+            let left = match merged.get(&key) {
+                Some(left) => *left,
+                None => {
+                    merged.insert(key, right);
+                    continue;
+                }
+            };
+            let stake = match policy {
+                StakeMergePolicy::Sum => u128::from(left)
+                    .checked_add(u128::from(right))
+                    .map(Amount::from)
+                    .ok_or_else(|| GenesisError::StakeOverflow { key: key.clone() })?,
+                StakeMergePolicy::RequireEqual if left == right => left,
+                StakeMergePolicy::RequireEqual | StakeMergePolicy::Reject => {
+                    return Err(GenesisError::StakeConflict { key, left, right });
+                }
+            };
+            merged.insert(key, stake);
+        }
+        Ok(merged)
+    }
+
+    /// Serialize this note in a self-identifying wire format, for tools which do not share our
+    /// serialization code.
+    ///
+    /// The format is [GenesisNote::WIRE_MAGIC], then a [GenesisNote::WIRE_VERSION] byte, then the
+    /// length of the body as a little-endian `u64`, then the body. The body is a byte giving the
+    /// commitment version of the note, then the staking key scheme of the chain as a little-endian
+    /// `u16`, then the canonical serialization of the note.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let note = canonical::serialize(self).unwrap();
+        let body_len = 1 + 2 + note.len();
+        let mut bytes = Vec::with_capacity(Self::WIRE_HEADER_LEN + body_len);
+        bytes.extend_from_slice(&Self::WIRE_MAGIC);
+        bytes.push(Self::WIRE_VERSION);
+        bytes.extend_from_slice(&(body_len as u64).to_le_bytes());
+        bytes.push(self.commitment_version.to_byte());
+        bytes.extend_from_slice(&self.chain.staking_key_scheme.to_le_bytes());
+        bytes.extend_from_slice(&note);
+        bytes
+    }
+
+    /// Parse a note serialized by [GenesisNote::to_wire].
+    ///
+    /// Notes in older versions of the wire format, which do not record the commitment version or the
+    /// staking key scheme, use the default commitment version and the current scheme.
+    pub fn from_wire(bytes: &[u8]) -> Result<Self, GenesisError> {
+        let invalid = |reason: String| GenesisError::InvalidWireFormat { reason };
+        if bytes.len() < Self::WIRE_HEADER_LEN {
+            return Err(invalid(format!(
+                "expected a {}-byte header, got {} bytes",
+                Self::WIRE_HEADER_LEN,
+                bytes.len()
+            )));
+        }
+        let (magic, rest) = bytes.split_at(4);
+        if magic != Self::WIRE_MAGIC {
+            return Err(invalid(format!("bad magic {:02x?}", magic)));
+        }
+        let (version, rest) = rest.split_at(1);
+        let wire_version = version[0];
+        if wire_version == 0 || wire_version > Self::WIRE_VERSION {
+            return Err(invalid(format!(
+                "unsupported version {} (expected at most {})",
+                wire_version,
+                Self::WIRE_VERSION
+            )));
+        }
+        let (len, body) = rest.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        if len != body.len() as u64 {
+            return Err(invalid(format!(
+                "length prefix is {} bytes, but {} bytes follow",
+                len,
+                body.len()
+            )));
+        }
+        let (commitment_version, body) = if wire_version < 2 {
+            (GenesisCommitmentVersion::default(), body)
+        } else {
+            let (version, body) = body
+                .split_first()
+                .ok_or_else(|| invalid("missing commitment version".into()))?;
+            let version = GenesisCommitmentVersion::from_byte(*version)
+                .ok_or_else(|| invalid(format!("unknown commitment version {}", version)))?;
+            (version, body)
+        };
+        let (staking_key_scheme, body) = if wire_version < 3 {
+            (StakingKey::SCHEME, body)
+        } else {
+            if body.len() < 2 {
+                return Err(invalid("missing staking key scheme".into()));
+            }
+            let (scheme, body) = body.split_at(2);
+            (u16::from_le_bytes(scheme.try_into().unwrap()), body)
+        };
+        let mut note: Self =
+            canonical::deserialize(body).map_err(|err| invalid(err.to_string()))?;
+        note.chain.staking_key_scheme = staking_key_scheme;
+        Ok(note.with_commitment_version(commitment_version))
+    }
+
+    /// Check that `expected_root` is the root of the record Merkle tree of this note.
+    ///
+    /// A node which obtained its genesis record tree separately from the note can use this to
+    /// confirm that the tree matches the canonical one.
+    pub fn verify_record_root(&self, expected_root: NodeValue) -> Result<(), GenesisError> {
+        let actual = self.record_merkle_tree().commitment().root_value;
+        if actual == expected_root {
+            Ok(())
+        } else {
+            Err(GenesisError::RecordRootMismatch {
+                expected: expected_root,
+                actual,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{ElaboratedBlock, EspressoTransaction};
+    use crate::universal_params::VERIF_CRS;
+    use jf_cap::{keys::UserKeyPair, structs::AssetCodeSeed};
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    #[test]
+    fn test_genesis_note_size() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+
+        let empty = GenesisNote::new(chain.clone(), Arc::new(vec![]), BTreeMap::new());
+        assert!(empty.is_empty());
+        assert_eq!(
+            empty.serialized_size(),
+            canonical::serialize(&empty).unwrap().len()
+        );
+
+        // A note is not empty if it has either records or validators.
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let record = GenesisNote::faucet_record(
+            &mut rng,
+            &owner,
+            AssetDefinition::native(),
+            Amount::from(1000u64),
+        );
+        let with_records = GenesisNote::new(chain.clone(), Arc::new(vec![record]), BTreeMap::new());
+        assert!(!with_records.is_empty());
+        let stake_table = [(StakingKey::generate(&mut rng).0, Amount::from(10u64))]
+            .into_iter()
+            .collect();
+        let with_validators = GenesisNote::new(chain, Arc::new(vec![]), stake_table);
+        assert!(!with_validators.is_empty());
+
+        for note in [with_records, with_validators] {
+            assert_eq!(
+                note.serialized_size(),
+                canonical::serialize(&note).unwrap().len()
+            );
+            assert!(note.serialized_size() > empty.serialized_size());
+        }
+    }
+
+    #[test]
+    fn test_faucet_record_deterministic() {
+        let owner = UserKeyPair::generate(&mut ChaChaRng::from_seed([0u8; 32])).pub_key();
+        let amount = Amount::from(1000u64);
+        let record = |seed| {
+            GenesisNote::faucet_record(
+                &mut ChaChaRng::from_seed(seed),
+                &owner,
+                AssetDefinition::native(),
+                amount,
+            )
+        };
+
+        let ro = record([1u8; 32]);
+        assert_eq!(ro.pub_key, owner);
+        assert_eq!(ro.amount, amount);
+        assert_eq!(ro.asset_def, AssetDefinition::native());
+        assert_eq!(ro.freeze_flag, FreezeFlag::Unfrozen);
+
+        // The same RNG state gives the same commitment, and a different one gives a different
+        // commitment.
+        assert_eq!(
+            RecordCommitment::from(&ro),
+            RecordCommitment::from(&record([1u8; 32]))
+        );
+        assert_ne!(
+            RecordCommitment::from(&ro),
+            RecordCommitment::from(&record([2u8; 32]))
+        );
+    }
+
+    #[test]
+    fn test_seeded_faucet_records() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let grants = (0..3)
+            .map(|i| {
+                (
+                    UserKeyPair::generate(&mut rng).pub_key(),
+                    AssetDefinition::native(),
+                    Amount::from(1000u64 * (i + 1)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let build = |seed| {
+            GenesisNote::new(
+                chain.clone(),
+                Arc::new(GenesisNote::seeded_faucet_records(seed, grants.clone())),
+                BTreeMap::new(),
+            )
+        };
+
+        // Two builds from the same seed are identical, down to the commitment.
+        let note = build([1u8; 32]);
+        assert_eq!(note.faucet_records.len(), grants.len());
+        assert_eq!(note.commit(), build([1u8; 32]).commit());
+        // A different seed gives different blinding factors, and so a different commitment.
+        assert_ne!(note.commit(), build([2u8; 32]).commit());
+    }
+
+    #[test]
+    fn test_supply_by_asset() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let other = AssetDefinition::new(
+            AssetCode::new_domestic(AssetCodeSeed::generate(&mut rng), b"other"),
+            Default::default(),
+        )
+        .unwrap();
+        let note = |grants: &[(&AssetDefinition, u128)]| {
+            let records = grants
+                .iter()
+                .map(|(asset, amount)| {
+                    GenesisNote::faucet_record(
+                        &mut ChaChaRng::from_seed([1u8; 32]),
+                        &owner,
+                        (*asset).clone(),
+                        Amount::from(*amount),
+                    )
+                })
+                .collect();
+            GenesisNote::new(chain.clone(), Arc::new(records), BTreeMap::new())
+        };
+
+        let native = AssetDefinition::native();
+        let supply = note(&[(&native, 100), (&other, 5), (&native, 200), (&other, 7)])
+            .supply_by_asset()
+            .unwrap();
+        assert_eq!(supply.len(), 2);
+        assert_eq!(supply[&native.code], Amount::from(300u128));
+        assert_eq!(supply[&other.code], Amount::from(12u128));
+
+        // A note without records has no supply.
+        assert!(note(&[]).supply_by_asset().unwrap().is_empty());
+
+        // A total which does not fit in an amount is an error, not a wrapped or saturated sum.
+        match note(&[(&native, 1), (&other, u128::MAX), (&other, 1)]).supply_by_asset() {
+            Err(GenesisError::SupplyOverflow { asset }) => assert_eq!(asset, other.code),
+            res => panic!("expected SupplyOverflow, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_verify_record_root() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let records = (0..2)
+            .map(|_| {
+                GenesisNote::faucet_record(
+                    &mut rng,
+                    &owner,
+                    AssetDefinition::native(),
+                    Amount::from(1000u64),
+                )
+            })
+            .collect::<Vec<_>>();
+        let note = GenesisNote::new(chain.clone(), Arc::new(records.clone()), BTreeMap::new());
+
+        // The root of a tree built from the same records matches.
+        let mut tree = MerkleTree::new(MERKLE_HEIGHT).unwrap();
+        for record in &records {
+            tree.push(RecordCommitment::from(record).to_field_element());
+        }
+        note.verify_record_root(tree.commitment().root_value)
+            .unwrap();
+
+        // The root of a tree missing one of the records does not.
+        let partial = GenesisNote::new(chain, Arc::new(records[..1].to_vec()), BTreeMap::new());
+        let root = partial.record_merkle_tree().commitment().root_value;
+        match note.verify_record_root(root) {
+            Err(GenesisError::RecordRootMismatch { expected, actual }) => {
+                assert_eq!(expected, root);
+                assert_eq!(actual, tree.commitment().root_value);
+            }
+            res => panic!("expected RecordRootMismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_commitment_hex() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let record = GenesisNote::faucet_record(
+            &mut rng,
+            &owner,
+            AssetDefinition::native(),
+            Amount::from(1000u64),
+        );
+        let note = GenesisNote::new(chain.clone(), Arc::new(vec![record]), BTreeMap::new());
+
+        // The string round trips to the commitment.
+        let hex = note.commitment_hex();
+        assert!(hex.starts_with("GENS~"));
+        assert_eq!(GenesisNote::parse_commitment(&hex).unwrap(), note.commit());
+
+        // The string is stable for the same note, and distinct for a different note.
+        assert_eq!(hex, note.clone().commitment_hex());
+        let empty = GenesisNote::new(chain, Arc::new(vec![]), BTreeMap::new());
+        assert_ne!(hex, empty.commitment_hex());
+
+        // Strings with the wrong tag or malformed contents are rejected.
+        let wrong_tag = TaggedBase64::new("BLOCK", &canonical::serialize(&note.commit()).unwrap())
+            .unwrap()
+            .to_string();
+        GenesisNote::parse_commitment(&wrong_tag).unwrap_err();
+        GenesisNote::parse_commitment("GENS~notbase64!").unwrap_err();
+        GenesisNote::parse_commitment("").unwrap_err();
+    }
+
+    #[test]
+    fn test_validate_stake_table() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let mut stake_table: BTreeMap<_, _> = (0..3)
+            .map(|_| (StakingKey::generate(&mut rng).0, Amount::from(10u64)))
+            .collect();
+        GenesisNote::new(chain.clone(), Arc::new(vec![]), stake_table.clone())
+            .validate_stake_table()
+            .unwrap();
+
+        // Inject the identity key, which must be rejected.
+        let identity = StakingKey::from_private(&StakingPrivKey::default());
+        stake_table.insert(identity.clone(), Amount::from(10u64));
+        let note = GenesisNote::new(chain, Arc::new(vec![]), stake_table);
+        match note.validate_stake_table() {
+            Err(GenesisError::InvalidStakingKey { key, .. }) => assert_eq!(key, identity),
+            res => panic!("expected InvalidStakingKey, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_staking_key_scheme() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let key = StakingKey::generate(&mut rng).0;
+        let stake_table = BTreeMap::from([(key.clone(), Amount::from(10u64))]);
+        assert_eq!(key.scheme(), StakingKey::SCHEME);
+
+        // Chains use the current scheme by default, which accepts current keys.
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        assert_eq!(chain.staking_key_scheme, StakingKey::SCHEME);
+        GenesisNote::new(chain.clone(), Arc::new(vec![]), stake_table.clone())
+            .validate_stake_table()
+            .unwrap();
+
+        // The scheme is not part of the serialization of the chain, which is unchanged from before
+        // the scheme was recorded.
+        let bytes = canonical::serialize(&chain).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                canonical::serialize(&chain.protocol_version).unwrap(),
+                canonical::serialize(&chain.chain_id).unwrap(),
+                canonical::serialize(&chain.verif_crs).unwrap(),
+                canonical::serialize(&chain.vrf_seed).unwrap(),
+                canonical::serialize(&chain.committee_size).unwrap(),
+            ]
+            .concat()
+        );
+        assert_eq!(
+            canonical::deserialize::<ChainVariables>(&bytes).unwrap(),
+            chain
+        );
+
+        // A chain declaring a different scheme rejects keys of the current scheme.
+        let mut future = chain.clone();
+        future.staking_key_scheme = StakingKey::SCHEME + 1;
+        assert_eq!(future.commit(), chain.commit());
+        assert_ne!(future, chain);
+        let note = GenesisNote::new(future, Arc::new(vec![]), stake_table);
+
+        // The wire format carries the declared scheme.
+        assert_eq!(GenesisNote::from_wire(&note.to_wire()).unwrap(), note);
+
+        match note.validate_stake_table() {
+            Err(GenesisError::StakingKeySchemeMismatch {
+                key: bad_key,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(bad_key, key);
+                assert_eq!(expected, StakingKey::SCHEME + 1);
+                assert_eq!(actual, StakingKey::SCHEME);
+            }
+            res => panic!("expected StakingKeySchemeMismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_check_chain_id() {
+        let note = GenesisNote::new(
+            ChainVariables::new(42, VERIF_CRS.clone(), 1),
+            Arc::new(vec![]),
+            BTreeMap::new(),
+        );
+        note.check_chain_id(42).unwrap();
+        match note.check_chain_id(43) {
+            Err(GenesisError::ChainIdMismatch { expected, actual }) => {
+                assert_eq!(expected, 43);
+                assert_eq!(actual, 42);
+            }
+            res => panic!("expected ChainIdMismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_commitment_versions() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let records = Arc::new(vec![GenesisNote::faucet_record(
+            &mut rng,
+            &owner,
+            AssetDefinition::native(),
+            Amount::from(1000u64),
+        )]);
+        let stake_table: BTreeMap<_, _> = (0..3)
+            .map(|_| (StakingKey::generate(&mut rng).0, Amount::from(10u64)))
+            .collect();
+        let note = GenesisNote::new(chain.clone(), records.clone(), stake_table);
+        let no_stake = GenesisNote::new(chain, records, BTreeMap::new());
+        assert_eq!(note.commitment_version, GenesisCommitmentVersion::V2);
+
+        // V1 produces the historical commitment, which covers only the chain and the records.
+        let v1 = note
+            .clone()
+            .with_commitment_version(GenesisCommitmentVersion::V1);
+        let historical = RawCommitmentBuilder::<GenesisNote>::new("GenesisNote")
+            .field("chain", note.chain.commit())
+            .var_size_bytes(&canonical::serialize(&note.faucet_records).unwrap())
+            .finalize();
+        assert_eq!(v1.commit(), historical);
+        assert_eq!(
+            v1.commit(),
+            no_stake
+                .clone()
+                .with_commitment_version(GenesisCommitmentVersion::V1)
+                .commit()
+        );
+
+        // V2 also covers the stake table.
+        let new = RawCommitmentBuilder::<GenesisNote>::new("GenesisNote")
+            .field("chain", note.chain.commit())
+            .var_size_bytes(&canonical::serialize(&note.faucet_records).unwrap())
+            .var_size_bytes(&canonical::serialize(&note.stake_table).unwrap())
+            .finalize();
+        assert_eq!(note.commit(), new);
+        assert_ne!(note.commit(), v1.commit());
+        assert_ne!(note.commit(), no_stake.commit());
+
+        // The version is not part of the note's canonical serialization, so notes serialize as they
+        // did before versions existed, but it is carried by serde and by the wire format.
+        let bytes = canonical::serialize(&v1).unwrap();
+        assert_eq!(bytes, canonical::serialize(&note).unwrap());
+        assert_eq!(canonical::deserialize::<GenesisNote>(&bytes).unwrap(), note);
+        let historical_bytes = [
+            canonical::serialize(&note.chain).unwrap(),
+            canonical::serialize(&note.faucet_records).unwrap(),
+            canonical::serialize(&note.stake_table).unwrap(),
+        ]
+        .concat();
+        assert_eq!(bytes, historical_bytes);
+        assert_eq!(
+            bincode::deserialize::<GenesisNote>(&bincode::serialize(&v1).unwrap()).unwrap(),
+            v1
+        );
+        // A note serialized before versions existed has the version it was committed with.
+        let mut json = serde_json::to_value(&note).unwrap();
+        json.as_object_mut().unwrap().remove("commitment_version");
+        assert_eq!(serde_json::from_value::<GenesisNote>(json).unwrap(), v1);
+        assert_eq!(GenesisNote::from_wire(&v1.to_wire()).unwrap(), v1);
+        for (s, version) in [
+            ("v1", GenesisCommitmentVersion::V1),
+            ("v2", GenesisCommitmentVersion::V2),
+        ] {
+            assert_eq!(s.parse::<GenesisCommitmentVersion>().unwrap(), version);
+            assert_eq!(version.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_genesis_block_serialization() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let chain = ChainVariables::new(42, VERIF_CRS.clone(), 1);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let records = Arc::new(vec![GenesisNote::faucet_record(
+            &mut rng,
+            &owner,
+            AssetDefinition::native(),
+            Amount::from(1000u64),
+        )]);
+        let stake_table: BTreeMap<_, _> = (0..3)
+            .map(|_| (StakingKey::generate(&mut rng).0, Amount::from(10u64)))
+            .collect();
+        let note = GenesisNote::new(chain, records, stake_table);
+
+        for version in [GenesisCommitmentVersion::V1, GenesisCommitmentVersion::V2] {
+            let block = ElaboratedBlock::genesis(note.clone().with_commitment_version(version));
+            let commit = block.commit();
+
+            // The block keeps its commitment version, and so its hash, through every encoding.
+            let json: ElaboratedBlock =
+                serde_json::from_str(&serde_json::to_string(&block).unwrap()).unwrap();
+            let bin: ElaboratedBlock =
+                bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+            let ark: ElaboratedBlock =
+                canonical::deserialize(&canonical::serialize(&block).unwrap()).unwrap();
+            for block in [json, bin, ark] {
+                assert_eq!(
+                    block.block.0[0],
+                    EspressoTransaction::Genesis(note.clone().with_commitment_version(version))
+                );
+                assert_eq!(block.commit(), commit);
+            }
+        }
+
+        // V1 genesis transactions encode exactly as they did before notes had versions, so blocks
+        // stored by older nodes still deserialize.
+        let v1 = note.with_commitment_version(GenesisCommitmentVersion::V1);
+        let txn = EspressoTransaction::Genesis(v1.clone());
+        assert_eq!(
+            bincode::serialize(&txn).unwrap(),
+            [
+                bincode::serialize(&0u32).unwrap(),
+                bincode::serialize(&LegacyGenesisNoteRef::from(&v1)).unwrap(),
+            ]
+            .concat()
+        );
+        assert_eq!(
+            canonical::serialize(&txn).unwrap(),
+            [vec![2], canonical::serialize(&v1).unwrap()].concat()
+        );
+    }
+
+    #[test]
+    fn test_genesis_wire_format() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let owner = UserKeyPair::generate(&mut rng).pub_key();
+        let record = GenesisNote::faucet_record(
+            &mut rng,
+            &owner,
+            AssetDefinition::native(),
+            Amount::from(1000u64),
+        );
+        let note = GenesisNote::new(
+            ChainVariables::new(42, VERIF_CRS.clone(), 1),
+            Arc::new(vec![record]),
+            BTreeMap::new(),
+        );
+
+        // A valid note round trips.
+        let bytes = note.to_wire();
+        assert_eq!(&bytes[..4], b"ESGN");
+        assert_eq!(bytes[4], GenesisNote::WIRE_VERSION);
+        assert_eq!(GenesisNote::from_wire(&bytes).unwrap(), note);
+
+        // Wrong magic.
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        assert!(matches!(
+            GenesisNote::from_wire(&bad_magic),
+            Err(GenesisError::InvalidWireFormat { reason }) if reason.contains("magic")
+        ));
+
+        // Unsupported version.
+        let mut bad_version = bytes.clone();
+        bad_version[4] = GenesisNote::WIRE_VERSION + 1;
+        assert!(matches!(
+            GenesisNote::from_wire(&bad_version),
+            Err(GenesisError::InvalidWireFormat { reason }) if reason.contains("version")
+        ));
+
+        // Version 1 has no commitment version, and uses the default.
+        let note_bytes = canonical::serialize(&note).unwrap();
+        let v1 = [
+            &GenesisNote::WIRE_MAGIC[..],
+            &[1],
+            &(note_bytes.len() as u64).to_le_bytes(),
+            &note_bytes,
+        ]
+        .concat();
+        assert_eq!(GenesisNote::from_wire(&v1).unwrap(), note);
+
+        // Truncated, either in the body or in the header itself.
+        assert!(matches!(
+            GenesisNote::from_wire(&bytes[..bytes.len() - 1]),
+            Err(GenesisError::InvalidWireFormat { reason }) if reason.contains("length prefix")
+        ));
+        assert!(matches!(
+            GenesisNote::from_wire(&bytes[..6]),
+            Err(GenesisError::InvalidWireFormat { reason }) if reason.contains("header")
+        ));
+    }
+
+    #[test]
+    fn test_merge_stake_tables() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let keys = (0..3)
+            .map(|_| StakingKey::generate(&mut rng).0)
+            .collect::<Vec<_>>();
+        let table = |entries: &[(usize, u128)]| {
+            entries
+                .iter()
+                .map(|(i, amount)| (keys[*i].clone(), Amount::from(*amount)))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        // Disjoint tables are unioned under every policy.
+        for policy in [
+            StakeMergePolicy::Sum,
+            StakeMergePolicy::RequireEqual,
+            StakeMergePolicy::Reject,
+        ] {
+            assert_eq!(
+                GenesisNote::merge_stake_tables(table(&[(0, 10)]), table(&[(1, 20)]), policy)
+                    .unwrap(),
+                table(&[(0, 10), (1, 20)])
+            );
+        }
+
+        // Overlapping keys are summed, or kept if they agree.
+        let a = table(&[(0, 10), (1, 20)]);
+        let b = table(&[(1, 5), (2, 30)]);
+        assert_eq!(
+            GenesisNote::merge_stake_tables(a.clone(), b.clone(), StakeMergePolicy::Sum).unwrap(),
+            table(&[(0, 10), (1, 25), (2, 30)])
+        );
+        assert_eq!(
+            GenesisNote::merge_stake_tables(
+                a.clone(),
+                table(&[(1, 20)]),
+                StakeMergePolicy::RequireEqual
+            )
+            .unwrap(),
+            a
+        );
+
+        // Overlapping keys which the policy does not allow are conflicts.
+        for policy in [StakeMergePolicy::RequireEqual, StakeMergePolicy::Reject] {
+            match GenesisNote::merge_stake_tables(a.clone(), b.clone(), policy) {
+                Err(GenesisError::StakeConflict { key, left, right }) => {
+                    assert_eq!(key, keys[1]);
+                    assert_eq!(left, Amount::from(20u128));
+                    assert_eq!(right, Amount::from(5u128));
+                }
+                res => panic!("expected StakeConflict, got {:?}", res),
+            }
+        }
+
+        // A sum which does not fit in an amount is an error, not a wrapped or saturated sum.
+        match GenesisNote::merge_stake_tables(
+            table(&[(0, 1), (1, u128::MAX)]),
+            table(&[(1, 1)]),
+            StakeMergePolicy::Sum,
+        ) {
+            Err(GenesisError::StakeOverflow { key }) => assert_eq!(key, keys[1]),
+            res => panic!("expected StakeOverflow, got {:?}", res),
+        }
+    }
 }
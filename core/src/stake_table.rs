@@ -19,6 +19,7 @@ use jf_cap::structs::Amount;
 use jf_primitives::signatures::{BLSSignatureScheme, SignatureScheme as _};
 use jf_primitives::vrf::blsvrf::BLSVRFScheme;
 use jf_utils::tagged_blob;
+use lazy_static::lazy_static;
 use rand::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaChaRng;
 use serde::{Deserialize, Serialize};
@@ -100,6 +101,15 @@ impl SignatureKey for StakingKey {
     }
 }
 
+lazy_static! {
+    /// The length of the encoding of a key of [StakingKey::SCHEME].
+    static ref SCHEME_KEY_LEN: usize = StakingKey::generated_from_seed_indexed([0; 32], 0)
+        .0
+        .to_bytes()
+        .0
+        .len();
+}
+
 impl From<&StakingPrivKey> for StakingKey {
     fn from(pk: &StakingPrivKey) -> Self {
         Self::from_private(pk)
@@ -107,6 +117,30 @@ impl From<&StakingPrivKey> for StakingKey {
 }
 
 impl StakingKey {
+    /// The version of the signature scheme used by staking keys.
+    ///
+    /// This is BLS over BLS12-381. Chains declare the scheme their staking keys use in
+    /// [ChainVariables](crate::state::ChainVariables), so that nodes built for a different scheme
+    /// reject the chain's stake table rather than misinterpreting its keys.
+    pub const SCHEME: u16 = 1;
+
+    /// The scheme of a key whose encoding is not that of any scheme we know.
+    ///
+    /// No chain uses this scheme, so such keys are always rejected.
+    pub const UNKNOWN_SCHEME: u16 = 0;
+
+    /// The version of the signature scheme this key uses, derived from its encoding.
+    ///
+    /// The schemes we know encode their keys with different lengths, so the length of the encoding
+    /// identifies the scheme.
+    pub fn scheme(&self) -> u16 {
+        if self.to_bytes().0.len() == *SCHEME_KEY_LEN {
+            Self::SCHEME
+        } else {
+            Self::UNKNOWN_SCHEME
+        }
+    }
+
     pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> (Self, StakingPrivKey) {
         let mut seed = [0; 32];
         rng.fill_bytes(&mut seed);
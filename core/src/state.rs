@@ -21,11 +21,13 @@ pub use crate::util::canonical;
 pub use hotshot_types::data::ViewNumber as ConsensusTime;
 pub use state_comm::LedgerStateCommitment;
 
-use crate::genesis::GenesisNote;
+use crate::genesis::{
+    GenesisCommitmentVersion, GenesisNote, LegacyGenesisNote, LegacyGenesisNoteRef,
+};
 use crate::stake_table::{
     CommittableStakeTableSetCommitment, CommittableStakeTableSetFrontier, StakeTableCommitment,
     StakeTableHash, StakeTableMap, StakeTableSetCommitment, StakeTableSetFrontier,
-    StakeTableSetHistory, StakeTableSetMT,
+    StakeTableSetHistory, StakeTableSetMT, StakingKey,
 };
 
 use crate::state::state_comm::CommittableAmount;
@@ -57,7 +59,7 @@ use std::num::NonZeroU64;
 use std::sync::Arc;
 use typenum::U32;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// A transaction tht can be either a CAP transaction or a collect reward transaction
 pub enum EspressoTransaction {
     Genesis(GenesisNote),
@@ -65,6 +67,56 @@ pub enum EspressoTransaction {
     Reward(Box<CollectRewardNote>),
 }
 
+/// The serde form of an [EspressoTransaction].
+///
+/// Genesis notes committed with [GenesisCommitmentVersion::V1] are serialized as `Genesis`, exactly
+/// as they were before notes had commitment versions, so stored blocks still deserialize, and do so
+/// with the version they were created with. Notes committed with later versions are serialized as
+/// `VersionedGenesis`, which carries the version.
+#[derive(Serialize)]
+#[serde(rename = "EspressoTransaction")]
+enum EspressoTransactionRef<'a> {
+    Genesis(LegacyGenesisNoteRef<'a>),
+    CAP(&'a TransactionNote),
+    Reward(&'a CollectRewardNote),
+    VersionedGenesis(&'a GenesisNote),
+}
+
+/// The owned counterpart of [EspressoTransactionRef], for deserializing.
+#[derive(Deserialize)]
+#[serde(rename = "EspressoTransaction")]
+enum EspressoTransactionSer {
+    Genesis(LegacyGenesisNote),
+    CAP(TransactionNote),
+    Reward(Box<CollectRewardNote>),
+    VersionedGenesis(GenesisNote),
+}
+
+impl Serialize for EspressoTransaction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Genesis(note) if note.commitment_version == GenesisCommitmentVersion::V1 => {
+                EspressoTransactionRef::Genesis(note.into())
+            }
+            Self::Genesis(note) => EspressoTransactionRef::VersionedGenesis(note),
+            Self::CAP(txn) => EspressoTransactionRef::CAP(txn),
+            Self::Reward(note) => EspressoTransactionRef::Reward(note),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for EspressoTransaction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match EspressoTransactionSer::deserialize(deserializer)? {
+            EspressoTransactionSer::Genesis(note) => Self::Genesis(note.into()),
+            EspressoTransactionSer::CAP(txn) => Self::CAP(txn),
+            EspressoTransactionSer::Reward(note) => Self::Reward(note),
+            EspressoTransactionSer::VersionedGenesis(note) => Self::Genesis(note),
+        })
+    }
+}
+
 impl EspressoTransaction {
     pub fn is_genesis(&self) -> bool {
         matches!(self, Self::Genesis(_))
@@ -84,11 +136,22 @@ impl CanonicalSerialize for EspressoTransaction {
                 writer.write_all(&[flag])?;
                 <CollectRewardNote as CanonicalSerialize>::serialize(reward_note, &mut writer)
             }
-            Self::Genesis(genesis_note) => {
+            // As with serde, V1 genesis notes are serialized as they were before versions existed.
+            // Notes with later versions use a different flag, and are followed by their version.
+            Self::Genesis(genesis_note)
+                if genesis_note.commitment_version == GenesisCommitmentVersion::V1 =>
+            {
                 let flag = 2;
                 writer.write_all(&[flag])?;
                 <GenesisNote as CanonicalSerialize>::serialize(genesis_note, &mut writer)
             }
+            Self::Genesis(genesis_note) => {
+                let flag = 3;
+                writer.write_all(&[flag])?;
+                <GenesisNote as CanonicalSerialize>::serialize(genesis_note, &mut writer)?;
+                writer.write_all(&[genesis_note.commitment_version.to_byte()])?;
+                Ok(())
+            }
         }
     }
 
@@ -96,7 +159,12 @@ impl CanonicalSerialize for EspressoTransaction {
         match self {
             Self::CAP(txn) => txn.serialized_size() + 1,
             Self::Reward(reward) => reward.serialized_size() + 1,
-            Self::Genesis(genesis) => genesis.serialized_size() + 1,
+            Self::Genesis(genesis)
+                if genesis.commitment_version == GenesisCommitmentVersion::V1 =>
+            {
+                genesis.serialized_size() + 1
+            }
+            Self::Genesis(genesis) => genesis.serialized_size() + 2,
         }
     }
 }
@@ -116,8 +184,17 @@ impl CanonicalDeserialize for EspressoTransaction {
                 <CollectRewardNote as CanonicalDeserialize>::deserialize(&mut r)?,
             ))),
             2 => Ok(Self::Genesis(
-                <GenesisNote as CanonicalDeserialize>::deserialize(&mut r)?,
+                <GenesisNote as CanonicalDeserialize>::deserialize(&mut r)?
+                    .with_commitment_version(GenesisCommitmentVersion::V1),
             )),
+            3 => {
+                let note = <GenesisNote as CanonicalDeserialize>::deserialize(&mut r)?;
+                let mut version = [0u8; 1];
+                r.read_exact(&mut version)?;
+                let version = GenesisCommitmentVersion::from_byte(version[0])
+                    .ok_or(SerializationError::InvalidData)?;
+                Ok(Self::Genesis(note.with_commitment_version(version)))
+            }
             _ => Err(SerializationError::InvalidData),
         }
     }
@@ -1146,7 +1223,7 @@ impl<T: CanonicalDeserialize> CanonicalDeserialize for ArcSer<T> {
 
 /// Global variables for an Espresso blockchain.
 #[ser_test(ark(false))]
-#[derive(Clone, Debug, Serialize, Deserialize, CanonicalDeserialize, CanonicalSerialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChainVariables {
     /// The version of the protocol this chain is currently using.
     ///
@@ -1166,6 +1243,51 @@ pub struct ChainVariables {
 
     /// Committee size
     pub committee_size: u64,
+
+    /// The version of the signature scheme used by the staking keys of this chain.
+    ///
+    /// See [StakingKey::SCHEME]. This is not serialized, so that chain variables persisted before
+    /// the scheme was recorded still deserialize, and deserialized chain variables use the current
+    /// scheme. For the same reason it is not part of the commitment, which would otherwise change
+    /// when chain variables are deserialized. The genesis wire format ([GenesisNote::to_wire])
+    /// records it, and validators check their stake table against it at startup.
+    #[serde(skip, default = "default_staking_key_scheme")]
+    pub staking_key_scheme: u16,
+}
+
+impl CanonicalSerialize for ChainVariables {
+    fn serialize<W: Write>(&self, mut writer: W) -> Result<(), SerializationError> {
+        self.protocol_version.serialize(&mut writer)?;
+        self.chain_id.serialize(&mut writer)?;
+        self.verif_crs.serialize(&mut writer)?;
+        self.vrf_seed.serialize(&mut writer)?;
+        self.committee_size.serialize(&mut writer)
+    }
+
+    fn serialized_size(&self) -> usize {
+        self.protocol_version.serialized_size()
+            + self.chain_id.serialized_size()
+            + self.verif_crs.serialized_size()
+            + self.vrf_seed.serialized_size()
+            + self.committee_size.serialized_size()
+    }
+}
+
+impl CanonicalDeserialize for ChainVariables {
+    fn deserialize<R: Read>(mut reader: R) -> Result<Self, SerializationError> {
+        Ok(Self {
+            protocol_version: CanonicalDeserialize::deserialize(&mut reader)?,
+            chain_id: CanonicalDeserialize::deserialize(&mut reader)?,
+            verif_crs: CanonicalDeserialize::deserialize(&mut reader)?,
+            vrf_seed: CanonicalDeserialize::deserialize(&mut reader)?,
+            committee_size: CanonicalDeserialize::deserialize(&mut reader)?,
+            staking_key_scheme: default_staking_key_scheme(),
+        })
+    }
+}
+
+fn default_staking_key_scheme() -> u16 {
+    StakingKey::SCHEME
 }
 
 #[tagged_blob("VRFSEED")]
@@ -1232,13 +1354,15 @@ impl<'a> Arbitrary<'a> for ChainVariables {
             verif_crs: VERIF_CRS.clone().into(),
             vrf_seed: u.arbitrary()?,
             committee_size: u.arbitrary()?,
+            staking_key_scheme: StakingKey::SCHEME,
         })
     }
 }
 
 impl PartialEq for ChainVariables {
     fn eq(&self, other: &Self) -> bool {
-        self.commit() == other.commit()
+        // The staking key scheme is not committed to, so compare it separately.
+        self.commit() == other.commit() && self.staking_key_scheme == other.staking_key_scheme
     }
 }
 
@@ -1266,6 +1390,7 @@ impl ChainVariables {
                 .finalize()
                 .into(),
             committee_size,
+            staking_key_scheme: StakingKey::SCHEME,
         }
     }
 }
@@ -1629,6 +1754,23 @@ impl ValidatorState {
         Ok((Block(txns), nullifiers_proofs, verified_rewards_proofs))
     }
 
+    /// Check whether a single transaction would be accepted in the next block, without applying it.
+    ///
+    /// The transaction is validated as if it were the only transaction in a block built on top of
+    /// this state.
+    ///
+    /// # Errors
+    /// Any of the errors returned by [ValidatorState::validate_block_check].
+    pub fn validate_transaction(&self, txn: &ElaboratedTransaction) -> Result<(), ValidationError> {
+        self.validate_block_check(
+            &self.prev_commit_time,
+            self.commit(),
+            Block(vec![txn.txn.clone()]),
+            vec![txn.proofs.clone()],
+        )?;
+        Ok(())
+    }
+
     /// Performs validation for a block, updating the ValidatorState.
     ///
     /// If successful, returns
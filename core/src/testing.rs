@@ -1564,6 +1564,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_transaction() {
+        let mut state = MultiXfrTestState::initialize(
+            [0x7bu8; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 0,
+                    asset_amount: 1,
+                },
+                vec![],
+            ),
+        )
+        .unwrap();
+        let txn = state
+            .generate_transactions(
+                vec![(TestTxSpec::OneInput { rec: 0, key: 1 }, false)],
+                TxnPrintInfo::new_no_time(0, 1),
+            )
+            .unwrap()
+            .remove(0)
+            .transaction;
+        let comm = state.validator.commit();
+
+        // A well-formed transaction passes validation.
+        state.validator.validate_transaction(&txn).unwrap();
+
+        // A transaction with proofs that don't match the transaction type fails.
+        let malformed = ElaboratedTransaction {
+            proofs: EspressoTxnHelperProofs::Genesis,
+            ..txn
+        };
+        assert!(matches!(
+            state.validator.validate_transaction(&malformed),
+            Err(ValidationError::InconsistentHelperProofs)
+        ));
+
+        // Validation does not change the state.
+        assert_eq!(state.validator.commit(), comm);
+    }
+
     #[test]
     fn test_sliding_nullifiers_valid() {
         test_sliding_nullifiers(false);
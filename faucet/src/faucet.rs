@@ -6,42 +6,58 @@
 
 use async_channel as mpmc;
 use async_std::{
-    sync::{Arc, Mutex, RwLock},
-    task::{sleep, spawn, JoinHandle},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    task::{sleep, spawn, spawn_blocking, JoinHandle},
 };
-use atomic_store::{load_store::BincodeLoadStore, AppendLog, AtomicStore, AtomicStoreLoader};
-use clap::Parser;
+use atomic_store::{
+    load_store::{BincodeLoadStore, LoadStore},
+    AppendLog, AtomicStore, AtomicStoreLoader, PersistenceError,
+};
+use clap::{error::ErrorKind, parser::ValueSource, ArgAction, CommandFactory, Parser};
+use cld::ClDuration;
 use espresso_client::{
-    events::EventIndex,
-    hd::Mnemonic,
+    events::{EventIndex, EventSource},
+    hd::{KeyTree, Mnemonic},
     ledger_state::{TransactionStatus, TransactionUID},
     loader::{MnemonicPasswordLogin, RecoveryLoader},
     network::NetworkBackend,
     records::Record,
-    EspressoKeystore, RecordAmount,
+    EspressoKeystore, EspressoKeystoreError, RecordAmount,
 };
 use espresso_core::{ledger::EspressoLedger, universal_params::UNIVERSAL_PARAM};
 use faucet_types::*;
 use futures::{
     channel::mpsc,
-    future::{join_all, FutureExt},
+    future::{join_all, Future, FutureExt},
+    select, select_biased,
     stream::StreamExt,
 };
 use jf_cap::{
     keys::{UserKeyPair, UserPubKey},
     structs::{AssetCode, FreezeFlag},
 };
+use primitive_types::U256;
 use rand::{
     distributions::{Alphanumeric, DistString},
-    SeedableRng,
+    thread_rng, Rng, SeedableRng,
 };
 use rand_chacha::ChaChaRng;
 use reef::traits::Validator;
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::fs;
+use std::iter;
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tide_disco::{App, RequestParams, StatusCode, Url};
 use tracing::{error, info, warn};
 
@@ -56,9 +72,20 @@ pub struct FaucetOptions {
     pub mnemonic: Mnemonic,
 
     /// path to the faucet keystore
+    ///
+    /// Defaults to a directory specific to the faucet's mnemonic under the local data directory. A
+    /// keystore at the default path used by older versions, which was shared by all faucets, is
+    /// moved there on startup.
     #[arg(long = "keystore-path", env = "ESPRESSO_FAUCET_WALLET_STORE_PATH")]
     pub faucet_keystore_path: Option<PathBuf>,
 
+    /// path to the persistent request queue
+    ///
+    /// This allows the queue to live on a different disk than the keystore. Defaults to the
+    /// keystore path.
+    #[arg(long, env = "ESPRESSO_FAUCET_QUEUE_PATH")]
+    pub queue_path: Option<PathBuf>,
+
     /// password on the faucet account keyfile
     #[arg(
         long = "keystore-password",
@@ -71,21 +98,113 @@ pub struct FaucetOptions {
     #[arg(long, env = "ESPRESSO_FAUCET_PORT", default_value = "50079")]
     pub faucet_port: u16,
 
+    /// path of a Unix domain socket to serve on, instead of `faucet-port`
+    ///
+    /// This is useful for sidecar deployments, where the faucet should only be reachable from the
+    /// same host. A socket left at this path by a previous run is replaced.
+    #[arg(long, env = "ESPRESSO_FAUCET_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
     /// override path to API specification
     #[arg(long, env = "ESPRESSO_FAUCET_API_PATH")]
     pub api_path: Option<PathBuf>,
 
     /// size of transfer for faucet grant
+    ///
+    /// This may be a decimal number, with at most `decimals` decimal places.
     #[arg(long, env = "ESPRESSO_FAUCET_GRANT_SIZE", default_value = "5000")]
-    pub grant_size: u64,
+    pub grant_size: DecimalAmount,
+
+    /// path to a TOML file configuring per-asset grant sizes and fees
+    ///
+    /// The file should contain a `[grant_sizes]` table mapping asset codes to the size of each
+    /// grant record of that asset. Each asset listed in the table is granted alongside the native
+    /// asset. Assets which are not listed, including the native asset, use `grant_size`.
+    ///
+    /// The file may also contain a `[fees]` table mapping asset codes to the fee paid for each
+    /// transfer of that asset. Fees are always paid in the native asset. Assets which are not
+    /// listed use `fee_size`.
+    #[arg(long, env = "ESPRESSO_FAUCET_GRANT_SIZES_PATH")]
+    pub grant_sizes_path: Option<PathBuf>,
+
+    /// comma-separated list of the only assets the faucet will dispense
+    ///
+    /// Assets configured in `grant_sizes_path` which are not in this list are not granted, even if
+    /// the faucet holds records of them. The native asset is always allowed, since it is needed to
+    /// pay fees. If not provided, every configured asset is allowed.
+    #[arg(long, env = "ESPRESSO_FAUCET_ALLOWED_ASSETS", value_delimiter = ',')]
+    pub allowed_assets: Vec<AssetCode>,
 
     /// number of grants to give out per request
     #[arg(long, env = "ESPRESSO_FAUCET_NUM_GRANTS", default_value = "5")]
     pub num_grants: usize,
 
+    /// largest amount of the native asset a single request may ask for
+    ///
+    /// Requests may ask for a specific amount, which is granted in records of `grant_size`. The
+    /// amount is rounded up to a whole number of records, or down if rounding up would exceed this
+    /// maximum. If not provided, the maximum is the default grant of `num_grants * grant_size`.
+    #[arg(long, env = "ESPRESSO_FAUCET_MAX_GRANT_PER_REQUEST")]
+    pub max_grant_per_request: Option<u64>,
+
+    /// largest amount of the native asset to dispense in any 24 hour window
+    ///
+    /// Once this much has been granted since the start of the current window, new requests are
+    /// rejected until the window ends, and workers hold any transfer which would take the total
+    /// over the cap until then. The window is fixed rather than rolling: it starts with the first
+    /// grant after the previous window ended, and the whole cap becomes available again 24 hours
+    /// later. The accounting is persisted in the keystore directory, so restarting the faucet does
+    /// not reset the window. If not provided, there is no limit.
+    #[arg(long, env = "ESPRESSO_FAUCET_DAILY_CAP")]
+    pub daily_cap: Option<u64>,
+
+    /// balance of the native asset below which new requests are shed
+    ///
+    /// While the faucet's balance is below this threshold, new requests are rejected with an error
+    /// telling the client when to retry, rather than piling up in the queue behind workers waiting
+    /// for funds. This may be a decimal number, with at most `decimals` decimal places. If not
+    /// provided, requests are accepted regardless of the balance.
+    ///
+    /// Requests are checked against the balance last seen by the workers, the record breaker, or
+    /// the balance poll, which runs every `balance-poll-interval`, so a change in the balance may
+    /// take that long to be reflected.
+    #[arg(long, env = "ESPRESSO_FAUCET_MIN_BALANCE")]
+    pub min_balance: Option<DecimalAmount>,
+
+    /// URL of an upstream faucet from which this faucet refills itself
+    ///
+    /// This is the base URL of the upstream faucet's API, ending in a slash, e.g.
+    /// `http://faucet.example.com/api/`. Every `balance-poll-interval`, if the faucet's balance of
+    /// the native asset is below `upstream-refill-threshold`, the faucet requests a grant for its
+    /// own key from the upstream faucet and waits up to `balance-wait-timeout` for it to arrive. If
+    /// not provided, the faucet never refills itself.
+    #[arg(long, env = "ESPRESSO_FAUCET_UPSTREAM_FAUCET_URL")]
+    pub upstream_faucet_url: Option<Url>,
+
+    /// balance of the native asset below which the faucet requests funds from `upstream-faucet-url`
+    ///
+    /// This may be a decimal number, with at most `decimals` decimal places. If not provided, the
+    /// faucet refills itself once it can no longer pay for a full request of `num-grants` grants.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_UPSTREAM_REFILL_THRESHOLD",
+        requires = "upstream_faucet_url"
+    )]
+    pub upstream_refill_threshold: Option<DecimalAmount>,
+
     /// fee for faucet grant
+    ///
+    /// This may be a decimal number, with at most `decimals` decimal places.
     #[arg(long, env = "ESPRESSO_FAUCET_FEE_SIZE", default_value = "100")]
-    pub fee_size: u64,
+    pub fee_size: DecimalAmount,
+
+    /// number of decimal places of the native asset
+    ///
+    /// Decimal amounts given for `grant_size` and `fee_size` are converted to base units of the
+    /// native asset by shifting them this many places. With the default of 0, amounts are given in
+    /// base units.
+    #[arg(long, env = "ESPRESSO_FAUCET_DECIMALS", default_value = "0")]
+    pub decimals: u8,
 
     /// number of records to maintain simultaneously.
     ///
@@ -102,6 +221,18 @@ pub struct FaucetOptions {
     )]
     pub num_records: usize,
 
+    /// hard cap on the number of grant records to maintain.
+    ///
+    /// If `num_records` is larger than this, it is reduced to this value. This prevents a
+    /// misconfigured `num_records` from causing the faucet to spend a very long time breaking up
+    /// records at startup.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_MAX_GRANT_RECORDS",
+        default_value = "1000"
+    )]
+    pub max_grant_records: usize,
+
     /// URL for the Espresso Query Service.
     #[arg(
         long,
@@ -126,1092 +257,9231 @@ pub struct FaucetOptions {
     )]
     pub submit_url: Url,
 
+    /// timeout for requests to the query service, address book, and validator
+    ///
+    /// Requests which take longer fail, instead of hanging the faucet. This also bounds how long
+    /// the faucet waits for the query service to become available at startup.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_UPSTREAM_TIMEOUT",
+        default_value = "300s",
+        value_parser = parse_duration
+    )]
+    pub upstream_timeout: Duration,
+
     /// Maximum number of outstanding requests to allow in the queue.
     ///
     /// If not provided, the queue can grow arbitrarily large.
     #[arg(long, env = "ESPRESSO_FAUCET_MAX_QUEUE_LENGTH")]
     pub max_queue_len: Option<usize>,
 
+    /// How long the queue may stay full before the faucet escalates.
+    ///
+    /// A queue which is persistently at `max-queue-len` means the faucet is either being abused or
+    /// does not have enough workers. Once requests have been rejected for a full queue for this
+    /// long, the faucet logs an error and, if `alert-url` is set, posts a `QueueAlert` to it. The
+    /// faucet escalates once each time the queue fills up. If not provided, the faucet never
+    /// escalates.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_QUEUE_FULL_ALERT_AFTER",
+        value_parser = parse_duration
+    )]
+    pub queue_full_alert_after: Option<Duration>,
+
+    /// URL to which alerts are posted as JSON, such as when the queue stays full
+    #[arg(long, env = "ESPRESSO_FAUCET_ALERT_URL")]
+    pub alert_url: Option<Url>,
+
+    /// Number of stale entries in the persistent queue log which triggers compaction.
+    ///
+    /// Every change to the request queue is appended to a log on disk, so the log grows without
+    /// bound as requests are added and removed. If the log contains more than this many entries for
+    /// requests which have already been removed, it is rewritten at startup to contain only the
+    /// requests which are still in the queue.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_QUEUE_COMPACTION_THRESHOLD",
+        default_value = "10000"
+    )]
+    pub queue_compaction_threshold: usize,
+
+    /// Serialization format for the persistent request queue, either `bincode` or `cbor`.
+    ///
+    /// CBOR is self-describing, so a queue written in CBOR can still be read after the layout of
+    /// the queue entries changes. The format of an existing queue cannot be changed: the faucet
+    /// refuses to start if the configured format differs from the one the queue was written in.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_QUEUE_LOG_FORMAT",
+        default_value = "bincode"
+    )]
+    pub queue_log_format: QueueLogFormat,
+
     /// Number of worker threads.
     ///
     /// It is a good idea to configure the faucet so that this is the same as
     /// `num_records / num_grants`.
     #[arg(long, env = "ESPRESSO_FAUCET_NUM_WORKERS", default_value = "5")]
     pub num_workers: usize,
-}
 
-impl FaucetOptions {
-    fn keystore_path(&self) -> PathBuf {
-        self.faucet_keystore_path.clone().unwrap_or_else(|| {
-            dirs::data_local_dir()
-                .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("./")))
-                .join(".espresso")
-                .join("espresso")
-                .join("faucet")
-                .join("keystore")
-        })
-    }
-}
+    /// Derive the number of worker threads from the number of records and grants.
+    ///
+    /// If set, `num_workers` is ignored, and the faucet uses `num_records / num_grants` workers.
+    #[arg(long, env = "ESPRESSO_FAUCET_AUTO_WORKERS")]
+    pub auto_workers: bool,
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
-pub enum FaucetStatus {
-    Initializing,
-    Available,
-}
+    /// Generate a proof at startup, before accepting requests.
+    ///
+    /// The first proof generated by the faucet is much slower than subsequent ones. With this
+    /// option, the faucet pays that cost before it becomes available, instead of making the first
+    /// request wait.
+    ///
+    /// The proof is for a real transaction: on every start, the faucet transfers one grant-sized
+    /// record to itself with a fee of 0, and waits for the transaction to complete while holding
+    /// the keystore lock. Startup takes at least one block longer, and no other task can use the
+    /// keystore until the transfer is final.
+    #[arg(long, env = "ESPRESSO_FAUCET_PREWARM")]
+    pub prewarm: bool,
 
-#[derive(Clone)]
-struct FaucetState {
-    keystore: Arc<Mutex<EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>>>,
-    status: Arc<RwLock<FaucetStatus>>,
-    queue: FaucetQueue,
-    grant_size: RecordAmount,
-    num_grants: usize,
-    fee_size: RecordAmount,
-    num_records: usize,
-    // Channel to signal when the distribution of records owned by the faucet changes. This will
-    // wake the record breaker thread (which waits on the receiver) so it can create more records by
-    // breaking up larger ones to maintain the target of `num_records`.
-    //
-    // We use a bounded channel so that a crashed or deadlocked record breaker thread that is not
-    // pulling messages out of the queue does not result in an unbounded memory leak.
-    signal_breaker_thread: mpsc::Sender<()>,
-}
+    /// Token authorizing privileged requests, such as high priority grants.
+    ///
+    /// Privileged requests must include the header `Authorization: Bearer <token>`. If no token is
+    /// set, privileged requests are always rejected.
+    #[arg(long, env = "ESPRESSO_FAUCET_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
 
-impl FaucetState {
-    pub async fn new(
-        keystore: EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>,
-        signal_breaker_thread: mpsc::Sender<()>,
-        opt: &FaucetOptions,
-    ) -> Result<Self, FaucetError> {
-        Ok(Self {
-            keystore: Arc::new(Mutex::new(keystore)),
-            status: Arc::new(RwLock::new(FaucetStatus::Initializing)),
-            queue: FaucetQueue::load(&opt.keystore_path(), opt.max_queue_len).await?,
-            grant_size: opt.grant_size.into(),
-            num_grants: opt.num_grants,
-            fee_size: opt.fee_size.into(),
-            num_records: opt.num_records,
-            signal_breaker_thread,
-        })
-    }
-}
+    /// Serve a status report for operators at `/api/status`, and aggregate stats at `/api/stats`.
+    ///
+    /// The report includes the faucet status, the number of queued requests, and the balance of the
+    /// faucet. The stats add the totals granted since the faucet started, for dashboards.
+    ///
+    /// The report is JSON rather than an HTML page at `/`, because the web framework serves its
+    /// generated API documentation at `/` and only serializes the responses of API routes, so
+    /// there is no way to serve a page of our own there.
+    #[arg(long, env = "ESPRESSO_FAUCET_STATUS_PAGE")]
+    pub status_page: bool,
 
-/// A shared, asynchronous queue of requests.
-///
-/// The queue is a model of an ordered map from public keys requesting assets to the number of
-/// record grants they have received. It is represented as an explicit `HashMap`, which is the
-/// authoritative data structure, as well as an auxiliary, implicit queue in the form of an
-/// unbounded multi-producer, multi-consumer channel.
-///
-/// When a new request comes in, it can be added to the queue with [FaucetQueue::push]. This will
-/// perform validity checks and then add a new entry mapping the public key to 0. It will also send
-/// the public key as a message on the channel. A worker thread will then pick the message off the
-/// channel using [FaucetQueue::pop], and start generating transfers to it. Each time the worker
-/// completes a transfer to the public key, it will call [FaucetQueue::grant], which increments the
-/// counter associated with that public key, persists the change, and instructs the worker to
-/// either continue transferring to the same key or to move on to the next key.
-///
-/// The queue is persistent, so that if the faucet crashes or gets restarted, it doesn't lose the
-/// queue of pending requests. The persistent queue is represented as a log of index entries, of the
-/// form `UserPubKey -> Option<usize>`. An entry `key -> Some(n)` corresponds to updating the
-/// counter associated with `key` to `n`. An entry `key -> None` corresponds to deleting the entry
-/// for `key`. We can recover the in-memory index by simply replaying each log entry and inserting
-/// or deleting into a `HashMap` as indicated.
-///
-/// Note that the persistent data format also encodes the order in which requests were added to the
-/// queue. A new request being added to the queue corresponds to an entry `key -> Some(0)`, so the
-/// queue simply consists of the most recent `key -> Some(0)` entry for each key, in order,
-/// filtering out keys that have a more recent `key -> None` entry.
-#[derive(Clone)]
-struct FaucetQueue {
-    sender: mpmc::Sender<(UserPubKey, usize)>,
-    receiver: mpmc::Receiver<(UserPubKey, usize)>,
-    index: Arc<Mutex<FaucetQueueIndex>>,
-    max_len: Option<usize>,
-}
+    /// Start as a warm standby for another faucet.
+    ///
+    /// A standby keeps its keystore in sync with the ledger, but does not accept requests, break
+    /// up records or make grants until an operator promotes it with `POST /api/promote`. Until
+    /// then, it only reads the request queue at `queue-path`, which should point at the queue of
+    /// the faucet it is standing in for, and never writes to it. On promotion, it loads the queue
+    /// for writing and starts processing. The other faucet should be stopped before promoting the
+    /// standby, since they share the faucet's records and queue.
+    #[arg(long, env = "ESPRESSO_FAUCET_STANDBY")]
+    pub standby: bool,
 
-// A persistent ordered set.
-struct FaucetQueueIndex {
-    index: HashMap<UserPubKey, usize>,
-    store: AtomicStore,
-    queue: AppendLog<BincodeLoadStore<(UserPubKey, Option<usize>)>>,
-}
+    /// Keep a persistent history of every grant, and serve it to operators at
+    /// `/api/history/:pubkey`.
+    ///
+    /// The history is stored in the keystore directory and is never truncated.
+    #[arg(long, env = "ESPRESSO_FAUCET_GRANT_HISTORY")]
+    pub grant_history: bool,
 
-impl FaucetQueueIndex {
-    fn len(&self) -> usize {
-        self.index.len()
-    }
+    /// Do not break up records to maintain `num_records`.
+    ///
+    /// This is useful for a faucet which already owns enough records of a suitable size, for
+    /// example from a genesis block with pre-split records. Grants are made from existing records
+    /// only, and no transactions are spent splitting records.
+    #[arg(long, env = "ESPRESSO_FAUCET_NO_BREAKER")]
+    pub no_breaker: bool,
 
-    /// Add an element to the persistent index.
+    /// Wait for each grant to be confirmed before counting it towards a request.
     ///
-    /// Returns `true` if the element was inserted or `false` if it was already in the index.
-    fn insert(&mut self, key: UserPubKey) -> Result<bool, FaucetError> {
-        if self.index.contains_key(&key) {
-            // If the key is already in the index, we don't have to persist anything.
-            return Ok(false);
-        }
+    /// By default, a grant counts as soon as its transaction is submitted. With this option, the
+    /// worker waits until the transaction is retired, and retries the grant if the transaction is
+    /// rejected. This makes requests slower to complete, but a request is only marked complete
+    /// once its grants are final.
+    #[arg(long, env = "ESPRESSO_FAUCET_CONFIRM_GRANTS")]
+    pub confirm_grants: bool,
 
-        // Add the key to our persistent log.
-        self.queue
-            .store_resource(&(key.clone(), Some(0)))
-            .map_err(|err| {
-                error!("storage error adding {} to queue: {}", key, err);
-                err
-            })?;
-        self.queue.commit_version().unwrap();
-        self.store.commit_version().unwrap();
-        // If successful, add it to our in-memory index.
-        self.index.insert(key, 0);
-        Ok(true)
-    }
+    /// Break up records only into whole multiples of the grant size plus the fee.
+    ///
+    /// By default, the record breaker splits records in half, which leaves the faucet holding
+    /// records of odd sizes, and grants paid from them return odd amounts of change. With this
+    /// option, each record created by breaking up a larger one is worth exactly the cost of some
+    /// number of grants, so a grant can spend a single record of exactly the right size and leave
+    /// no change. At most one record, holding the remainder, is of any other size.
+    #[arg(long, env = "ESPRESSO_FAUCET_EXACT_DENOMINATIONS")]
+    pub exact_denominations: bool,
 
-    /// Increment the number of grants received by an element in the index.
+    /// Longest time a worker waits for another request to share a transfer with.
     ///
-    /// If the new number of grants is at least `max_grants`, the entry is removed from the index.
-    /// Otherwise, the counter is simply updated.
+    /// Each transfer can create two grant records. When a worker has a request which is owed only
+    /// one more grant, it waits up to this long for another pending request, and grants to both
+    /// recipients in a single transaction. This improves throughput when many requests arrive at
+    /// once, at the cost of some latency for the first request. A window of `0s`, the default,
+    /// disables batching.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_BATCH_WINDOW",
+        default_value = "0s",
+        value_parser = parse_duration
+    )]
+    pub batch_window: Duration,
+
+    /// Maximum number of workers which may grant to the same recipient at once.
     ///
-    /// Returns `true` if this key needs more grants.
-    fn grant(
-        &mut self,
-        key: UserPubKey,
-        granted: usize,
-        max_grants: usize,
-    ) -> Result<bool, FaucetError> {
-        let grants_given = self.index[&key] + granted;
-        if grants_given >= max_grants {
-            // If this is the last grant to this key, remove it from the index.
-            self.remove(&key)?;
-            Ok(false)
-        } else {
-            // Update the entry in our persistent log.
-            self.queue
-                .store_resource(&(key.clone(), Some(grants_given)))
-                .map_err(|err| {
-                    error!("storage error updating {} in queue: {}", key, err);
-                    err
-                })?;
-            self.queue.commit_version().unwrap();
-            self.store.commit_version().unwrap();
-            // If successful, update our in-memory index.
-            self.index.insert(key, grants_given);
-            Ok(true)
-        }
-    }
+    /// A worker refreshes its count of a recipient's grants once it is allowed to work on the
+    /// recipient, so with the default of 1, a recipient is never granted more than it is owed,
+    /// however its requests are scheduled. Higher limits let several workers share a recipient which
+    /// is owed many grants, but workers racing on the same recipient may grant it more than it is
+    /// owed.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_MAX_WORKERS_PER_RECIPIENT",
+        default_value = "1"
+    )]
+    pub max_workers_per_recipient: usize,
 
-    /// Remove an element from the persistent set.
-    fn remove(&mut self, key: &UserPubKey) -> Result<(), FaucetError> {
-        // Make a persistent note to remove the key.
-        self.queue
-            .store_resource(&(key.clone(), None))
-            .map_err(|err| {
-                error!("storage error removing {} from queue: {}", key, err);
-                err
-            })?;
-        self.queue.commit_version().unwrap();
-        self.store.commit_version().unwrap();
-        // Update our in-memory set.
-        self.index.remove(key);
-        Ok(())
-    }
+    /// How long a worker waits for the keystore lock before reporting a possible deadlock.
+    ///
+    /// Workers keep waiting for the lock after the timeout, but log an error each time the timeout
+    /// elapses, so that a stuck transfer or a lock which is never released is easy to spot.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_LOCK_TIMEOUT",
+        default_value = "5m",
+        value_parser = parse_duration
+    )]
+    pub lock_timeout: Duration,
 
-    /// Get the number of grants already given to this key.
-    fn grants(&self, key: &UserPubKey) -> usize {
-        self.index[key]
-    }
-}
+    /// How long to let workers finish the requests they are working on when shutting down.
+    ///
+    /// On SIGINT or SIGTERM, the faucet stops taking new requests and waits up to this long for
+    /// each worker to finish its current request before stopping. Requests which are not finished
+    /// in time stay in the queue and are granted after the faucet restarts.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_SHUTDOWN_TIMEOUT",
+        default_value = "30s",
+        value_parser = parse_duration
+    )]
+    pub shutdown_timeout: Duration,
 
-impl FaucetQueue {
-    async fn load(store: &Path, max_len: Option<usize>) -> Result<Self, FaucetError> {
-        // Load from storage.
-        let mut loader = AtomicStoreLoader::load(store, "queue")?;
-        let persistent_queue = AppendLog::load(&mut loader, Default::default(), "requests", 1024)?;
-        let store = AtomicStore::open(loader)?;
+    /// The largest number of keys allowed in a single group request.
+    ///
+    /// A group request grants to several keys as a unit: the request only counts as complete once
+    /// every key in the group has received its grants, and if any transfer to the group fails, the
+    /// whole group goes back to the queue. A limit below 2 disables group requests.
+    #[arg(long, env = "ESPRESSO_FAUCET_MAX_GROUP_SIZE", default_value = "8")]
+    pub max_group_size: usize,
 
-        // Traverse the persisted queue entries backwards. This ensures that we encounter the most
-        // recent value for each key first. If the most recent value for a given key is `Some(n)`,
-        // it gets added to the index. If it is `None`, we just store `None` in `index` so that if
-        // we see this key again, we know we are not seeing the most recent value.
-        let mut index = HashMap::new();
-        // In addition, for the most recent `Some(0)` entry for each `key`, we also add that key to
-        // the message channel, as long as there is not a more recent `None` entry. We use the set
-        // `processed` to keep track of which elements have already been processed into the message
-        // channel if necessary. An element is `processed` if we have added it to the message
-        // channel, or if we have encountered a `None` entry for it and skipped it.
-        let mut processed = HashSet::new();
-        // We are encountering requests in reverse order, so if we need to add them to the queue, we
-        // will add them to this [Vec] and then reverse it at the end before adding them to the
-        // message channel.
-        let mut queue = Vec::new();
-        let entries: Vec<(UserPubKey, Option<usize>)> =
-            persistent_queue.iter().collect::<Result<_, _>>()?;
-        for (key, val) in entries.into_iter().rev() {
-            if !index.contains_key(&key) {
-                if let Some(val) = val {
-                    // This is the most recent value for `key`, and it is an insert, which means
-                    // `key` is in the queue. Go ahead and add it to the index and the message
-                    // channel.
-                    index.insert(key.clone(), Some(val));
-                } else {
-                    // This is the most recent value for `key`, and it is a delete, which means
-                    // `key` is not in the queue. Remember this information in `index`.
-                    index.insert(key.clone(), None);
-                }
-            }
+    /// Rescan the ledger for the faucet key from the beginning on startup.
+    ///
+    /// Normally the keystore resumes scanning from where it left off, which is logged and saved
+    /// to the `scan_checkpoint` file in the keystore directory every `checkpoint-interval`. A full
+    /// rescan may be needed if the keystore's view of the ledger is wrong, for example after a
+    /// reorg. The faucet is unavailable until the rescan completes.
+    #[arg(long, env = "ESPRESSO_FAUCET_RESET_SCAN")]
+    pub reset_scan: bool,
 
-            if !processed.contains(&key) {
-                // We have seen neither a `Some(0)` or `None` entry for this element.
-                if val == Some(0) {
-                    // In the case of a `Some(0)` entry, the element should be in the queue.
-                    queue.push(key.clone());
-                    processed.insert(key);
-                } else if val == None {
-                    // In the case of a `None` entry, just add the element to `processed` so that it
-                    // will not be added to the queue later.
-                    processed.insert(key);
-                }
-            }
-        }
+    /// How often to save the keystore's scan checkpoint.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_CHECKPOINT_INTERVAL",
+        default_value = "1m",
+        value_parser = parse_duration
+    )]
+    pub checkpoint_interval: Duration,
 
-        // Post-process `index` to remove [None] values.
-        let index = index
-            .into_iter()
-            .filter_map(|(key, val)| val.map(|val| (key, val)))
-            .collect::<HashMap<_, _>>();
+    /// Directory in which to keep a backup of the keystore directory.
+    ///
+    /// Every `backup-interval`, the keystore directory is copied to `keystore` in this directory,
+    /// replacing the previous backup. If the request queue is stored outside the keystore
+    /// directory (see `queue-path`), it is copied to `queue` in this directory. Every writer to
+    /// these directories is paused while they are copied, so the backup is consistent. To restore,
+    /// point `keystore-path` at the `keystore` backup, and `queue-path` at the `queue` backup if
+    /// there is one. If not provided, no backups are made.
+    #[arg(long, env = "ESPRESSO_FAUCET_BACKUP_DIR")]
+    pub backup_dir: Option<PathBuf>,
 
-        let (sender, receiver) = mpmc::unbounded();
-        for key in queue.into_iter().rev() {
-            let grants = index[&key];
-            // `send` only fails if the receiving end of the channel has been dropped, but we have
-            // the receiving end right now, so this `unwrap` will never fail.
-            sender.send((key, grants)).await.unwrap();
-        }
+    /// How often to back up the keystore to `backup-dir`.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_BACKUP_INTERVAL",
+        default_value = "1h",
+        value_parser = parse_duration
+    )]
+    pub backup_interval: Duration,
 
-        Ok(Self {
-            index: Arc::new(Mutex::new(FaucetQueueIndex {
-                index,
-                queue: persistent_queue,
-                store,
-            })),
-            sender,
-            receiver,
-            max_len,
-        })
-    }
+    /// Longest time to wait at startup for the faucet to have a nonzero balance.
+    ///
+    /// If the network is slow to deliver the faucet's records, the balance may read zero right
+    /// after the keystore finishes scanning. The faucet polls its balance every
+    /// `balance-poll-interval` until it is nonzero, so that it does not split records or start
+    /// serving requests with an empty keystore. If the balance is still zero after this long, the
+    /// faucet starts anyway.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_BALANCE_WAIT_TIMEOUT",
+        default_value = "5m",
+        value_parser = parse_duration
+    )]
+    pub balance_wait_timeout: Duration,
 
-    async fn push(&self, key: UserPubKey) -> Result<(), FaucetError> {
-        {
-            // Try to insert this key into the index.
-            let mut index = self.index.lock().await;
-            if let Some(max_len) = self.max_len {
-                if index.len() >= max_len {
-                    warn!("rejecting {} because queue is full ({})", key, max_len);
-                    return Err(FaucetError::QueueFull { max_len });
+    /// How often to poll the faucet's balance while waiting for it to be nonzero at startup.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_BALANCE_POLL_INTERVAL",
+        default_value = "5s",
+        value_parser = parse_duration
+    )]
+    pub balance_poll_interval: Duration,
+
+    /// URL to which a copy of every grant is posted, for analytics
+    ///
+    /// Grants are posted as JSON arrays of `GrantEvent`, in batches of up to `observer-batch-size`
+    /// events. A partial batch is posted once its first event is `observer-flush-interval` old.
+    /// Delivery is best effort: grants never wait for the observer, and events which cannot be
+    /// delivered are logged and dropped.
+    #[arg(long, env = "ESPRESSO_FAUCET_OBSERVER_URL")]
+    pub observer_url: Option<Url>,
+
+    /// maximum number of grant events in each batch posted to `observer-url`
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_OBSERVER_BATCH_SIZE",
+        default_value = "100"
+    )]
+    pub observer_batch_size: usize,
+
+    /// longest time to hold a grant event before posting it to `observer-url`
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_OBSERVER_FLUSH_INTERVAL",
+        default_value = "10s",
+        value_parser = parse_duration
+    )]
+    pub observer_flush_interval: Duration,
+
+    /// path to a TOML file of faucet options
+    ///
+    /// Keys are the names of the command line options, e.g. `grant-size = "5000"` or
+    /// `no-breaker = true`. Options given on the command line or in the environment override the
+    /// file, which overrides the defaults.
+    #[arg(long, env = "ESPRESSO_FAUCET_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+impl FaucetOptions {
+    /// Parse options from `args`, filling in any which are not given from the `--config` file.
+    pub fn try_parse_layered<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let mut args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+        let mut command = Self::command();
+        // Parse leniently the first time around, since required options may be in the file.
+        let matches = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)?;
+        if let Some(path) = matches.get_one::<PathBuf>("config") {
+            let bytes = fs::read(path).map_err(|err| {
+                command.error(
+                    ErrorKind::Io,
+                    format!("unable to read {}: {}", path.display(), err),
+                )
+            })?;
+            let file: toml::value::Table = toml::from_slice(&bytes).map_err(|err| {
+                command.error(
+                    ErrorKind::InvalidValue,
+                    format!("malformed config file {}: {}", path.display(), err),
+                )
+            })?;
+            for (key, value) in file {
+                let long = key.replace('_', "-");
+                let arg = match command
+                    .get_arguments()
+                    .find(|arg| {
+                        arg.get_long() == Some(long.as_str()) || arg.get_id() == key.as_str()
+                    })
+                    .cloned()
+                {
+                    Some(arg) if arg.get_id() != "config" => arg,
+                    _ => {
+                        return Err(command.error(
+                            ErrorKind::UnknownArgument,
+                            format!("unknown option {} in config file {}", key, path.display()),
+                        ))
+                    }
+                };
+                // Options given explicitly take precedence over the file.
+                if matches!(
+                    matches.value_source(arg.get_id().as_str()),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                ) {
+                    continue;
+                }
+                let flag = format!("--{}", arg.get_long().unwrap_or(long.as_str()));
+                let values = match value {
+                    toml::Value::Array(values) => values,
+                    value => vec![value],
+                };
+                for value in values {
+                    match value {
+                        toml::Value::Boolean(set)
+                            if matches!(arg.get_action(), ArgAction::SetTrue) =>
+                        {
+                            if set {
+                                args.push(flag.clone().into());
+                            }
+                        }
+                        toml::Value::String(value) => {
+                            args.push(format!("{}={}", flag, value).into())
+                        }
+                        toml::Value::Integer(_) | toml::Value::Float(_) => {
+                            args.push(format!("{}={}", flag, value).into())
+                        }
+                        value => {
+                            return Err(command.error(
+                                ErrorKind::InvalidValue,
+                                format!(
+                                    "invalid value {} for {} in config file {}",
+                                    value,
+                                    key,
+                                    path.display()
+                                ),
+                            ))
+                        }
+                    }
                 }
-            }
-            if !index.insert(key.clone())? {
-                warn!("rejecting {} because it is already in the queue", key);
-                return Err(FaucetError::AlreadyInQueue { key });
             }
         }
-        // If we successfully added the key to the index, we can send it to a receiver.
-        if self.sender.send((key, 0)).await.is_err() {
-            warn!("failed to add request to the queue: channel is closed");
+        Self::try_parse_from(args)
+    }
+
+    /// The path to the faucet keystore.
+    ///
+    /// The default path includes an identifier derived from the mnemonic, so that distinct faucets
+    /// running on the same host do not share a keystore.
+    fn keystore_path(&self) -> PathBuf {
+        self.faucet_keystore_path.clone().unwrap_or_else(|| {
+            default_faucet_dir()
+                .join(faucet_id(&self.mnemonic))
+                .join("keystore")
+        })
+    }
+
+    /// Move a keystore from the default path shared by all faucets, which older versions used, to
+    /// the default path for this faucet.
+    ///
+    /// This keeps the keystore, and the queue and other state stored alongside it, when a faucet
+    /// using the default path is upgraded. It does nothing if an explicit keystore path is given.
+    fn migrate_legacy_keystore(&self) -> std::io::Result<()> {
+        if self.faucet_keystore_path.is_none() {
+            migrate_keystore(
+                &default_faucet_dir().join("keystore"),
+                &self.keystore_path(),
+            )?;
         }
         Ok(())
     }
 
-    async fn pop(&mut self) -> Option<(UserPubKey, usize)> {
-        let req = self.receiver.next().await?;
-        Some(req)
+    /// The path to the persistent request queue.
+    fn queue_path(&self) -> PathBuf {
+        self.queue_path
+            .clone()
+            .unwrap_or_else(|| self.keystore_path())
     }
 
-    async fn grant(&mut self, request: UserPubKey, granted: usize, max_grants: usize) -> bool {
-        self.index
-            .lock()
-            .await
-            .grant(request, granted, max_grants)
-            .unwrap_or(false)
+    fn grant_sizes_config(&self) -> Result<GrantSizesConfig, FaucetError> {
+        let path = match &self.grant_sizes_path {
+            Some(path) => path,
+            None => return Ok(GrantSizesConfig::default()),
+        };
+        let bytes = fs::read(path).map_err(|err| FaucetError::Config {
+            msg: format!("unable to read {}: {}", path.display(), err),
+        })?;
+        toml::from_slice(&bytes).map_err(|err| FaucetError::Config {
+            msg: format!("malformed grant sizes file {}: {}", path.display(), err),
+        })
     }
 
-    async fn fail(&mut self, key: UserPubKey) {
-        let grants = { self.index.lock().await.grants(&key) };
-        if let Err(err) = self.sender.send((key, grants)).await {
-            error!(
-                "error re-adding failed request; request will be dropped. {}",
-                err
-            );
+    /// Log the effective configuration as a single structured event.
+    ///
+    /// Secrets (the mnemonic, the keystore password, and the admin token) are redacted. Paths are
+    /// logged after defaults have been resolved.
+    fn log_config(&self) {
+        const REDACTED: &str = "<redacted>";
+        info!(
+            mnemonic = REDACTED,
+            keystore_path = %self.keystore_path().display(),
+            queue_path = %self.queue_path().display(),
+            keystore_password = REDACTED,
+            faucet_port = self.faucet_port,
+            unix_socket = ?self.unix_socket,
+            api_path = ?self.api_path,
+            grant_size = %self.grant_size,
+            grant_sizes_path = ?self.grant_sizes_path,
+            allowed_assets = ?self.allowed_assets,
+            num_grants = self.num_grants,
+            max_grant_per_request = ?self.max_grant_per_request,
+            daily_cap = ?self.daily_cap,
+            min_balance = ?self.min_balance,
+            upstream_faucet_url = ?self.upstream_faucet_url.as_ref().map(Url::as_str),
+            upstream_refill_threshold = ?self.upstream_refill_threshold,
+            fee_size = %self.fee_size,
+            decimals = self.decimals,
+            num_records = self.num_records,
+            max_grant_records = self.max_grant_records,
+            esqs_url = %self.esqs_url,
+            address_book_url = %self.address_book_url,
+            submit_url = %self.submit_url,
+            upstream_timeout = ?self.upstream_timeout,
+            max_queue_len = ?self.max_queue_len,
+            queue_full_alert_after = ?self.queue_full_alert_after,
+            alert_url = ?self.alert_url.as_ref().map(Url::as_str),
+            queue_compaction_threshold = self.queue_compaction_threshold,
+            queue_log_format = %self.queue_log_format,
+            num_workers = self.num_workers(),
+            auto_workers = self.auto_workers,
+            prewarm = self.prewarm,
+            admin_token = self.admin_token.as_ref().map(|_| REDACTED),
+            status_page = self.status_page,
+            standby = self.standby,
+            grant_history = self.grant_history,
+            no_breaker = self.no_breaker,
+            confirm_grants = self.confirm_grants,
+            exact_denominations = self.exact_denominations,
+            batch_window = ?self.batch_window,
+            max_workers_per_recipient = self.max_workers_per_recipient,
+            lock_timeout = ?self.lock_timeout,
+            shutdown_timeout = ?self.shutdown_timeout,
+            max_group_size = self.max_group_size,
+            reset_scan = self.reset_scan,
+            checkpoint_interval = ?self.checkpoint_interval,
+            backup_dir = ?self.backup_dir,
+            backup_interval = ?self.backup_interval,
+            balance_wait_timeout = ?self.balance_wait_timeout,
+            balance_poll_interval = ?self.balance_poll_interval,
+            observer_url = ?self.observer_url.as_ref().map(Url::as_str),
+            observer_batch_size = self.observer_batch_size,
+            observer_flush_interval = ?self.observer_flush_interval,
+            config = ?self.config,
+            "faucet configuration"
+        );
+    }
+
+    /// The address to serve the faucet API on, in a form accepted by `App::serve`.
+    fn listen_address(&self) -> std::io::Result<String> {
+        match &self.unix_socket {
+            Some(path) => {
+                remove_stale_socket(path)?;
+                Ok(format!("http+unix://{}", path.display()))
+            }
+            None => Ok(format!("0.0.0.0:{}", self.faucet_port)),
         }
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct HealthCheck {
-    pub status: FaucetStatus,
-}
+    /// The size of each grant record of the native asset, in base units.
+    fn grant_size(&self) -> Result<RecordAmount, FaucetError> {
+        self.grant_size
+            .to_base_units(self.decimals)
+            .map(RecordAmount::from)
+            .map_err(|msg| FaucetError::Config {
+                msg: format!("invalid grant size {}: {}", self.grant_size, msg),
+            })
+    }
+
+    /// The fee paid for each faucet transfer, in base units.
+    fn fee_size(&self) -> Result<RecordAmount, FaucetError> {
+        self.fee_size
+            .to_base_units(self.decimals)
+            .map(RecordAmount::from)
+            .map_err(|msg| FaucetError::Config {
+                msg: format!("invalid fee size {}: {}", self.fee_size, msg),
+            })
+    }
+
+    /// The balance below which new requests are shed, in base units.
+    fn min_balance(&self) -> Result<Option<RecordAmount>, FaucetError> {
+        self.min_balance
+            .as_ref()
+            .map(|min_balance| {
+                min_balance
+                    .to_base_units(self.decimals)
+                    .map(RecordAmount::from)
+                    .map_err(|msg| FaucetError::Config {
+                        msg: format!("invalid minimum balance {}: {}", min_balance, msg),
+                    })
+            })
+            .transpose()
+    }
+
+    /// The balance below which the faucet refills itself from its upstream faucet, in base units.
+    fn upstream_refill_threshold(&self) -> Result<RecordAmount, FaucetError> {
+        match &self.upstream_refill_threshold {
+            Some(threshold) => threshold
+                .to_base_units(self.decimals)
+                .map(RecordAmount::from)
+                .map_err(|msg| FaucetError::Config {
+                    msg: format!("invalid upstream refill threshold {}: {}", threshold, msg),
+                }),
+            None => Ok(self.grant_size()? * self.num_grants as u64 + self.fee_size()?),
+        }
+    }
 
-impl tide_disco::healthcheck::HealthCheck for HealthCheck {
-    fn status(&self) -> StatusCode {
-        // The healtcheck should succeed even if the status is [Initializing], otherwise the load
-        // balancer may kill us while we are initializing.
-        StatusCode::Ok
+    /// The number of worker threads to spawn.
+    fn num_workers(&self) -> usize {
+        if self.auto_workers {
+            // Each worker needs `num_grants` records to serve a request, so with `num_records`
+            // records we can serve this many requests simultaneously. Always use at least one
+            // worker, so that the faucet can make progress even if it has very few records.
+            (self.num_records.min(self.max_grant_records) / self.num_grants.max(1)).max(1)
+        } else {
+            self.num_workers
+        }
     }
 }
 
-/// Return a JSON expression with status 200 indicating the server
-/// is up and running. The JSON expression is simply,
-///    `{"status": Status}`
-/// where `Status` is one of
-/// * "initializing"
-/// * "available"
-/// When the server is running but unable to process requests
-/// normally, a response with status 503 and payload {"status":
-/// "unavailable"} should be added.
-async fn healthcheck(state: &FaucetState) -> HealthCheck {
-    HealthCheck {
-        status: *state.status.read().await,
+/// Remove a socket left at `path` by a previous run, so that it can be bound again.
+///
+/// Fails if something other than a socket exists at `path`, rather than deleting it.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
     }
 }
 
-async fn check_service_available(state: &FaucetState) -> Result<(), FaucetError> {
-    if *state.status.read().await == FaucetStatus::Available {
-        Ok(())
-    } else {
-        Err(FaucetError::Unavailable)
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    ClDuration::from_str(s)
+        .map(Duration::from)
+        .map_err(|err| err.to_string())
+}
+
+/// The directory under which faucets keep their keystores by default.
+fn default_faucet_dir() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("./")))
+        .join(".espresso")
+        .join("espresso")
+        .join("faucet")
+}
+
+/// Move the keystore at `legacy` to `keystore`, if there is not already a keystore there.
+///
+/// Returns whether the keystore was moved.
+fn migrate_keystore(legacy: &Path, keystore: &Path) -> std::io::Result<bool> {
+    if !legacy.exists() {
+        return Ok(false);
+    }
+    if keystore.exists() {
+        warn!(
+            "ignoring faucet keystore at legacy default path {}, using {}",
+            legacy.display(),
+            keystore.display()
+        );
+        return Ok(false);
+    }
+    info!(
+        "moving faucet keystore from legacy default path {} to {}",
+        legacy.display(),
+        keystore.display()
+    );
+    if let Some(parent) = keystore.parent() {
+        fs::create_dir_all(parent)?;
     }
+    fs::rename(legacy, keystore)?;
+    Ok(true)
 }
 
-async fn request_fee_assets(req: RequestParams, state: &FaucetState) -> Result<(), FaucetError> {
-    check_service_available(state).await?;
-    let pub_key: UserPubKey = req.body_auto()?;
-    state.queue.push(pub_key).await
+/// A short identifier for the faucet owning `mnemonic`.
+///
+/// The identifier is a hash of the address of the first sending key derived from `mnemonic`, so it
+/// is stable across restarts but does not reveal the mnemonic.
+fn faucet_id(mnemonic: &Mnemonic) -> String {
+    let address = KeyTree::from_mnemonic(mnemonic)
+        .sending_key_stream()
+        .sending_key(0)
+        .pub_key()
+        .address();
+    hex::encode(&Sha3_256::digest(address.to_string().as_bytes())[..8])
 }
 
-async fn worker(id: usize, mut state: FaucetState) {
-    'wait_for_requests: while let Some((pub_key, mut grants)) = state.queue.pop().await {
-        assert!(grants < state.num_grants);
-        loop {
-            // If we don't have a sufficient balance, to transfer, it is probably only because some
-            // transactions are in flight. We are likely to get change back when the transactions
-            // complete, so wait until we have a sufficient balance to do our job.
-            let (mut keystore, balance) = loop {
-                let keystore = state.keystore.lock().await;
-                let balance = keystore.balance(&AssetCode::native()).await;
-                if balance < state.grant_size.into() {
-                    warn!(
-                        "worker {}: insufficient balance for transfer, sleeping for 30s",
-                        id
-                    );
-                    drop(keystore);
-                    sleep(Duration::from_secs(30)).await;
-                } else {
-                    let records = spendable_records(&keystore, state.grant_size).await.count();
-                    info!(
-                        "worker {}: keystore balance before transfer: {} across {} records",
-                        id, balance, records
-                    );
-                    break (keystore, balance);
-                }
-            };
-            let (res, new_grants) =
-                if state.num_grants - grants > 1 && balance >= (state.grant_size * 2).into() {
-                    // If the receiver is still owed multiple grants and we have enough balance to
-                    // make 2 simultaneous grants, take advantage of the 3-output proving key to
-                    // create 2 grants at the same time.
-                    info!(
-                        "worker {}: transferring 2 records of {} tokens each to {}",
-                        id,
-                        state.grant_size,
-                        pub_key.address()
-                    );
-                    (
-                        keystore
-                            .transfer(
-                                None,
-                                &AssetCode::native(),
-                                &[
-                                    (pub_key.clone(), state.grant_size),
-                                    (pub_key.clone(), state.grant_size),
-                                ],
-                                state.fee_size,
-                            )
-                            .await,
-                        2,
-                    )
-                } else {
-                    info!(
-                        "worker {}: transferring 1 record of {} tokens to {}",
-                        id,
-                        state.grant_size,
-                        pub_key.address()
-                    );
-                    (
-                        keystore
-                            .transfer(
-                                None,
-                                &AssetCode::native(),
-                                &[(pub_key.clone(), state.grant_size)],
-                                state.fee_size,
-                            )
-                            .await,
-                        1,
-                    )
-                };
-            if let Err(err) = res {
-                error!("worker {}: failed to transfer: {}", id, err);
-                // If we failed, mark the request as failed in the queue so it can be retried later.
-                state.queue.fail(pub_key).await;
-                continue 'wait_for_requests;
-            }
+/// A non-negative decimal amount, such as `1.5`, given on the command line.
+///
+/// The amount is converted to base units of an asset using [DecimalAmount::to_base_units], once
+/// the number of decimal places of the asset is known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecimalAmount {
+    whole: String,
+    // Digits after the decimal point, with trailing zeros removed.
+    frac: String,
+}
 
-            // Update the queue with the results of this grant; find out if the key needs more
-            // grants or not.
-            if !state
-                .queue
-                .grant(pub_key.clone(), new_grants, state.num_grants)
-                .await
-            {
-                break;
-            }
-            grants += new_grants;
+impl DecimalAmount {
+    /// Convert to base units of an asset with `decimals` decimal places.
+    ///
+    /// Fails if the amount has more decimal places than the asset, or if the amount in base units
+    /// does not fit in a `u64`.
+    pub fn to_base_units(&self, decimals: u8) -> Result<u64, String> {
+        let decimals = decimals as usize;
+        if self.frac.len() > decimals {
+            return Err(format!(
+                "too many decimal places (at most {} are allowed)",
+                decimals
+            ));
         }
+        let digits = format!("{}{:0<width$}", self.whole, self.frac, width = decimals);
+        // Any string of digits which does not parse must be too large.
+        digits
+            .parse()
+            .map_err(|_| format!("amount does not fit in {} base units", u64::MAX))
+    }
 
-        // Signal the record breaking thread that we have spent some records, so that it can create
-        // more by breaking up larger records.
-        if state.signal_breaker_thread.clone().try_send(()).is_err() {
-            error!(
-                "worker {}: error signalling the breaker thread. Perhaps it has crashed?",
-                id
-            );
+    /// The decimal amount of `amount` base units of an asset with `decimals` decimal places.
+    ///
+    /// This is the inverse of [DecimalAmount::to_base_units], and is used to display balances.
+    pub fn from_base_units(amount: U256, decimals: u8) -> Self {
+        let decimals = decimals as usize;
+        // Pad with leading zeros so there is at least one digit before the decimal point.
+        let digits = format!("{:0>width$}", amount.to_string(), width = decimals + 1);
+        let (whole, frac) = digits.split_at(digits.len() - decimals);
+        Self {
+            whole: whole.to_string(),
+            frac: frac.trim_end_matches('0').to_string(),
         }
     }
+}
 
-    warn!("worker {}: exiting, request queue closed", id);
+impl FromStr for DecimalAmount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+        if (whole.is_empty() && frac.is_empty())
+            || !whole
+                .chars()
+                .chain(frac.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(format!("invalid amount {:?}, expected a decimal number", s));
+        }
+        Ok(Self {
+            // An amount like `.5` has an implicit whole part of 0.
+            whole: if whole.is_empty() { "0" } else { whole }.to_string(),
+            frac: frac.trim_end_matches('0').to_string(),
+        })
+    }
 }
 
-async fn spendable_records(
-    keystore: &EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>,
-    grant_size: RecordAmount,
-) -> impl Iterator<Item = Record> {
-    let now = keystore.read().await.state().validator.block_height();
-    keystore.records().await.into_iter().filter(move |record| {
-        record.asset_code() == AssetCode::native()
-            && record.amount() >= grant_size
-            && record.freeze_flag() == FreezeFlag::Unfrozen
-            && !record.on_hold(now)
-    })
+impl Display for DecimalAmount {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.frac.is_empty() {
+            write!(f, "{}", self.whole)
+        } else {
+            write!(f, "{}.{}", self.whole, self.frac)
+        }
+    }
+}
+
+/// The contents of the file at [FaucetOptions::grant_sizes_path].
+#[derive(Debug, Default, Deserialize)]
+struct GrantSizesConfig {
+    #[serde(default)]
+    grant_sizes: HashMap<String, u64>,
+    #[serde(default)]
+    fees: HashMap<String, u64>,
+}
+
+/// Parse a table from a [GrantSizesConfig] mapping asset codes to amounts.
+fn parse_asset_table(
+    table: HashMap<String, u64>,
+) -> Result<HashMap<AssetCode, RecordAmount>, FaucetError> {
+    table
+        .into_iter()
+        .map(|(asset, amount)| {
+            let asset = asset.parse().map_err(|err| FaucetError::Config {
+                msg: format!("invalid asset code {}: {}", asset, err),
+            })?;
+            Ok((asset, amount.into()))
+        })
+        .collect()
 }
 
-/// Worker task to maintain at least `state.num_records` in the faucet keystore.
+type FaucetKeystore = EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>;
+
+/// The priority of a faucet request.
 ///
-/// When signalled on `wakeup`, this thread will break large records into small records of size
-/// `state.grant_size`, until there are at least `state.num_records` distinct records in the
-/// keystore.
+/// High priority requests are always serviced before normal priority requests. Making a high
+/// priority request requires the admin token.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = FaucetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(FaucetError::InvalidPriority {
+                priority: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Per-request parameters, persisted alongside the request queue.
 ///
-/// The record breakup is only triggered when the number of available records is less than half of
-/// the desired number of records, and in that case we always replenish all the way to the desired
-/// number of records if possible. This prevents us from generating a record transaction every time
-/// we do a transfer, and ensures that whenever we do break up records, we break up many at a time,
-/// so we can take advantage of the parallelism of having multiple record breakup transactions in
-/// flight at the same time.
-async fn maintain_enough_records(state: FaucetState, mut wakeup: mpsc::Receiver<()>) {
-    loop {
-        // Wait until we have few enough records that we need to break them up, and we have a big
-        // enough record to break up.
-        //
-        // This is a simulation of a condvar loop, since async condvar is unstable, hence the manual
-        // drop and reacquisition of the keystore mutex guard.
-        loop {
-            let keystore = state.keystore.lock().await;
-            let records = spendable_records(&keystore, state.grant_size)
-                .await
-                .collect::<Vec<_>>();
-            if records.len() >= state.num_records / 2 {
-                // We have enough records for now, wait for a signal that the number of records has
-                // changed.
-                info!(
-                    "got {}/{} records, waiting for a change",
-                    records.len(),
-                    state.num_records
-                );
-            } else if !records
-                .into_iter()
-                .any(|record| record.amount() > state.grant_size * 2u64)
-            {
-                // There are no big records to break up, so there's nothing for us to do. Exit
-                // the inner loop and wait for a notification that the record distribution has
-                // changed.
-                warn!("not enough records, but no large records to break up");
-            } else {
-                // We don't have enough records and we do have a big record to break up. Break out
-                // of the wait loop and enter the next loop to break up our records.
-                break;
-            }
+/// New fields are only ever appended. The `serde(default)` attributes let a CBOR queue written
+/// before a field was added load as is. Bincode is not self-describing, so bincode entries in the
+/// older layouts are decoded by [QueueEntry::from_bincode] instead.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct RequestInfo {
+    priority: Priority,
+    /// The number of grants requested, if different from the faucet's default `num_grants`.
+    num_grants: Option<usize>,
+    /// A label chosen by the client, recorded when the request completes.
+    #[serde(default)]
+    label: Option<String>,
+    /// The other keys in a group request, which are granted to together with this key as a unit.
+    #[serde(default)]
+    group: Vec<UserPubKey>,
+}
 
-            drop(keystore);
-            wakeup.next().await;
+/// The layout of [RequestInfo] before requests could carry a label.
+#[derive(Serialize, Deserialize)]
+struct RequestInfoV1 {
+    priority: Priority,
+    num_grants: Option<usize>,
+}
+
+/// The layout of [RequestInfo] before group requests.
+#[derive(Serialize, Deserialize)]
+struct RequestInfoV2 {
+    priority: Priority,
+    num_grants: Option<usize>,
+    label: Option<String>,
+}
+
+impl From<RequestInfoV1> for RequestInfo {
+    fn from(info: RequestInfoV1) -> Self {
+        Self {
+            priority: info.priority,
+            num_grants: info.num_grants,
+            ..Default::default()
         }
+    }
+}
 
-        if let Some(transactions) = break_up_records(&state).await {
-            // If we succeeded, wait until we are signalled again. Even though we may not have
-            // enough records just yet, we will when the transactions submitted by
-            // `break_up_records` finalize. Returning to the previous loop and checking if we have
-            // enough records might spuriously lead us to call `break_up_records` again, which would
-            // be an unnecessary waste of time.
-            info!(
-                "will have sufficient records after {} transactions, waiting for a change",
-                transactions.len()
-            );
-            wakeup.next().await;
+impl From<RequestInfoV2> for RequestInfo {
+    fn from(info: RequestInfoV2) -> Self {
+        Self {
+            priority: info.priority,
+            num_grants: info.num_grants,
+            label: info.label,
+            ..Default::default()
         }
     }
 }
 
-/// Break records into smaller pieces to create at least `state.num_records` total.
-///
-/// If successful, returns a list of transaction receipts which will give at least
-/// `state.num_records` when they are finalized. If there were not enough large records to break up
-/// to obtain the desired number of records, returns [None].
-async fn break_up_records(state: &FaucetState) -> Option<Vec<TransactionUID<EspressoLedger>>> {
-    // Break up records until we have enough again.
-    loop {
-        // Generate as many transactions as we can simultaneously.
-        let mut transactions = Vec::new();
-        loop {
-            // Acquire the keystore lock inside the loop, so we release it after each transfer.
-            // Holding the lock for too long can unneccessarily slow down faucet requests.
-            let mut keystore = state.keystore.lock().await;
-            let pub_key = keystore.sending_keys().await[0].pub_key().clone();
-            let records = spendable_records(&keystore, state.grant_size)
-                .await
-                .collect::<Vec<_>>();
+/// The header in which clients can attach a label to a request.
+const LABEL_HEADER: &str = "X-Faucet-Label";
 
-            if records.len() + 2 * transactions.len() >= state.num_records {
-                // We will have enough records again once the pending transactions finish. Return
-                // _without_ waiting for pending transactions to finish: if we know we are going to
-                // have enough records once they finish, there is no point in holding the keystore
-                // lock and just waiting. Perhaps a faucet request can be filled using the records
-                // we already have while the last few transactions are pending.
-                //
-                // Return the list of transaction receipts so the caller can wait on them if they
-                // want.
-                return Some(transactions);
-            }
+/// The maximum length, in bytes, of a request label.
+const MAX_LABEL_LEN: usize = 64;
 
-            let largest_record = match records
-                .into_iter()
-                .max_by(|x, y| x.amount().cmp(&y.amount()))
-            {
-                Some(record) if record.amount() >= state.grant_size * 2u64 => record,
-                _ => {
-                    // There are no records large enough to break up. Break out of the loop and wait
-                    // for the transactions we have already initiated to finish. The change from
-                    // those transactions will give us more records to break up.
-                    break;
-                }
-            };
+#[derive(Clone)]
+struct FaucetState {
+    keystore: Arc<Mutex<FaucetKeystore>>,
+    status: Arc<RwLock<FaucetStatus>>,
+    queue: FaucetQueue,
+    grant_size: RecordAmount,
+    // Per-asset overrides of `grant_size`. Every asset in this map is granted to each recipient, in
+    // addition to the native asset.
+    grant_sizes: Arc<HashMap<AssetCode, RecordAmount>>,
+    num_grants: usize,
+    // The largest amount of the native asset a single request may ask for.
+    max_grant_per_request: u64,
+    // Persistent accounting of the native asset dispensed in the current window, if there is a
+    // daily cap.
+    daily_cap: Option<Arc<Mutex<DailyCap>>>,
+    // Persistent log of every grant, if the grant history is enabled.
+    grant_history: Option<Arc<Mutex<GrantHistory>>>,
+    // The native asset balance below which new requests are shed, if any.
+    min_balance: Option<RecordAmount>,
+    // The native asset balance as of the last time a task holding the keystore lock checked it.
+    // Request handlers read this rather than locking the keystore, so that they never wait behind a
+    // transfer.
+    balance: Arc<RwLock<U256>>,
+    fee_size: RecordAmount,
+    // Per-asset overrides of `fee_size`, for transfers of assets in `grant_sizes`.
+    fees: Arc<HashMap<AssetCode, RecordAmount>>,
+    num_records: usize,
+    // Number of decimal places of the native asset, for displaying amounts.
+    decimals: u8,
+    admin_token: Option<String>,
+    // Channel to signal when the distribution of records owned by the faucet changes. This will
+    // wake the record breaker thread (which waits on the receiver) so it can create more records by
+    // breaking up larger ones to maintain the target of `num_records`.
+    //
+    // We use a bounded channel so that a crashed or deadlocked record breaker thread that is not
+    // pulling messages out of the queue does not result in an unbounded memory leak.
+    signal_breaker_thread: mpsc::Sender<()>,
+    // Whether the record breaker thread is running.
+    breaker: bool,
+    // Whether to wait for grants to be confirmed before counting them.
+    confirm_grants: bool,
+    // Whether to break up records only into whole multiples of the cost of a grant.
+    exact_denominations: bool,
+    // How long a worker waits for a second recipient to share a transfer with, or zero to disable
+    // batching.
+    batch_window: Duration,
+    // Limits the number of workers granting to each recipient at once.
+    recipients: RecipientLocks,
+    // How long a worker waits for the keystore lock before logging a possible deadlock.
+    lock_timeout: Duration,
+    // The largest number of keys allowed in a group request.
+    max_group_size: usize,
+    // Channel to the task which mirrors grants to the observer, if there is one. The channel is
+    // bounded so that an observer which is down or slow cannot cause an unbounded memory leak.
+    observer: Option<mpmc::Sender<GrantEvent>>,
+    // Running totals since the faucet started, for the shutdown report.
+    stats: Arc<FaucetStats>,
+    // What each worker is currently doing, and since when, by worker ID.
+    workers: Arc<RwLock<BTreeMap<usize, (WorkerTask, Instant)>>>,
+    // The most recent grant failures, oldest first, bounded by `MAX_RECENT_FAILURES`.
+    recent_failures: Arc<Mutex<VecDeque<FailureReport>>>,
+    // Transactions granting to keys which an admin grant is waiting on, by key.
+    grant_receipts: Arc<Mutex<HashMap<UserPubKey, GrantReceipts>>>,
+    // Set when the faucet starts shutting down, so that workers stop taking new requests.
+    shutting_down: Arc<AtomicBool>,
+    // Tasks which maintain the faucet in the background, such as the record breaker, so that they
+    // can be stopped when the faucet shuts down.
+    background_tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
 
-            let split_amount = largest_record.amount() / 2;
-            let change_amount = largest_record.amount() - split_amount;
+/// The transactions granting to a key while admin grants are waiting for its request to complete.
+#[derive(Default)]
+struct GrantReceipts {
+    // The number of admin grants waiting on the key.
+    waiters: usize,
+    receipts: Vec<TransactionUID<EspressoLedger>>,
+}
 
-            info!(
-                "breaking up a record of size {} into records of size {} and {}",
-                largest_record.amount(),
-                split_amount,
-                change_amount,
+impl FaucetState {
+    pub async fn new(
+        keystore: FaucetKeystore,
+        signal_breaker_thread: mpsc::Sender<()>,
+        opt: &FaucetOptions,
+    ) -> Result<Self, FaucetError> {
+        let config = opt.grant_sizes_config()?;
+        let mut grant_sizes = parse_asset_table(config.grant_sizes)?;
+        let fees = parse_asset_table(config.fees)?;
+        let fee_size = opt.fee_size()?;
+        // A fee is only ever paid when the faucet transfers an asset, so a fee for an asset the
+        // faucet does not grant is almost certainly a mistake.
+        if let Some(asset) = fees
+            .keys()
+            .find(|asset| **asset != AssetCode::native() && !grant_sizes.contains_key(asset))
+        {
+            return Err(FaucetError::Config {
+                msg: format!(
+                    "fee configured for asset {}, which is not granted by the faucet",
+                    asset
+                ),
+            });
+        }
+        if !opt.allowed_assets.is_empty() {
+            grant_sizes.retain(|asset, _| {
+                let allowed = *asset == AssetCode::native() || opt.allowed_assets.contains(asset);
+                if !allowed {
+                    warn!(
+                        "asset {} is configured but not allowed, it will not be granted",
+                        asset
+                    );
+                }
+                allowed
+            });
+        }
+        if opt.num_records > opt.max_grant_records {
+            warn!(
+                "requested {} records, but the maximum is {}; only {} records will be maintained",
+                opt.num_records, opt.max_grant_records, opt.max_grant_records
             );
-
-            // There is not yet an interface for transferring a specific record, so we just have to
-            // specify the appropriate amounts and trust that Seahorse will use the largest record
-            // available (it should). We specify two outputs so that if an existing record with
-            // `change_amount` exists it won't be used "as is", which would prevent this loop
-            // from making progress.
-            let receipt = match keystore
-                .transfer(
-                    None,
-                    &AssetCode::native(),
-                    &[
-                        (pub_key.clone(), change_amount),
-                        (pub_key.clone(), split_amount),
-                    ],
-                    0u64,
-                )
-                .await
-            {
-                Ok(receipt) => receipt,
-                Err(err) => {
-                    // If our transfers start failing, we will assume there is something wrong and
-                    // try not to put extra stress on the system. Break out of the inner loop and
-                    // wait for the transactions we did initiate to finish.
-                    error!("record breakup transfer failed: {}", err);
-                    break;
+        }
+        // The native asset may be overridden in the per-asset configuration like any other.
+        let grant_size = match grant_sizes.get(&AssetCode::native()) {
+            Some(size) => *size,
+            None => opt.grant_size()?,
+        };
+        // By default, requests can ask for as much as the default grant, but no more.
+        let max_grant_per_request = opt.max_grant_per_request.unwrap_or_else(|| {
+            u64::try_from(U256::from(grant_size) * opt.num_grants).unwrap_or(u64::MAX)
+        });
+        let daily_cap = match opt.daily_cap {
+            Some(cap) => Some(Arc::new(Mutex::new(DailyCap::load(
+                &opt.keystore_path(),
+                cap,
+            )?))),
+            None => None,
+        };
+        let grant_history = if opt.grant_history {
+            Some(Arc::new(Mutex::new(GrantHistory::load(
+                &opt.keystore_path(),
+            )?)))
+        } else {
+            None
+        };
+        let observer = opt.observer_url.clone().map(|url| {
+            let (sender, receiver) = mpmc::bounded(MAX_PENDING_GRANT_EVENTS);
+            spawn(mirror_grants(
+                url,
+                opt.observer_batch_size.max(1),
+                opt.observer_flush_interval,
+                receiver,
+            ));
+            sender
+        });
+        let balance = keystore.balance(&AssetCode::native()).await;
+        Ok(Self {
+            keystore: Arc::new(Mutex::new(keystore)),
+            status: Arc::new(RwLock::new(FaucetStatus::Initializing)),
+            queue: {
+                // A standby must not write to the queue of the faucet it is standing in for, which
+                // may be running, so it only takes a snapshot until it is promoted.
+                let queue = if opt.standby {
+                    FaucetQueue::load_read_only(
+                        &opt.queue_path(),
+                        opt.max_queue_len,
+                        opt.queue_log_format,
+                    )
+                    .await?
+                } else {
+                    FaucetQueue::load(
+                        &opt.queue_path(),
+                        opt.max_queue_len,
+                        opt.queue_compaction_threshold,
+                        opt.queue_log_format,
+                    )
+                    .await?
+                };
+                match opt.queue_full_alert_after {
+                    Some(after) => queue.escalate_when_full(after, opt.alert_url.clone()),
+                    None => queue,
                 }
-            };
-            transactions.push(receipt);
+            },
+            grant_size,
+            grant_sizes: Arc::new(grant_sizes),
+            num_grants: opt.num_grants,
+            max_grant_per_request,
+            daily_cap,
+            grant_history,
+            min_balance: opt.min_balance()?,
+            balance: Arc::new(RwLock::new(balance)),
+            fee_size,
+            fees: Arc::new(fees),
+            num_records: opt.num_records.min(opt.max_grant_records),
+            decimals: opt.decimals,
+            admin_token: opt.admin_token.clone(),
+            signal_breaker_thread,
+            breaker: !opt.no_breaker,
+            confirm_grants: opt.confirm_grants,
+            exact_denominations: opt.exact_denominations,
+            batch_window: opt.batch_window,
+            recipients: RecipientLocks::new(opt.max_workers_per_recipient.max(1)),
+            lock_timeout: opt.lock_timeout,
+            max_group_size: opt.max_group_size,
+            observer,
+            stats: Arc::new(FaucetStats::new()),
+            workers: Default::default(),
+            recent_failures: Default::default(),
+            grant_receipts: Default::default(),
+            shutting_down: Default::default(),
+            background_tasks: Default::default(),
+        })
+    }
+
+    /// Run `task` in the background until the faucet shuts down.
+    async fn spawn_background(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.background_tasks.lock().await.push(spawn(task));
+    }
+
+    /// Record that worker `id` has started on `task`.
+    async fn set_worker_task(&self, id: usize, task: WorkerTask) {
+        self.workers
+            .write()
+            .await
+            .insert(id, (task, Instant::now()));
+    }
+
+    /// Mirror a grant to the observer, if there is one.
+    ///
+    /// This never blocks. If the observer has fallen too far behind, the event is dropped.
+    fn observe_grant(&self, event: GrantEvent) {
+        if let Some(observer) = &self.observer {
+            if observer.try_send(event).is_err() {
+                warn!("grant observer is not keeping up, dropping grant event");
+            }
         }
+    }
 
-        if transactions.is_empty() {
-            // We did not have sufficient records to generate any break-up transactions. Give up
-            // early and return with fewer-than-desired records. When the allocation of records
-            // changes, the record breaker thread will be notified and we will get to try again.
-            warn!("No large records to break up");
-            return None;
+    /// Record a failed attempt to grant to `pub_key`, for the `failures` endpoint.
+    async fn record_failure(&self, pub_key: &UserPubKey, error: impl Display) {
+        let mut failures = self.recent_failures.lock().await;
+        if failures.len() >= MAX_RECENT_FAILURES {
+            failures.pop_front();
         }
+        failures.push_back(FailureReport {
+            pub_key: pub_key.clone(),
+            error: error.to_string(),
+            time: unix_now(),
+        });
+    }
 
-        // If we get here, it means we generated some transactions, but it was not enough to give us
-        // the desired number of records. Wait until those transactions finish, then repeat the
-        // process, splitting up the outputs of those transactions.
-        //
-        // Note we have to reacquire the lock, since we released it at the end of the previous loop.
-        // This is good: it potentially allows another thread to grab the lock and make a transfer
-        // before we acquire it, during time where we would just be idly waiting. If this happens,
-        // it only means we spend less time waiting for our transactions once we are able to
-        // reacquire the lock.
-        info!(
-            "waiting for {} transactions before breaking more records",
-            transactions.len()
-        );
-        let keystore = state.keystore.lock().await;
-        for result in join_all(
-            transactions
-                .iter()
-                .map(|receipt| keystore.await_transaction(receipt)),
-        )
-        .await
-        {
-            if !matches!(result, Ok(TransactionStatus::Retired)) {
-                error!(
-                    "record breakup transfer did not complete successfully ({:?})",
-                    result
-                );
-            }
+    /// Note that `receipt` granted to `pub_key`, if an admin grant is waiting on `pub_key`.
+    ///
+    /// This must be called before the grant is counted in the queue, so that a waiting admin grant
+    /// has the receipt by the time it sees the request complete.
+    async fn record_receipt(&self, pub_key: &UserPubKey, receipt: &TransactionUID<EspressoLedger>) {
+        if let Some(waiting) = self.grant_receipts.lock().await.get_mut(pub_key) {
+            waiting.receipts.push(receipt.clone());
         }
     }
-}
 
-/// `faucet_key_pair` - If provided, will be added to the faucet keystore.
-pub async fn init_web_server(
-    rng: &mut ChaChaRng,
-    opt: &FaucetOptions,
-    faucet_key_pair: Option<UserKeyPair>,
-) -> std::io::Result<JoinHandle<std::io::Result<()>>> {
-    let mut password = opt.faucet_password.clone();
-    if password.is_empty() {
-        password = Alphanumeric.sample_string(rng, 16);
+    /// The amount of the native asset counted against the daily cap for `grants` grant records.
+    fn daily_cap_amount(&self, grants: usize) -> u64 {
+        u64::try_from(U256::from(self.grant_size) * grants).unwrap_or(u64::MAX)
     }
-    let mut loader = RecoveryLoader::new(rng, opt.keystore_path(), opt.mnemonic.clone(), password);
-    let backend = NetworkBackend::new(
-        &UNIVERSAL_PARAM,
-        opt.esqs_url.clone(),
-        opt.address_book_url.clone(),
-        opt.submit_url.clone(),
-    )
-    .await
-    .unwrap();
-    let mut keystore = EspressoKeystore::new(backend, &mut loader).await.unwrap();
 
-    // If a faucet key pair is provided, add it to the keystore. Otherwise, if we're initializing
-    // for the first time, we need to generate a key. The faucet should be set up so that the
-    // first HD sending key is the faucet key.
-    let new_key = if let Some(key) = faucet_key_pair {
-        keystore
-            .add_account(key.clone(), "faucet".into(), EventIndex::default())
-            .await
-            .unwrap();
-        Some(key.pub_key())
-    } else if keystore.sending_keys().await.is_empty() {
-        // We pass `EventIndex::default()` to start a scan of the ledger from the beginning, in
-        // order to discover the faucet record.
-        Some(
-            keystore
-                .generate_sending_account("faucet".into(), Some(EventIndex::default()))
-                .await
-                .unwrap(),
-        )
-    } else {
-        None
-    };
+    /// How long a transfer of `grants` grant records must be held so that it does not exceed the
+    /// daily cap, or `None` if it can be made now.
+    async fn daily_cap_wait(&self, grants: usize) -> Option<Duration> {
+        let daily_cap = self.daily_cap.as_ref()?;
+        let amount = self.daily_cap_amount(grants);
+        daily_cap.lock().await.wait_time(amount, unix_now())
+    }
 
-    // Start the app before we wait for the key scan to complete. If we have to restart the faucet
-    // service from scratch (for example, if the keystore storage format changes and we need to
-    // recreate our files from a mnemonic) the key scan could take a very long time. We want the
-    // healthcheck endpoint to be available and returning "initializing" during that time, so the
-    // load balancer doesn't kill the service before it has a chance to start up. Other endpoints
-    // will fail while the app is initializing. Once initialization is complete, the healthcheck
-    // state will change to "available" and the other endpoints will start to work.
-    //
-    // The app state includes a bounded channel used to signal the record breaking thread when we
-    // need it to break large records into smaller ones. We use the total number of records to
-    // maintain as a conservative upper bound on how backed up the message channel can get.
-    let signal_breaker_thread = mpsc::channel(opt.num_records);
-    let state = FaucetState::new(keystore, signal_breaker_thread.0, opt)
-        .await
-        .unwrap();
-    let mut app = App::<FaucetState, FaucetError>::with_state(state.clone());
-    let api = match &opt.api_path {
-        Some(path) => toml::from_slice(&fs::read(path)?).unwrap(),
-        None => toml::from_str(include_str!("../api/api.toml")).unwrap(),
-    };
-    app.module("api", api)
-        .unwrap()
-        .at("request_fee_assets", |req, state| {
-            request_fee_assets(req, state).boxed()
-        })
-        .unwrap()
-        .with_health_check(|state| async move { healthcheck(state).await }.boxed());
-    let address = format!("0.0.0.0:{}", opt.faucet_port);
-    let handle = spawn(app.serve(address));
+    /// Update the cached native asset balance from `keystore`, which the caller has locked.
+    async fn refresh_balance(&self, keystore: &FaucetKeystore) -> U256 {
+        let balance = keystore.balance(&AssetCode::native()).await;
+        *self.balance.write().await = balance;
+        balance
+    }
 
-    if let Some(key) = new_key {
-        // Wait until we have scanned the ledger for records belonging to this key.
-        state
-            .keystore
-            .lock()
-            .await
-            .await_sending_key_scan(&key.address())
-            .await
-            .unwrap();
+    /// Lock the keystore on behalf of worker `id`, reporting a possible deadlock if the lock is
+    /// held for longer than `lock_timeout`.
+    async fn lock_keystore(&self, id: usize) -> MutexGuard<'_, FaucetKeystore> {
+        lock_with_timeout(id, &self.keystore, self.lock_timeout, &self.stats).await
     }
 
-    let bal = state
-        .keystore
-        .lock()
-        .await
-        .balance(&AssetCode::native())
-        .await;
-    tracing::info!("Keystore balance before init: {}", bal);
+    /// Mirror `grants` records of each of `assets` transferred to `pub_key` to the observer.
+    async fn observe_grants(
+        &self,
+        pub_key: &UserPubKey,
+        assets: &[(AssetCode, RecordAmount)],
+        grants: usize,
+        label: Option<String>,
+    ) {
+        let time = unix_now();
+        for (asset, grant_size) in assets {
+            let event = GrantEvent {
+                pub_key: pub_key.clone(),
+                asset: *asset,
+                grants,
+                grant_size: *grant_size,
+                label: label.clone(),
+                time,
+            };
+            if let Some(history) = &self.grant_history {
+                if let Err(err) = history.lock().await.record(&event) {
+                    error!("failed to record grant to {} in history: {}", pub_key, err);
+                }
+            }
+            self.observe_grant(event);
+        }
+    }
 
-    // Create at least `opt.num_records` if possible, before starting to handle requests.
-    if let Some(transactions) = break_up_records(&state).await {
-        let keystore = state.keystore.lock().await;
-        join_all(
-            transactions
-                .iter()
-                .map(|receipt| keystore.await_transaction(receipt)),
-        )
-        .await;
+    /// Wait until the faucet is not paused.
+    async fn wait_while_paused(&self) {
+        self.wait_while(FaucetStatus::Paused).await
     }
 
-    // Spawn a thread to continuously break records into smaller records to maintain
-    // `opt.num_records` at a time.
-    spawn(maintain_enough_records(
-        state.clone(),
-        signal_breaker_thread.1,
-    ));
+    /// Wait until the faucet's status is not `status`.
+    async fn wait_while(&self, status: FaucetStatus) {
+        while *self.status.read().await == status {
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
 
-    // Spawn the worker threads that will handle faucet requests.
-    for id in 0..opt.num_workers {
-        spawn(worker(id, state.clone()));
+    /// The number of grant records needed to grant `amount` of the native asset.
+    ///
+    /// The amount is rounded up to a whole number of records, unless that would take the grant over
+    /// the amount a single request is allowed to ask for, in which case it is rounded down. Fails if
+    /// `amount` is more than a single request is allowed to ask for, or if not even one record fits
+    /// within that limit.
+    fn grants_for_amount(&self, amount: u64) -> Result<usize, FaucetError> {
+        let too_large = FaucetError::GrantTooLarge {
+            requested: amount,
+            max: self.max_grant_per_request,
+        };
+        if amount > self.max_grant_per_request {
+            return Err(too_large);
+        }
+        // Round up, so the recipient gets at least what they asked for, and always grant at least
+        // one record.
+        let grant_size = U256::from(self.grant_size);
+        let max = U256::from(self.max_grant_per_request);
+        let mut grants = ((U256::from(amount) + grant_size - 1) / grant_size).max(U256::one());
+        // The cap applies to what is actually granted, so round down if rounding up exceeds it.
+        if grants * grant_size > max {
+            grants = max / grant_size;
+        }
+        if grants.is_zero() {
+            return Err(too_large);
+        }
+        Ok(grants.low_u64() as usize)
     }
 
-    *state.status.write().await = FaucetStatus::Available;
+    /// Format an amount of the native asset with the configured number of decimal places.
+    fn format_amount(&self, amount: impl Into<U256>) -> DecimalAmount {
+        DecimalAmount::from_base_units(amount.into(), self.decimals)
+    }
 
-    Ok(handle)
-}
+    /// The size of each grant record of `asset`.
+    fn grant_size(&self, asset: &AssetCode) -> RecordAmount {
+        self.grant_sizes
+            .get(asset)
+            .copied()
+            .unwrap_or(self.grant_size)
+    }
 
-#[async_std::main]
-async fn main() -> Result<(), std::io::Error> {
-    tracing_subscriber::fmt()
-        .with_ansi(false)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    /// The fee paid for each transfer of `asset`.
+    fn fee_size(&self, asset: &AssetCode) -> RecordAmount {
+        self.fees.get(asset).copied().unwrap_or(self.fee_size)
+    }
 
-    // Initialize the faucet web server.
-    init_web_server(
-        &mut ChaChaRng::from_entropy(),
-        &FaucetOptions::parse(),
-        None,
-    )
-    .await?
-    .await?;
+    /// The size of the pieces records of `asset` are broken into, if breakups use exact
+    /// denominations.
+    ///
+    /// This is the cost of a single grant of `asset`: the grant size, plus the fee for the native
+    /// asset, which pays the fees of every transfer.
+    fn breakup_denomination(
+        &self,
+        asset: &AssetCode,
+        grant_size: RecordAmount,
+    ) -> Option<RecordAmount> {
+        if !self.exact_denominations {
+            None
+        } else if *asset == AssetCode::native() {
+            Some(grant_size + self.fee_size(asset))
+        } else {
+            Some(grant_size)
+        }
+    }
 
-    Ok(())
+    /// Non-native assets which are granted alongside the native asset.
+    fn extra_assets(&self) -> impl Iterator<Item = (AssetCode, RecordAmount)> + '_ {
+        self.grant_sizes
+            .iter()
+            .filter(|(asset, _)| **asset != AssetCode::native())
+            .map(|(asset, size)| (*asset, *size))
+    }
+
+    /// Every asset which is granted, starting with the native asset, with its grant size.
+    fn assets(&self) -> impl Iterator<Item = (AssetCode, RecordAmount)> + '_ {
+        iter::once((AssetCode::native(), self.grant_size)).chain(self.extra_assets())
+    }
 }
 
-#[cfg(all(test, feature = "slow-tests"))]
-mod test {
-    use super::*;
-    use async_std::task::{sleep, spawn_blocking};
-    use escargot::CargoBuild;
-    use espresso_client::{hd::KeyTree, loader::CreateLoader};
-    use espresso_validator::testing::{minimal_test_network, retry};
+/// A shared, asynchronous queue of requests.
+///
+/// The queue is a model of an ordered map from public keys requesting assets to the number of
+/// record grants they have received. It is represented as an explicit `HashMap`, which is the
+/// authoritative data structure, as well as an auxiliary, implicit queue in the form of an
+/// unbounded multi-producer, multi-consumer channel. There is a separate channel for each
+/// [Priority], and workers always take a high priority request if one is waiting.
+///
+/// When a new request comes in, it can be added to the queue with [FaucetQueue::push]. This will
+/// perform validity checks and then add a new entry mapping the public key to 0. It will also send
+/// the public key as a message on the channel. If the public key is already in the queue, the new
+/// request is merged into the existing entry instead, so that a burst of requests from the same key
+/// results in a single grant. A worker thread will then pick the message off the
+/// channel using [FaucetQueue::pop], and start generating transfers to it. Each time the worker
+/// completes a transfer to the public key, it will call [FaucetQueue::grant], which increments the
+/// counter associated with that public key, persists the change, and instructs the worker to
+/// either continue transferring to the same key or to move on to the next key.
+///
+/// The queue is persistent, so that if the faucet crashes or gets restarted, it doesn't lose the
+/// queue of pending requests. The persistent queue is represented as a log of index entries, of the
+/// form `UserPubKey -> Option<usize>`. An entry `key -> Some(n)` corresponds to updating the
+/// counter associated with `key` to `n`. An entry `key -> None` corresponds to deleting the entry
+/// for `key`. We can recover the in-memory index by simply replaying each log entry and inserting
+/// or deleting into a `HashMap` as indicated.
+///
+/// Note that the persistent data format also encodes the order in which requests were added to the
+/// queue. A new request being added to the queue corresponds to an entry `key -> Some(0)`, so the
+/// queue simply consists of the most recent `key -> Some(0)` entry for each key, in order,
+/// filtering out keys that have a more recent `key -> None` entry.
+///
+/// The parameters of each request, such as its priority, are persisted in a separate log of entries
+/// `UserPubKey -> RequestInfo`, one for each time a key is added to the queue. The parameters of a
+/// request in the queue are given by the most recent entry for its key. Keys with no entries (for
+/// example, those added before these parameters were introduced) use the default parameters.
+#[derive(Clone)]
+struct FaucetQueue {
+    sender: mpmc::Sender<(UserPubKey, usize)>,
+    receiver: mpmc::Receiver<(UserPubKey, usize)>,
+    high_priority_sender: mpmc::Sender<(UserPubKey, usize)>,
+    high_priority_receiver: mpmc::Receiver<(UserPubKey, usize)>,
+    index: Arc<Mutex<FaucetQueueIndex>>,
+    max_len: Option<usize>,
+    escalation: Option<QueueEscalation>,
+}
+
+/// How to escalate when the queue stays full.
+#[derive(Clone)]
+struct QueueEscalation {
+    // How long the queue may stay full before escalating.
+    after: Duration,
+    // Where to post a `QueueAlert` when escalating, if anywhere.
+    alert_url: Option<Url>,
+    // The number of times the queue has escalated.
+    escalations: Arc<AtomicUsize>,
+}
+
+// A persistent ordered set.
+struct FaucetQueueIndex {
+    index: HashMap<UserPubKey, usize>,
+    // Parameters of keys in `index`. Keys which are not in this map use the default parameters.
+    info: HashMap<UserPubKey, RequestInfo>,
+    // The persistent logs, or [None] for a read-only snapshot of a queue another faucet is writing.
+    persistent: Option<QueueStore>,
+    // When requests were first rejected because the queue was full, if it has been full ever since.
+    full_since: Option<Instant>,
+    // Whether we have already escalated since the queue filled up.
+    escalated: bool,
+}
+
+/// The persistent logs backing a [FaucetQueueIndex].
+struct QueueStore {
+    store: AtomicStore,
+    queue: QueueLog,
+    info_log: RequestInfoLog,
+}
+
+impl FaucetQueueIndex {
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// The persistent logs of this index, which a read-only snapshot does not have.
+    fn persistent(&mut self) -> Result<&mut QueueStore, FaucetError> {
+        self.persistent
+            .as_mut()
+            .ok_or_else(|| FaucetError::Persistence {
+                msg: "the request queue is a read-only snapshot".into(),
+            })
+    }
+
+    /// Add an element to the persistent index.
+    ///
+    /// Returns `true` if the element was inserted or `false` if it was already in the index.
+    fn insert(&mut self, key: UserPubKey, info: RequestInfo) -> Result<bool, FaucetError> {
+        if self.index.contains_key(&key) {
+            // If the key is already in the index, we don't have to persist anything.
+            return Ok(false);
+        }
+
+        // Add the key to our persistent logs.
+        let persistent = self.persistent()?;
+        persistent
+            .info_log
+            .store_resource(&(key.clone(), info.clone()))
+            .map_err(|err| {
+                error!("storage error adding {} to queue: {}", key, err);
+                err
+            })?;
+        persistent
+            .queue
+            .store_resource(&(key.clone(), Some(0)))
+            .map_err(|err| {
+                error!("storage error adding {} to queue: {}", key, err);
+                err
+            })?;
+        persistent.info_log.commit_version().unwrap();
+        persistent.queue.commit_version().unwrap();
+        persistent.store.commit_version().unwrap();
+        // If successful, add it to our in-memory index.
+        if info != RequestInfo::default() {
+            self.info.insert(key.clone(), info);
+        }
+        self.index.insert(key, 0);
+        Ok(true)
+    }
+
+    /// Replace the parameters of the request from `key`, which must be in the index.
+    fn set_info(&mut self, key: UserPubKey, info: RequestInfo) -> Result<(), FaucetError> {
+        let persistent = self.persistent()?;
+        persistent
+            .info_log
+            .store_resource(&(key.clone(), info.clone()))
+            .map_err(|err| {
+                error!("storage error updating {} in queue: {}", key, err);
+                err
+            })?;
+        persistent.info_log.commit_version().unwrap();
+        persistent.store.commit_version().unwrap();
+        self.info.insert(key, info);
+        Ok(())
+    }
+
+    /// Increment the number of grants received by an element in the index.
+    ///
+    /// If the new number of grants is at least the number requested (`max_grants` unless the
+    /// request asked for a specific number) the entry is removed from the index. Otherwise, the
+    /// counter is simply updated.
+    ///
+    /// Returns `true` if this key needs more grants.
+    fn grant(
+        &mut self,
+        key: UserPubKey,
+        granted: usize,
+        max_grants: usize,
+    ) -> Result<bool, FaucetError> {
+        let max_grants = self.info(&key).num_grants.unwrap_or(max_grants);
+        let grants_given = self.index[&key] + granted;
+        if grants_given >= max_grants {
+            // If this is the last grant to this key, remove it from the index.
+            self.remove(&key)?;
+            Ok(false)
+        } else {
+            // Update the entry in our persistent log.
+            let persistent = self.persistent()?;
+            persistent
+                .queue
+                .store_resource(&(key.clone(), Some(grants_given)))
+                .map_err(|err| {
+                    error!("storage error updating {} in queue: {}", key, err);
+                    err
+                })?;
+            persistent.queue.commit_version().unwrap();
+            persistent.store.commit_version().unwrap();
+            // If successful, update our in-memory index.
+            self.index.insert(key, grants_given);
+            Ok(true)
+        }
+    }
+
+    /// Remove an element from the persistent set.
+    fn remove(&mut self, key: &UserPubKey) -> Result<(), FaucetError> {
+        // Make a persistent note to remove the key.
+        let persistent = self.persistent()?;
+        persistent
+            .queue
+            .store_resource(&(key.clone(), None))
+            .map_err(|err| {
+                error!("storage error removing {} from queue: {}", key, err);
+                err
+            })?;
+        persistent.queue.commit_version().unwrap();
+        persistent.store.commit_version().unwrap();
+        // Update our in-memory set.
+        self.index.remove(key);
+        self.info.remove(key);
+        Ok(())
+    }
+
+    /// Get the number of grants already given to this key.
+    fn grants(&self, key: &UserPubKey) -> usize {
+        self.index[key]
+    }
+
+    /// Get the number of grants already given to this key, if it is in the index.
+    fn get(&self, key: &UserPubKey) -> Option<usize> {
+        self.index.get(key).copied()
+    }
+
+    /// Get the parameters of the request from this key.
+    fn info(&self, key: &UserPubKey) -> RequestInfo {
+        self.info.get(key).cloned().unwrap_or_default()
+    }
+}
+
+impl FaucetQueue {
+    /// Load the persistent queue from `path`.
+    ///
+    /// If the persistent log contains more than `compaction_threshold` stale entries (entries which
+    /// have been superseded by a more recent entry for the same key) it is compacted, so that it
+    /// only contains entries for requests which are still in the queue.
+    ///
+    /// The queue is read and written in `format`, which must match the format of any existing queue
+    /// at `path`.
+    async fn load(
+        path: &Path,
+        max_len: Option<usize>,
+        compaction_threshold: usize,
+        format: QueueLogFormat,
+    ) -> Result<Self, FaucetError> {
+        check_queue_log_format(path, format)?;
+        recover_queue_compaction(path)?;
+        let mut persistent = open_queue_store(path, format)?;
+        let contents = replay_queue(&persistent)?;
+
+        // Each live key needs at most two entries: one to insert it into the queue and one to
+        // record the number of grants it has received.
+        let live_entries = 2 * contents.index.len();
+        if contents.num_entries > live_entries.saturating_add(compaction_threshold) {
+            info!(
+                "compacting faucet queue log ({} entries, {} requests in queue)",
+                contents.num_entries,
+                contents.index.len()
+            );
+            drop(persistent);
+            persistent = compact_queue_log(
+                path,
+                &contents.queue,
+                &contents.index,
+                &contents.info,
+                format,
+            )?;
+        }
+
+        Ok(Self::from_contents(contents, Some(persistent), max_len).await)
+    }
+
+    /// Load a read-only snapshot of the persistent queue at `path`, which another faucet may be
+    /// writing at the same time.
+    ///
+    /// Unlike [load](Self::load), this never writes to `path`: it does not record the format of the
+    /// queue, finish an interrupted compaction or compact the log, and it closes the persistent logs
+    /// as soon as it has read them. Requests cannot be added to or granted from the snapshot. If
+    /// there is no queue at `path` yet, the snapshot is empty.
+    async fn load_read_only(
+        path: &Path,
+        max_len: Option<usize>,
+        format: QueueLogFormat,
+    ) -> Result<Self, FaucetError> {
+        if !check_existing_queue_log_format(path, format)? {
+            return Ok(Self::from_contents(Default::default(), None, max_len).await);
+        }
+        // The other faucet replaces the files of the queue while it compacts it, so wait until it
+        // is done, and try again if it started while we were reading.
+        let compacting = || {
+            [COMPACTION_DIR, STALE_TMP_DIR, STALE_DIR]
+                .iter()
+                .any(|dir| path.join(dir).exists())
+        };
+        for _ in 0..READ_ONLY_LOAD_ATTEMPTS {
+            if compacting() {
+                sleep(READ_ONLY_LOAD_RETRY_INTERVAL).await;
+                continue;
+            }
+            let contents = open_queue_store(path, format).and_then(|store| replay_queue(&store));
+            if compacting() {
+                continue;
+            }
+            return Ok(Self::from_contents(contents?, None, max_len).await);
+        }
+        Err(FaucetError::Persistence {
+            msg: format!(
+                "the request queue at {} is being compacted, or a compaction was interrupted",
+                path.display()
+            ),
+        })
+    }
+
+    /// Build a queue from the `contents` of its persistent logs.
+    ///
+    /// `persistent` holds the open logs, or [None] for a read-only snapshot.
+    async fn from_contents(
+        contents: QueueContents,
+        persistent: Option<QueueStore>,
+        max_len: Option<usize>,
+    ) -> Self {
+        let QueueContents {
+            queue, index, info, ..
+        } = contents;
+        let (sender, receiver) = mpmc::unbounded();
+        let (high_priority_sender, high_priority_receiver) = mpmc::unbounded();
+        for key in queue {
+            let grants = index[&key];
+            let sender = match info.get(&key).map(|info| info.priority) {
+                Some(Priority::High) => &high_priority_sender,
+                _ => &sender,
+            };
+            // `send` only fails if the receiving end of the channel has been dropped, but we have
+            // the receiving end right now, so this `unwrap` will never fail.
+            sender.send((key, grants)).await.unwrap();
+        }
+
+        Self {
+            index: Arc::new(Mutex::new(FaucetQueueIndex {
+                index,
+                info,
+                persistent,
+                full_since: None,
+                escalated: false,
+            })),
+            sender,
+            receiver,
+            high_priority_sender,
+            high_priority_receiver,
+            max_len,
+            escalation: None,
+        }
+    }
+
+    /// Escalate when requests have been rejected for a full queue for longer than `after`.
+    ///
+    /// When escalating, the queue logs an error and posts a `QueueAlert` to `alert_url`, if there
+    /// is one.
+    fn escalate_when_full(mut self, after: Duration, alert_url: Option<Url>) -> Self {
+        self.escalation = Some(QueueEscalation {
+            after,
+            alert_url,
+            escalations: Default::default(),
+        });
+        self
+    }
+
+    /// The number of times this queue has escalated because it stayed full.
+    fn escalations(&self) -> usize {
+        self.escalation
+            .as_ref()
+            .map(|escalation| escalation.escalations.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+
+    /// Note that a request was rejected because the queue is full, and escalate if it has been
+    /// full for too long.
+    fn check_full(&self, index: &mut FaucetQueueIndex, max_len: usize) {
+        let escalation = match &self.escalation {
+            Some(escalation) => escalation,
+            None => return,
+        };
+        let full_for = index.full_since.get_or_insert_with(Instant::now).elapsed();
+        if index.escalated || full_for < escalation.after {
+            return;
+        }
+        index.escalated = true;
+        escalation.escalations.fetch_add(1, Ordering::SeqCst);
+        error!(
+            "faucet queue has been full ({} requests) for {:?}; the faucet may be under attack or \
+             need more workers",
+            max_len, full_for
+        );
+        if let Some(url) = escalation.alert_url.clone() {
+            let alert = QueueAlert {
+                max_len,
+                full_secs: full_for.as_secs(),
+            };
+            spawn(async move {
+                if let Err(err) = post_json(&url, &alert).await {
+                    warn!("failed to post queue alert to {}: {}", url, err);
+                }
+            });
+        }
+    }
+
+    /// The channel for requests with priority `priority`.
+    fn sender(&self, priority: Priority) -> &mpmc::Sender<(UserPubKey, usize)> {
+        match priority {
+            Priority::High => &self.high_priority_sender,
+            Priority::Normal => &self.sender,
+        }
+    }
+
+    /// Add a request from `key` to the queue.
+    ///
+    /// If `key` already has a request in the queue which has not been fully granted, the new
+    /// request is merged into the existing one: it succeeds without adding anything to the queue,
+    /// and the existing request keeps its parameters and its place in line.
+    async fn push(&self, key: UserPubKey, info: RequestInfo) -> Result<Pushed, FaucetError> {
+        {
+            let mut index = self.index.lock().await;
+            if let Some(grants) = index.get(&key) {
+                info!(
+                    "{} is already in the queue with {} grants, merging requests",
+                    key, grants
+                );
+                return Ok(Pushed::Existing {
+                    grants,
+                    info: index.info(&key),
+                });
+            }
+            if let Some(max_len) = self.max_len {
+                if index.len() >= max_len {
+                    warn!("rejecting {} because queue is full ({})", key, max_len);
+                    self.check_full(&mut index, max_len);
+                    return Err(FaucetError::QueueFull { max_len });
+                }
+            }
+            // There is room in the queue, so it is no longer persistently full.
+            index.full_since = None;
+            index.escalated = false;
+            // Insert this key into the index. We checked above that it is not already there.
+            index.insert(key.clone(), info.clone())?;
+        }
+        // If we successfully added the key to the index, we can send it to a receiver.
+        if self.sender(info.priority).send((key, 0)).await.is_err() {
+            warn!("failed to add request to the queue: channel is closed");
+        }
+        Ok(Pushed::New)
+    }
+
+    /// Add a high priority request for at least `count` more grants to `key`.
+    ///
+    /// Unlike [push](Self::push), this does not merge into an existing request from `key` as it is.
+    /// The existing request keeps the grants it has already received, but it is raised to high
+    /// priority, and the number of grants it asks for (`default_grants` unless it asked for a
+    /// specific number) is raised if necessary so that `key` receives at least `count` more.
+    async fn push_grant(
+        &self,
+        key: UserPubKey,
+        count: usize,
+        default_grants: usize,
+    ) -> Result<(), FaucetError> {
+        loop {
+            if self.upgrade(&key, count, default_grants).await? {
+                return Ok(());
+            }
+            let info = RequestInfo {
+                priority: Priority::High,
+                num_grants: Some(count),
+                label: None,
+                group: Vec::new(),
+            };
+            if let Pushed::New = self.push(key.clone(), info).await? {
+                return Ok(());
+            }
+            // A request from `key` was added since we checked, so upgrade that one instead.
+        }
+    }
+
+    /// Raise the request from `key` to high priority, and to at least `count` more grants.
+    ///
+    /// Returns `false` if `key` has no request in the queue.
+    async fn upgrade(
+        &self,
+        key: &UserPubKey,
+        count: usize,
+        default_grants: usize,
+    ) -> Result<bool, FaucetError> {
+        let grants = {
+            let mut index = self.index.lock().await;
+            let grants = match index.get(key) {
+                Some(grants) => grants,
+                None => return Ok(false),
+            };
+            let mut info = index.info(key);
+            let num_grants = info
+                .num_grants
+                .unwrap_or(default_grants)
+                .max(grants + count);
+            info!(
+                "raising request from {} to high priority with {} grants ({} received)",
+                key, num_grants, grants
+            );
+            info.priority = Priority::High;
+            info.num_grants = Some(num_grants);
+            index.set_info(key.clone(), info)?;
+            grants
+        };
+        // Send the key on the high priority channel, so it does not have to wait behind normal
+        // priority requests. Its message in the normal priority channel is left behind, and is
+        // skipped by the worker which receives it if the request is complete by then.
+        if self
+            .sender(Priority::High)
+            .send((key.clone(), grants))
+            .await
+            .is_err()
+        {
+            warn!("failed to add request to the queue: channel is closed");
+        }
+        Ok(true)
+    }
+
+    /// Replace the contents of this queue with the persistent queue at `path`.
+    ///
+    /// This is used by a standby to take over the queue of the faucet it was standing in for, when
+    /// it is promoted. The queue is loaded for writing, replacing any read-only snapshot this queue
+    /// held. Any requests already waiting in this queue are discarded in favor of the reloaded
+    /// ones.
+    async fn reload(
+        &self,
+        path: &Path,
+        compaction_threshold: usize,
+        format: QueueLogFormat,
+    ) -> Result<(), FaucetError> {
+        let mut index = self.index.lock().await;
+        let fresh = Self::load(path, self.max_len, compaction_threshold, format).await?;
+        while self.receiver.try_recv().is_ok() {}
+        while self.high_priority_receiver.try_recv().is_ok() {}
+        while let Ok(request) = fresh.receiver.try_recv() {
+            self.sender.send(request).await.unwrap();
+        }
+        while let Ok(request) = fresh.high_priority_receiver.try_recv() {
+            self.high_priority_sender.send(request).await.unwrap();
+        }
+        // `fresh` is not shared, so this is its only reference to the index.
+        let fresh_index = match Arc::try_unwrap(fresh.index) {
+            Ok(fresh_index) => fresh_index.into_inner(),
+            Err(_) => unreachable!(),
+        };
+        *index = fresh_index;
+        info!("reloaded request queue with {} requests", index.len());
+        Ok(())
+    }
+
+    async fn pop(&mut self) -> Option<(UserPubKey, usize)> {
+        // Prefer high priority requests whenever both channels are ready.
+        select_biased! {
+            req = self.high_priority_receiver.next() => req,
+            req = self.receiver.next() => req,
+        }
+    }
+
+    async fn grant(&mut self, request: UserPubKey, granted: usize, max_grants: usize) -> bool {
+        self.index
+            .lock()
+            .await
+            .grant(request, granted, max_grants)
+            .unwrap_or(false)
+    }
+
+    /// The label attached to the request from `key`, if any.
+    async fn label(&self, key: &UserPubKey) -> Option<String> {
+        self.index.lock().await.info(key).label
+    }
+
+    /// The other keys in the group request from `key`, if it is a group request.
+    async fn group(&self, key: &UserPubKey) -> Vec<UserPubKey> {
+        self.index.lock().await.info(key).group
+    }
+
+    /// The number of grants requested by `key`, or `default` if it did not ask for a specific
+    /// number.
+    async fn num_grants(&self, key: &UserPubKey, default: usize) -> usize {
+        self.index
+            .lock()
+            .await
+            .info(key)
+            .num_grants
+            .unwrap_or(default)
+    }
+
+    async fn fail(&mut self, key: UserPubKey) {
+        let (grants, priority) = {
+            let index = self.index.lock().await;
+            (index.grants(&key), index.info(&key).priority)
+        };
+        if let Err(err) = self.sender(priority).send((key, grants)).await {
+            error!(
+                "error re-adding failed request; request will be dropped. {}",
+                err
+            );
+        }
+    }
+}
+
+/// The outcome of adding a request to a [FaucetQueue].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pushed {
+    /// The request was added to the queue.
+    New,
+    /// The key already had a request in the queue, with parameters `info`, which has received
+    /// `grants` grants so far. The new request was merged into it.
+    Existing { grants: usize, info: RequestInfo },
+}
+
+type QueueLog = AppendLog<QueueLoadStore<(UserPubKey, Option<usize>)>>;
+type RequestInfoLog = AppendLog<QueueLoadStore<(UserPubKey, RequestInfo)>>;
+
+/// Serialization format for the entries of the persistent request queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueLogFormat {
+    /// Compact, but not self-describing. This is the format of queues written before the format
+    /// was configurable.
+    #[default]
+    Bincode,
+    /// Self-describing, so entries remain readable when fields are added.
+    Cbor,
+}
+
+impl FromStr for QueueLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Self::Bincode),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!(
+                "invalid queue log format {}, expected \"bincode\" or \"cbor\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for QueueLogFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Bincode => write!(f, "bincode"),
+            Self::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+/// An entry in one of the persistent queue logs.
+trait QueueEntry: Serialize + DeserializeOwned {
+    /// Decode an entry written in [QueueLogFormat::Bincode].
+    ///
+    /// Entry types whose layout has changed override this to also accept their older layouts.
+    fn from_bincode(stream: &[u8]) -> Result<Self, PersistenceError> {
+        BincodeLoadStore::<Self>::default().load(stream)
+    }
+}
+
+impl QueueEntry for (UserPubKey, Option<usize>) {}
+
+impl QueueEntry for (UserPubKey, RequestInfo) {
+    fn from_bincode(stream: &[u8]) -> Result<Self, PersistenceError> {
+        // Each layout appends fields to the one before it, so an entry in an older layout is too
+        // short to decode in a newer one. Try the current layout first, and report its error if
+        // none of them fit.
+        let err = match BincodeLoadStore::<Self>::default().load(stream) {
+            Ok(entry) => return Ok(entry),
+            Err(err) => err,
+        };
+        if let Ok((key, info)) =
+            BincodeLoadStore::<(UserPubKey, RequestInfoV2)>::default().load(stream)
+        {
+            return Ok((key, info.into()));
+        }
+        if let Ok((key, info)) =
+            BincodeLoadStore::<(UserPubKey, RequestInfoV1)>::default().load(stream)
+        {
+            return Ok((key, info.into()));
+        }
+        Err(err)
+    }
+}
+
+/// A [LoadStore] which serializes queue entries in a configurable [QueueLogFormat].
+#[derive(Debug)]
+struct QueueLoadStore<T> {
+    format: QueueLogFormat,
+    _marker: PhantomData<T>,
+}
+
+impl<T> QueueLoadStore<T> {
+    fn new(format: QueueLogFormat) -> Self {
+        Self {
+            format,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: QueueEntry> LoadStore for QueueLoadStore<T> {
+    type ParamType = T;
+
+    fn load(&self, stream: &[u8]) -> Result<T, PersistenceError> {
+        match self.format {
+            QueueLogFormat::Bincode => T::from_bincode(stream),
+            QueueLogFormat::Cbor => {
+                ciborium::de::from_reader(stream).map_err(|err| PersistenceError::OtherLoadError {
+                    inner: Box::new(err),
+                })
+            }
+        }
+    }
+
+    fn store(&mut self, param: &T) -> Result<Vec<u8>, PersistenceError> {
+        match self.format {
+            QueueLogFormat::Bincode => BincodeLoadStore::<T>::default().store(param),
+            QueueLogFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(param, &mut bytes).map_err(|err| {
+                    PersistenceError::OtherStoreError {
+                        inner: Box::new(err),
+                    }
+                })?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// The contents of a persistent queue, replayed from its logs.
+#[derive(Default)]
+struct QueueContents {
+    // The keys in the queue, in order.
+    queue: Vec<UserPubKey>,
+    // The number of grants each key in the queue has received.
+    index: HashMap<UserPubKey, usize>,
+    // The parameters of keys in the queue which do not use the defaults.
+    info: HashMap<UserPubKey, RequestInfo>,
+    // The number of entries in the request log.
+    num_entries: usize,
+}
+
+/// Open the persistent queue at `path`, whose entries are in `format`.
+fn open_queue_store(path: &Path, format: QueueLogFormat) -> Result<QueueStore, FaucetError> {
+    let mut loader = AtomicStoreLoader::load(path, "queue")?;
+    let queue = AppendLog::load(&mut loader, QueueLoadStore::new(format), "requests", 1024)?;
+    let info_log = AppendLog::load(
+        &mut loader,
+        QueueLoadStore::new(format),
+        "request_info",
+        1024,
+    )?;
+    let store = AtomicStore::open(loader)?;
+    Ok(QueueStore {
+        store,
+        queue,
+        info_log,
+    })
+}
+
+/// Replay the logs of a persistent queue to find the requests which are still in the queue.
+fn replay_queue(store: &QueueStore) -> Result<QueueContents, FaucetError> {
+    // Traverse the persisted queue entries backwards. This ensures that we encounter the most
+    // recent value for each key first. If the most recent value for a given key is `Some(n)`,
+    // it gets added to the index. If it is `None`, we just store `None` in `index` so that if
+    // we see this key again, we know we are not seeing the most recent value.
+    let mut index = HashMap::new();
+    // In addition, for the most recent `Some(0)` entry for each `key`, we also add that key to
+    // the message channel, as long as there is not a more recent `None` entry. We use the set
+    // `processed` to keep track of which elements have already been processed into the message
+    // channel if necessary. An element is `processed` if we have added it to the message
+    // channel, or if we have encountered a `None` entry for it and skipped it.
+    let mut processed = HashSet::new();
+    // We are encountering requests in reverse order, so if we need to add them to the queue, we
+    // will add them to this [Vec] and then reverse it at the end before adding them to the
+    // message channel.
+    let mut queue = Vec::new();
+    let entries = read_queue_log(&store.queue, "queue")?;
+    let num_entries = entries.len();
+    for (key, val) in entries.into_iter().rev() {
+        if !index.contains_key(&key) {
+            if let Some(val) = val {
+                // This is the most recent value for `key`, and it is an insert, which means
+                // `key` is in the queue. Go ahead and add it to the index and the message
+                // channel.
+                index.insert(key.clone(), Some(val));
+            } else {
+                // This is the most recent value for `key`, and it is a delete, which means
+                // `key` is not in the queue. Remember this information in `index`.
+                index.insert(key.clone(), None);
+            }
+        }
+
+        if !processed.contains(&key) {
+            // We have seen neither a `Some(0)` or `None` entry for this element.
+            if val == Some(0) {
+                // In the case of a `Some(0)` entry, the element should be in the queue.
+                queue.push(key.clone());
+                processed.insert(key);
+            } else if val == None {
+                // In the case of a `None` entry, just add the element to `processed` so that it
+                // will not be added to the queue later.
+                processed.insert(key);
+            }
+        }
+    }
+
+    // Post-process `index` to remove [None] values.
+    let index = index
+        .into_iter()
+        .filter_map(|(key, val)| val.map(|val| (key, val)))
+        .collect::<HashMap<_, _>>();
+    queue.reverse();
+
+    // Replay the request parameter log. Later entries override earlier ones, since each entry
+    // records the parameters of the most recent request from its key.
+    let mut info = HashMap::new();
+    for (key, key_info) in read_queue_log(&store.info_log, "request parameter")? {
+        info.insert(key, key_info);
+    }
+    info.retain(|key, key_info| index.contains_key(key) && *key_info != RequestInfo::default());
+
+    Ok(QueueContents {
+        queue,
+        index,
+        info,
+        num_entries,
+    })
+}
+
+/// How many times to try loading a read-only snapshot of a queue which is being compacted.
+const READ_ONLY_LOAD_ATTEMPTS: usize = 10;
+
+/// How long to wait between attempts to load a read-only snapshot of a queue.
+const READ_ONLY_LOAD_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Read every entry of a persistent queue log, whose entries are described as `name` entries.
+///
+/// If the faucet was interrupted while writing an entry (for example, by a power loss) the last
+/// entry in the log may be truncated and fail to deserialize. Rather than refusing to start, we
+/// skip such an entry, which recovers the log as of the last intact entry. At worst, this loses the
+/// one update which was being written when we were interrupted. A corrupt entry anywhere else
+/// cannot be explained by an interrupted write, so it is an error.
+fn read_queue_log<T: QueueEntry>(
+    log: &AppendLog<QueueLoadStore<T>>,
+    name: &str,
+) -> Result<Vec<T>, FaucetError> {
+    let mut entries = Vec::new();
+    let mut corrupt = None;
+    for (i, entry) in log.iter().enumerate() {
+        if let Some((index, err)) = corrupt.take() {
+            return Err(FaucetError::Persistence {
+                msg: format!("corrupt {} entry {} is not the last: {}", name, index, err),
+            });
+        }
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(err) => corrupt = Some((i, err)),
+        }
+    }
+    if let Some((index, err)) = corrupt {
+        warn!("skipping truncated {} entry {}: {}", name, index, err);
+    }
+    Ok(entries)
+}
+
+/// Check that the queue at `path`, if there is one, was written in `format`.
+///
+/// The format of a queue is recorded in a file alongside the queue when the queue is created. A
+/// queue with no such record predates the option, and so is in the default format.
+fn check_queue_log_format(path: &Path, format: QueueLogFormat) -> Result<(), FaucetError> {
+    if check_existing_queue_log_format(path, format)? {
+        return Ok(());
+    }
+    let format_path = path.join("queue_format");
+    fs::create_dir_all(path)
+        .and_then(|()| fs::write(&format_path, format.to_string()))
+        .map_err(|err| FaucetError::Persistence {
+            msg: format!("unable to write {}: {}", format_path.display(), err),
+        })
+}
+
+/// Check that the queue at `path`, if there is one, was written in `format`, without recording the
+/// format of a new queue.
+///
+/// Returns whether there is a queue at `path`.
+fn check_existing_queue_log_format(
+    path: &Path,
+    format: QueueLogFormat,
+) -> Result<bool, FaucetError> {
+    let format_path = path.join("queue_format");
+    let existing = match fs::read_to_string(&format_path) {
+        Ok(existing) => Some(
+            existing
+                .trim()
+                .parse()
+                .map_err(|msg| FaucetError::Config { msg })?,
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let has_queue = fs::read_dir(path)
+                .map(|mut entries| {
+                    entries.any(|entry| {
+                        entry.map_or(false, |entry| {
+                            entry.file_name().to_string_lossy().starts_with("requests")
+                        })
+                    })
+                })
+                .unwrap_or(false);
+            has_queue.then(QueueLogFormat::default)
+        }
+        Err(err) => {
+            return Err(FaucetError::Persistence {
+                msg: format!("unable to read {}: {}", format_path.display(), err),
+            })
+        }
+    };
+    match existing {
+        Some(existing) if existing != format => Err(FaucetError::Config {
+            msg: format!(
+                "the request queue at {} is in {} format, but the configured format is {}",
+                path.display(),
+                existing,
+                format
+            ),
+        }),
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+/// Replace the persistent queue at `path` with a new log containing only the live requests.
+///
+/// `queue` is the list of keys in the queue, in order, `index` maps each key to the number of
+/// grants it has already received, and `info` holds the parameters of keys which do not use the
+/// defaults. The new log is written in `format`. The rewritten log replays to the same queue and
+/// index as the original, but without the history of requests which have already been removed.
+///
+/// The compacted log is written to a temporary directory inside `path` and committed before it
+/// replaces the original, so that if the faucet crashes during compaction, either the original or
+/// the compacted log survives intact. See [recover_queue_compaction].
+fn compact_queue_log(
+    path: &Path,
+    queue: &[UserPubKey],
+    index: &HashMap<UserPubKey, usize>,
+    info: &HashMap<UserPubKey, RequestInfo>,
+    format: QueueLogFormat,
+) -> Result<QueueStore, FaucetError> {
+    let compaction_err = |err: std::io::Error| FaucetError::Persistence {
+        msg: format!(
+            "unable to compact request queue at {}: {}",
+            path.display(),
+            err
+        ),
+    };
+    let tmp = path.join(COMPACTION_TMP_DIR);
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp).map_err(compaction_err)?;
+    }
+    write_compacted_queue(&tmp, queue, index, info, format)?;
+    // Once the compacted log is complete, mark it as such, so that an interrupted compaction is
+    // finished rather than discarded.
+    fs::rename(&tmp, path.join(COMPACTION_DIR)).map_err(compaction_err)?;
+    finish_queue_compaction(path).map_err(compaction_err)?;
+    open_queue_store(path, format)
+}
+
+/// The directory in which a compacted queue log is written.
+const COMPACTION_TMP_DIR: &str = ".queue_compaction.tmp";
+/// The directory holding a complete compacted queue log, until it replaces the original.
+const COMPACTION_DIR: &str = ".queue_compaction";
+/// The directory into which the files of the original queue log are moved.
+const STALE_TMP_DIR: &str = ".queue_stale.tmp";
+/// The directory holding all the files of the original queue log, until they are deleted.
+const STALE_DIR: &str = ".queue_stale";
+
+/// Write a new persistent queue in `dir` containing only the requests in `queue`.
+fn write_compacted_queue(
+    dir: &Path,
+    queue: &[UserPubKey],
+    index: &HashMap<UserPubKey, usize>,
+    info: &HashMap<UserPubKey, RequestInfo>,
+    format: QueueLogFormat,
+) -> Result<(), FaucetError> {
+    fs::create_dir_all(dir).map_err(|err| FaucetError::Persistence {
+        msg: format!("unable to create {}: {}", dir.display(), err),
+    })?;
+    let mut loader = AtomicStoreLoader::create(dir, "queue")?;
+    let mut log = AppendLog::create(&mut loader, QueueLoadStore::new(format), "requests", 1024)?;
+    let mut info_log = AppendLog::create(
+        &mut loader,
+        QueueLoadStore::new(format),
+        "request_info",
+        1024,
+    )?;
+    let mut store = AtomicStore::open(loader)?;
+    for key in queue {
+        log.store_resource(&(key.clone(), Some(0)))?;
+        let grants = index[key];
+        if grants > 0 {
+            log.store_resource(&(key.clone(), Some(grants)))?;
+        }
+        // We only need to record parameters which differ from the defaults.
+        if let Some(key_info) = info.get(key) {
+            info_log.store_resource(&(key.clone(), key_info.clone()))?;
+        }
+    }
+    log.commit_version()?;
+    info_log.commit_version()?;
+    store.commit_version()?;
+    Ok(())
+}
+
+/// Replace the files of the persistent queue at `path` with the compacted ones in [COMPACTION_DIR].
+///
+/// The original files are all moved aside before any compacted file is moved into place, and each
+/// step is marked by renaming a directory, so this can be interrupted at any point and finished by
+/// calling it again.
+fn finish_queue_compaction(path: &Path) -> std::io::Result<()> {
+    let compacted = path.join(COMPACTION_DIR);
+    let stale = path.join(STALE_DIR);
+    if !stale.exists() {
+        let stale_tmp = path.join(STALE_TMP_DIR);
+        fs::create_dir_all(&stale_tmp)?;
+        move_queue_files(path, &stale_tmp)?;
+        fs::rename(&stale_tmp, &stale)?;
+    }
+    move_queue_files(&compacted, path)?;
+    fs::remove_dir(&compacted)?;
+    fs::remove_dir_all(&stale)
+}
+
+/// Clean up after an interrupted compaction of the persistent queue at `path`, if there was one.
+///
+/// If the compacted log was completely written, the compaction is finished. Otherwise the original
+/// log is still intact, and the partially written compacted log is discarded.
+fn recover_queue_compaction(path: &Path) -> Result<(), FaucetError> {
+    let recovery_err = |err: std::io::Error| FaucetError::Persistence {
+        msg: format!(
+            "unable to recover interrupted compaction of request queue at {}: {}",
+            path.display(),
+            err
+        ),
+    };
+    let tmp = path.join(COMPACTION_TMP_DIR);
+    if tmp.exists() {
+        warn!("discarding incomplete compaction of request queue");
+        fs::remove_dir_all(&tmp).map_err(recovery_err)?;
+    }
+    if path.join(COMPACTION_DIR).exists() {
+        warn!("finishing interrupted compaction of request queue");
+        finish_queue_compaction(path).map_err(recovery_err)?;
+    } else if path.join(STALE_DIR).exists() {
+        fs::remove_dir_all(path.join(STALE_DIR)).map_err(recovery_err)?;
+    }
+    Ok(())
+}
+
+/// Move the files of a persistent queue from the directory `from` to `to`.
+///
+/// The queue may share its directory with the keystore, so only the files of the queue's atomic
+/// store and its logs are moved.
+fn move_queue_files(from: &Path, to: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_queue_file = {
+            let name = name.to_string_lossy();
+            name != "queue_format"
+                && ["queue", "requests", "request_info"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+        };
+        if is_queue_file && entry.file_type()?.is_file() {
+            fs::rename(entry.path(), to.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+/// The length of the window over which the daily cap is enforced.
+const DAILY_CAP_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+type DispensedLog = AppendLog<BincodeLoadStore<(u64, u64)>>;
+
+/// Persistent accounting of the native asset dispensed by the faucet, for enforcing a daily cap.
+///
+/// The faucet dispenses at most `cap` in each window of [DAILY_CAP_WINDOW]. A window starts with
+/// the first grant after the previous window ended. The state is persisted as a log of entries
+/// `(window_start, dispensed)`, where `window_start` is in seconds since the Unix epoch. The most
+/// recent intact entry is the current state.
+struct DailyCap {
+    cap: u64,
+    window_start: u64,
+    dispensed: u64,
+    store: AtomicStore,
+    log: DispensedLog,
+}
+
+impl DailyCap {
+    /// Load the accounting for a faucet with a cap of `cap` from `path`.
+    fn load(path: &Path, cap: u64) -> Result<Self, FaucetError> {
+        let mut loader = AtomicStoreLoader::load(path, "daily_cap")?;
+        let log: DispensedLog =
+            AppendLog::load(&mut loader, Default::default(), "dispensed", 1024)?;
+        let store = AtomicStore::open(loader)?;
+        let mut window_start = 0;
+        let mut dispensed = 0;
+        // As with the request queue, skip any entries which were truncated by an interruption.
+        for (i, entry) in log.iter().enumerate() {
+            match entry {
+                Ok(entry) => (window_start, dispensed) = entry,
+                Err(err) => warn!("skipping corrupt daily cap entry {}: {}", i, err),
+            }
+        }
+        Ok(Self {
+            cap,
+            window_start,
+            dispensed,
+            store,
+            log,
+        })
+    }
+
+    /// The amount dispensed in the window containing `now`.
+    fn dispensed(&self, now: u64) -> u64 {
+        if now >= self.window_start + DAILY_CAP_WINDOW.as_secs() {
+            0
+        } else {
+            self.dispensed
+        }
+    }
+
+    /// Check that a new request may be accepted at time `now`.
+    fn check(&self, now: u64) -> Result<(), FaucetError> {
+        if self.dispensed(now) >= self.cap {
+            warn!(
+                "rejecting request because the daily cap of {} has been reached",
+                self.cap
+            );
+            return Err(FaucetError::DailyCapReached { cap: self.cap });
+        }
+        Ok(())
+    }
+
+    /// How long to wait from `now` before `amount` can be dispensed without exceeding the cap, or
+    /// `None` if it can be dispensed now.
+    ///
+    /// Workers granting at the same time may each pass this check before either records its
+    /// grant, so the cap can be overshot by one transfer per worker. A window in which nothing has
+    /// been dispensed admits any amount, so a transfer larger than the cap is not held forever.
+    fn wait_time(&self, amount: u64, now: u64) -> Option<Duration> {
+        let dispensed = self.dispensed(now);
+        if dispensed == 0 || dispensed.saturating_add(amount) <= self.cap {
+            return None;
+        }
+        Some(Duration::from_secs(
+            self.window_start + DAILY_CAP_WINDOW.as_secs() - now,
+        ))
+    }
+
+    /// Persistently record that `amount` was dispensed at time `now`.
+    fn record(&mut self, amount: u64, now: u64) -> Result<(), FaucetError> {
+        let (window_start, dispensed) = if now >= self.window_start + DAILY_CAP_WINDOW.as_secs() {
+            // The previous window has ended, start a new one.
+            (now, amount)
+        } else {
+            (self.window_start, self.dispensed.saturating_add(amount))
+        };
+        self.log.store_resource(&(window_start, dispensed))?;
+        self.log.commit_version()?;
+        self.store.commit_version()?;
+        self.window_start = window_start;
+        self.dispensed = dispensed;
+        Ok(())
+    }
+}
+
+type GrantHistoryLog = AppendLog<BincodeLoadStore<GrantEvent>>;
+
+/// A persistent log of every grant made by the faucet, for operators investigating abuse.
+struct GrantHistory {
+    store: AtomicStore,
+    log: GrantHistoryLog,
+}
+
+impl GrantHistory {
+    /// Load the grant history from `path`.
+    fn load(path: &Path) -> Result<Self, FaucetError> {
+        let mut loader = AtomicStoreLoader::load(path, "grant_history")?;
+        let log = AppendLog::load(&mut loader, Default::default(), "grants", 1024)?;
+        let store = AtomicStore::open(loader)?;
+        Ok(Self { store, log })
+    }
+
+    /// Persistently record `event`.
+    fn record(&mut self, event: &GrantEvent) -> Result<(), FaucetError> {
+        self.log.store_resource(event)?;
+        self.log.commit_version()?;
+        self.store.commit_version()?;
+        Ok(())
+    }
+
+    /// All grants to `pub_key`, oldest first.
+    ///
+    /// The log is not indexed by key, so this scans the whole history.
+    fn grants_to(&self, pub_key: &UserPubKey) -> Vec<GrantEvent> {
+        self.log
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| match entry {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    warn!("skipping corrupt grant history entry {}: {}", i, err);
+                    None
+                }
+            })
+            .filter(|event| event.pub_key == *pub_key)
+            .collect()
+    }
+}
+
+/// The current time in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn status_report(state: &FaucetState) -> Result<StatusReport, FaucetError> {
+    let status = *state.status.read().await;
+    let queue_len = state.queue.index.lock().await.len();
+    let balance = state
+        .keystore
+        .lock()
+        .await
+        .balance(&AssetCode::native())
+        .await;
+    Ok(StatusReport {
+        status,
+        queue_len,
+        balance: balance.to_string(),
+    })
+}
+
+async fn stats_report(state: &FaucetState) -> Result<StatsReport, FaucetError> {
+    let grants = state.stats.grants.load(Ordering::SeqCst);
+    let queue_len = state.queue.index.lock().await.len();
+    let balance = state
+        .keystore
+        .lock()
+        .await
+        .balance(&AssetCode::native())
+        .await;
+    Ok(StatsReport {
+        grants,
+        // Every grant of the native asset is a record of `grant_size`.
+        tokens_dispensed: (U256::from(state.grant_size) * grants).to_string(),
+        queue_len,
+        uptime_secs: state.stats.started.elapsed().as_secs(),
+        balance: balance.to_string(),
+    })
+}
+
+/// Running totals of the work done by the faucet since it started.
+#[derive(Debug)]
+struct FaucetStats {
+    started: Instant,
+    /// The number of grant records transferred.
+    grants: AtomicUsize,
+    /// The number of transactions submitted to grant the native asset.
+    transactions: AtomicUsize,
+    /// The number of attempts to grant to a request which failed and had to be retried.
+    failures: AtomicUsize,
+    /// The number of times a worker waited longer than `lock_timeout` for the keystore lock.
+    lock_timeouts: AtomicUsize,
+}
+
+impl FaucetStats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            grants: AtomicUsize::new(0),
+            transactions: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            lock_timeouts: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// A summary of the work done by the faucet, reported when it shuts down.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The number of grant records transferred.
+    pub grants: usize,
+    /// The number of transactions submitted to grant the native asset.
+    pub transactions: usize,
+    /// The number of attempts to grant to a request which failed and had to be retried.
+    pub failures: usize,
+    /// The number of times a worker waited longer than `lock_timeout` for the keystore lock.
+    pub lock_timeouts: usize,
+    /// The number of requests which have not yet received all of their grants.
+    pub queue_len: usize,
+    pub uptime: Duration,
+}
+
+async fn shutdown_report(state: &FaucetState) -> ShutdownReport {
+    ShutdownReport {
+        grants: state.stats.grants.load(Ordering::SeqCst),
+        transactions: state.stats.transactions.load(Ordering::SeqCst),
+        failures: state.stats.failures.load(Ordering::SeqCst),
+        lock_timeouts: state.stats.lock_timeouts.load(Ordering::SeqCst),
+        queue_len: state.queue.index.lock().await.len(),
+        uptime: state.stats.started.elapsed(),
+    }
+}
+
+/// Log `report` as a single structured event.
+fn log_shutdown_report(report: &ShutdownReport) {
+    info!(
+        grants = report.grants,
+        transactions = report.transactions,
+        failures = report.failures,
+        lock_timeouts = report.lock_timeouts,
+        queue_len = report.queue_len,
+        uptime = ?report.uptime,
+        "faucet shutting down"
+    );
+}
+
+/// How often to check whether the workers have finished their requests when shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stop the faucet `workers` and background tasks, then log a [ShutdownReport].
+///
+/// The workers stop taking new requests, and have up to `timeout` to finish the requests they are
+/// working on. Then the workers and the background tasks are cancelled. Requests which were not
+/// finished stay in the persistent queue.
+async fn shut_down(state: &FaucetState, workers: Vec<JoinHandle<()>>, timeout: Duration) {
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let finished = async_std::future::timeout(timeout, async {
+        while state
+            .workers
+            .read()
+            .await
+            .values()
+            .any(|(task, _)| *task != WorkerTask::Idle)
+        {
+            sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    if finished.is_err() {
+        warn!(
+            "workers did not finish their requests within {:?}, stopping them anyway",
+            timeout
+        );
+    }
+    join_all(workers.into_iter().map(|worker| worker.cancel())).await;
+    let background = std::mem::take(&mut *state.background_tasks.lock().await);
+    join_all(background.into_iter().map(|task| task.cancel())).await;
+    log_shutdown_report(&shutdown_report(state).await);
+}
+
+/// What a faucet worker is currently doing.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerTask {
+    /// Waiting for a request.
+    Idle,
+    /// Granting assets to `pub_key`.
+    Processing { pub_key: UserPubKey },
+}
+
+/// The state of a faucet worker, for debugging.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WorkerReport {
+    pub id: usize,
+    pub task: WorkerTask,
+    /// How long the worker has been on its current task, in seconds.
+    pub elapsed_secs: u64,
+}
+
+/// A failed attempt to grant to a request.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FailureReport {
+    pub pub_key: UserPubKey,
+    pub error: String,
+    /// The time of the failure, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// The number of grant failures kept for the `failures` endpoint.
+const MAX_RECENT_FAILURES: usize = 100;
+
+async fn failures(
+    req: RequestParams,
+    state: &FaucetState,
+) -> Result<Vec<FailureReport>, FaucetError> {
+    check_admin_token(&req, state)?;
+    let limit = req
+        .opt_integer_param("limit")?
+        .unwrap_or(MAX_RECENT_FAILURES);
+    Ok(state
+        .recent_failures
+        .lock()
+        .await
+        .iter()
+        .rev()
+        .take(limit)
+        .cloned()
+        .collect())
+}
+
+/// The expected cost of a grant of the native asset, given the faucet's current records.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GrantEstimate {
+    /// Whether the faucet's spendable records cover the grant and its fee.
+    pub feasible: bool,
+    /// The number of records the transfer is expected to spend.
+    pub inputs: usize,
+    /// The number of records the transfer is expected to create, including change.
+    pub outputs: usize,
+    /// The fee paid by the transfer, in the native asset.
+    pub fee: RecordAmount,
+}
+
+/// Estimate the cost of transferring `amount` plus `fee` out of `records`, without transferring.
+///
+/// Records are spent largest first, which uses the fewest inputs. The keystore is free to choose
+/// differently, so the number of inputs is a lower bound. If the records do not cover the
+/// transfer, the estimate spends all of them and is not feasible.
+fn estimate_transfer(
+    records: impl IntoIterator<Item = RecordAmount>,
+    amount: RecordAmount,
+    fee: RecordAmount,
+) -> GrantEstimate {
+    let mut records = records.into_iter().map(U256::from).collect::<Vec<_>>();
+    records.sort_by(|a, b| b.cmp(a));
+    let needed = U256::from(amount) + U256::from(fee);
+    let mut total = U256::zero();
+    let mut inputs = 0;
+    for record in records {
+        if total >= needed {
+            break;
+        }
+        total += record;
+        inputs += 1;
+    }
+    GrantEstimate {
+        feasible: total >= needed,
+        inputs,
+        // One output for the grant, and one for change if the inputs are worth more than needed.
+        outputs: if total > needed { 2 } else { 1 },
+        fee,
+    }
+}
+
+async fn estimate_grant(
+    req: RequestParams,
+    state: &FaucetState,
+) -> Result<GrantEstimate, FaucetError> {
+    check_admin_token(&req, state)?;
+    let keystore = state.keystore.lock().await;
+    let now = keystore.read().await.state().validator.block_height();
+    let records = keystore
+        .records()
+        .await
+        .into_iter()
+        .filter(|record| {
+            record.asset_code() == AssetCode::native()
+                && record.freeze_flag() == FreezeFlag::Unfrozen
+                && !record.on_hold(now)
+        })
+        .map(|record| record.amount());
+    Ok(estimate_transfer(
+        records,
+        state.grant_size,
+        state.fee_size(&AssetCode::native()),
+    ))
+}
+
+async fn history(req: RequestParams, state: &FaucetState) -> Result<Vec<GrantEvent>, FaucetError> {
+    check_admin_token(&req, state)?;
+    let pub_key: UserPubKey = req
+        .string_param("pubkey")?
+        .parse()
+        .map_err(|err| bad_request("pubkey", format!("invalid tagged-base64: {}", err)))?;
+    // The route is only registered when the history is enabled.
+    let history = state.grant_history.as_ref().unwrap();
+    Ok(history.lock().await.grants_to(&pub_key))
+}
+
+async fn workers(
+    req: RequestParams,
+    state: &FaucetState,
+) -> Result<Vec<WorkerReport>, FaucetError> {
+    check_admin_token(&req, state)?;
+    Ok(state
+        .workers
+        .read()
+        .await
+        .iter()
+        .map(|(id, (task, since))| WorkerReport {
+            id: *id,
+            task: task.clone(),
+            elapsed_secs: since.elapsed().as_secs(),
+        })
+        .collect())
+}
+
+/// The version of the faucet build which is running.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The version of the faucet crate.
+    pub version: String,
+    /// The git commit the faucet was built from, or `unknown`.
+    pub git_sha: String,
+    /// The time of the build, in seconds since the Unix epoch.
+    pub build_timestamp: u64,
+}
+
+fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("ESPRESSO_FAUCET_GIT_SHA").to_string(),
+        build_timestamp: env!("ESPRESSO_FAUCET_BUILD_TIMESTAMP").parse().unwrap(),
+    }
+}
+
+/// Return a JSON expression with status 200 indicating the server
+/// is up and running. The JSON expression is simply,
+///    `{"status": Status}`
+/// where `Status` is one of
+/// * "initializing"
+/// * "available"
+/// When the server is running but unable to process requests
+/// normally, a response with status 503 and payload {"status":
+/// "unavailable"} should be added.
+async fn healthcheck(state: &FaucetState) -> HealthCheck {
+    HealthCheck {
+        status: *state.status.read().await,
+    }
+}
+
+async fn check_service_available(state: &FaucetState) -> Result<(), FaucetError> {
+    match *state.status.read().await {
+        FaucetStatus::Available => Ok(()),
+        FaucetStatus::Paused => Err(FaucetError::Paused),
+        FaucetStatus::Initializing => Err(FaucetError::Unavailable),
+        FaucetStatus::Standby => Err(FaucetError::Standby),
+    }
+}
+
+/// How long clients are told to wait before retrying a request shed because of a low balance.
+const LOW_BALANCE_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Shed new requests while the faucet's balance is below `min_balance`.
+async fn check_balance(state: &FaucetState) -> Result<(), FaucetError> {
+    let min_balance = match state.min_balance {
+        Some(min_balance) => min_balance,
+        None => return Ok(()),
+    };
+    // Don't make the request wait behind a transfer for the keystore lock. The cached balance is
+    // kept up to date by the tasks which use the keystore.
+    let balance = *state.balance.read().await;
+    if balance < min_balance.into() {
+        warn!(
+            "shedding request, balance {} is below the minimum {}",
+            state.format_amount(balance),
+            state.format_amount(min_balance)
+        );
+        return Err(FaucetError::TemporarilyUnavailable {
+            retry_after: LOW_BALANCE_RETRY_AFTER.as_secs(),
+        });
+    }
+    Ok(())
+}
+
+/// Check that `req` is authorized with the admin token.
+///
+/// If no admin token is configured, no request is authorized.
+fn check_admin_token(req: &RequestParams, state: &FaucetState) -> Result<(), FaucetError> {
+    let token = state
+        .admin_token
+        .as_ref()
+        .ok_or(FaucetError::Unauthorized)?;
+    match req.header("Authorization") {
+        Some(auth) if auth.as_str() == format!("Bearer {}", token) => Ok(()),
+        _ => Err(FaucetError::Unauthorized),
+    }
+}
+
+async fn pause(req: RequestParams, state: &FaucetState) -> Result<(), FaucetError> {
+    check_admin_token(&req, state)?;
+    let mut status = state.status.write().await;
+    match *status {
+        FaucetStatus::Initializing => return Err(FaucetError::Unavailable),
+        FaucetStatus::Standby => return Err(FaucetError::Standby),
+        _ => {}
+    }
+    *status = FaucetStatus::Paused;
+    info!("faucet paused");
+    Ok(())
+}
+
+async fn resume(req: RequestParams, state: &FaucetState) -> Result<(), FaucetError> {
+    check_admin_token(&req, state)?;
+    let mut status = state.status.write().await;
+    match *status {
+        FaucetStatus::Initializing => return Err(FaucetError::Unavailable),
+        // A standby must be promoted, which does more than just start processing requests.
+        FaucetStatus::Standby => return Err(FaucetError::Standby),
+        _ => {}
+    }
+    *status = FaucetStatus::Available;
+    info!("faucet resumed");
+    Ok(())
+}
+
+/// Promote a standby faucet, so that it takes over processing requests.
+///
+/// The faucet returns to initializing while it reloads the queue and prepares its records, and
+/// becomes available once it is ready, as if it had just started.
+async fn promote(req: RequestParams, state: &FaucetState) -> Result<(), FaucetError> {
+    check_admin_token(&req, state)?;
+    let mut status = state.status.write().await;
+    if *status != FaucetStatus::Standby {
+        return Err(FaucetError::NotStandby);
+    }
+    *status = FaucetStatus::Initializing;
+    info!("promoting standby faucet");
+    Ok(())
+}
+
+/// Rescan the ledger for the faucet's records, starting from query service event `from`.
+///
+/// This is a repair tool for when the faucet's balance looks wrong because a record was missed. The
+/// keystore is locked until the scan finishes, so the workers wait for it.
+async fn rescan(req: RequestParams, state: &FaucetState) -> Result<RescanReport, FaucetError> {
+    check_admin_token(&req, state)?;
+    let from = req.opt_integer_param("from")?.unwrap_or(0);
+    let mut keystore = state.keystore.lock().await;
+    let balance_before = keystore.balance(&AssetCode::native()).await;
+    let key = keystore.sending_keys().await[0].clone();
+    warn!(
+        "rescanning the ledger for {} from event {}",
+        key.address(),
+        from
+    );
+    let internal = |err: EspressoKeystoreError| FaucetError::Internal {
+        status: StatusCode::InternalServerError,
+        msg: format!("rescan failed: {}", err),
+    };
+    keystore
+        .add_account(
+            key.clone(),
+            "faucet".into(),
+            EventIndex::from_source(EventSource::QueryService, from),
+        )
+        .await
+        .map_err(internal)?;
+    keystore
+        .await_sending_key_scan(&key.address())
+        .await
+        .map_err(internal)?;
+    let balance_after = state.refresh_balance(&keystore).await;
+    info!(
+        "rescan complete, balance {} before and {} after",
+        state.format_amount(balance_before),
+        state.format_amount(balance_after)
+    );
+    Ok(RescanReport {
+        from,
+        balance_before: balance_before.to_string(),
+        balance_after: balance_after.to_string(),
+    })
+}
+
+async fn request_fee_assets(
+    req: RequestParams,
+    state: &FaucetState,
+) -> Result<GrantInfo, FaucetError> {
+    check_service_available(state).await?;
+    let priority = match req.opt_string_param("priority")? {
+        Some(priority) => priority.parse()?,
+        None => Priority::Normal,
+    };
+    if priority == Priority::High {
+        check_admin_token(&req, state)?;
+    }
+    let amount: Option<u64> = req.opt_integer_param("amount")?;
+    let num_grants = match amount {
+        Some(amount) => Some(state.grants_for_amount(amount)?),
+        None => None,
+    };
+    if let Some(daily_cap) = &state.daily_cap {
+        daily_cap.lock().await.check(unix_now())?;
+    }
+    check_balance(state).await?;
+    let label = parse_label(&req)?;
+    let pub_key = parse_pub_key(&req)?;
+    queue_request(
+        state,
+        pub_key,
+        RequestInfo {
+            priority,
+            num_grants,
+            label,
+            group: Vec::new(),
+        },
+    )
+    .await
+}
+
+/// Request grants for a group of keys which are treated as a unit.
+///
+/// The group is queued as a single request from its first key. It is complete only once every key
+/// in the group has received the faucet's default number of grants.
+async fn request_group(req: RequestParams, state: &FaucetState) -> Result<GrantInfo, FaucetError> {
+    check_service_available(state).await?;
+    if let Some(daily_cap) = &state.daily_cap {
+        daily_cap.lock().await.check(unix_now())?;
+    }
+    check_balance(state).await?;
+    let label = parse_label(&req)?;
+    if state.max_group_size < 2 {
+        return Err(bad_request("pub_keys", "group requests are disabled"));
+    }
+    let mut keys = parse_pub_keys(&req)?;
+    if keys.len() < 2 || keys.len() > state.max_group_size {
+        return Err(bad_request(
+            "pub_keys",
+            format!(
+                "a group must have between 2 and {} keys, got {}",
+                state.max_group_size,
+                keys.len()
+            ),
+        ));
+    }
+    let mut seen = HashSet::new();
+    if let Some(key) = keys.iter().find(|key| !seen.insert(key)) {
+        return Err(bad_request(
+            "pub_keys",
+            format!("duplicate key {}", key.address()),
+        ));
+    }
+    let leader = keys.remove(0);
+    queue_request(
+        state,
+        leader,
+        RequestInfo {
+            priority: Priority::Normal,
+            num_grants: None,
+            label,
+            group: keys,
+        },
+    )
+    .await
+}
+
+/// How often an admin grant checks whether the request it queued is complete.
+const GRANT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Grant a specific number of records to a key, ahead of any normal priority requests.
+///
+/// If the key already has a request in the queue, that request is raised to high priority and to
+/// at least `count` more grants. Either way, this waits until the key's request is complete and
+/// returns the transactions which granted to it in the meantime.
+async fn grant(
+    req: RequestParams,
+    state: &FaucetState,
+) -> Result<Vec<TransactionUID<EspressoLedger>>, FaucetError> {
+    check_admin_token(&req, state)?;
+    check_service_available(state).await?;
+    let count: usize = req.integer_param("count")?;
+    if count == 0 {
+        return Err(FaucetError::InvalidGrantCount { count });
+    }
+    let pub_key = parse_pub_key(&req)?;
+
+    // Start collecting receipts before queueing the request, so that we don't miss any.
+    state
+        .grant_receipts
+        .lock()
+        .await
+        .entry(pub_key.clone())
+        .or_default()
+        .waiters += 1;
+    let res = state
+        .queue
+        .push_grant(pub_key.clone(), count, state.num_grants)
+        .await;
+    if res.is_ok() {
+        while state.queue.index.lock().await.get(&pub_key).is_some() {
+            sleep(GRANT_POLL_INTERVAL).await;
+        }
+    }
+
+    let mut grant_receipts = state.grant_receipts.lock().await;
+    let waiting = grant_receipts.get_mut(&pub_key).unwrap();
+    waiting.waiters -= 1;
+    let receipts = if waiting.waiters == 0 {
+        grant_receipts.remove(&pub_key).unwrap().receipts
+    } else {
+        waiting.receipts.clone()
+    };
+    res.map(|()| receipts)
+}
+
+/// Parse the public key in the body of a request.
+///
+/// This accepts the same encodings as `body_auto`, but tells the client exactly what is wrong with
+/// a malformed request, rather than reporting a generic deserialization error.
+fn parse_pub_key(req: &RequestParams) -> Result<UserPubKey, FaucetError> {
+    let content_type = req
+        .header("Content-Type")
+        .map(|content_type| content_type.as_str().to_string());
+    parse_pub_key_body(content_type.as_deref(), &req.body_bytes())
+}
+
+fn parse_pub_key_body(content_type: Option<&str>, body: &[u8]) -> Result<UserPubKey, FaucetError> {
+    parse_body(content_type, body, "pub_key", |value| {
+        if value.is_string() {
+            Ok(())
+        } else {
+            Err("expected a tagged-base64 string")
+        }
+    })
+}
+
+/// Parse the list of public keys in the body of a group request.
+fn parse_pub_keys(req: &RequestParams) -> Result<Vec<UserPubKey>, FaucetError> {
+    let content_type = req
+        .header("Content-Type")
+        .map(|content_type| content_type.as_str().to_string());
+    parse_pub_keys_body(content_type.as_deref(), &req.body_bytes())
+}
+
+fn parse_pub_keys_body(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Vec<UserPubKey>, FaucetError> {
+    parse_body(content_type, body, "pub_keys", |value| {
+        match value.as_array() {
+            Some(keys) if keys.iter().all(|key| key.is_string()) => Ok(()),
+            _ => Err("expected an array of tagged-base64 strings"),
+        }
+    })
+}
+
+/// Decode a request body in either of the encodings accepted by `body_auto`.
+///
+/// A JSON body must pass `check_json` before it is decoded, so that a value of the wrong shape is
+/// reported as such rather than as a generic deserialization error. Errors name `field`.
+fn parse_body<T: DeserializeOwned>(
+    content_type: Option<&str>,
+    body: &[u8],
+    field: &str,
+    check_json: impl FnOnce(&serde_json::Value) -> Result<(), &'static str>,
+) -> Result<T, FaucetError> {
+    if body.is_empty() {
+        return Err(bad_request(field, "missing"));
+    }
+    // Ignore parameters of the content type, such as `charset`.
+    match content_type.and_then(|content_type| content_type.split(';').next()) {
+        Some(content_type) if content_type.trim() == "application/json" => {
+            let value: serde_json::Value = serde_json::from_slice(body)
+                .map_err(|err| bad_request(field, format!("invalid JSON: {}", err)))?;
+            check_json(&value).map_err(|reason| bad_request(field, reason))?;
+            serde_json::from_value(value)
+                .map_err(|err| bad_request(field, format!("invalid tagged-base64: {}", err)))
+        }
+        Some(content_type) if content_type.trim() == "application/octet-stream" => {
+            bincode::deserialize(body)
+                .map_err(|err| bad_request(field, format!("invalid binary encoding: {}", err)))
+        }
+        Some(content_type) => Err(bad_request(
+            "Content-Type",
+            format!(
+                "unsupported content type {}, expected application/json or \
+                 application/octet-stream",
+                content_type.trim()
+            ),
+        )),
+        None => Err(bad_request("Content-Type", "missing")),
+    }
+}
+
+/// Parse the optional label a client attached to a request.
+fn parse_label(req: &RequestParams) -> Result<Option<String>, FaucetError> {
+    match req.header(LABEL_HEADER) {
+        Some(label) => {
+            let label = label.as_str();
+            if label.len() > MAX_LABEL_LEN {
+                return Err(FaucetError::LabelTooLong {
+                    len: label.len(),
+                    max: MAX_LABEL_LEN,
+                });
+            }
+            Ok(Some(label.to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn bad_request(field: &str, reason: impl Into<String>) -> FaucetError {
+    FaucetError::BadRequest {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+/// Add a request to the queue and describe the grant it will receive.
+async fn queue_request(
+    state: &FaucetState,
+    pub_key: UserPubKey,
+    info: RequestInfo,
+) -> Result<GrantInfo, FaucetError> {
+    let (num_grants, granted) = match state.queue.push(pub_key, info.clone()).await? {
+        Pushed::New => (info.num_grants, 0),
+        Pushed::Existing { grants, info } => (info.num_grants, grants),
+    };
+    Ok(GrantInfo {
+        asset: AssetCode::native(),
+        num_grants: num_grants.unwrap_or(state.num_grants),
+        granted,
+        grant_size: state.grant_size,
+    })
+}
+
+/// The interval between the start times of consecutive workers.
+const WORKER_START_STAGGER: Duration = Duration::from_millis(500);
+
+/// How long worker `id` should wait before it starts handling requests.
+///
+/// Starting all the workers at once would have them contend for the keystore lock and the submit
+/// service at the same time. Instead, worker `id` starts at a random time in the interval
+/// `[id, id + 1) * WORKER_START_STAGGER`, so that load ramps up gradually.
+fn worker_start_delay(id: usize, rng: &mut impl Rng) -> Duration {
+    WORKER_START_STAGGER * id as u32 + WORKER_START_STAGGER.mul_f64(rng.gen())
+}
+
+/// The number of times a worker which panics is restarted before it is given up on.
+const MAX_WORKER_RESTARTS: usize = 10;
+
+/// Run worker `id` using `run`, restarting it if it panics.
+///
+/// A request which the worker was processing when it panicked is put back in the queue, so that it
+/// can be retried by the replacement worker or any other. The worker is restarted at most
+/// [MAX_WORKER_RESTARTS] times, after which it is assumed to be failing deterministically.
+async fn supervise_worker<F, Fut>(id: usize, mut state: FaucetState, start_delay: Duration, run: F)
+where
+    F: Fn(usize, FaucetState, Duration) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut start_delay = start_delay;
+    for restarts in 0.. {
+        if AssertUnwindSafe(run(id, state.clone(), start_delay))
+            .catch_unwind()
+            .await
+            .is_ok()
+        {
+            // The worker exited normally, because the request queue was closed or the faucet is
+            // shutting down.
+            return;
+        }
+
+        let task = state
+            .workers
+            .read()
+            .await
+            .get(&id)
+            .map(|(task, _)| task.clone());
+        if let Some(WorkerTask::Processing { pub_key }) = task {
+            if state.queue.index.lock().await.get(&pub_key).is_some() {
+                state.stats.failures.fetch_add(1, Ordering::SeqCst);
+                state.queue.fail(pub_key).await;
+            }
+        }
+        if restarts >= MAX_WORKER_RESTARTS {
+            error!(
+                "worker {}: panicked after {} restarts, giving up",
+                id, MAX_WORKER_RESTARTS
+            );
+            state.workers.write().await.remove(&id);
+            return;
+        }
+        error!(
+            "worker {}: panicked, restarting ({}/{})",
+            id,
+            restarts + 1,
+            MAX_WORKER_RESTARTS
+        );
+        start_delay = Duration::ZERO;
+    }
+}
+
+/// How often a worker checks whether it may start granting to a recipient which is busy.
+const RECIPIENT_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Per-recipient locks, limiting the number of workers granting to each recipient at once.
+#[derive(Clone)]
+struct RecipientLocks {
+    limit: usize,
+    // The number of workers currently granting to each recipient. This lock is never held across
+    // an `await`, so it can be released when a [RecipientGuard] is dropped.
+    in_flight: Arc<std::sync::Mutex<HashMap<UserPubKey, usize>>>,
+}
+
+/// Permission to grant to a recipient, released when dropped.
+struct RecipientGuard {
+    key: UserPubKey,
+    in_flight: Arc<std::sync::Mutex<HashMap<UserPubKey, usize>>>,
+}
+
+impl Drop for RecipientGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl RecipientLocks {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Start granting to `key`, if fewer than `limit` workers are already granting to it.
+    fn try_acquire(&self, key: &UserPubKey) -> Option<RecipientGuard> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(key.clone()).or_default();
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(RecipientGuard {
+            key: key.clone(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Wait until fewer than `limit` workers are granting to `key`, then start granting to it.
+    async fn acquire(&self, key: &UserPubKey) -> RecipientGuard {
+        loop {
+            if let Some(guard) = self.try_acquire(key) {
+                return guard;
+            }
+            sleep(RECIPIENT_LOCK_POLL_INTERVAL).await;
+        }
+    }
+}
+
+async fn worker(id: usize, mut state: FaucetState, start_delay: Duration) {
+    sleep(start_delay).await;
+    'wait_for_requests: loop {
+        state.set_worker_task(id, WorkerTask::Idle).await;
+        // Don't take requests off the queue while the faucet is paused.
+        state.wait_while_paused().await;
+        let (pub_key, mut grants) = match state.queue.pop().await {
+            Some(request) => request,
+            None => break,
+        };
+        // Once the faucet is shutting down, leave new requests in the persistent queue, to be
+        // granted after it restarts.
+        if state.shutting_down.load(Ordering::SeqCst) {
+            info!("worker {}: exiting, faucet is shutting down", id);
+            return;
+        }
+        state
+            .set_worker_task(
+                id,
+                WorkerTask::Processing {
+                    pub_key: pub_key.clone(),
+                },
+            )
+            .await;
+        // We may have been waiting in `pop` when the faucet was paused. If so, hold on to the
+        // request until the faucet is resumed.
+        state.wait_while_paused().await;
+
+        // Don't let more workers than allowed grant to this key at once. Another worker may have
+        // granted to it while we waited, so refresh the number of grants it has received.
+        let _recipient = state.recipients.acquire(&pub_key).await;
+        grants = match state.queue.index.lock().await.get(&pub_key) {
+            Some(grants) => grants,
+            None => {
+                info!(
+                    "worker {}: request from {} was completed by another worker",
+                    id,
+                    pub_key.address()
+                );
+                continue;
+            }
+        };
+
+        let group = state.queue.group(&pub_key).await;
+        if !group.is_empty() {
+            grant_group(id, &mut state, pub_key, grants, group).await;
+            signal_breaker(id, &state);
+            continue;
+        }
+
+        loop {
+            // An admin grant can raise the number of grants owed while we are granting, so read it
+            // again on every round.
+            let owed = state
+                .queue
+                .num_grants(&pub_key, state.num_grants)
+                .await
+                .saturating_sub(grants);
+            if owed == 0 {
+                break;
+            }
+
+            // If the receiver is owed only one more grant, the transfer has room for a grant to
+            // another receiver, so try to share it with the next request in the queue.
+            let mut batched = if owed == 1 {
+                next_batched_request(id, &mut state, &pub_key).await
+            } else {
+                None
+            };
+
+            // Lock the keystore field directly, rather than through `lock_keystore`, so that the
+            // guard does not borrow all of `state` while we update the queue.
+            let (mut keystore, balance) = lock_with_balance(
+                id,
+                &state.keystore,
+                state.grant_size,
+                state.lock_timeout,
+                &state.stats,
+            )
+            .await;
+            let records = spendable_records(&keystore, &AssetCode::native(), state.grant_size)
+                .await
+                .count();
+            info!(
+                "worker {}: keystore balance before transfer: {} across {} records",
+                id,
+                state.format_amount(balance),
+                records
+            );
+            // If the receiver is still owed multiple grants and we have enough balance to make 2
+            // simultaneous grants, take advantage of the 3-output proving key to create 2 grants at
+            // the same time.
+            let new_grants = if owed > 1 && balance >= (state.grant_size * 2).into() {
+                2
+            } else {
+                1
+            };
+            // Only share the transfer if we can afford both grants. Otherwise, return the other
+            // request to the queue for a later transfer.
+            if balance < (state.grant_size * 2).into() {
+                if let Some((key, _, _)) = batched.take() {
+                    state.queue.fail(key).await;
+                }
+            }
+            let mut recipients = vec![pub_key.clone(); new_grants];
+            recipients.extend(batched.iter().map(|(key, _, _)| key.clone()));
+            // If this transfer would take us over the daily cap, hold on to the request until the
+            // window ends, and let the other request be granted with a later transfer.
+            if let Some(wait) = state.daily_cap_wait(recipients.len()).await {
+                drop(keystore);
+                if let Some((key, _, _)) = batched {
+                    state.queue.fail(key).await;
+                }
+                warn!(
+                    "worker {}: holding request from {} for {:?} to stay within the daily cap",
+                    id,
+                    pub_key.address(),
+                    wait
+                );
+                sleep(wait).await;
+                continue;
+            }
+            let res = transfer_all_assets(id, &state, &mut keystore, &recipients).await;
+            let receipt = match res {
+                Ok(receipt) => receipt,
+                Err(err) => {
+                    error!("worker {}: failed to transfer: {}", id, err);
+                    // If we failed, mark the requests as failed in the queue so they can be
+                    // retried later.
+                    for key in iter::once(pub_key).chain(batched.map(|(key, _, _)| key)) {
+                        state.record_failure(&key, &err).await;
+                        state.stats.failures.fetch_add(1, Ordering::SeqCst);
+                        state.queue.fail(key).await;
+                    }
+                    continue 'wait_for_requests;
+                }
+            };
+            state.stats.transactions.fetch_add(1, Ordering::SeqCst);
+            state.refresh_balance(&keystore).await;
+            let granted_assets = state.assets().collect::<Vec<_>>();
+            drop(keystore);
+
+            if state.confirm_grants {
+                let status = confirm_grant(id, &state, &receipt).await;
+                if !matches!(status, Ok(TransactionStatus::Retired)) {
+                    error!(
+                        "worker {}: grant to {} was not confirmed ({:?})",
+                        id,
+                        pub_key.address(),
+                        status
+                    );
+                    for key in iter::once(pub_key).chain(batched.map(|(key, _, _)| key)) {
+                        state
+                            .record_failure(&key, format!("grant was not confirmed ({:?})", status))
+                            .await;
+                        state.stats.failures.fetch_add(1, Ordering::SeqCst);
+                        state.queue.fail(key).await;
+                    }
+                    continue 'wait_for_requests;
+                }
+            }
+            let total_grants = recipients.len();
+            if let Some(daily_cap) = &state.daily_cap {
+                let amount = state.daily_cap_amount(total_grants);
+                if let Err(err) = daily_cap.lock().await.record(amount, unix_now()) {
+                    error!(
+                        "worker {}: failed to record grant against daily cap: {}",
+                        id, err
+                    );
+                }
+            }
+
+            // Update the queue with the results of this grant; find out if the key needs more
+            // grants or not.
+            state.stats.grants.fetch_add(total_grants, Ordering::SeqCst);
+            if let Some((key, key_grants, _)) = batched {
+                state.record_receipt(&key, &receipt).await;
+                let label = state.queue.label(&key).await;
+                state
+                    .observe_grants(&key, &granted_assets, 1, label.clone())
+                    .await;
+                if state.queue.grant(key.clone(), 1, state.num_grants).await {
+                    // The other receiver is owed more grants; it waits its turn in the queue.
+                    state.queue.fail(key).await;
+                } else {
+                    log_completed_request(id, &key, key_grants + 1, label.as_deref());
+                }
+            }
+            state.record_receipt(&pub_key, &receipt).await;
+            let label = state.queue.label(&pub_key).await;
+            state
+                .observe_grants(&pub_key, &granted_assets, new_grants, label.clone())
+                .await;
+            if !state
+                .queue
+                .grant(pub_key.clone(), new_grants, state.num_grants)
+                .await
+            {
+                log_completed_request(id, &pub_key, grants + new_grants, label.as_deref());
+                break;
+            }
+            grants += new_grants;
+        }
+
+        signal_breaker(id, &state);
+    }
+
+    warn!("worker {}: exiting, request queue closed", id);
+}
+
+/// Signal the record breaking thread that we have spent some records, so that it can create more
+/// by breaking up larger records.
+fn signal_breaker(id: usize, state: &FaucetState) {
+    if state.breaker && state.signal_breaker_thread.clone().try_send(()).is_err() {
+        error!(
+            "worker {}: error signalling the breaker thread. Perhaps it has crashed?",
+            id
+        );
+    }
+}
+
+/// Grant to the group request from `leader`, whose other keys are `members`.
+///
+/// The group is granted to as a unit. Each round transfers one grant to every key in the group,
+/// two keys per transaction, and only counts towards the request once every transfer in the round
+/// has succeeded. If any transfer fails, the whole group is returned to the queue and the round is
+/// retried from the start. Transfers which succeeded before the failure are not undone, so keys
+/// early in the group may receive extra records when a round is retried.
+async fn grant_group(
+    id: usize,
+    state: &mut FaucetState,
+    leader: UserPubKey,
+    mut grants: usize,
+    members: Vec<UserPubKey>,
+) {
+    let group = iter::once(leader.clone())
+        .chain(members)
+        .collect::<Vec<_>>();
+    info!(
+        "worker {}: granting to group of {} keys led by {}",
+        id,
+        group.len(),
+        leader.address()
+    );
+    // As in `worker`, an admin grant can raise the number of grants owed while we are granting.
+    while grants < state.queue.num_grants(&leader, state.num_grants).await {
+        // The round counts as a unit, so hold the whole group if the round would take us over the
+        // daily cap.
+        if let Some(wait) = state.daily_cap_wait(group.len()).await {
+            warn!(
+                "worker {}: holding group led by {} for {:?} to stay within the daily cap",
+                id,
+                leader.address(),
+                wait
+            );
+            sleep(wait).await;
+            continue;
+        }
+        let mut receipts = Vec::new();
+        let mut granted_assets = Vec::new();
+        for recipients in group.chunks(2) {
+            let (mut keystore, _) = lock_with_balance(
+                id,
+                &state.keystore,
+                state.grant_size * recipients.len() as u64,
+                state.lock_timeout,
+                &state.stats,
+            )
+            .await;
+            let res = transfer_all_assets(id, state, &mut keystore, recipients).await;
+            match res {
+                Ok(receipt) => receipts.push(receipt),
+                Err(err) => {
+                    error!("worker {}: failed to transfer to group: {}", id, err);
+                    drop(keystore);
+                    fail_group(state, &group, &err).await;
+                    return;
+                }
+            }
+            state.stats.transactions.fetch_add(1, Ordering::SeqCst);
+            state.refresh_balance(&keystore).await;
+            for key in recipients {
+                granted_assets.push((key.clone(), state.assets().collect::<Vec<_>>()));
+            }
+        }
+
+        if state.confirm_grants {
+            for receipt in &receipts {
+                let status = confirm_grant(id, state, receipt).await;
+                if !matches!(status, Ok(TransactionStatus::Retired)) {
+                    error!(
+                        "worker {}: grant to group led by {} was not confirmed ({:?})",
+                        id,
+                        leader.address(),
+                        status
+                    );
+                    fail_group(
+                        state,
+                        &group,
+                        format!("grant was not confirmed ({:?})", status),
+                    )
+                    .await;
+                    return;
+                }
+            }
+        }
+        if let Some(daily_cap) = &state.daily_cap {
+            let amount = state.daily_cap_amount(group.len());
+            if let Err(err) = daily_cap.lock().await.record(amount, unix_now()) {
+                error!(
+                    "worker {}: failed to record grant against daily cap: {}",
+                    id, err
+                );
+            }
+        }
+
+        // Every transfer in the round succeeded, so the round counts for the whole group.
+        state.stats.grants.fetch_add(group.len(), Ordering::SeqCst);
+        let label = state.queue.label(&leader).await;
+        for (key, assets) in &granted_assets {
+            state.observe_grants(key, assets, 1, label.clone()).await;
+        }
+        for (recipients, receipt) in group.chunks(2).zip(&receipts) {
+            for key in recipients {
+                state.record_receipt(key, receipt).await;
+            }
+        }
+        grants += 1;
+        if !state.queue.grant(leader.clone(), 1, state.num_grants).await {
+            log_completed_request(id, &leader, grants, label.as_deref());
+        }
+    }
+}
+
+/// How often a worker checks the status of a grant it is waiting to confirm.
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Wait on behalf of worker `id` for the grant `receipt` to reach a final status.
+///
+/// The keystore is only locked while checking the status, not for the whole wait, so that other
+/// workers and the breaker can use the keystore while the grant is pending.
+async fn confirm_grant(
+    id: usize,
+    state: &FaucetState,
+    receipt: &TransactionUID<EspressoLedger>,
+) -> Result<TransactionStatus, EspressoKeystoreError> {
+    loop {
+        let status = state
+            .lock_keystore(id)
+            .await
+            .transaction_status(receipt)
+            .await?;
+        if status.is_final() {
+            return Ok(status);
+        }
+        sleep(CONFIRM_POLL_INTERVAL).await;
+    }
+}
+
+/// Record a failed transfer to every key in `group` and return the group to the queue.
+async fn fail_group(state: &mut FaucetState, group: &[UserPubKey], err: impl Display) {
+    let err = err.to_string();
+    for key in group {
+        state.record_failure(key, &err).await;
+        state.stats.failures.fetch_add(1, Ordering::SeqCst);
+    }
+    // The group is queued under its first key, so re-queueing that key retries the whole group.
+    state.queue.fail(group[0].clone()).await;
+}
+
+/// Lock `mutex` on behalf of worker `id`.
+///
+/// If the lock cannot be acquired within `timeout`, the worker may be deadlocked, so this logs an
+/// error and counts a lock timeout in `stats` each time the timeout elapses, then keeps waiting.
+async fn lock_with_timeout<'a, T>(
+    id: usize,
+    mutex: &'a Mutex<T>,
+    timeout: Duration,
+    stats: &FaucetStats,
+) -> MutexGuard<'a, T> {
+    let mut waited = Duration::ZERO;
+    loop {
+        match async_std::future::timeout(timeout, mutex.lock()).await {
+            Ok(guard) => {
+                if !waited.is_zero() {
+                    warn!(
+                        "worker {}: acquired the keystore lock after {:?}",
+                        id,
+                        waited + timeout
+                    );
+                }
+                return guard;
+            }
+            Err(_) => {
+                waited += timeout;
+                stats.lock_timeouts.fetch_add(1, Ordering::SeqCst);
+                error!(
+                    "worker {}: still waiting for the keystore lock after {:?}, possible deadlock",
+                    id, waited
+                );
+            }
+        }
+    }
+}
+
+/// Lock `keystore` on behalf of worker `id` once it holds at least `amount` of the native asset.
+///
+/// Returns the guard and the balance. If we don't have a sufficient balance to transfer, it is
+/// probably only because some transactions are in flight. We are likely to get change back when the
+/// transactions complete, so wait until we have a sufficient balance to do our job.
+async fn lock_with_balance<'a>(
+    id: usize,
+    keystore: &'a Mutex<FaucetKeystore>,
+    amount: RecordAmount,
+    lock_timeout: Duration,
+    stats: &FaucetStats,
+) -> (MutexGuard<'a, FaucetKeystore>, U256) {
+    loop {
+        let guard = lock_with_timeout(id, keystore, lock_timeout, stats).await;
+        let balance = guard.balance(&AssetCode::native()).await;
+        if balance >= amount.into() {
+            return (guard, balance);
+        }
+        warn!(
+            "worker {}: insufficient balance for transfer, sleeping for 30s",
+            id
+        );
+        drop(guard);
+        sleep(Duration::from_secs(30)).await;
+    }
+}
+
+/// Wait up to the batch window for another request to share a transfer with the request worker
+/// `id` is processing.
+///
+/// Returns the other request, the number of grants it has already received, and permission to grant
+/// to it, or [None] if batching is disabled or no request arrives within the window.
+async fn next_batched_request(
+    id: usize,
+    state: &mut FaucetState,
+    current: &UserPubKey,
+) -> Option<(UserPubKey, usize, RecipientGuard)> {
+    if state.batch_window.is_zero() {
+        return None;
+    }
+    let (pub_key, _) = async_std::future::timeout(state.batch_window, state.queue.pop())
+        .await
+        .ok()??;
+    // A busy recipient, including the one we are already granting to, is in the index of the
+    // worker granting to it, which will finish the request or return it to the queue. Drop this
+    // entry rather than queueing the recipient a second time.
+    if &pub_key == current {
+        return None;
+    }
+    let guard = state.recipients.try_acquire(&pub_key)?;
+    // As in `worker`, the entry may be stale, so refresh the number of grants from the index.
+    let (grants, group) = {
+        let index = state.queue.index.lock().await;
+        match index.get(&pub_key) {
+            Some(grants) => (grants, index.info(&pub_key).group),
+            None => {
+                info!(
+                    "worker {}: request from {} was completed by another worker",
+                    id,
+                    pub_key.address()
+                );
+                return None;
+            }
+        }
+    };
+    // A group request must be granted to as a unit by `grant_group`, so it can't share a transfer.
+    // Leave it in the queue for a worker of its own.
+    if !group.is_empty() {
+        drop(guard);
+        state.queue.fail(pub_key).await;
+        return None;
+    }
+    info!(
+        "worker {}: sharing transfer with request from {}",
+        id,
+        pub_key.address()
+    );
+    Some((pub_key, grants, guard))
+}
+
+/// Record the completion of a request, along with the label the client attached to it, if any.
+fn log_completed_request(worker: usize, pub_key: &UserPubKey, grants: usize, label: Option<&str>) {
+    info!(
+        label = label.unwrap_or(""),
+        "worker {}: completed request from {} with {} grants",
+        worker,
+        pub_key.address(),
+        grants
+    );
+}
+
+/// The maximum number of grant events waiting to be posted to the observer.
+const MAX_PENDING_GRANT_EVENTS: usize = 10000;
+
+/// The time to wait for the observer or alert endpoint to accept a post.
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Post batches of grant events from `events` to the observer at `url`.
+///
+/// A batch is posted once it holds `batch_size` events, or once its first event is
+/// `flush_interval` old, whichever comes first. Batches which cannot be posted are dropped.
+async fn mirror_grants(
+    url: Url,
+    batch_size: usize,
+    flush_interval: Duration,
+    events: mpmc::Receiver<GrantEvent>,
+) {
+    while let Ok(event) = events.recv().await {
+        let mut batch = vec![event];
+        let deadline = Instant::now() + flush_interval;
+        while batch.len() < batch_size {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match async_std::future::timeout(remaining, events.recv()).await {
+                Ok(Ok(event)) => batch.push(event),
+                // Post what we have if the batch is due or the faucet is shutting down.
+                _ => break,
+            }
+        }
+        if let Err(err) = post_json(&url, &batch).await {
+            warn!(
+                "failed to post {} grant events to observer {}: {}",
+                batch.len(),
+                url,
+                err
+            );
+        }
+    }
+}
+
+/// Post `body` to `url` as JSON.
+async fn post_json<T: Serialize>(url: &Url, body: &T) -> Result<(), String> {
+    let req = surf::post(url)
+        .body_json(body)
+        .map_err(|err| err.to_string())?;
+    let res = async_std::future::timeout(POST_TIMEOUT, req)
+        .await
+        .map_err(|_| format!("timed out after {:?}", POST_TIMEOUT))?
+        .map_err(|err| err.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("server responded with status {}", res.status()))
+    }
+}
+
+/// Transfer a record of `grant_size` units of `asset` to each of `recipients` in a single
+/// transaction.
+///
+/// A key which appears more than once in `recipients` receives one record for each appearance.
+async fn transfer_grants(
+    id: usize,
+    keystore: &mut FaucetKeystore,
+    asset: &AssetCode,
+    recipients: &[UserPubKey],
+    grant_size: RecordAmount,
+    fee_size: RecordAmount,
+) -> Result<TransactionUID<EspressoLedger>, EspressoKeystoreError> {
+    let mut addresses = recipients
+        .iter()
+        .map(|key| key.address().to_string())
+        .collect::<Vec<_>>();
+    addresses.dedup();
+    info!(
+        "worker {}: transferring {} record(s) of {} {} to {}",
+        id,
+        recipients.len(),
+        grant_size,
+        asset,
+        addresses.join(", ")
+    );
+    let outputs = recipients
+        .iter()
+        .map(|key| (key.clone(), grant_size))
+        .collect::<Vec<_>>();
+    keystore.transfer(None, asset, &outputs, fee_size).await
+}
+
+/// Transfer one grant of every granted asset to each of `recipients`.
+///
+/// The additional assets are transferred before the native asset, so that if any transfer fails,
+/// the receivers have not been granted the native asset and the request can simply be retried.
+/// Transfers of additional assets which succeeded before the failure are not undone, so receivers
+/// may get extra records of those assets when the request is retried.
+///
+/// Returns the receipt of the native transfer.
+async fn transfer_all_assets(
+    id: usize,
+    state: &FaucetState,
+    keystore: &mut FaucetKeystore,
+    recipients: &[UserPubKey],
+) -> Result<TransactionUID<EspressoLedger>, EspressoKeystoreError> {
+    for (asset, grant_size) in state.extra_assets() {
+        transfer_grants(
+            id,
+            keystore,
+            &asset,
+            recipients,
+            grant_size,
+            state.fee_size(&asset),
+        )
+        .await?;
+    }
+    transfer_grants(
+        id,
+        keystore,
+        &AssetCode::native(),
+        recipients,
+        state.grant_size,
+        state.fee_size(&AssetCode::native()),
+    )
+    .await
+}
+
+async fn spendable_records(
+    keystore: &FaucetKeystore,
+    asset: &AssetCode,
+    grant_size: RecordAmount,
+) -> impl Iterator<Item = Record> {
+    let now = keystore.read().await.state().validator.block_height();
+    let asset = *asset;
+    keystore.records().await.into_iter().filter(move |record| {
+        record.asset_code() == asset
+            && record.amount() >= grant_size
+            && record.freeze_flag() == FreezeFlag::Unfrozen
+            && !record.on_hold(now)
+    })
+}
+
+/// Worker task to maintain at least `state.num_records` of each granted asset in the faucet
+/// keystore.
+///
+/// When signalled on `wakeup`, this thread will break large records into small records of the grant
+/// size of their asset, until there are at least `state.num_records` distinct records of each asset
+/// in the keystore.
+///
+/// The record breakup is only triggered when the number of available records is less than half of
+/// the desired number of records, and in that case we always replenish all the way to the desired
+/// number of records if possible. This prevents us from generating a record transaction every time
+/// we do a transfer, and ensures that whenever we do break up records, we break up many at a time,
+/// so we can take advantage of the parallelism of having multiple record breakup transactions in
+/// flight at the same time.
+async fn maintain_enough_records(state: FaucetState, mut wakeup: mpsc::Receiver<()>) {
+    loop {
+        // Wait until we have few enough records that we need to break them up, and we have a big
+        // enough record to break up.
+        //
+        // This is a simulation of a condvar loop, since async condvar is unstable, hence the manual
+        // drop and reacquisition of the keystore mutex guard.
+        loop {
+            let keystore = state.keystore.lock().await;
+            let mut needs_breakup = false;
+            for (asset, grant_size) in state.assets() {
+                let records = spendable_records(&keystore, &asset, grant_size)
+                    .await
+                    .collect::<Vec<_>>();
+                let min_piece = state
+                    .breakup_denomination(&asset, grant_size)
+                    .unwrap_or(grant_size);
+                if records.len() >= state.num_records / 2 {
+                    // We have enough records of this asset for now.
+                    info!(
+                        "got {}/{} records of {}, waiting for a change",
+                        records.len(),
+                        state.num_records,
+                        asset
+                    );
+                } else if !records
+                    .into_iter()
+                    .any(|record| record.amount() > min_piece * 2u64)
+                {
+                    // There are no big records of this asset to break up, so there's nothing for us
+                    // to do until the record distribution changes.
+                    warn!(
+                        "not enough records of {}, but no large records to break up",
+                        asset
+                    );
+                } else {
+                    // We don't have enough records and we do have a big record to break up.
+                    needs_breakup = true;
+                }
+            }
+            if needs_breakup {
+                // Break out of the wait loop and enter the next loop to break up our records.
+                break;
+            }
+
+            // Wait for a signal that the number of records has changed.
+            state.refresh_balance(&keystore).await;
+            drop(keystore);
+            wakeup.next().await;
+        }
+
+        if let Some(transactions) = break_up_records(&state).await {
+            // If we succeeded, wait until we are signalled again. Even though we may not have
+            // enough records just yet, we will when the transactions submitted by
+            // `break_up_records` finalize. Returning to the previous loop and checking if we have
+            // enough records might spuriously lead us to call `break_up_records` again, which would
+            // be an unnecessary waste of time.
+            info!(
+                "will have sufficient records after {} transactions, waiting for a change",
+                transactions.len()
+            );
+            wakeup.next().await;
+        }
+    }
+}
+
+/// The number of grant records of `asset` the current balance can support, up to
+/// `state.num_records`.
+///
+/// If the balance is too small to support `state.num_records` records of `grant_size`, logs a
+/// warning and returns the number of records that the balance can support.
+async fn supported_records(
+    state: &FaucetState,
+    keystore: &FaucetKeystore,
+    asset: &AssetCode,
+    grant_size: RecordAmount,
+) -> usize {
+    let balance = keystore.balance(asset).await;
+    let supported = balance / U256::from(grant_size);
+    if supported < U256::from(state.num_records) {
+        warn!(
+            "balance of {} {} can only support {} records of size {}, fewer than the {} requested",
+            state.format_amount(balance),
+            asset,
+            supported,
+            state.format_amount(grant_size),
+            state.num_records
+        );
+        supported.as_usize()
+    } else {
+        state.num_records
+    }
+}
+
+/// Split a record of `amount` into two pieces, `(split, change)`, for the record breaker.
+///
+/// The pieces are as close to equal as possible. If there is a `denomination`, `split` is a whole
+/// multiple of it, and so is `change` except for any remainder which does not divide evenly.
+fn split_record(
+    amount: RecordAmount,
+    denomination: Option<RecordAmount>,
+) -> (RecordAmount, RecordAmount) {
+    let split = match denomination {
+        Some(denomination) => {
+            let pieces = U256::from(amount) / U256::from(denomination);
+            denomination * (u64::try_from(pieces).unwrap_or(u64::MAX) / 2)
+        }
+        None => amount / 2,
+    };
+    (split, amount - split)
+}
+
+/// Break records of every granted asset into smaller pieces to create at least
+/// `state.num_records` of each.
+///
+/// Returns the transaction receipts of all the break-up transactions, or [None] if no asset had
+/// large enough records to break up. See [break_up_asset_records].
+async fn break_up_records(state: &FaucetState) -> Option<Vec<TransactionUID<EspressoLedger>>> {
+    let mut transactions = None;
+    for (asset, grant_size) in state.assets() {
+        if let Some(receipts) = break_up_asset_records(state, &asset, grant_size).await {
+            transactions.get_or_insert_with(Vec::new).extend(receipts);
+        }
+    }
+    transactions
+}
+
+/// Break records of `asset` into smaller pieces to create at least `state.num_records` total.
+///
+/// If successful, returns a list of transaction receipts which will give at least
+/// `state.num_records` when they are finalized. If there were not enough large records to break up
+/// to obtain the desired number of records, returns [None].
+///
+/// If the faucet's balance cannot support `state.num_records` records, we only aim for as many
+/// records as the balance can support, so that an unsatisfiable target does not keep us splitting
+/// records indefinitely.
+async fn break_up_asset_records(
+    state: &FaucetState,
+    asset: &AssetCode,
+    grant_size: RecordAmount,
+) -> Option<Vec<TransactionUID<EspressoLedger>>> {
+    let denomination = state.breakup_denomination(asset, grant_size);
+    let target = {
+        let keystore = state.keystore.lock().await;
+        supported_records(state, &keystore, asset, grant_size).await
+    };
+
+    // Break up records until we have enough again.
+    loop {
+        // Generate as many transactions as we can simultaneously.
+        let mut transactions = Vec::new();
+        loop {
+            // Acquire the keystore lock inside the loop, so we release it after each transfer.
+            // Holding the lock for too long can unneccessarily slow down faucet requests.
+            let mut keystore = state.keystore.lock().await;
+            let pub_key = keystore.sending_keys().await[0].pub_key().clone();
+            let records = spendable_records(&keystore, asset, grant_size)
+                .await
+                .collect::<Vec<_>>();
+
+            if records.len() + 2 * transactions.len() >= target {
+                // We will have enough records again once the pending transactions finish. Return
+                // _without_ waiting for pending transactions to finish: if we know we are going to
+                // have enough records once they finish, there is no point in holding the keystore
+                // lock and just waiting. Perhaps a faucet request can be filled using the records
+                // we already have while the last few transactions are pending.
+                //
+                // Return the list of transaction receipts so the caller can wait on them if they
+                // want.
+                return Some(transactions);
+            }
+
+            let min_piece = denomination.unwrap_or(grant_size);
+            let largest_record = match records
+                .into_iter()
+                .max_by(|x, y| x.amount().cmp(&y.amount()))
+            {
+                Some(record) if record.amount() >= min_piece * 2u64 => record,
+                _ => {
+                    // There are no records large enough to break up. Break out of the loop and wait
+                    // for the transactions we have already initiated to finish. The change from
+                    // those transactions will give us more records to break up.
+                    break;
+                }
+            };
+
+            let (split_amount, change_amount) = split_record(largest_record.amount(), denomination);
+
+            info!(
+                "breaking up a record of {} of size {} into records of size {} and {}",
+                asset,
+                state.format_amount(largest_record.amount()),
+                state.format_amount(split_amount),
+                state.format_amount(change_amount),
+            );
+
+            // There is not yet an interface for transferring a specific record, so we just have to
+            // specify the appropriate amounts and trust that Seahorse will use the largest record
+            // available (it should). We specify two outputs so that if an existing record with
+            // `change_amount` exists it won't be used "as is", which would prevent this loop
+            // from making progress.
+            let receipt = match keystore
+                .transfer(
+                    None,
+                    asset,
+                    &[
+                        (pub_key.clone(), change_amount),
+                        (pub_key.clone(), split_amount),
+                    ],
+                    0u64,
+                )
+                .await
+            {
+                Ok(receipt) => receipt,
+                Err(err) => {
+                    // If our transfers start failing, we will assume there is something wrong and
+                    // try not to put extra stress on the system. Break out of the inner loop and
+                    // wait for the transactions we did initiate to finish.
+                    error!("record breakup transfer failed: {}", err);
+                    break;
+                }
+            };
+            state.refresh_balance(&keystore).await;
+            transactions.push(receipt);
+        }
+
+        if transactions.is_empty() {
+            // We did not have sufficient records to generate any break-up transactions. Give up
+            // early and return with fewer-than-desired records. When the allocation of records
+            // changes, the record breaker thread will be notified and we will get to try again.
+            warn!("No large records to break up");
+            return None;
+        }
+
+        // If we get here, it means we generated some transactions, but it was not enough to give us
+        // the desired number of records. Wait until those transactions finish, then repeat the
+        // process, splitting up the outputs of those transactions.
+        //
+        // Note we have to reacquire the lock, since we released it at the end of the previous loop.
+        // This is good: it potentially allows another thread to grab the lock and make a transfer
+        // before we acquire it, during time where we would just be idly waiting. If this happens,
+        // it only means we spend less time waiting for our transactions once we are able to
+        // reacquire the lock.
+        info!(
+            "waiting for {} transactions before breaking more records",
+            transactions.len()
+        );
+        let keystore = state.keystore.lock().await;
+        for result in join_all(
+            transactions
+                .iter()
+                .map(|receipt| keystore.await_transaction(receipt)),
+        )
+        .await
+        {
+            if !matches!(result, Ok(TransactionStatus::Retired)) {
+                error!(
+                    "record breakup transfer did not complete successfully ({:?})",
+                    result
+                );
+            }
+        }
+    }
+}
+
+/// Connect to the upstream services used by the faucet keystore.
+async fn network_backend(
+    opt: &FaucetOptions,
+) -> Result<NetworkBackend<'static>, EspressoKeystoreError> {
+    NetworkBackend::with_timeout(
+        &UNIVERSAL_PARAM,
+        opt.esqs_url.clone(),
+        opt.address_book_url.clone(),
+        opt.submit_url.clone(),
+        opt.upstream_timeout,
+    )
+    .await
+}
+
+/// Open the faucet keystore.
+///
+/// `faucet_key_pair` - If provided, will be added to the faucet keystore.
+///
+/// Returns the keystore, along with the faucet key if it was newly added to the keystore. In that
+/// case, the keystore must finish scanning the ledger for this key before the faucet is usable.
+async fn open_keystore(
+    rng: &mut ChaChaRng,
+    opt: &FaucetOptions,
+    faucet_key_pair: Option<UserKeyPair>,
+) -> (FaucetKeystore, Option<UserPubKey>) {
+    let mut password = opt.faucet_password.clone();
+    if password.is_empty() {
+        password = Alphanumeric.sample_string(rng, 16);
+    }
+    let mut loader = RecoveryLoader::new(rng, opt.keystore_path(), opt.mnemonic.clone(), password);
+    let backend = network_backend(opt).await.unwrap();
+    let mut keystore = EspressoKeystore::new(backend, &mut loader).await.unwrap();
+
+    // If a faucet key pair is provided, add it to the keystore. Otherwise, if we're initializing
+    // for the first time, we need to generate a key. The faucet should be set up so that the
+    // first HD sending key is the faucet key.
+    let new_key = if let Some(key) = faucet_key_pair {
+        keystore
+            .add_account(key.clone(), "faucet".into(), EventIndex::default())
+            .await
+            .unwrap();
+        Some(key.pub_key())
+    } else if keystore.sending_keys().await.is_empty() {
+        // We pass `EventIndex::default()` to start a scan of the ledger from the beginning, in
+        // order to discover the faucet record.
+        Some(
+            keystore
+                .generate_sending_account("faucet".into(), Some(EventIndex::default()))
+                .await
+                .unwrap(),
+        )
+    } else if opt.reset_scan {
+        // Re-add the existing faucet key with a scan from the beginning of the ledger, ignoring
+        // wherever the keystore left off.
+        let key = keystore.sending_keys().await[0].clone();
+        warn!(
+            "resetting keystore scan, rescanning the ledger from the beginning for {}",
+            key.address()
+        );
+        keystore
+            .add_account(key.clone(), "faucet".into(), EventIndex::default())
+            .await
+            .unwrap();
+        Some(key.pub_key())
+    } else {
+        if let Some(index) = load_scan_checkpoint(&opt.keystore_path()) {
+            info!("resuming keystore scan from checkpoint {:?}", index);
+        }
+        None
+    };
+
+    (keystore, new_key)
+}
+
+/// The file in the keystore directory recording how far the keystore has scanned the ledger.
+const SCAN_CHECKPOINT_FILE: &str = "scan_checkpoint";
+
+/// Save and log the position in the ledger which `keystore` has scanned up to.
+async fn save_scan_checkpoint(
+    keystore: &FaucetKeystore,
+    dir: &Path,
+) -> std::io::Result<EventIndex> {
+    let index = keystore.now().await;
+    fs::write(dir.join(SCAN_CHECKPOINT_FILE), serde_json::to_vec(&index)?)?;
+    info!("keystore scan checkpoint: {:?}", index);
+    Ok(index)
+}
+
+/// The last scan checkpoint saved in `dir`, if any.
+fn load_scan_checkpoint(dir: &Path) -> Option<EventIndex> {
+    serde_json::from_slice(&fs::read(dir.join(SCAN_CHECKPOINT_FILE)).ok()?).ok()
+}
+
+/// Save the keystore's scan checkpoint in `dir` every `interval`.
+async fn checkpoint_scan(state: FaucetState, dir: PathBuf, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        let keystore = state.keystore.lock().await;
+        if let Err(err) = save_scan_checkpoint(&keystore, &dir).await {
+            warn!("failed to save keystore scan checkpoint: {}", err);
+        }
+    }
+}
+
+/// The name of the keystore backup in the backup directory.
+const BACKUP_NAME: &str = "keystore";
+
+/// The name of the request queue backup in the backup directory, if the queue is stored outside
+/// the keystore directory.
+const QUEUE_BACKUP_NAME: &str = "queue";
+
+/// Copy the contents of the directory `src` into `dst`, recursively.
+fn copy_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the backup `dst` with the finished copy `tmp`, keeping the previous backup in `old`
+/// until the new one is in place.
+fn replace_backup(tmp: &Path, old: &Path, dst: &Path) -> std::io::Result<()> {
+    if dst.exists() {
+        fs::rename(dst, old)?;
+    }
+    fs::rename(tmp, dst)?;
+    if old.exists() {
+        fs::remove_dir_all(old)?;
+    }
+    Ok(())
+}
+
+/// Back up the keystore directory `src` to [BACKUP_NAME] in `backup_dir`.
+///
+/// If the request queue directory `queue` is not inside `src`, it is backed up to
+/// [QUEUE_BACKUP_NAME] in `backup_dir` as well.
+///
+/// Every writer to these directories is paused during the copy, so that no commits land in the
+/// middle of it: the keystore's own event handling, which commits while holding the keystore state
+/// lock rather than the faucet's keystore mutex, the scan checkpoint, the request queue, the daily
+/// cap and the grant history. The copies are made in temporary directories which then replace the
+/// previous backups, so a failed backup never clobbers a good one. Returns the path of the keystore
+/// backup.
+async fn backup_keystore(
+    state: &FaucetState,
+    src: &Path,
+    queue: &Path,
+    backup_dir: &Path,
+) -> std::io::Result<PathBuf> {
+    if backup_dir.starts_with(src) || backup_dir.starts_with(queue) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "backup directory is inside the keystore or queue directory",
+        ));
+    }
+    let mut backups = vec![(src.to_owned(), BACKUP_NAME)];
+    if !queue.starts_with(src) {
+        backups.push((queue.to_owned(), QUEUE_BACKUP_NAME));
+    }
+    let backups = backups
+        .into_iter()
+        .map(|(src, name)| {
+            (
+                src,
+                backup_dir.join(format!("{}.tmp", name)),
+                backup_dir.join(format!("{}.old", name)),
+                backup_dir.join(name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let stale = backups
+        .iter()
+        .flat_map(|(_, tmp, old, _)| [tmp.clone(), old.clone()])
+        .collect::<Vec<_>>();
+    let dir = backup_dir.to_owned();
+    spawn_blocking(move || {
+        fs::create_dir_all(&dir)?;
+        for dir in stale {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await?;
+
+    {
+        let keystore = state.keystore.lock().await;
+        let _keystore_state = keystore.read().await;
+        let _queue = state.queue.index.lock().await;
+        let _daily_cap = match &state.daily_cap {
+            Some(daily_cap) => Some(daily_cap.lock().await),
+            None => None,
+        };
+        let _grant_history = match &state.grant_history {
+            Some(history) => Some(history.lock().await),
+            None => None,
+        };
+        let copies = backups
+            .iter()
+            .map(|(src, tmp, _, _)| (src.clone(), tmp.clone()))
+            .collect::<Vec<_>>();
+        spawn_blocking(move || {
+            for (src, tmp) in copies {
+                copy_dir(&src, &tmp)?;
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .await?;
+    }
+
+    let dst = backups[0].3.clone();
+    spawn_blocking(move || {
+        for (_, tmp, old, dst) in backups {
+            replace_backup(&tmp, &old, &dst)?;
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await?;
+    Ok(dst)
+}
+
+/// Back up the keystore directory `src` and the request queue directory `queue` to `backup_dir`
+/// every `interval`.
+async fn backup_keystore_periodically(
+    state: FaucetState,
+    src: PathBuf,
+    queue: PathBuf,
+    backup_dir: PathBuf,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+        match backup_keystore(&state, &src, &queue, &backup_dir).await {
+            Ok(path) => info!("backed up keystore to {}", path.display()),
+            Err(err) => error!("failed to back up keystore: {}", err),
+        }
+    }
+}
+
+/// Refresh the cached balance of the native asset every `interval`.
+async fn poll_balance(state: FaucetState, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        state.refresh_balance(&*state.keystore.lock().await).await;
+    }
+}
+
+/// Refill the faucet from the upstream faucet at `url` whenever its balance drops below
+/// `threshold`.
+///
+/// The balance is checked every `interval`. When it is low, we request a grant for the faucet's own
+/// key from the upstream faucet and then wait up to `timeout` for the balance to rise before
+/// checking again, so that we don't pile up requests while a grant is in flight.
+async fn refill_from_upstream(
+    state: FaucetState,
+    url: Url,
+    threshold: RecordAmount,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let request_url = match url.join("request_fee_assets") {
+        Ok(request_url) => request_url,
+        Err(err) => {
+            error!("invalid upstream faucet URL {}: {}", url, err);
+            return;
+        }
+    };
+    loop {
+        // A standby shares its key with the faucet it is standing in for, so leave refilling to
+        // the active faucet.
+        state.wait_while(FaucetStatus::Standby).await;
+
+        let (pub_key, balance) = {
+            let keystore = state.keystore.lock().await;
+            (
+                keystore.sending_keys().await[0].pub_key().clone(),
+                state.refresh_balance(&keystore).await,
+            )
+        };
+        if balance >= threshold.into() {
+            sleep(interval).await;
+            continue;
+        }
+
+        info!(
+            "balance {} is below {}, requesting funds from upstream faucet {}",
+            state.format_amount(balance),
+            state.format_amount(threshold),
+            url
+        );
+        if let Err(err) = post_json(&request_url, &pub_key).await {
+            warn!(
+                "failed to request funds from upstream faucet {}: {}",
+                url, err
+            );
+            sleep(interval).await;
+            continue;
+        }
+
+        let start = Instant::now();
+        loop {
+            sleep(interval).await;
+            let new_balance = state.refresh_balance(&*state.keystore.lock().await).await;
+            if new_balance > balance {
+                info!(
+                    "received {} from upstream faucet {}",
+                    state.format_amount(new_balance - balance),
+                    url
+                );
+                break;
+            }
+            if start.elapsed() >= timeout {
+                warn!(
+                    "no funds from upstream faucet {} after {:?}",
+                    url,
+                    start.elapsed()
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Build the web app serving the faucet API from `state`.
+fn faucet_app(
+    state: FaucetState,
+    opt: &FaucetOptions,
+) -> std::io::Result<App<FaucetState, FaucetError>> {
+    let mut app = App::<FaucetState, FaucetError>::with_state(state);
+    app.with_version(env!("CARGO_PKG_VERSION").parse().unwrap());
+    let api = match &opt.api_path {
+        Some(path) => toml::from_slice(&fs::read(path)?).unwrap(),
+        None => toml::from_str(include_str!("../api/api.toml")).unwrap(),
+    };
+    let module = app
+        .module("api", api)
+        .unwrap()
+        .at("build", |_req, _state| async { Ok(build_info()) }.boxed())
+        .unwrap()
+        .at("request_fee_assets", |req, state| {
+            request_fee_assets(req, state).boxed()
+        })
+        .unwrap()
+        .at("pause", |req, state| pause(req, state).boxed())
+        .unwrap()
+        .at("resume", |req, state| resume(req, state).boxed())
+        .unwrap()
+        .at("promote", |req, state| promote(req, state).boxed())
+        .unwrap()
+        .at("rescan", |req, state| rescan(req, state).boxed())
+        .unwrap()
+        .at("workers", |req, state| workers(req, state).boxed())
+        .unwrap()
+        .at("failures", |req, state| failures(req, state).boxed())
+        .unwrap()
+        .at("estimate_grant", |req, state| {
+            estimate_grant(req, state).boxed()
+        })
+        .unwrap()
+        .at("grant", |req, state| grant(req, state).boxed())
+        .unwrap()
+        .at("request_group", |req, state| {
+            request_group(req, state).boxed()
+        })
+        .unwrap();
+    if opt.status_page {
+        module
+            .at("status", |_req, state| status_report(state).boxed())
+            .unwrap()
+            .at("stats", |_req, state| stats_report(state).boxed())
+            .unwrap();
+    }
+    if opt.grant_history {
+        module
+            .at("history", |req, state| history(req, state).boxed())
+            .unwrap();
+    }
+    module.with_health_check(|state| async move { healthcheck(state).await }.boxed());
+    Ok(app)
+}
+
+/// Generate a proof so that the first grant does not pay the one-time cost of warming up the prover.
+///
+/// This transfers a single grant-sized record from the faucet to itself, so it does not change the
+/// number of records available for grants.
+async fn prewarm_prover(state: &FaucetState) {
+    let mut keystore = state.keystore.lock().await;
+    let pub_key = keystore.sending_keys().await[0].pub_key().clone();
+    let start = Instant::now();
+    let receipt = match keystore
+        .transfer(
+            None,
+            &AssetCode::native(),
+            &[(pub_key, state.grant_size)],
+            0u64,
+        )
+        .await
+    {
+        Ok(receipt) => receipt,
+        Err(err) => {
+            // Pre-warming is just an optimization, so we can keep going if it fails.
+            warn!("failed to pre-warm the prover: {}", err);
+            return;
+        }
+    };
+    info!("pre-warmed the prover in {:?}", start.elapsed());
+    if let Err(err) = keystore.await_transaction(&receipt).await {
+        warn!("pre-warm transfer did not complete: {}", err);
+    }
+}
+
+/// Poll `balance` every `interval` until it is nonzero, or until `timeout` has passed.
+///
+/// Returns the last balance read, which is zero only if the timeout expired.
+async fn wait_for_balance<F, Fut>(mut balance: F, interval: Duration, timeout: Duration) -> U256
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = U256>,
+{
+    let start = Instant::now();
+    loop {
+        let bal = balance().await;
+        if !bal.is_zero() {
+            return bal;
+        }
+        if start.elapsed() >= timeout {
+            warn!(
+                "faucet balance is still zero after {:?}, starting anyway",
+                start.elapsed()
+            );
+            return bal;
+        }
+        info!(
+            "faucet balance is zero after {:?}, waiting for records to arrive",
+            start.elapsed()
+        );
+        sleep(interval).await;
+    }
+}
+
+/// Bring the faucet from [FaucetStatus::Initializing] to [FaucetStatus::Available].
+///
+/// This waits for the keystore to discover `new_key`, if there is one, breaks up records until
+/// there are enough to serve requests, and then spawns the background tasks which handle requests
+/// and maintain the record supply. Returns the handles of the worker tasks. The other background
+/// tasks are kept in `state`, to be stopped by [shut_down].
+async fn start_faucet(
+    state: FaucetState,
+    new_key: Option<UserPubKey>,
+    breaker_signals: mpsc::Receiver<()>,
+    opt: &FaucetOptions,
+) -> Vec<JoinHandle<()>> {
+    if let Some(key) = new_key {
+        // Wait until we have scanned the ledger for records belonging to this key.
+        state
+            .keystore
+            .lock()
+            .await
+            .await_sending_key_scan(&key.address())
+            .await
+            .unwrap();
+    }
+
+    // Start refilling before we wait for a nonzero balance, so that a faucet which starts out empty
+    // can fund itself.
+    if let Some(url) = &opt.upstream_faucet_url {
+        match opt.upstream_refill_threshold() {
+            Ok(threshold) => {
+                state
+                    .spawn_background(refill_from_upstream(
+                        state.clone(),
+                        url.clone(),
+                        threshold,
+                        opt.balance_poll_interval,
+                        opt.balance_wait_timeout,
+                    ))
+                    .await;
+            }
+            Err(err) => error!("not refilling from upstream faucet {}: {}", url, err),
+        }
+    }
+
+    let bal = wait_for_balance(
+        || async { state.refresh_balance(&*state.keystore.lock().await).await },
+        opt.balance_poll_interval,
+        opt.balance_wait_timeout,
+    )
+    .await;
+    tracing::info!("Keystore balance before init: {}", state.format_amount(bal));
+
+    if opt.standby {
+        // Keep tracking the ledger, but don't touch the faucet's records or the queue until we are
+        // promoted. We only have a read-only snapshot of the queue, which the faucet we are
+        // standing in for may have changed since, so load it for writing before taking over.
+        *state.status.write().await = FaucetStatus::Standby;
+        info!("faucet is a standby, waiting to be promoted");
+        loop {
+            state.wait_while(FaucetStatus::Standby).await;
+            match state
+                .queue
+                .reload(
+                    &opt.queue_path(),
+                    opt.queue_compaction_threshold,
+                    opt.queue_log_format,
+                )
+                .await
+            {
+                Ok(()) => break,
+                Err(err) => {
+                    // We cannot grant from the snapshot, so remain a standby until promotion is
+                    // retried.
+                    error!(
+                        "failed to load the request queue on promotion, remaining a standby: {}",
+                        err
+                    );
+                    *state.status.write().await = FaucetStatus::Standby;
+                }
+            }
+        }
+    }
+
+    // Create at least `opt.num_records` if possible, before starting to handle requests.
+    if state.breaker {
+        if let Some(transactions) = break_up_records(&state).await {
+            let keystore = state.keystore.lock().await;
+            join_all(
+                transactions
+                    .iter()
+                    .map(|receipt| keystore.await_transaction(receipt)),
+            )
+            .await;
+        }
+    }
+
+    if opt.prewarm {
+        prewarm_prover(&state).await;
+    }
+
+    // Spawn a thread to continuously break records into smaller records to maintain
+    // `opt.num_records` at a time.
+    if state.breaker {
+        state
+            .spawn_background(maintain_enough_records(state.clone(), breaker_signals))
+            .await;
+    }
+
+    state
+        .spawn_background(checkpoint_scan(
+            state.clone(),
+            opt.keystore_path(),
+            opt.checkpoint_interval,
+        ))
+        .await;
+    // While requests are being shed, no worker touches the keystore, so poll the balance to notice
+    // when the faucet is funded again.
+    if state.min_balance.is_some() {
+        state
+            .spawn_background(poll_balance(state.clone(), opt.balance_poll_interval))
+            .await;
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        state
+            .spawn_background(backup_keystore_periodically(
+                state.clone(),
+                opt.keystore_path(),
+                opt.queue_path(),
+                backup_dir.clone(),
+                opt.backup_interval,
+            ))
+            .await;
+    }
+
+    // Spawn the worker threads that will handle faucet requests.
+    let workers = (0..opt.num_workers())
+        .map(|id| {
+            let start_delay = worker_start_delay(id, &mut thread_rng());
+            spawn(supervise_worker(id, state.clone(), start_delay, worker))
+        })
+        .collect();
+
+    *state.status.write().await = FaucetStatus::Available;
+    workers
+}
+
+/// Run the faucet web server until it fails or receives a signal to shut down.
+///
+/// On SIGINT or SIGTERM, the server stops taking requests, the workers are given up to
+/// `opt.shutdown_timeout` to finish the requests they are working on, and then the workers and the
+/// background tasks are stopped and a [ShutdownReport] is logged before returning.
+///
+/// `faucet_key_pair` - If provided, will be added to the faucet keystore.
+pub async fn init_web_server(
+    rng: &mut ChaChaRng,
+    opt: &FaucetOptions,
+    faucet_key_pair: Option<UserKeyPair>,
+) -> std::io::Result<()> {
+    opt.log_config();
+    opt.migrate_legacy_keystore()?;
+    let (keystore, new_key) = open_keystore(rng, opt, faucet_key_pair).await;
+
+    // Start the app before we wait for the key scan to complete. If we have to restart the faucet
+    // service from scratch (for example, if the keystore storage format changes and we need to
+    // recreate our files from a mnemonic) the key scan could take a very long time. We want the
+    // healthcheck endpoint to be available and returning "initializing" during that time, so the
+    // load balancer doesn't kill the service before it has a chance to start up. Other endpoints
+    // will fail while the app is initializing. Once initialization is complete, the healthcheck
+    // state will change to "available" and the other endpoints will start to work.
+    //
+    // The app state includes a bounded channel used to signal the record breaking thread when we
+    // need it to break large records into smaller ones. We use the total number of records to
+    // maintain as a conservative upper bound on how backed up the message channel can get.
+    let signal_breaker_thread = mpsc::channel(opt.num_records.min(opt.max_grant_records));
+    let state = FaucetState::new(keystore, signal_breaker_thread.0, opt)
+        .await
+        .unwrap();
+    let app = faucet_app(state.clone(), opt)?;
+    let mut server = Box::pin(app.serve(opt.listen_address()?)).fuse();
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let mut start = Box::pin(start_faucet(
+        state.clone(),
+        new_key,
+        signal_breaker_thread.1,
+        opt,
+    ))
+    .fuse();
+
+    // Serve requests while the faucet starts up, and after, until we are told to shut down. A
+    // signal during start-up abandons it, before any workers are spawned.
+    let mut workers = vec![];
+    loop {
+        select! {
+            started = start => workers = started,
+            res = server => return res,
+            signal = signals.next().fuse() => {
+                if let Some(signal) = signal {
+                    info!("received signal {}", signal);
+                }
+                break;
+            }
+        }
+    }
+
+    // Dropping the server future stops it from accepting requests.
+    drop(server);
+    drop(start);
+    shut_down(&state, workers, opt.shutdown_timeout).await;
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() -> Result<(), std::io::Error> {
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    // Initialize the faucet web server.
+    let opt =
+        FaucetOptions::try_parse_layered(std::env::args_os()).unwrap_or_else(|err| err.exit());
+    init_web_server(&mut ChaChaRng::from_entropy(), &opt, None).await
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::*;
+    use tempdir::TempDir;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn test_auto_workers() {
+        let mnemonic = KeyTree::random(&mut ChaChaRng::from_seed([6u8; 32])).1;
+        let opt = |args: &[&str]| {
+            FaucetOptions::try_parse_from(
+                ["faucet", "--mnemonic", &mnemonic.to_string()]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            )
+            .unwrap()
+        };
+
+        // Without `--auto-workers`, `num_workers` is used as is.
+        assert_eq!(
+            opt(&[
+                "--num-records",
+                "20",
+                "--num-grants",
+                "2",
+                "--num-workers",
+                "3"
+            ])
+            .num_workers(),
+            3
+        );
+        // With `--auto-workers`, the worker count is `num_records / num_grants`.
+        for (num_records, num_grants) in [(20, 2), (25, 5), (7, 2), (10, 1)] {
+            assert_eq!(
+                opt(&[
+                    "--auto-workers",
+                    "--num-records",
+                    &num_records.to_string(),
+                    "--num-grants",
+                    &num_grants.to_string(),
+                    "--num-workers",
+                    "3",
+                ])
+                .num_workers(),
+                num_records / num_grants
+            );
+        }
+        // There is always at least one worker.
+        assert_eq!(
+            opt(&["--auto-workers", "--num-records", "1", "--num-grants", "5"]).num_workers(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_worker_start_delay() {
+        let mut rng = ChaChaRng::from_seed([14u8; 32]);
+        let delays = (0..10)
+            .map(|id| worker_start_delay(id, &mut rng))
+            .collect::<Vec<_>>();
+        // Each worker starts in its own slot, so no two workers start at the same time.
+        for (id, delay) in delays.iter().enumerate() {
+            assert!(*delay >= WORKER_START_STAGGER * id as u32);
+            assert!(*delay < WORKER_START_STAGGER * (id + 1) as u32);
+        }
+        // The start times are jittered within each slot, rather than evenly spaced.
+        assert!(delays
+            .iter()
+            .enumerate()
+            .any(|(id, delay)| *delay != WORKER_START_STAGGER * id as u32));
+    }
+
+    #[test]
+    fn test_decimal_amount() {
+        // Valid decimals are shifted by the number of decimal places of the asset.
+        for (amount, decimals, base_units) in [
+            ("5000", 0, 5000),
+            ("1.5", 2, 150),
+            ("1.50", 1, 15),
+            ("0.001", 3, 1),
+            (".25", 2, 25),
+            (".0", 0, 0),
+            ("3.", 2, 300),
+            ("18446744073709551615", 0, u64::MAX),
+        ] {
+            assert_eq!(
+                amount
+                    .parse::<DecimalAmount>()
+                    .unwrap()
+                    .to_base_units(decimals)
+                    .unwrap(),
+                base_units,
+                "{} with {} decimals",
+                amount,
+                decimals
+            );
+        }
+
+        // Malformed amounts are rejected when parsing.
+        for amount in ["", ".", "-1", "1.2.3", "1e5", "abc"] {
+            amount.parse::<DecimalAmount>().unwrap_err();
+        }
+
+        // Amounts which overflow are rejected.
+        for (amount, decimals) in [("18446744073709551616", 0), ("18446744073709551615", 1)] {
+            let err = amount
+                .parse::<DecimalAmount>()
+                .unwrap()
+                .to_base_units(decimals)
+                .unwrap_err();
+            assert!(err.contains("does not fit"), "{}", err);
+        }
+
+        // Amounts with more precision than the asset are rejected.
+        for (amount, decimals) in [("1.5", 0), ("0.001", 2)] {
+            let err = amount
+                .parse::<DecimalAmount>()
+                .unwrap()
+                .to_base_units(decimals)
+                .unwrap_err();
+            assert!(err.contains("too many decimal places"), "{}", err);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_log_config() {
+        let mut rng = ChaChaRng::from_seed([17u8; 32]);
+        let mnemonic = KeyTree::random(&mut rng).1;
+        let opt = FaucetOptions::try_parse_from([
+            "faucet",
+            "--mnemonic",
+            &mnemonic.to_string(),
+            "--keystore-password",
+            "hunter2",
+            "--admin-token",
+            "secret-token",
+            "--faucet-port",
+            "51234",
+        ])
+        .unwrap();
+        opt.log_config();
+
+        // The configuration should be logged, including resolved defaults.
+        assert!(logs_contain("faucet configuration"));
+        assert!(logs_contain("51234"));
+        assert!(logs_contain(&opt.keystore_path().display().to_string()));
+        assert!(logs_contain(opt.esqs_url.as_str()));
+
+        // Secrets should not be logged.
+        assert!(!logs_contain(&mnemonic.to_string()));
+        assert!(!logs_contain("hunter2"));
+        assert!(!logs_contain("secret-token"));
+    }
+
+    #[test]
+    fn test_default_keystore_path() {
+        let mut rng = ChaChaRng::from_seed([18u8; 32]);
+        let options = |mnemonic: &Mnemonic| {
+            FaucetOptions::try_parse_from(["faucet", "--mnemonic", &mnemonic.to_string()]).unwrap()
+        };
+        let mnemonic1 = KeyTree::random(&mut rng).1;
+        let mnemonic2 = KeyTree::random(&mut rng).1;
+
+        // Distinct mnemonics get distinct default paths, but the path for a given mnemonic is
+        // stable.
+        assert_ne!(
+            options(&mnemonic1).keystore_path(),
+            options(&mnemonic2).keystore_path()
+        );
+        assert_eq!(
+            options(&mnemonic1).keystore_path(),
+            options(&mnemonic1).keystore_path()
+        );
+
+        // An explicit path overrides the default.
+        let opt = FaucetOptions::try_parse_from([
+            "faucet",
+            "--mnemonic",
+            &mnemonic1.to_string(),
+            "--keystore-path",
+            "/tmp/faucet-keystore",
+        ])
+        .unwrap();
+        assert_eq!(opt.keystore_path(), PathBuf::from("/tmp/faucet-keystore"));
+    }
+
+    #[test]
+    fn test_migrate_keystore() {
+        let dir = TempDir::new("espresso_faucet_migrate").unwrap();
+        let legacy = dir.path().join("keystore");
+        let keystore = dir.path().join("0123456789abcdef").join("keystore");
+
+        // Without a legacy keystore, there is nothing to do.
+        assert!(!migrate_keystore(&legacy, &keystore).unwrap());
+        assert!(!keystore.exists());
+
+        // A legacy keystore is moved to the new path, with its contents.
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("queue_format"), "bincode").unwrap();
+        assert!(migrate_keystore(&legacy, &keystore).unwrap());
+        assert!(!legacy.exists());
+        assert_eq!(
+            fs::read_to_string(keystore.join("queue_format")).unwrap(),
+            "bincode"
+        );
+
+        // An existing keystore at the new path is never replaced.
+        fs::create_dir_all(&legacy).unwrap();
+        assert!(!migrate_keystore(&legacy, &keystore).unwrap());
+        assert!(legacy.exists());
+        assert!(keystore.join("queue_format").exists());
+    }
+
+    #[test]
+    fn test_daily_cap() {
+        let dir = TempDir::new("espresso_faucet_daily_cap").unwrap();
+        let window = DAILY_CAP_WINDOW.as_secs();
+        let start = 1_000_000;
+        {
+            let mut cap = DailyCap::load(dir.path(), 100).unwrap();
+            cap.check(start).unwrap();
+            cap.record(60, start).unwrap();
+            cap.check(start + 1).unwrap();
+            cap.record(60, start + 1).unwrap();
+
+            // Once the cap is exceeded, new requests are rejected for the rest of the window.
+            match cap.check(start + window - 1) {
+                Err(FaucetError::DailyCapReached { cap }) => assert_eq!(cap, 100),
+                res => panic!("expected DailyCapReached, got {:?}", res),
+            }
+        }
+
+        // The accounting is persisted.
+        let mut cap = DailyCap::load(dir.path(), 100).unwrap();
+        assert_eq!(cap.dispensed(start + 1), 120);
+        cap.check(start + window - 1).unwrap_err();
+
+        // Transfers which would exceed the cap are held until the window ends.
+        assert_eq!(
+            cap.wait_time(1, start + 10),
+            Some(Duration::from_secs(window - 10))
+        );
+        assert_eq!(cap.wait_time(1, start + window), None);
+
+        // At the window boundary, the cap resets, and the next grant starts a new window.
+        cap.check(start + window).unwrap();
+        cap.record(10, start + window).unwrap();
+        assert_eq!(cap.dispensed(start + window), 10);
+        assert_eq!(
+            DailyCap::load(dir.path(), 100)
+                .unwrap()
+                .dispensed(start + window),
+            10
+        );
+    }
+
+    #[test]
+    fn test_format_amount() {
+        for (amount, decimals, expected) in [
+            (1234500u64, 4, "123.45"),
+            (5, 3, "0.005"),
+            (0, 2, "0"),
+            (1000, 0, "1000"),
+            (100, 2, "1"),
+        ] {
+            let formatted = DecimalAmount::from_base_units(U256::from(amount), decimals);
+            assert_eq!(formatted.to_string(), expected);
+            // Formatting is the inverse of parsing.
+            assert_eq!(formatted, expected.parse().unwrap());
+            assert_eq!(formatted.to_base_units(decimals), Ok(amount));
+        }
+    }
+
+    #[test]
+    fn test_config_file() {
+        let mut rng = ChaChaRng::from_seed([24u8; 32]);
+        let mnemonic = KeyTree::random(&mut rng).1;
+        let dir = TempDir::new("espresso_faucet_config").unwrap();
+        let path = dir.path().join("faucet.toml");
+        fs::write(
+            &path,
+            format!(
+                r#"
+                mnemonic = "{}"
+                grant-size = "1234"
+                num_grants = 7
+                no-breaker = true
+                "#,
+                mnemonic
+            ),
+        )
+        .unwrap();
+
+        let opt = FaucetOptions::try_parse_layered([
+            "faucet",
+            "--config",
+            path.to_str().unwrap(),
+            "--num-grants",
+            "3",
+        ])
+        .unwrap();
+        // Options in the file override defaults...
+        assert_eq!(opt.mnemonic.to_string(), mnemonic.to_string());
+        assert_eq!(opt.grant_size, "1234".parse().unwrap());
+        assert!(opt.no_breaker);
+        // ...but not options given on the command line.
+        assert_eq!(opt.num_grants, 3);
+        // Options in neither still get their defaults.
+        assert_eq!(opt.fee_size, "100".parse().unwrap());
+
+        // Unknown options in the file are an error.
+        fs::write(&path, "not_an_option = 1").unwrap();
+        FaucetOptions::try_parse_layered(["faucet", "--config", path.to_str().unwrap()])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_pub_key() {
+        let mut rng = ChaChaRng::from_seed([33u8; 32]);
+        let pub_key = UserKeyPair::generate(&mut rng).pub_key();
+        let reason = |content_type, body: &[u8]| match parse_pub_key_body(content_type, body) {
+            Err(FaucetError::BadRequest { field, reason }) => format!("{}: {}", field, reason),
+            res => panic!("expected BadRequest, got {:?}", res),
+        };
+
+        // Both encodings of a valid key are accepted.
+        let json = serde_json::to_vec(&pub_key).unwrap();
+        let binary = bincode::serialize(&pub_key).unwrap();
+        assert_eq!(
+            parse_pub_key_body(Some("application/json"), &json).unwrap(),
+            pub_key
+        );
+        assert_eq!(
+            parse_pub_key_body(Some("application/json; charset=utf-8"), &json).unwrap(),
+            pub_key
+        );
+        assert_eq!(
+            parse_pub_key_body(Some("application/octet-stream"), &binary).unwrap(),
+            pub_key
+        );
+
+        // Missing fields.
+        assert_eq!(reason(Some("application/json"), &[]), "pub_key: missing");
+        assert_eq!(reason(None, &json), "Content-Type: missing");
+
+        // Malformed fields.
+        assert!(reason(Some("application/json"), b"{").starts_with("pub_key: invalid JSON"));
+        assert_eq!(
+            reason(Some("application/json"), b"42"),
+            "pub_key: expected a tagged-base64 string"
+        );
+        assert!(reason(Some("application/json"), b"\"USERPUBKEY~nope\"")
+            .starts_with("pub_key: invalid tagged-base64"));
+        assert!(reason(
+            Some("application/octet-stream"),
+            &binary[..binary.len() / 2]
+        )
+        .starts_with("pub_key: invalid binary encoding"));
+        assert!(reason(Some("text/plain"), &json).starts_with("Content-Type: unsupported"));
+
+        // Group requests carry a list of keys.
+        let keys = vec![pub_key.clone(), UserKeyPair::generate(&mut rng).pub_key()];
+        assert_eq!(
+            parse_pub_keys_body(
+                Some("application/json"),
+                &serde_json::to_vec(&keys).unwrap()
+            )
+            .unwrap(),
+            keys
+        );
+        assert_eq!(
+            parse_pub_keys_body(
+                Some("application/octet-stream"),
+                &bincode::serialize(&keys).unwrap()
+            )
+            .unwrap(),
+            keys
+        );
+        match parse_pub_keys_body(Some("application/json"), &json) {
+            Err(FaucetError::BadRequest { field, reason }) => {
+                assert_eq!(field, "pub_keys");
+                assert_eq!(reason, "expected an array of tagged-base64 strings");
+            }
+            res => panic!("expected BadRequest, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_request_info_layouts() {
+        let mut rng = ChaChaRng::from_seed([52u8; 32]);
+        let key = UserKeyPair::generate(&mut rng).pub_key();
+        let store = QueueLoadStore::<(UserPubKey, RequestInfo)>::new(QueueLogFormat::Bincode);
+
+        // Bincode entries written before fields were added to `RequestInfo` still load, with the
+        // new fields defaulted.
+        let v1 = bincode::serialize(&(
+            key.clone(),
+            RequestInfoV1 {
+                priority: Priority::High,
+                num_grants: Some(3),
+            },
+        ))
+        .unwrap();
+        let v2 = bincode::serialize(&(
+            key.clone(),
+            RequestInfoV2 {
+                priority: Priority::High,
+                num_grants: Some(3),
+                label: Some("ci".into()),
+            },
+        ))
+        .unwrap();
+        let info = RequestInfo {
+            priority: Priority::High,
+            num_grants: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(store.load(&v1).unwrap(), (key.clone(), info.clone()));
+        let info = RequestInfo {
+            label: Some("ci".into()),
+            ..info
+        };
+        assert_eq!(store.load(&v2).unwrap(), (key.clone(), info.clone()));
+
+        // Entries in the current layout round trip.
+        let info = RequestInfo {
+            group: vec![UserKeyPair::generate(&mut rng).pub_key()],
+            ..info
+        };
+        let mut store = store;
+        let current = store.store(&(key.clone(), info.clone())).unwrap();
+        assert_eq!(store.load(&current).unwrap(), (key, info));
+
+        // A truncated entry is still an error.
+        store.load(&v1[..v1.len() - 1]).unwrap_err();
+    }
+
+    /// The outcome of a simulated record breakup.
+    #[derive(Debug)]
+    struct BreakupStats {
+        /// The number of times the breaker had to wait for its pending transactions to finalize.
+        rounds: usize,
+        transactions: usize,
+        records: usize,
+    }
+
+    /// Model the strategy of [break_up_records] with transactions which split a record into
+    /// `outputs` pieces.
+    ///
+    /// [break_up_records] is written against the concrete keystore type, so it cannot be run
+    /// against a mock. This follows the same strategy: in each round, keep splitting the largest
+    /// available record until the records available once the round's transactions finalize meet
+    /// `target`, or nothing is left to split, then wait for the round to finalize.
+    fn simulate_breakup(
+        initial: &[u64],
+        grant_size: u64,
+        target: usize,
+        outputs: usize,
+    ) -> BreakupStats {
+        let mut records = initial.to_vec();
+        let mut stats = BreakupStats {
+            rounds: 0,
+            transactions: 0,
+            records: 0,
+        };
+        loop {
+            let mut pending = Vec::new();
+            let mut transactions = 0;
+            while records.len() + outputs * transactions < target {
+                records.sort_unstable();
+                match records.last() {
+                    Some(&largest) if largest >= grant_size * outputs as u64 => {
+                        records.pop();
+                        let piece = largest / outputs as u64;
+                        pending.extend(iter::repeat(piece).take(outputs - 1));
+                        pending.push(largest - piece * (outputs as u64 - 1));
+                        transactions += 1;
+                    }
+                    _ => break,
+                }
+            }
+            if transactions == 0 {
+                break;
+            }
+            stats.rounds += 1;
+            stats.transactions += transactions;
+            records.extend(pending);
+            if records.len() >= target {
+                break;
+            }
+        }
+        stats.records = records.len();
+        stats
+    }
+
+    #[test]
+    fn test_record_breakup_strategies() {
+        let grant_size = 1000;
+        let target = 100;
+        let initial = [grant_size * 1_000_000];
+        let results = [2, 3]
+            .into_iter()
+            .map(|outputs| simulate_breakup(&initial, grant_size, target, outputs))
+            .collect::<Vec<_>>();
+        for stats in &results {
+            assert!(stats.records >= target, "{:?}", stats);
+        }
+        // Each round multiplies the number of records by up to the number of outputs, so splitting
+        // three ways reaches the target in fewer rounds.
+        assert!(results[1].rounds < results[0].rounds);
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod test {
+    use super::*;
+    use async_std::{
+        io::{Read, Write},
+        io::{ReadExt, WriteExt},
+        net::{TcpListener, TcpStream},
+        os::unix::net::UnixStream,
+        task::{sleep, spawn_blocking},
+    };
+    use escargot::CargoBuild;
+    use espresso_client::loader::CreateLoader;
+    use espresso_validator::testing::{minimal_test_network, retry, TestNetwork};
+    use faucet_types::faucet_client::FaucetClient;
     use futures::future::join_all;
-    use jf_cap::structs::AssetDefinition;
+    use jf_cap::structs::{AssetDefinition, AssetPolicy};
     use portpicker::pick_unused_port;
-    use primitive_types::U256;
     use rand::Rng;
     use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
     use std::path::PathBuf;
-    use std::process::Child;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::time::Duration;
     use tempdir::TempDir;
     use tracing_test::traced_test;
 
-    struct Faucet {
-        esqs_url: Url,
-        submit_url: Url,
-        address_book_url: Url,
-        mnemonic: Mnemonic,
-        dir: PathBuf,
-        port: u16,
-        grant_size: RecordAmount,
-        num_grants: usize,
-        num_requests: usize,
-        process: Option<Child>,
+    struct Faucet {
+        esqs_url: Url,
+        submit_url: Url,
+        address_book_url: Url,
+        mnemonic: Mnemonic,
+        dir: PathBuf,
+        port: u16,
+        grant_size: RecordAmount,
+        num_grants: usize,
+        num_requests: usize,
+        num_workers: usize,
+        // Additional command line arguments to pass to the faucet.
+        extra_args: Vec<String>,
+        process: Option<Child>,
+    }
+
+    impl Faucet {
+        /// A faucet for `network`, keeping its keystore in `dir`, which makes single grants of
+        /// 1000 to one request at a time on an unused port.
+        ///
+        /// Tests override the fields they care about with struct update syntax.
+        fn new(network: &TestNetwork, mnemonic: Mnemonic, dir: &Path) -> Self {
+            Self {
+                esqs_url: network.query_api.clone(),
+                submit_url: network.submit_api.clone(),
+                address_book_url: network.address_book_api.clone(),
+                mnemonic,
+                dir: dir.to_owned(),
+                port: pick_unused_port().unwrap(),
+                grant_size: RecordAmount::from(1000u64),
+                num_grants: 1,
+                num_requests: 1,
+                num_workers: 1,
+                extra_args: vec![],
+                process: None,
+            }
+        }
+
+        /// The command which runs this faucet.
+        fn command(&self) -> Command {
+            let esqs_url = self.esqs_url.to_string();
+            let submit_url = self.submit_url.to_string();
+            let address_book_url = self.address_book_url.to_string();
+            let mnemonic = self.mnemonic.to_string();
+            let dir = self.dir.display().to_string();
+            let port = self.port.to_string();
+            let grant_size = self.grant_size.to_string();
+            let num_grants = self.num_grants.to_string();
+            let num_requests = self.num_requests.to_string();
+            let num_workers = self.num_workers.to_string();
+            let num_records = (self.num_grants * self.num_requests).to_string();
+
+            let mut command = CargoBuild::new()
+                .current_release()
+                .bin("faucet")
+                .run()
+                .unwrap()
+                .command();
+            command
+                .args([
+                    "--esqs-url",
+                    &esqs_url,
+                    "--submit-url",
+                    &submit_url,
+                    "--address-book-url",
+                    &address_book_url,
+                    "--mnemonic",
+                    &mnemonic,
+                    "--keystore-path",
+                    &dir,
+                    "--faucet-port",
+                    &port,
+                    "--grant-size",
+                    &grant_size,
+                    "--num-grants",
+                    &num_grants,
+                    "--num-records",
+                    &num_records,
+                    "--max-queue-len",
+                    &num_requests,
+                    "--num-workers",
+                    &num_workers,
+                ])
+                .args(&self.extra_args);
+            command
+        }
+
+        async fn start(&mut self) {
+            self.process = Some(self.command().spawn().unwrap());
+            self.wait_until_available().await;
+        }
+
+        async fn wait_until_available(&self) {
+            loop {
+                if let Ok(health) = surf_disco::get::<HealthCheck, FaucetError>(
+                    format!("http://localhost:{}/api/healthcheck", self.port)
+                        .parse()
+                        .unwrap(),
+                )
+                .send()
+                .await
+                {
+                    if health.status == FaucetStatus::Available {
+                        break;
+                    }
+                }
+
+                sleep(Duration::from_secs(10)).await;
+            }
+        }
+
+        async fn stop(&mut self) {
+            if let Some(mut process) = self.process.take() {
+                spawn_blocking(move || {
+                    process.kill().unwrap();
+                    process.wait().unwrap();
+                })
+                .await;
+            }
+        }
+
+        async fn restart(&mut self) {
+            self.stop().await;
+            self.start().await;
+        }
+
+        fn client(&self) -> surf_disco::Client<FaucetError> {
+            surf_disco::Client::new(
+                format!("http://localhost:{}/api/", self.port)
+                    .parse()
+                    .unwrap(),
+            )
+        }
+    }
+
+    /// The in-process counterpart of [Faucet].
+    ///
+    /// The faucet runs in the test process, so tests can inspect and manipulate its state directly.
+    struct InProcessFaucet {
+        opt: FaucetOptions,
+        state: FaucetState,
+        new_key: Option<UserPubKey>,
+        breaker_signals: Option<mpsc::Receiver<()>>,
+    }
+
+    impl InProcessFaucet {
+        /// Open a faucet for `network`, keeping its keystore in `dir` and listening on an unused
+        /// port, and serve its API.
+        ///
+        /// `extra_args` are added to the command line after the network and keystore options. The
+        /// faucet does not take requests until it is started.
+        async fn new(
+            rng: &mut ChaChaRng,
+            network: &TestNetwork,
+            mnemonic: &Mnemonic,
+            dir: &Path,
+            extra_args: &[&str],
+        ) -> Self {
+            let mnemonic = mnemonic.to_string();
+            let dir = dir.display().to_string();
+            let port = pick_unused_port().unwrap().to_string();
+            let esqs_url = network.query_api.to_string();
+            let submit_url = network.submit_api.to_string();
+            let address_book_url = network.address_book_api.to_string();
+            let opt = FaucetOptions::try_parse_from(
+                [
+                    "faucet",
+                    "--mnemonic",
+                    &mnemonic,
+                    "--keystore-path",
+                    &dir,
+                    "--faucet-port",
+                    &port,
+                    "--esqs-url",
+                    &esqs_url,
+                    "--submit-url",
+                    &submit_url,
+                    "--address-book-url",
+                    &address_book_url,
+                ]
+                .into_iter()
+                .chain(extra_args.iter().copied()),
+            )
+            .unwrap();
+            let (keystore, new_key) = open_keystore(rng, &opt, None).await;
+            let (signal_breaker_thread, breaker_signals) = mpsc::channel(opt.num_records);
+            let state = FaucetState::new(keystore, signal_breaker_thread, &opt)
+                .await
+                .unwrap();
+            spawn(
+                faucet_app(state.clone(), &opt)
+                    .unwrap()
+                    .serve(opt.listen_address().unwrap()),
+            );
+            Self {
+                opt,
+                state,
+                new_key,
+                breaker_signals: Some(breaker_signals),
+            }
+        }
+
+        async fn start(&mut self) {
+            start_faucet(
+                self.state.clone(),
+                self.new_key.take(),
+                self.breaker_signals.take().unwrap(),
+                &self.opt,
+            )
+            .await;
+        }
+
+        fn client(&self) -> surf_disco::Client<FaucetError> {
+            surf_disco::Client::new(
+                format!("http://localhost:{}/api/", self.opt.faucet_port)
+                    .parse()
+                    .unwrap(),
+            )
+        }
+    }
+
+    /// Start an in-process faucet with `extra_args`, and return its state and a client for its
+    /// API.
+    async fn start_in_process(
+        rng: &mut ChaChaRng,
+        network: &TestNetwork,
+        mnemonic: &Mnemonic,
+        dir: &Path,
+        extra_args: &[&str],
+    ) -> (FaucetState, surf_disco::Client<FaucetError>) {
+        let mut faucet = InProcessFaucet::new(rng, network, mnemonic, dir, extra_args).await;
+        faucet.start().await;
+        (faucet.state.clone(), faucet.client())
+    }
+
+    /// Send a `HEAD` request for `path` and return the status code and the body of the response.
+    async fn http_head(port: u16, path: &str) -> (u16, Vec<u8>) {
+        let stream = TcpStream::connect(("localhost", port)).await.unwrap();
+        http_request(stream, "HEAD", path).await
+    }
+
+    /// Send a request for `path` over `stream` and return the status code and the body of the
+    /// response.
+    ///
+    /// The HTTP clients used elsewhere in these tests have no way to send a `HEAD` request, or to
+    /// connect over a Unix socket, so this speaks HTTP/1.1 directly.
+    async fn http_request(
+        mut stream: impl Read + Write + Unpin,
+        method: &str,
+        path: &str,
+    ) -> (u16, Vec<u8>) {
+        stream
+            .write_all(
+                format!(
+                    "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+                    method, path
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut res = vec![];
+        stream.read_to_end(&mut res).await.unwrap();
+        let end_of_headers = res
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let status_line = String::from_utf8_lossy(&res[..end_of_headers])
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        (status, res[end_of_headers + 4..].to_vec())
+    }
+
+    /// Start a mock grant observer or alert endpoint on `port`.
+    ///
+    /// Each JSON body posted to the server is forwarded to the returned channel.
+    async fn mock_observer<T: DeserializeOwned + Send + 'static>(port: u16) -> mpmc::Receiver<T> {
+        let listener = TcpListener::bind(("localhost", port)).await.unwrap();
+        let (sender, receiver) = mpmc::unbounded();
+        spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                let mut stream = stream.unwrap();
+                let mut req = vec![];
+                let mut buf = [0u8; 4096];
+                let end_of_headers = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "observer connection closed before end of headers");
+                    req.extend_from_slice(&buf[..n]);
+                    if let Some(pos) = req.windows(4).position(|window| window == b"\r\n\r\n") {
+                        break pos;
+                    }
+                };
+                let headers = String::from_utf8_lossy(&req[..end_of_headers]).to_lowercase();
+                let len: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .unwrap()
+                    .trim()
+                    .parse()
+                    .unwrap();
+                if headers.contains("expect: 100-continue") {
+                    stream
+                        .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+                let mut body = req[end_of_headers + 4..].to_vec();
+                while body.len() < len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "observer connection closed before end of body");
+                    body.extend_from_slice(&buf[..n]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                sender
+                    .send(serde_json::from_slice(&body).unwrap())
+                    .await
+                    .unwrap();
+            }
+        });
+        receiver
+    }
+
+    /// Create a test network with a faucet record owned by the key of a new random mnemonic.
+    async fn faucet_test_network(rng: &mut ChaChaRng) -> (TestNetwork, Mnemonic) {
+        let (key_stream, mnemonic) = KeyTree::random(rng);
+        let faucet_key_pair = key_stream
+            .derive_sub_tree("keystore".as_bytes())
+            .derive_sub_tree("user".as_bytes())
+            .derive_user_key_pair(&0u64.to_le_bytes());
+        let network = minimal_test_network(rng, faucet_key_pair.pub_key(), None).await;
+        (network, mnemonic)
+    }
+
+    async fn create_receiver(
+        rng: &mut ChaChaRng,
+        network: &TestNetwork,
+    ) -> (
+        EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>,
+        UserPubKey,
+        TempDir,
+    ) {
+        let receiver_dir = TempDir::new("espresso_keystore_receiver").unwrap();
+        let receiver_mnemonic = KeyTree::random(rng).1;
+        let mut receiver_loader = CreateLoader::exclusive(
+            rng,
+            PathBuf::from(receiver_dir.path()),
+            receiver_mnemonic,
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        );
+        let backend = NetworkBackend::new(
+            &UNIVERSAL_PARAM,
+            network.query_api.clone(),
+            network.address_book_api.clone(),
+            network.submit_api.clone(),
+        )
+        .await
+        .unwrap();
+        let mut receiver = EspressoKeystore::new(backend, &mut receiver_loader)
+            .await
+            .unwrap();
+        let receiver_key = receiver
+            .generate_sending_account("receiver".into(), None)
+            .await
+            .unwrap();
+        (receiver, receiver_key, receiver_dir)
+    }
+
+    /// Define and mint `num_assets` custom assets owned by the faucet key.
+    ///
+    /// This opens the faucet keystore at `dir` directly, so it must be called while the faucet
+    /// process is not running.
+    async fn mint_faucet_assets(
+        rng: &mut ChaChaRng,
+        network: &TestNetwork,
+        mnemonic: &Mnemonic,
+        dir: &Path,
+        num_assets: usize,
+    ) -> Vec<AssetCode> {
+        let mut loader = RecoveryLoader::new(
+            rng,
+            dir.to_owned(),
+            mnemonic.clone(),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        );
+        let backend = NetworkBackend::new(
+            &UNIVERSAL_PARAM,
+            network.query_api.clone(),
+            network.address_book_api.clone(),
+            network.submit_api.clone(),
+        )
+        .await
+        .unwrap();
+        let mut keystore = EspressoKeystore::new(backend, &mut loader).await.unwrap();
+        let key = keystore
+            .generate_sending_account("faucet".into(), Some(EventIndex::default()))
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&key.address())
+            .await
+            .unwrap();
+
+        let mut assets = Vec::new();
+        for i in 0..num_assets {
+            let asset = keystore
+                .define_asset(format!("faucet asset {}", i), &[], AssetPolicy::default())
+                .await
+                .unwrap();
+            let receipt = keystore
+                .mint(None, 0, &asset.code, 1u64 << 32, key.clone())
+                .await
+                .unwrap();
+            assert!(keystore
+                .await_transaction(&receipt)
+                .await
+                .unwrap()
+                .succeeded());
+            assets.push(asset.code);
+        }
+        assets
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_log_completed_request() {
+        let mut rng = ChaChaRng::from_seed([25u8; 32]);
+        let pub_key = UserKeyPair::generate(&mut rng).pub_key();
+        log_completed_request(0, &pub_key, 3, Some("ci-run-1234"));
+        assert!(logs_contain("completed request"));
+        assert!(logs_contain(&pub_key.address().to_string()));
+        assert!(logs_contain("label=\"ci-run-1234\""));
+    }
+
+    #[async_std::test]
+    async fn test_queue_path() {
+        let mut rng = ChaChaRng::from_seed([38u8; 32]);
+        let mnemonic = KeyTree::random(&mut rng).1;
+        let keystore_dir = TempDir::new("espresso_faucet_keystore").unwrap();
+        let queue_dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let options = |extra_args: &[&str]| {
+            let mut args = vec![
+                "faucet".to_string(),
+                "--mnemonic".to_string(),
+                mnemonic.to_string(),
+                "--keystore-path".to_string(),
+                keystore_dir.path().display().to_string(),
+            ];
+            args.extend(extra_args.iter().map(|arg| arg.to_string()));
+            FaucetOptions::try_parse_from(args).unwrap()
+        };
+
+        // By default, the queue lives with the keystore.
+        assert_eq!(options(&[]).queue_path(), keystore_dir.path());
+
+        // With `--queue-path`, the queue is persisted to the given directory, and nothing is
+        // written to the keystore directory.
+        let opt = options(&["--queue-path", &queue_dir.path().display().to_string()]);
+        assert_eq!(opt.queue_path(), queue_dir.path());
+        let key = UserKeyPair::generate(&mut rng).pub_key();
+        {
+            let queue = FaucetQueue::load(
+                &opt.queue_path(),
+                None,
+                usize::MAX,
+                QueueLogFormat::default(),
+            )
+            .await
+            .unwrap();
+            queue
+                .push(key.clone(), RequestInfo::default())
+                .await
+                .unwrap();
+        }
+        assert!(!queue_log_files(queue_dir.path()).is_empty());
+        assert!(queue_log_files(keystore_dir.path()).is_empty());
+
+        // The request is still there when the queue is reloaded from the same directory.
+        let mut queue = FaucetQueue::load(
+            &opt.queue_path(),
+            None,
+            usize::MAX,
+            QueueLogFormat::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(queue.pop().await.unwrap(), (key, 0));
+    }
+
+    /// The files making up the persistent queue log in `dir`.
+    fn queue_log_files(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("requests")
+            })
+            .collect()
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_compaction() {
+        let mut rng = ChaChaRng::from_seed([7u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let log_size = || {
+            queue_log_files(dir.path())
+                .into_iter()
+                .map(|path| fs::metadata(path).unwrap().len())
+                .sum::<u64>()
+        };
+        let keys = (0..20)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        {
+            // Add many requests and then remove most of them, leaving stale entries in the log.
+            let queue = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap();
+            for key in &keys {
+                queue
+                    .push(key.clone(), RequestInfo::default())
+                    .await
+                    .unwrap();
+            }
+            let mut index = queue.index.lock().await;
+            for key in &keys[2..] {
+                index.remove(key).unwrap();
+            }
+            assert!(index.grant(keys[1].clone(), 1, 5).unwrap());
+        }
+        let size = log_size();
+
+        // Reloading below the compaction threshold leaves the log alone.
+        FaucetQueue::load(dir.path(), None, 1000, QueueLogFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(log_size(), size);
+
+        // Above the threshold, the log is compacted, and both the compacted queue and the queue
+        // reloaded from the compacted log match the original.
+        let check_queue = |mut queue: FaucetQueue| {
+            let keys = keys.clone();
+            async move {
+                assert_eq!(queue.index.lock().await.len(), 2);
+                assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+                assert_eq!(queue.pop().await.unwrap(), (keys[1].clone(), 1));
+                assert!(queue.receiver.is_empty());
+            }
+        };
+        check_queue(
+            FaucetQueue::load(dir.path(), None, 0, QueueLogFormat::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(log_size() < size);
+        check_queue(
+            FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+
+        // A compaction which was interrupted before the compacted log was complete is discarded,
+        // leaving the original log.
+        let size = log_size();
+        let tmp = dir.path().join(COMPACTION_TMP_DIR);
+        fs::create_dir(&tmp).unwrap();
+        fs::write(tmp.join("requests_0"), b"partial").unwrap();
+        check_queue(
+            FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(!tmp.exists());
+        assert_eq!(log_size(), size);
+
+        // One which was interrupted after the compacted log was complete is finished.
+        let index = [(keys[0].clone(), 0), (keys[1].clone(), 1)]
+            .into_iter()
+            .collect();
+        write_compacted_queue(
+            &dir.path().join(COMPACTION_DIR),
+            &keys[..2],
+            &index,
+            &HashMap::new(),
+            QueueLogFormat::default(),
+        )
+        .unwrap();
+        check_queue(
+            FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(!dir.path().join(COMPACTION_DIR).exists());
+        assert!(!dir.path().join(STALE_DIR).exists());
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_truncated_log() {
+        let mut rng = ChaChaRng::from_seed([5u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+
+        // Simulate an interrupted write by chopping off the end of the last entry in the log.
+        let (log, entries) = write_queue_log(dir.path(), &keys).await;
+        let (start, len) = entries[2];
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .set_len((start + len - 4) as u64)
+            .unwrap();
+
+        // The queue should recover up to the last intact entry.
+        let mut queue = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(queue.index.lock().await.len(), 2);
+        assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+        assert_eq!(queue.pop().await.unwrap(), (keys[1].clone(), 0));
+        assert!(queue.receiver.is_empty());
+
+        // The recovered queue should still be usable.
+        queue
+            .push(keys[2].clone(), RequestInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(queue.pop().await.unwrap(), (keys[2].clone(), 0));
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_corrupt_log() {
+        let mut rng = ChaChaRng::from_seed([50u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+
+        // Corrupt an entry in the middle of the log. This cannot be the result of an interrupted
+        // write, so the queue should refuse to load rather than silently dropping the entry.
+        let (log, entries) = write_queue_log(dir.path(), &keys).await;
+        let (start, len) = entries[1];
+        let mut bytes = fs::read(&log).unwrap();
+        for byte in &mut bytes[start..start + len] {
+            *byte = 0xff;
+        }
+        fs::write(&log, bytes).unwrap();
+        assert!(matches!(
+            FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default()).await,
+            Err(FaucetError::Persistence { .. })
+        ));
+    }
+
+    /// Push requests from `keys` to a new queue in `dir`.
+    ///
+    /// Returns the file of the persistent queue log which holds the new entries, and the offset and
+    /// length of the entry for each key in that file.
+    async fn write_queue_log(dir: &Path, keys: &[UserPubKey]) -> (PathBuf, Vec<(usize, usize)>) {
+        {
+            let queue = FaucetQueue::load(dir, None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap();
+            for key in keys {
+                queue
+                    .push(key.clone(), RequestInfo::default())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let mut store = QueueLoadStore::<(UserPubKey, Option<usize>)>::new(Default::default());
+        let serialized = keys
+            .iter()
+            .map(|key| store.store(&(key.clone(), Some(0))).unwrap())
+            .collect::<Vec<_>>();
+        let find = |bytes: &[u8], entry: &[u8]| {
+            bytes
+                .windows(entry.len())
+                .position(|window| window == entry)
+        };
+        let log = queue_log_files(dir)
+            .into_iter()
+            .find(|path| find(&fs::read(path).unwrap(), &serialized[0]).is_some())
+            .expect("no queue log file holds the new entries");
+        let bytes = fs::read(&log).unwrap();
+        let entries = serialized
+            .iter()
+            .map(|entry| (find(&bytes, entry).unwrap(), entry.len()))
+            .collect();
+        (log, entries)
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_read_only() {
+        let mut rng = ChaChaRng::from_seed([51u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..20)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let dir_contents = || {
+            let mut contents = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let metadata = entry.metadata().unwrap();
+                    (
+                        entry.file_name(),
+                        metadata.len(),
+                        metadata.modified().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            contents.sort();
+            contents
+        };
+        // The keys in a snapshot, in queue order.
+        let snapshot_keys = |snapshot: FaucetQueue| {
+            let mut keys = Vec::new();
+            while let Ok((key, _)) = snapshot.receiver.try_recv() {
+                keys.push(key);
+            }
+            keys
+        };
+
+        // A snapshot of a queue which does not exist yet is empty, and does not create it.
+        let snapshot = FaucetQueue::load_read_only(dir.path(), None, QueueLogFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.index.lock().await.len(), 0);
+        assert_eq!(dir_contents(), vec![]);
+
+        // Leave stale entries in the log, which loading the queue for writing would compact.
+        let primary = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+            .await
+            .unwrap();
+        for key in &keys[..5] {
+            primary
+                .push(key.clone(), RequestInfo::default())
+                .await
+                .unwrap();
+        }
+        for key in &keys[2..5] {
+            primary.index.lock().await.remove(key).unwrap();
+        }
+
+        // The snapshot sees the live requests, but does not compact or otherwise write the queue,
+        // and cannot be written itself.
+        let contents = dir_contents();
+        let snapshot = FaucetQueue::load_read_only(dir.path(), None, QueueLogFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(dir_contents(), contents);
+        assert!(matches!(
+            snapshot.push(keys[5].clone(), RequestInfo::default()).await,
+            Err(FaucetError::Persistence { .. })
+        ));
+        assert_eq!(dir_contents(), contents);
+        assert_eq!(snapshot_keys(snapshot), keys[..2]);
+
+        // Take snapshots while the primary is adding requests. Each one sees the requests which
+        // were added before it, in order.
+        let adding = spawn({
+            let primary = primary.clone();
+            let keys = keys.clone();
+            async move {
+                for key in &keys[5..] {
+                    primary
+                        .push(key.clone(), RequestInfo::default())
+                        .await
+                        .unwrap();
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }
+        });
+        let mut live = keys[..2].to_vec();
+        live.extend_from_slice(&keys[5..]);
+        let mut seen = 0;
+        while seen < live.len() {
+            let snapshot = FaucetQueue::load_read_only(dir.path(), None, QueueLogFormat::default())
+                .await
+                .unwrap();
+            let snapshot = snapshot_keys(snapshot);
+            assert_eq!(snapshot, live[..snapshot.len()]);
+            assert!(snapshot.len() >= seen);
+            seen = snapshot.len();
+        }
+        adding.await;
+    }
+
+    async fn parallel_request(num_requests: usize, restart: bool) {
+        let mut rng = ChaChaRng::from_seed([1u8; 32]);
+
+        // Create test network with a faucet key pair.
+        let (key_stream, mnemonic) = KeyTree::random(&mut rng);
+        let faucet_key_pair = key_stream
+            .derive_sub_tree("keystore".as_bytes())
+            .derive_sub_tree("user".as_bytes())
+            .derive_user_key_pair(&0u64.to_le_bytes());
+        let network = minimal_test_network(&mut rng, faucet_key_pair.pub_key(), None).await;
+
+        // Initiate a faucet server with the mnemonic associated with the faucet key pair.
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let faucet_port = pick_unused_port().unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 5;
+        let mut faucet = Faucet {
+            port: faucet_port,
+            grant_size,
+            num_grants,
+            num_requests,
+            num_workers: num_requests,
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        println!("Faucet server initiated.");
+        let client = surf_disco::Client::<FaucetError>::new(
+            format!("http://localhost:{}/api/", faucet_port)
+                .parse()
+                .unwrap(),
+        );
+
+        // Check the status is "available".
+        assert_eq!(
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .unwrap(),
+            HealthCheck {
+                status: FaucetStatus::Available
+            }
+        );
+
+        // Create receiver keystores.
+        let mut keystores = Vec::new();
+        let mut keys = Vec::new();
+        let mut temp_dirs = Vec::new();
+        for i in 0..num_requests {
+            let (receiver, receiver_key, receiver_dir) = create_receiver(&mut rng, &network).await;
+            println!("Receiver keystore {} created.", i);
+
+            temp_dirs.push(receiver_dir);
+            keystores.push(receiver);
+            keys.push(receiver_key);
+        }
+
+        join_all(keys.iter().map(|key| {
+            let client = &client;
+            async move {
+                // Request native asset for the receiver.
+                client
+                    .post::<GrantInfo>("request_fee_assets")
+                    .body_binary(&key)
+                    .unwrap()
+                    .send()
+                    .await
+                    .unwrap();
+                println!("Asset transferred.");
+            }
+        }))
+        .await;
+
+        if restart {
+            // After submitting all of the requests, wait a random amount of time, and then kill and
+            // restart the faucet, so that it has to reload from storage.
+            let delay = ChaChaRng::from_entropy().gen_range(0..30);
+            tracing::info!("Waiting {} seconds, then killing faucet", delay);
+            sleep(Duration::from_secs(delay)).await;
+            faucet.restart().await;
+        }
+
+        // Check the balances for each keystore.
+        join_all(
+            keystores
+                .into_iter()
+                .zip(keys)
+                .enumerate()
+                .map(|(i, (keystore, key))| async move {
+                    retry(|| async {
+                        let balance = keystore.balance(&AssetCode::native()).await;
+                        let desired = U256::from(grant_size) * num_grants;
+                        println!("Keystore {}: balance is {}/{}", i, balance, desired);
+                        if restart {
+                            // It is possible to get an extra record, if we shut down the faucet at
+                            // just the right time.
+                            balance >= desired
+                        } else {
+                            balance == desired
+                        }
+                    })
+                    .await;
+
+                    // We should have received at least `num_grants` records of `grant_size` each.
+                    let records = keystore.records().await;
+                    if restart {
+                        assert!(
+                            records.len() >= num_grants,
+                            "received {}/{}",
+                            records.len(),
+                            num_grants
+                        );
+                    } else {
+                        assert_eq!(records.len(), num_grants);
+                    }
+                    for record in records {
+                        assert_eq!(*record.asset_definition(), AssetDefinition::native());
+                        assert_eq!(*record.pub_key(), key);
+                        assert_eq!(record.amount(), grant_size);
+                    }
+                })
+                .collect::<Vec<_>>(),
+        )
+        .await;
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_transfer() {
+        parallel_request(1, false).await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_transfer_restart() {
+        parallel_request(1, true).await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_simultaneous_transfer_restart() {
+        parallel_request(5, true).await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_upstream_timeout() {
+        // Start an upstream service which accepts connections but never responds.
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        spawn(async move {
+            let mut connections = vec![];
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                connections.push(stream);
+            }
+        });
+
+        let mnemonic = KeyTree::random(&mut ChaChaRng::from_seed([19u8; 32])).1;
+        let opt = FaucetOptions::try_parse_from([
+            "faucet",
+            "--mnemonic",
+            &mnemonic.to_string(),
+            "--esqs-url",
+            &url,
+            "--address-book-url",
+            &url,
+            "--submit-url",
+            &url,
+            "--upstream-timeout",
+            "1s",
+        ])
+        .unwrap();
+        assert_eq!(opt.upstream_timeout, Duration::from_secs(1));
+
+        // Connecting should fail once the timeout expires, rather than hanging.
+        let start = Instant::now();
+        network_backend(&opt).await.unwrap_err();
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "timed out after {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_in_process() {
+        let mut rng = ChaChaRng::from_seed([4u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+
+        // Build the faucet and serve its API in this process, without initializing it yet.
+        let mut faucet = InProcessFaucet::new(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+            ],
+        )
+        .await;
+        let client = faucet.client();
+
+        // The healthcheck should be available while the faucet is initializing, but requests
+        // should be rejected.
+        retry(|| async {
+            matches!(
+                client.get::<HealthCheck>("healthcheck").send().await,
+                Ok(HealthCheck {
+                    status: FaucetStatus::Initializing
+                })
+            )
+        })
+        .await;
+        // Load balancers can probe the healthcheck with `HEAD`, which gets the same status without a
+        // body.
+        assert_eq!(
+            http_head(faucet.opt.faucet_port, "/api/healthcheck").await,
+            (200, vec![])
+        );
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        let err = client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            tide_disco::Error::status(&err),
+            StatusCode::ServiceUnavailable
+        );
+
+        // Once initialized, the faucet should report that it is available and serve requests.
+        faucet.start().await;
+        assert_eq!(
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .unwrap()
+                .status,
+            FaucetStatus::Available
+        );
+        // Labels longer than the maximum are rejected.
+        let err = client
+            .post::<GrantInfo>("request_fee_assets")
+            .header(LABEL_HEADER, "x".repeat(MAX_LABEL_LEN + 1))
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::BadRequest);
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .header(LABEL_HEADER, "ci-run-1234")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(grant_size) })
+            .await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_workers() {
+        let mut rng = ChaChaRng::from_seed([23u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+                "--admin-token",
+                "secret",
+            ],
+        )
+        .await;
+
+        // The endpoint requires the admin token.
+        let err = client
+            .get::<Vec<WorkerReport>>("workers")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+        let get_workers = || async {
+            client
+                .get::<Vec<WorkerReport>>("workers")
+                .header("Authorization", "Bearer secret")
+                .send()
+                .await
+                .unwrap()
+        };
+        retry(|| async {
+            let workers = get_workers().await;
+            workers.len() == 1 && workers[0].task == WorkerTask::Idle
+        })
+        .await;
+
+        // Pause the faucet and add a request directly to the queue. The worker takes the request,
+        // but holds on to it until the faucet resumes, so it stays busy with this key.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        *state.status.write().await = FaucetStatus::Paused;
+        state
+            .queue
+            .push(key.clone(), RequestInfo::default())
+            .await
+            .unwrap();
+        let busy = WorkerTask::Processing {
+            pub_key: key.clone(),
+        };
+        retry(|| async { get_workers().await[0].task == busy }).await;
+        assert_eq!(get_workers().await[0].id, 0);
+
+        // Once the faucet resumes, the worker finishes the grant and goes back to idle.
+        *state.status.write().await = FaucetStatus::Available;
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+        retry(|| async { get_workers().await[0].task == WorkerTask::Idle }).await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_upgrade_during_grant() {
+        let mut rng = ChaChaRng::from_seed([53u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, _) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "4",
+                "--num-workers",
+                "1",
+                "--lock-timeout",
+                "100ms",
+            ],
+        )
+        .await;
+
+        // Hold the keystore lock, so that the worker gets stuck in the middle of granting to the
+        // request, after it has worked out how many grants are owed.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        let keystore = state.keystore.lock().await;
+        state
+            .queue
+            .push(key.clone(), RequestInfo::default())
+            .await
+            .unwrap();
+        retry(|| async { state.stats.lock_timeouts.load(Ordering::SeqCst) > 0 }).await;
+
+        // Raise the request to 3 grants while the worker is granting the first one. The worker
+        // picks up the new count and finishes the request, instead of leaving it in the queue.
+        assert!(state
+            .queue
+            .upgrade(&key, 3, state.num_grants)
+            .await
+            .unwrap());
+        drop(keystore);
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(3000u64) })
+            .await;
+        retry(|| async { state.queue.index.lock().await.get(&key).is_none() }).await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_daily_cap() {
+        let mut rng = ChaChaRng::from_seed([54u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "2",
+                "--num-workers",
+                "1",
+                "--daily-cap",
+                "1500",
+            ],
+        )
+        .await;
+        let request = |key: UserPubKey| {
+            let client = &client;
+            async move {
+                client
+                    .post::<GrantInfo>("request_fee_assets")
+                    .body_binary(&key)
+                    .unwrap()
+                    .send()
+                    .await
+            }
+        };
+
+        // The first grant fits under the cap.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        request(key).await.unwrap();
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+
+        // The cap has not been reached, so the next request is accepted, but granting it would
+        // exceed the cap, so the worker holds it instead of granting.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        request(key.clone()).await.unwrap();
+        retry(|| async { logs_contain("to stay within the daily cap") }).await;
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!(receiver.balance(&AssetCode::native()).await, U256::zero());
+        assert!(state.queue.index.lock().await.get(&key).is_some());
+        assert_eq!(state.stats.grants.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn test_faucet_failures() {
+        let mut rng = ChaChaRng::from_seed([36u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (_, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-workers",
+                "1",
+                // The faucet's balance covers a grant, but not a grant plus this fee, so every
+                // transfer fails.
+                "--fee-size",
+                &(1u64 << 32).to_string(),
+                "--no-breaker",
+                "--admin-token",
+                "secret",
+            ],
+        )
+        .await;
+
+        // The endpoint requires the admin token.
+        let err = client
+            .get::<Vec<FailureReport>>("failures")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+        let get_failures = |route: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .get::<Vec<FailureReport>>(route)
+                    .header("Authorization", "Bearer secret")
+                    .send()
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(get_failures("failures").await, vec![]);
+
+        let (_receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        // The failed transfers show up, newest first, and the limit is respected.
+        retry(|| async { get_failures("failures").await.len() >= 2 }).await;
+        let failures = get_failures("failures/1").await;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].pub_key, key);
+        assert!(!failures[0].error.is_empty());
+        assert!(failures[0].time > 0);
+        let all = get_failures("failures").await;
+        assert!(all.windows(2).all(|pair| pair[0].time >= pair[1].time));
+        assert!(all.len() <= MAX_RECENT_FAILURES);
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_confirm_grants() {
+        let mut rng = ChaChaRng::from_seed([24u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--fee-size",
+                "100",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+                "--confirm-grants",
+            ],
+        )
+        .await;
+        let balance = state
+            .keystore
+            .lock()
+            .await
+            .balance(&AssetCode::native())
+            .await;
+
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        // Wait for the request to leave the queue. While the grant transaction is pending, the
+        // faucet's change is on hold, so if the grant were counted before the transaction retired,
+        // the faucet's balance would still be missing the change at this point.
+        while state.queue.index.lock().await.len() > 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            state
+                .keystore
+                .lock()
+                .await
+                .balance(&AssetCode::native())
+                .await,
+            balance - U256::from(1100u64)
+        );
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_client() {
+        let mut rng = ChaChaRng::from_seed([31u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let mut faucet = InProcessFaucet::new(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+                "--status-page",
+            ],
+        )
+        .await;
+        let client = FaucetClient::new(
+            format!("http://localhost:{}/api/", faucet.opt.faucet_port)
+                .parse()
+                .unwrap(),
+        );
+        retry(|| async { client.healthcheck().await.is_ok() }).await;
+        faucet.start().await;
+        assert_eq!(
+            client.healthcheck().await.unwrap().status,
+            FaucetStatus::Available
+        );
+
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        assert_eq!(
+            client.request_fee_assets(&key).await.unwrap(),
+            GrantInfo {
+                asset: AssetCode::native(),
+                num_grants: 1,
+                granted: 0,
+                grant_size: RecordAmount::from(1000u64),
+            }
+        );
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+        retry(|| async { client.request_status().await.unwrap().queue_len == 0 }).await;
+        assert_eq!(
+            client.request_status().await.unwrap().status,
+            FaucetStatus::Available
+        );
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_report() {
+        let mut rng = ChaChaRng::from_seed([30u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let faucet = Faucet {
+            num_requests: 2,
+            num_workers: 2,
+            extra_args: vec!["--status-page".to_string()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+
+        // Run the faucet with its log on a pipe, so we can check what it reports when it shuts
+        // down.
+        let mut process = faucet
+            .command()
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = process.stdout.take().unwrap();
+        let log = spawn_blocking(move || {
+            let mut log = String::new();
+            std::io::Read::read_to_string(&mut stdout, &mut log).unwrap();
+            log
+        });
+        faucet.wait_until_available().await;
+
+        let client = FaucetClient::new(
+            format!("http://localhost:{}/api/", faucet.port)
+                .parse()
+                .unwrap(),
+        );
+        let mut receivers = vec![];
+        for _ in 0..2 {
+            let (receiver, key, receiver_dir) = create_receiver(&mut rng, &network).await;
+            client.request_fee_assets(&key).await.unwrap();
+            receivers.push((receiver, receiver_dir));
+        }
+        for (receiver, _) in &receivers {
+            retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+                .await;
+        }
+        retry(|| async { client.request_status().await.unwrap().queue_len == 0 }).await;
+
+        // On SIGTERM, the faucet stops, logs its report and exits cleanly.
+        let pid = process.id().to_string();
+        let status = spawn_blocking(move || {
+            assert!(Command::new("kill")
+                .args(["-TERM", &pid])
+                .status()
+                .unwrap()
+                .success());
+            process.wait().unwrap()
+        })
+        .await;
+        assert!(status.success(), "faucet exited with {}", status);
+        let log = log.await;
+        assert!(log.contains("received signal"), "{}", log);
+        assert!(log.contains("faucet shutting down"), "{}", log);
+        assert!(log.contains("grants=2"), "{}", log);
+        assert!(log.contains("failures=0"), "{}", log);
+        assert!(log.contains("queue_len=0"), "{}", log);
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_graceful_shutdown() {
+        let mut rng = ChaChaRng::from_seed([55u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-workers",
+                "1",
+            ],
+        )
+        .await;
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+
+        // Hold the keystore lock, so that the worker is in the middle of the request when the faucet
+        // starts shutting down.
+        let keystore = state.keystore.lock().await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            state
+                .workers
+                .read()
+                .await
+                .values()
+                .any(|(task, _)| *task != WorkerTask::Idle)
+        })
+        .await;
+        let shutdown = spawn({
+            let state = state.clone();
+            async move { shut_down(&state, vec![], Duration::from_secs(60)).await }
+        });
+        drop(keystore);
+        shutdown.await;
+
+        // The worker finished its request before the faucet stopped, and the background tasks were
+        // stopped.
+        assert_eq!(state.stats.grants.load(Ordering::SeqCst), 1);
+        assert_eq!(state.queue.index.lock().await.len(), 0);
+        assert!(state.background_tasks.lock().await.is_empty());
+        assert!(logs_contain("faucet shutting down"));
+        assert!(!logs_contain("stopping them anyway"));
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_lock_timeout() {
+        let stats = FaucetStats::new();
+        let mutex = Arc::new(Mutex::new(()));
+
+        // Hold the lock for a while, as a stuck transfer would.
+        let (locked, wait_locked) = futures::channel::oneshot::channel();
+        spawn({
+            let mutex = mutex.clone();
+            async move {
+                let _guard = mutex.lock().await;
+                locked.send(()).unwrap();
+                sleep(Duration::from_millis(500)).await;
+            }
+        });
+        wait_locked.await.unwrap();
+
+        let _guard = lock_with_timeout(0, &mutex, Duration::from_millis(100), &stats).await;
+        assert!(stats.lock_timeouts.load(Ordering::SeqCst) > 0);
+        assert!(logs_contain("possible deadlock"));
+        assert!(logs_contain("acquired the keystore lock after"));
+    }
+
+    #[async_std::test]
+    async fn test_estimate_grant() {
+        let mut rng = ChaChaRng::from_seed([39u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-workers",
+                "1",
+                "--no-breaker",
+                "--admin-token",
+                "secret",
+            ],
+        )
+        .await;
+
+        // The endpoint requires the admin token.
+        let err = client
+            .get::<GrantEstimate>("estimate_grant")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+        let get_estimate = || async {
+            client
+                .get::<GrantEstimate>("estimate_grant")
+                .header("Authorization", "Bearer secret")
+                .send()
+                .await
+                .unwrap()
+        };
+        retry(|| async { get_estimate().await.feasible }).await;
+        let estimate = get_estimate().await;
+        assert_eq!(estimate.fee, state.fee_size(&AssetCode::native()));
+
+        // Make a real grant and check that it consumes what the estimate predicted.
+        async fn native_records(keystore: &FaucetKeystore) -> usize {
+            keystore
+                .records()
+                .await
+                .into_iter()
+                .filter(|record| record.asset_code() == AssetCode::native())
+                .count()
+        }
+        let (_receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        let mut keystore = state.keystore.lock().await;
+        let records_before = native_records(&keystore).await;
+        let balance_before = keystore.balance(&AssetCode::native()).await;
+        let receipt = transfer_grants(
+            0,
+            &mut keystore,
+            &AssetCode::native(),
+            &[key],
+            state.grant_size,
+            estimate.fee,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            keystore.await_transaction(&receipt).await.unwrap(),
+            TransactionStatus::Retired
+        );
+        let records_after = native_records(&keystore).await;
+        // Each input is spent, and each output other than the grant is change back to the faucet.
+        assert_eq!(
+            records_after + estimate.inputs,
+            records_before + estimate.outputs - 1
+        );
+        assert_eq!(
+            keystore.balance(&AssetCode::native()).await,
+            balance_before - U256::from(state.grant_size) - U256::from(estimate.fee)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_faucet_grant_history() {
+        let mut rng = ChaChaRng::from_seed([40u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (_, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-workers",
+                "1",
+                "--grant-history",
+                "--admin-token",
+                "secret",
+            ],
+        )
+        .await;
+
+        let (receiver1, key1, _receiver_dir1) = create_receiver(&mut rng, &network).await;
+        let (receiver2, key2, _receiver_dir2) = create_receiver(&mut rng, &network).await;
+        let other = UserKeyPair::generate(&mut rng).pub_key();
+        let history = |key: &UserPubKey| {
+            let client = &client;
+            let route = format!("history/{}", key);
+            async move {
+                client
+                    .get::<Vec<GrantEvent>>(&route)
+                    .header("Authorization", "Bearer secret")
+                    .send()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // The endpoint requires the admin token.
+        let err = client
+            .get::<Vec<GrantEvent>>(&format!("history/{}", key1))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+
+        // Grant once to the first key and twice to the second.
+        for key in [&key1, &key2] {
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(key)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+        }
+        retry(|| async { receiver1.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+        retry(|| async { receiver2.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key2)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async { receiver2.balance(&AssetCode::native()).await == U256::from(2000u64) })
+            .await;
+
+        // Each key's history contains only its own grants.
+        let history1 = history(&key1).await;
+        assert_eq!(history1.len(), 1);
+        assert_eq!(history1[0].pub_key, key1);
+        assert_eq!(history1[0].grant_size, RecordAmount::from(1000u64));
+        let history2 = history(&key2).await;
+        assert_eq!(history2.len(), 2);
+        assert!(history2.iter().all(|event| event.pub_key == key2));
+        assert!(history2[0].time <= history2[1].time);
+        assert_eq!(history(&other).await, vec![]);
+    }
+
+    #[async_std::test]
+    async fn test_faucet_standby() {
+        let mut rng = ChaChaRng::from_seed([42u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let mut faucet = InProcessFaucet::new(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--num-grants",
+                "1",
+                "--num-workers",
+                "1",
+                "--standby",
+                "--admin-token",
+                "secret",
+            ],
+        )
+        .await;
+        let state = faucet.state.clone();
+        let client = faucet.client();
+        // The queue of the faucet being stood in for, which it keeps writing while the standby
+        // runs.
+        let primary = FaucetQueue::load(
+            &faucet.opt.queue_path(),
+            None,
+            usize::MAX,
+            faucet.opt.queue_log_format,
+        )
+        .await
+        .unwrap();
+        // A standby does not finish starting until it is promoted, so start it in the background.
+        spawn(async move { faucet.start().await });
+        let health = || async {
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .unwrap()
+                .status
+        };
+        retry(|| async { health().await == FaucetStatus::Standby }).await;
+
+        // The standby rejects requests.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        let err = client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            tide_disco::Error::status(&err),
+            StatusCode::ServiceUnavailable
+        );
+
+        // The standby only has a read-only snapshot of the queue.
+        assert!(matches!(
+            state.queue.push(key.clone(), RequestInfo::default()).await,
+            Err(FaucetError::Persistence { .. })
+        ));
+
+        // A request queued by the faucet it is standing in for is not processed.
+        primary
+            .push(key.clone(), RequestInfo::default())
+            .await
+            .unwrap();
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(receiver.balance(&AssetCode::native()).await, U256::zero());
+        assert_eq!(health().await, FaucetStatus::Standby);
+
+        // The other faucet is stopped before the standby is promoted.
+        drop(primary);
+
+        // Promotion requires the admin token.
+        let err = client.post::<()>("promote").send().await.unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+
+        // Once promoted, the faucet takes over the queue and grants the pending request.
+        client
+            .post::<()>("promote")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(1000u64) })
+            .await;
+        retry(|| async { health().await == FaucetStatus::Available }).await;
+
+        // Now that it is not a standby, it cannot be promoted again.
+        let err = client
+            .post::<()>("promote")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn test_faucet_batch_window() {
+        let mut rng = ChaChaRng::from_seed([32u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--num-grants",
+                "1",
+                "--num-records",
+                "3",
+                "--num-workers",
+                "1",
+                "--batch-window",
+                "5s",
+            ],
+        )
+        .await;
+
+        // Create all the receivers first, so that the requests arrive together.
+        let mut receivers = vec![];
+        for _ in 0..3 {
+            receivers.push(create_receiver(&mut rng, &network).await);
+        }
+        join_all(receivers.iter().map(|(_, key, _)| {
+            let client = &client;
+            async move {
+                client
+                    .post::<GrantInfo>("request_fee_assets")
+                    .body_binary(key)
+                    .unwrap()
+                    .send()
+                    .await
+                    .unwrap();
+            }
+        }))
+        .await;
+
+        // Every receiver gets its grant.
+        for (receiver, _, _) in &receivers {
+            retry(|| async {
+                receiver.balance(&AssetCode::native()).await == U256::from(state.grant_size)
+            })
+            .await;
+        }
+        assert_eq!(state.stats.grants.load(Ordering::SeqCst), 3);
+        // With a single worker, the only way to grant to three receivers in fewer than three
+        // transactions is to share a transaction between two of them.
+        assert!(state.stats.transactions.load(Ordering::SeqCst) < 3);
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_unix_socket() {
+        let mut rng = ChaChaRng::from_seed([29u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let socket = faucet_dir.path().join("faucet.sock");
+        let mut faucet = InProcessFaucet::new(
+            &mut rng,
+            &network,
+            &mnemonic,
+            &faucet_dir.path().join("keystore"),
+            &[
+                "--unix-socket",
+                &socket.display().to_string(),
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+            ],
+        )
+        .await;
+        faucet.start().await;
+        let opt = faucet.opt;
+
+        // The faucet should serve the healthcheck over the socket.
+        retry(|| async { socket.exists() }).await;
+        let (status, body) = http_request(
+            UnixStream::connect(&socket).await.unwrap(),
+            "GET",
+            "/api/healthcheck",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_slice::<HealthCheck>(&body).unwrap().status,
+            FaucetStatus::Available
+        );
+
+        // A socket left behind by a previous run is replaced, but other files are not.
+        assert!(opt.listen_address().is_ok());
+        assert!(!socket.exists());
+        fs::write(&socket, "not a socket").unwrap();
+        opt.listen_address().unwrap_err();
+        assert!(socket.exists());
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_observer() {
+        let mut rng = ChaChaRng::from_seed([28u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let observer_port = pick_unused_port().unwrap();
+        let observer = mock_observer::<Vec<GrantEvent>>(observer_port).await;
+        let (_, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                "1000",
+                "--fee-size",
+                "100",
+                "--num-grants",
+                "1",
+                "--num-records",
+                "2",
+                "--num-workers",
+                "2",
+                "--observer-url",
+                &format!("http://localhost:{}/grants", observer_port),
+                "--observer-batch-size",
+                "2",
+                // Flush partial batches so rarely that the only way to receive the grants during
+                // the test is as a full batch.
+                "--observer-flush-interval",
+                "1h",
+            ],
+        )
+        .await;
+
+        let mut keys = vec![];
+        let mut receivers = vec![];
+        for _ in 0..2 {
+            let (receiver, key, receiver_dir) = create_receiver(&mut rng, &network).await;
+            receivers.push((receiver, receiver_dir));
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(&key)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+            keys.push(key);
+        }
+
+        // Both grants should arrive together, in a single batch.
+        let batch = async_std::future::timeout(Duration::from_secs(300), observer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.len(), 2);
+        for key in &keys {
+            let event = batch.iter().find(|event| event.pub_key == *key).unwrap();
+            assert_eq!(event.asset, AssetCode::native());
+            assert_eq!(event.grants, 1);
+            assert_eq!(event.grant_size, RecordAmount::from(1000u64));
+            assert_eq!(event.label, None);
+        }
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_worker_restart() {
+        let mut rng = ChaChaRng::from_seed([27u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                // Don't start any workers of our own; we will supervise one manually.
+                "--num-workers",
+                "0",
+            ],
+        )
+        .await;
+
+        // Run a worker which panics the first time it takes a request off the queue.
+        let panicked = Arc::new(AtomicBool::new(false));
+        spawn(supervise_worker(
+            0,
+            state.clone(),
+            Duration::ZERO,
+            move |id, mut state: FaucetState, start_delay| {
+                let panicked = panicked.clone();
+                async move {
+                    if !panicked.swap(true, Ordering::SeqCst) {
+                        let (pub_key, _) = state.queue.pop().await.unwrap();
+                        state
+                            .set_worker_task(id, WorkerTask::Processing { pub_key })
+                            .await;
+                        panic!("worker crashed while processing a request");
+                    }
+                    worker(id, state, start_delay).await
+                }
+            },
+        ));
+
+        // The request the worker crashed on is retried by its replacement.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(grant_size) })
+            .await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_no_breaker() {
+        let mut rng = ChaChaRng::from_seed([13u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--num-grants",
+                "2",
+                "--num-records",
+                "5",
+                "--num-workers",
+                "1",
+                "--no-breaker",
+            ],
+        )
+        .await;
+        retry(|| async {
+            matches!(
+                client.get::<HealthCheck>("healthcheck").send().await,
+                Ok(HealthCheck {
+                    status: FaucetStatus::Available
+                })
+            )
+        })
+        .await;
+
+        // The faucet should still own just the single record it was given in the genesis block,
+        // since it did not break it up before becoming available.
+        let num_records = || async {
+            let keystore = state.keystore.lock().await;
+            spendable_records(&keystore, &AssetCode::native(), grant_size)
+                .await
+                .count()
+        };
+        assert_eq!(num_records().await, 1);
+
+        // Grants are made from the existing record.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * 2u64
+        })
+        .await;
+
+        // Once the change from the grant comes back, the faucet should again have a single record,
+        // rather than the `num_records` the breaker would maintain.
+        retry(|| async { num_records().await == 1 }).await;
+    }
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_status_page() {
+        let mut rng = ChaChaRng::from_seed([11u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let mut faucet = Faucet {
+            extra_args: vec!["--status-page".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+
+        let report: StatusReport = faucet.client().get("status").send().await.unwrap();
+        assert_eq!(report.status, FaucetStatus::Available);
+        assert_eq!(report.queue_len, 0);
+        // The faucet pays no fees to break up its records, so it should still have the entire
+        // genesis balance.
+        assert_eq!(report.balance, (1u64 << 32).to_string());
+
+        // Operators read the status as a string, the same one the healthcheck reports.
+        let page: serde_json::Value = faucet.client().get("status").send().await.unwrap();
+        assert_eq!(page["status"], "available");
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_stats() {
+        let mut rng = ChaChaRng::from_seed([45u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 2;
+        let mut faucet = Faucet {
+            grant_size,
+            num_grants,
+            extra_args: vec!["--status-page".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+
+        let stats: StatsReport = client.get("stats").send().await.unwrap();
+        assert_eq!(stats.grants, 0);
+        assert_eq!(stats.tokens_dispensed, "0");
+        assert_eq!(stats.balance, (1u64 << 32).to_string());
+
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * num_grants
+        })
+        .await;
+
+        // The stats reflect both grants once the request has left the queue and the faucet has
+        // received its change. Both grants share a single transfer, which pays the default fee of
+        // 100.
+        let expected_balance = ((1u64 << 32) - 2100).to_string();
+        retry(|| async {
+            let stats: StatsReport = client.get("stats").send().await.unwrap();
+            stats.queue_len == 0 && stats.balance == expected_balance
+        })
+        .await;
+        let stats: StatsReport = client.get("stats").send().await.unwrap();
+        assert_eq!(stats.grants, 2);
+        assert_eq!(stats.tokens_dispensed, "2000");
+        assert_eq!(stats.queue_len, 0);
+        assert_eq!(stats.balance, expected_balance);
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_rescan() {
+        let mut rng = ChaChaRng::from_seed([47u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let port = pick_unused_port().unwrap();
+        let opt = FaucetOptions::try_parse_from([
+            "faucet".to_string(),
+            "--mnemonic".to_string(),
+            mnemonic.to_string(),
+            "--keystore-path".to_string(),
+            faucet_dir.path().display().to_string(),
+            "--faucet-port".to_string(),
+            port.to_string(),
+            "--esqs-url".to_string(),
+            network.query_api.to_string(),
+            "--submit-url".to_string(),
+            network.submit_api.to_string(),
+            "--address-book-url".to_string(),
+            network.address_book_api.to_string(),
+            "--admin-token".to_string(),
+            "secret".to_string(),
+        ])
+        .unwrap();
+
+        // Open the faucet keystore by hand, scanning for the faucet key from the current end of the
+        // ledger instead of the beginning, so that it misses the faucet record created at genesis.
+        let mut loader = RecoveryLoader::new(
+            &mut rng,
+            opt.keystore_path(),
+            mnemonic.clone(),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        );
+        let backend = network_backend(&opt).await.unwrap();
+        let mut keystore = EspressoKeystore::new(backend, &mut loader).await.unwrap();
+        let now = keystore.now().await;
+        let key = keystore
+            .generate_sending_account("faucet".into(), Some(now))
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&key.address())
+            .await
+            .unwrap();
+        assert_eq!(keystore.balance(&AssetCode::native()).await, U256::zero());
+
+        let (signal_breaker_thread, _breaker_signals) = mpsc::channel(opt.num_records);
+        let state = FaucetState::new(keystore, signal_breaker_thread, &opt)
+            .await
+            .unwrap();
+        spawn(
+            faucet_app(state.clone(), &opt)
+                .unwrap()
+                .serve(format!("0.0.0.0:{}", port)),
+        );
+        let client = surf_disco::Client::<FaucetError>::new(
+            format!("http://localhost:{}/api/", port).parse().unwrap(),
+        );
+        retry(|| async {
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .is_ok()
+        })
+        .await;
+
+        // The endpoint requires the admin token.
+        let err = client
+            .post::<RescanReport>("rescan")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+
+        // Rescanning from the beginning of the ledger discovers the missed record.
+        let report: RescanReport = client
+            .post("rescan/0")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(report.from, 0);
+        assert_eq!(report.balance_before, "0");
+        assert_eq!(report.balance_after, (1u64 << 32).to_string());
+        assert_eq!(
+            state
+                .keystore
+                .lock()
+                .await
+                .balance(&AssetCode::native())
+                .await,
+            U256::from(1u64 << 32)
+        );
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_backup() {
+        let mut rng = ChaChaRng::from_seed([48u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let queue_dir = TempDir::new("espresso_keystore_faucet_queue").unwrap();
+        let backup_dir = TempDir::new("espresso_keystore_faucet_backup").unwrap();
+        let port = pick_unused_port().unwrap();
+        let args = |keystore_path: &Path, queue_path: &Path, port: u16| {
+            vec![
+                "faucet".to_string(),
+                "--mnemonic".to_string(),
+                mnemonic.to_string(),
+                "--keystore-path".to_string(),
+                keystore_path.display().to_string(),
+                "--queue-path".to_string(),
+                queue_path.display().to_string(),
+                "--faucet-port".to_string(),
+                port.to_string(),
+                "--esqs-url".to_string(),
+                network.query_api.to_string(),
+                "--submit-url".to_string(),
+                network.submit_api.to_string(),
+                "--address-book-url".to_string(),
+                network.address_book_api.to_string(),
+                "--num-records".to_string(),
+                "2".to_string(),
+                "--backup-dir".to_string(),
+                backup_dir.path().display().to_string(),
+                "--backup-interval".to_string(),
+                "1s".to_string(),
+            ]
+        };
+        let opt =
+            FaucetOptions::try_parse_from(args(faucet_dir.path(), queue_dir.path(), port)).unwrap();
+        let (keystore, new_key) = open_keystore(&mut rng, &opt, None).await;
+        let (signal_breaker_thread, breaker_signals) = mpsc::channel(opt.num_records);
+        let state = FaucetState::new(keystore, signal_breaker_thread, &opt)
+            .await
+            .unwrap();
+        start_faucet(state.clone(), new_key, breaker_signals, &opt).await;
+
+        // A backup is produced, including the request queue stored outside the keystore
+        // directory, and old backups are replaced rather than accumulating.
+        let backup = backup_dir.path().join(BACKUP_NAME);
+        let queue_backup = backup_dir.path().join(QUEUE_BACKUP_NAME);
+        retry(|| async { backup.exists() && queue_backup.exists() }).await;
+        sleep(Duration::from_secs(3)).await;
+        let mut entries = fs::read_dir(backup_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, [BACKUP_NAME, QUEUE_BACKUP_NAME]);
+        assert!(!queue_log_files(&queue_backup).is_empty());
+
+        // The backup restores a working keystore, which already knows the faucet key and its
+        // records, without rescanning the ledger.
+        let restored = backup_dir.path().join("restored");
+        let restored_queue = backup_dir.path().join("restored_queue");
+        copy_dir(&backup, &restored).unwrap();
+        copy_dir(&queue_backup, &restored_queue).unwrap();
+        let restored_opt = FaucetOptions::try_parse_from(args(
+            &restored,
+            &restored_queue,
+            pick_unused_port().unwrap(),
+        ))
+        .unwrap();
+        let (keystore, new_key) = open_keystore(&mut rng, &restored_opt, None).await;
+        assert_eq!(new_key, None);
+        assert_eq!(
+            keystore.sending_keys().await[0].pub_key(),
+            state.keystore.lock().await.sending_keys().await[0].pub_key()
+        );
+        retry(|| async { keystore.balance(&AssetCode::native()).await == U256::from(1u64 << 32) })
+            .await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_upstream_refill() {
+        let mut rng = ChaChaRng::from_seed([49u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+
+        // The upstream faucet is a mock which reports each request it receives. The test plays the
+        // part of the upstream faucet's workers, using a keystore which owns the genesis funds.
+        let upstream_port = pick_unused_port().unwrap();
+        let upstream_requests = mock_observer::<UserPubKey>(upstream_port).await;
+        let upstream_dir = TempDir::new("espresso_keystore_upstream").unwrap();
+        let mut loader = RecoveryLoader::new(
+            &mut rng,
+            upstream_dir.path().to_owned(),
+            mnemonic,
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        );
+        let backend = NetworkBackend::new(
+            &UNIVERSAL_PARAM,
+            network.query_api.clone(),
+            network.address_book_api.clone(),
+            network.submit_api.clone(),
+        )
+        .await
+        .unwrap();
+        let mut upstream = EspressoKeystore::new(backend, &mut loader).await.unwrap();
+        let upstream_key = upstream
+            .generate_sending_account("upstream".into(), Some(EventIndex::default()))
+            .await
+            .unwrap();
+        upstream
+            .await_sending_key_scan(&upstream_key.address())
+            .await
+            .unwrap();
+
+        // The downstream faucet starts out with a key that owns nothing.
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let opt = FaucetOptions::try_parse_from(vec![
+            "faucet".to_string(),
+            "--mnemonic".to_string(),
+            KeyTree::random(&mut rng).1.to_string(),
+            "--keystore-path".to_string(),
+            faucet_dir.path().display().to_string(),
+            "--faucet-port".to_string(),
+            pick_unused_port().unwrap().to_string(),
+            "--esqs-url".to_string(),
+            network.query_api.to_string(),
+            "--submit-url".to_string(),
+            network.submit_api.to_string(),
+            "--address-book-url".to_string(),
+            network.address_book_api.to_string(),
+            "--num-records".to_string(),
+            "2".to_string(),
+            "--upstream-faucet-url".to_string(),
+            format!("http://localhost:{}/api/", upstream_port),
+            "--upstream-refill-threshold".to_string(),
+            "1000".to_string(),
+            "--balance-poll-interval".to_string(),
+            "1s".to_string(),
+        ])
+        .unwrap();
+        let (keystore, new_key) = open_keystore(&mut rng, &opt, None).await;
+        let (signal_breaker_thread, breaker_signals) = mpsc::channel(opt.num_records);
+        let state = FaucetState::new(keystore, signal_breaker_thread, &opt)
+            .await
+            .unwrap();
+        let faucet_key = state.keystore.lock().await.sending_keys().await[0]
+            .pub_key()
+            .clone();
+        spawn({
+            let state = state.clone();
+            async move { start_faucet(state, new_key, breaker_signals, &opt).await }
+        });
+
+        // The empty faucet asks the upstream faucet to fund its own key.
+        let requested =
+            async_std::future::timeout(Duration::from_secs(60), upstream_requests.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(requested, faucet_key);
+        let receipt = upstream
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(requested, RecordAmount::from(5000u64))],
+                100u64,
+            )
+            .await
+            .unwrap();
+        assert!(upstream
+            .await_transaction(&receipt)
+            .await
+            .unwrap()
+            .succeeded());
+
+        // The downstream faucet picks up the grant and starts serving requests, without asking for
+        // more while it is above the threshold.
+        retry(|| async { *state.status.read().await == FaucetStatus::Available }).await;
+        assert_eq!(
+            state
+                .keystore
+                .lock()
+                .await
+                .balance(&AssetCode::native())
+                .await,
+            U256::from(5000u64)
+        );
+        sleep(Duration::from_secs(3)).await;
+        assert!(upstream_requests.try_recv().is_err());
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_build_info() {
+        let mut rng = ChaChaRng::from_seed([22u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let mut faucet = Faucet::new(&network, mnemonic, faucet_dir.path());
+        faucet.start().await;
+
+        let info: BuildInfo = faucet.client().get("build").send().await.unwrap();
+        assert_eq!(info, build_info());
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_sha.is_empty());
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_priority() {
+        let mut rng = ChaChaRng::from_seed([9u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 4;
+        let mut faucet = Faucet {
+            grant_size,
+            num_grants,
+            num_requests: 3,
+            // Use a single worker so that requests are serviced one at a time.
+            num_workers: 1,
+            extra_args: vec!["--admin-token".into(), "secret".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+
+        let mut receivers = vec![];
+        for _ in 0..3 {
+            receivers.push(create_receiver(&mut rng, &network).await);
+        }
+
+        // High priority requests require the admin token.
+        let err = client
+            .post::<GrantInfo>("request_fee_assets/high")
+            .body_binary(&receivers[2].1)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+
+        // Make two normal priority requests. The worker starts on the first one right away, so the
+        // second one waits in the queue, where it should be overtaken by a later high priority
+        // request.
+        for (_, key, _) in &receivers[..2] {
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(key)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+        }
+        client
+            .post::<GrantInfo>("request_fee_assets/high")
+            .body_binary(&receivers[2].1)
+            .unwrap()
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+
+        let full_grant = U256::from(grant_size) * num_grants;
+        retry(|| async { receivers[2].0.balance(&AssetCode::native()).await == full_grant }).await;
+        assert!(receivers[1].0.balance(&AssetCode::native()).await < full_grant);
+        retry(|| async { receivers[1].0.balance(&AssetCode::native()).await == full_grant }).await;
+
+        faucet.stop().await;
     }
 
-    impl Faucet {
-        async fn start(&mut self) {
-            let esqs_url = self.esqs_url.to_string();
-            let submit_url = self.submit_url.to_string();
-            let address_book_url = self.address_book_url.to_string();
-            let mnemonic = self.mnemonic.to_string();
-            let dir = self.dir.display().to_string();
-            let port = self.port.to_string();
-            let grant_size = self.grant_size.to_string();
-            let num_grants = self.num_grants.to_string();
-            let num_requests = self.num_requests.to_string();
-            let num_records = (self.num_grants * self.num_requests).to_string();
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_admin_grant() {
+        let mut rng = ChaChaRng::from_seed([26u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 4;
+        let mut faucet = Faucet {
+            grant_size,
+            num_grants,
+            num_requests: 3,
+            // Use a single worker so that requests are serviced one at a time.
+            num_workers: 1,
+            extra_args: vec!["--admin-token".into(), "secret".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+
+        let mut receivers = vec![];
+        for _ in 0..6 {
+            receivers.push(create_receiver(&mut rng, &network).await);
+        }
+
+        // Manual grants require the admin token, and must grant at least one record.
+        let err = client
+            .post::<Vec<TransactionUID<EspressoLedger>>>("grant/2")
+            .body_binary(&receivers[2].1)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+        let err = client
+            .post::<Vec<TransactionUID<EspressoLedger>>>("grant/0")
+            .body_binary(&receivers[2].1)
+            .unwrap()
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::BadRequest);
 
-            self.process = Some(
-                CargoBuild::new()
-                    .current_release()
-                    .bin("faucet")
-                    .run()
-                    .unwrap()
-                    .command()
-                    .args([
-                        "--esqs-url",
-                        &esqs_url,
-                        "--submit-url",
-                        &submit_url,
-                        "--address-book-url",
-                        &address_book_url,
-                        "--mnemonic",
-                        &mnemonic,
-                        "--keystore-path",
-                        &dir,
-                        "--faucet-port",
-                        &port,
-                        "--grant-size",
-                        &grant_size,
-                        "--num-grants",
-                        &num_grants,
-                        "--num-records",
-                        &num_records,
-                        "--max-queue-len",
-                        &num_requests,
-                        "--num-workers",
-                        &num_requests,
-                    ])
-                    .spawn()
-                    .unwrap(),
-            );
+        // Make two normal priority requests. The worker starts on the first one right away, so the
+        // second one waits in the queue, where it should be overtaken by the manual grant.
+        for (_, key, _) in &receivers[..2] {
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(key)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+        }
+        // The response comes once the grant is made. Both records share a single transfer.
+        let receipts = client
+            .post::<Vec<TransactionUID<EspressoLedger>>>("grant/2")
+            .body_binary(&receivers[2].1)
+            .unwrap()
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 1);
 
-            // Wait for the service to become available.
-            loop {
-                if let Ok(health) = surf_disco::get::<HealthCheck, FaucetError>(
-                    format!("http://localhost:{}/api/healthcheck", port)
-                        .parse()
-                        .unwrap(),
-                )
+        let full_grant = U256::from(grant_size) * num_grants;
+        retry(|| async {
+            receivers[2].0.balance(&AssetCode::native()).await == U256::from(grant_size) * 2u64
+        })
+        .await;
+        assert!(receivers[1].0.balance(&AssetCode::native()).await < full_grant);
+        retry(|| async { receivers[1].0.balance(&AssetCode::native()).await == full_grant }).await;
+
+        // A manual grant to a key which is already waiting in the queue raises its request to high
+        // priority and to the manual count, rather than being merged into the request as it is.
+        for (_, key, _) in &receivers[3..] {
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(key)
+                .unwrap()
                 .send()
                 .await
-                {
-                    if health.status == FaucetStatus::Available {
-                        break;
+                .unwrap();
+        }
+        let receipts = client
+            .post::<Vec<TransactionUID<EspressoLedger>>>("grant/6")
+            .body_binary(&receivers[5].1)
+            .unwrap()
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 3);
+        retry(|| async {
+            receivers[5].0.balance(&AssetCode::native()).await == U256::from(grant_size) * 6u64
+        })
+        .await;
+        assert!(receivers[4].0.balance(&AssetCode::native()).await < full_grant);
+        retry(|| async { receivers[4].0.balance(&AssetCode::native()).await == full_grant }).await;
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_wait_for_balance() {
+        let interval = Duration::from_millis(10);
+
+        // The balance appears after a few polls, and we wait for it.
+        let polls = AtomicUsize::new(0);
+        let balance = wait_for_balance(
+            || {
+                let poll = polls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if poll < 3 {
+                        U256::zero()
+                    } else {
+                        U256::from(1000u64)
                     }
                 }
+            },
+            interval,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_eq!(balance, U256::from(1000u64));
+        assert_eq!(polls.load(Ordering::SeqCst), 4);
+        assert!(logs_contain("waiting for records to arrive"));
 
-                sleep(Duration::from_secs(10)).await;
+        // If the balance never appears, we give up after the timeout.
+        let balance = wait_for_balance(
+            || async { U256::zero() },
+            interval,
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(balance.is_zero());
+        assert!(logs_contain("starting anyway"));
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_reset_scan() {
+        let mut rng = ChaChaRng::from_seed([35u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let opt = |extra_args: &[&str]| {
+            FaucetOptions::try_parse_from(
+                [
+                    "faucet".to_string(),
+                    "--mnemonic".to_string(),
+                    mnemonic.to_string(),
+                    "--keystore-path".to_string(),
+                    faucet_dir.path().display().to_string(),
+                    "--esqs-url".to_string(),
+                    network.query_api.to_string(),
+                    "--submit-url".to_string(),
+                    network.submit_api.to_string(),
+                    "--address-book-url".to_string(),
+                    network.address_book_api.to_string(),
+                ]
+                .into_iter()
+                .chain(extra_args.iter().map(|arg| arg.to_string())),
+            )
+            .unwrap()
+        };
+
+        // The first time the keystore is opened, it scans for the new faucet key.
+        let (keystore, new_key) = open_keystore(&mut rng, &opt(&[]), None).await;
+        let key = new_key.unwrap();
+        keystore
+            .await_sending_key_scan(&key.address())
+            .await
+            .unwrap();
+        let balance = keystore.balance(&AssetCode::native()).await;
+        let checkpoint = save_scan_checkpoint(&keystore, faucet_dir.path())
+            .await
+            .unwrap();
+        assert_eq!(load_scan_checkpoint(faucet_dir.path()), Some(checkpoint));
+        drop(keystore);
+
+        // A normal restart resumes from the checkpoint, without a new scan.
+        let (keystore, new_key) = open_keystore(&mut rng, &opt(&[]), None).await;
+        assert_eq!(new_key, None);
+        assert!(logs_contain("resuming keystore scan from checkpoint"));
+        drop(keystore);
+
+        // With `--reset-scan`, the faucet key is scanned for again from the beginning, and the scan
+        // finds the same records.
+        let (keystore, new_key) = open_keystore(&mut rng, &opt(&["--reset-scan"]), None).await;
+        assert_eq!(new_key, Some(key.clone()));
+        keystore
+            .await_sending_key_scan(&key.address())
+            .await
+            .unwrap();
+        assert_eq!(keystore.balance(&AssetCode::native()).await, balance);
+    }
+
+    #[async_std::test]
+    async fn test_faucet_min_balance() {
+        let mut rng = ChaChaRng::from_seed([34u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let mut faucet = Faucet {
+            // The faucet starts with 2^32 units, so its balance is always below this threshold.
+            extra_args: vec![
+                "--status-page".into(),
+                "--min-balance".into(),
+                (1u64 << 33).to_string(),
+            ],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+        let (_receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+
+        // The request is shed, with a hint for when to retry, rather than queued.
+        let err = client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            tide_disco::Error::status(&err),
+            StatusCode::ServiceUnavailable
+        );
+        match err {
+            FaucetError::TemporarilyUnavailable { retry_after } => {
+                assert_eq!(retry_after, LOW_BALANCE_RETRY_AFTER.as_secs())
             }
+            err => panic!("expected TemporarilyUnavailable, got {:?}", err),
         }
+        let report: StatusReport = client.get("status").send().await.unwrap();
+        assert_eq!(report.queue_len, 0);
 
-        async fn stop(&mut self) {
-            if let Some(mut process) = self.process.take() {
-                spawn_blocking(move || {
-                    process.kill().unwrap();
-                    process.wait().unwrap();
-                })
-                .await;
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_pause() {
+        let mut rng = ChaChaRng::from_seed([15u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let mut faucet = Faucet {
+            grant_size,
+            extra_args: vec!["--admin-token".into(), "secret".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+
+        // Pausing requires the admin token.
+        let err = client.post::<()>("pause").send().await.unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::Unauthorized);
+        client
+            .post::<()>("pause")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .unwrap()
+                .status,
+            FaucetStatus::Paused
+        );
+
+        // Requests are rejected while the faucet is paused.
+        let err = client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            tide_disco::Error::status(&err),
+            StatusCode::ServiceUnavailable
+        );
+
+        // Once resumed, grants flow again.
+        client
+            .post::<()>("resume")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .get::<HealthCheck>("healthcheck")
+                .send()
+                .await
+                .unwrap()
+                .status,
+            FaucetStatus::Available
+        );
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async { receiver.balance(&AssetCode::native()).await == U256::from(grant_size) })
+            .await;
+
+        faucet.stop().await;
+    }
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_priority_persistence() {
+        let mut rng = ChaChaRng::from_seed([10u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..7)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let high_priority = RequestInfo {
+            priority: Priority::High,
+            ..Default::default()
+        };
+        {
+            let queue = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap();
+            queue
+                .push(keys[0].clone(), RequestInfo::default())
+                .await
+                .unwrap();
+            queue
+                .push(keys[1].clone(), RequestInfo::default())
+                .await
+                .unwrap();
+            queue
+                .push(keys[2].clone(), high_priority.clone())
+                .await
+                .unwrap();
+
+            // Add and remove some high priority requests, so that there is something to compact.
+            for key in &keys[3..] {
+                queue
+                    .push(key.clone(), high_priority.clone())
+                    .await
+                    .unwrap();
+                queue.index.lock().await.remove(key).unwrap();
+            }
+        }
+
+        // After a restart, the high priority request should still come first, both in a queue
+        // loaded as is and in one which has been compacted.
+        for compaction_threshold in [usize::MAX, 0] {
+            let mut queue = FaucetQueue::load(
+                dir.path(),
+                None,
+                compaction_threshold,
+                QueueLogFormat::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(queue.pop().await.unwrap(), (keys[2].clone(), 0));
+            assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+            assert_eq!(queue.pop().await.unwrap(), (keys[1].clone(), 0));
+        }
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_group_requeue() {
+        let mut rng = ChaChaRng::from_seed([37u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let group = RequestInfo {
+            group: keys[1..].to_vec(),
+            ..Default::default()
+        };
+        {
+            let mut queue = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::Cbor)
+                .await
+                .unwrap();
+            queue.push(keys[0].clone(), group.clone()).await.unwrap();
+
+            // The group is queued as a single request under its first key.
+            assert_eq!(queue.index.lock().await.len(), 1);
+            assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+            assert_eq!(queue.group(&keys[0]).await, keys[1..]);
+
+            // A transfer to one of the other keys fails. The worker returns the group to the queue
+            // without counting a grant for any of its keys, so the group is retried as a whole.
+            queue.fail(keys[0].clone()).await;
+            assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+            assert_eq!(queue.group(&keys[0]).await, keys[1..]);
+            for key in &keys[1..] {
+                assert_eq!(queue.index.lock().await.get(key), None);
+            }
+        }
+
+        // The group survives a restart, both in a queue loaded as is and in one which has been
+        // compacted.
+        for compaction_threshold in [usize::MAX, 0] {
+            let mut queue =
+                FaucetQueue::load(dir.path(), None, compaction_threshold, QueueLogFormat::Cbor)
+                    .await
+                    .unwrap();
+            assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+            assert_eq!(queue.group(&keys[0]).await, keys[1..]);
+        }
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_log_format() {
+        let mut rng = ChaChaRng::from_seed([21u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let high_priority = RequestInfo {
+            priority: Priority::High,
+            num_grants: Some(2),
+            label: Some("ci-run-1234".into()),
+            group: Vec::new(),
+        };
+        {
+            let queue = FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::Cbor)
+                .await
+                .unwrap();
+            queue
+                .push(keys[0].clone(), RequestInfo::default())
+                .await
+                .unwrap();
+            queue
+                .push(keys[1].clone(), high_priority.clone())
+                .await
+                .unwrap();
+            queue
+                .push(keys[2].clone(), RequestInfo::default())
+                .await
+                .unwrap();
+            assert!(queue.grant(keys[2].clone(), 1, 5).await);
+        }
+
+        // The queue reads back the same in CBOR, both as is and after compaction.
+        for compaction_threshold in [usize::MAX, 0] {
+            let mut queue =
+                FaucetQueue::load(dir.path(), None, compaction_threshold, QueueLogFormat::Cbor)
+                    .await
+                    .unwrap();
+            assert_eq!(queue.index.lock().await.info(&keys[1]), high_priority);
+            assert_eq!(queue.pop().await.unwrap(), (keys[1].clone(), 0));
+            assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+            assert_eq!(queue.pop().await.unwrap(), (keys[2].clone(), 1));
+        }
+
+        // Opening the queue in a different format fails, rather than misreading the entries.
+        match FaucetQueue::load(dir.path(), None, usize::MAX, QueueLogFormat::Bincode).await {
+            Err(FaucetError::Config { .. }) => {}
+            Err(err) => panic!("expected Config error, got {}", err),
+            Ok(_) => panic!("expected Config error, but queue loaded"),
+        }
+        assert_eq!(
+            "cbor".parse::<QueueLogFormat>().unwrap(),
+            QueueLogFormat::Cbor
+        );
+        "json".parse::<QueueLogFormat>().unwrap_err();
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_duplicate_request() {
+        let mut rng = ChaChaRng::from_seed([20u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let keys = (0..2)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let mut queue =
+            FaucetQueue::load(dir.path(), Some(1), usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap();
+        assert_eq!(
+            queue
+                .push(keys[0].clone(), RequestInfo::default())
+                .await
+                .unwrap(),
+            Pushed::New
+        );
+
+        // A duplicate request succeeds and refers to the existing entry, even though the queue is
+        // full, and it does not change the parameters of the existing request.
+        let high_priority = RequestInfo {
+            priority: Priority::High,
+            num_grants: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            queue.push(keys[0].clone(), high_priority).await.unwrap(),
+            Pushed::Existing {
+                grants: 0,
+                info: RequestInfo::default()
+            }
+        );
+        assert_eq!(
+            queue.index.lock().await.info(&keys[0]),
+            RequestInfo::default()
+        );
+        // A request from a different key is still rejected.
+        match queue.push(keys[1].clone(), RequestInfo::default()).await {
+            Err(FaucetError::QueueFull { max_len }) => assert_eq!(max_len, 1),
+            res => panic!("expected QueueFull, got {:?}", res),
+        }
+
+        // The key is only in the queue once.
+        assert_eq!(queue.pop().await.unwrap(), (keys[0].clone(), 0));
+        assert!(queue.receiver.is_empty());
+        assert!(queue.high_priority_receiver.is_empty());
+
+        // While the request is in flight, duplicates refer to its progress.
+        assert!(queue.grant(keys[0].clone(), 1, 5).await);
+        assert_eq!(
+            queue
+                .push(keys[0].clone(), RequestInfo::default())
+                .await
+                .unwrap(),
+            Pushed::Existing {
+                grants: 1,
+                info: RequestInfo::default()
             }
+        );
+
+        // Once the request has been fully granted, the key can make a new request.
+        assert!(!queue.grant(keys[0].clone(), 4, 5).await);
+        assert_eq!(
+            queue
+                .push(keys[0].clone(), RequestInfo::default())
+                .await
+                .unwrap(),
+            Pushed::New
+        );
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_queue_full_escalation() {
+        let mut rng = ChaChaRng::from_seed([44u8; 32]);
+        let dir = TempDir::new("espresso_faucet_queue").unwrap();
+        let alert_port = pick_unused_port().unwrap();
+        let alerts = mock_observer::<QueueAlert>(alert_port).await;
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+        let mut queue =
+            FaucetQueue::load(dir.path(), Some(1), usize::MAX, QueueLogFormat::default())
+                .await
+                .unwrap()
+                .escalate_when_full(
+                    Duration::from_millis(100),
+                    Some(
+                        format!("http://localhost:{}/alerts", alert_port)
+                            .parse()
+                            .unwrap(),
+                    ),
+                );
+        let assert_full = |res: Result<Pushed, FaucetError>| match res {
+            Err(FaucetError::QueueFull { max_len }) => assert_eq!(max_len, 1),
+            res => panic!("expected QueueFull, got {:?}", res),
+        };
+        queue
+            .push(keys[0].clone(), RequestInfo::default())
+            .await
+            .unwrap();
+
+        // A queue which has only just filled up does not escalate.
+        assert_full(queue.push(keys[1].clone(), RequestInfo::default()).await);
+        assert_eq!(queue.escalations(), 0);
+
+        // Once it has stayed full past the threshold, it escalates, logging an error and posting
+        // an alert.
+        sleep(Duration::from_millis(200)).await;
+        assert_full(queue.push(keys[1].clone(), RequestInfo::default()).await);
+        assert_eq!(queue.escalations(), 1);
+        assert!(logs_contain("faucet queue has been full"));
+        let alert = async_std::future::timeout(Duration::from_secs(60), alerts.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alert.max_len, 1);
+
+        // It only escalates once while it stays full.
+        assert_full(queue.push(keys[2].clone(), RequestInfo::default()).await);
+        assert_eq!(queue.escalations(), 1);
+
+        // Once there is room in the queue, the clock starts over the next time it fills up.
+        queue.pop().await.unwrap();
+        assert!(!queue.grant(keys[0].clone(), 1, 1).await);
+        queue
+            .push(keys[1].clone(), RequestInfo::default())
+            .await
+            .unwrap();
+        assert_full(queue.push(keys[2].clone(), RequestInfo::default()).await);
+        assert_eq!(queue.escalations(), 1);
+        sleep(Duration::from_millis(200)).await;
+        assert_full(queue.push(keys[2].clone(), RequestInfo::default()).await);
+        assert_eq!(queue.escalations(), 2);
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_recipient_concurrency() {
+        let mut rng = ChaChaRng::from_seed([46u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 3;
+        let mut faucet = InProcessFaucet::new(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--num-grants",
+                &num_grants.to_string(),
+                "--num-records",
+                "12",
+                "--num-workers",
+                "4",
+                "--batch-window",
+                "1s",
+                "--max-workers-per-recipient",
+                "1",
+            ],
+        )
+        .await;
+        let state = faucet.state.clone();
+
+        // Queue a request from each of two recipients, plus stale entries for the first, like those
+        // left in the channel when a request is re-queued while another worker is granting to it.
+        // Every worker, and the batching path of every worker, may pick up the first recipient.
+        let (receiver, receiver_key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        let (other, other_key, _other_dir) = create_receiver(&mut rng, &network).await;
+        for key in [&receiver_key, &other_key] {
+            state
+                .queue
+                .push(key.clone(), RequestInfo::default())
+                .await
+                .unwrap();
+        }
+        for _ in 0..3 {
+            state
+                .queue
+                .sender(Priority::Normal)
+                .send((receiver_key.clone(), 0))
+                .await
+                .unwrap();
         }
+        faucet.start().await;
 
-        async fn restart(&mut self) {
-            self.stop().await;
-            self.start().await;
+        // Each recipient gets exactly what it asked for, even after the stale entries have been
+        // processed, and every recipient lock is released.
+        retry(|| async { state.queue.index.lock().await.len() == 0 }).await;
+        for receiver in [&receiver, &other] {
+            retry(|| async {
+                receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * num_grants
+            })
+            .await;
         }
-    }
-
-    async fn parallel_request(num_requests: usize, restart: bool) {
-        let mut rng = ChaChaRng::from_seed([1u8; 32]);
+        sleep(Duration::from_secs(5)).await;
+        for receiver in [&receiver, &other] {
+            let records = receiver.records().await;
+            assert_eq!(records.len(), num_grants);
+            for record in records {
+                assert_eq!(record.asset_code(), AssetCode::native());
+                assert_eq!(record.amount(), grant_size);
+            }
+        }
+        assert!(state.recipients.in_flight.lock().unwrap().is_empty());
 
-        // Create test network with a faucet key pair.
-        let (key_stream, mnemonic) = KeyTree::random(&mut rng);
-        let faucet_key_pair = key_stream
-            .derive_sub_tree("keystore".as_bytes())
-            .derive_sub_tree("user".as_bytes())
-            .derive_user_key_pair(&0u64.to_le_bytes());
-        let network = minimal_test_network(&mut rng, faucet_key_pair.pub_key(), None).await;
+        // A busy recipient can't be taken by a worker with no room, but can once it's released.
+        let guard = state.recipients.try_acquire(&receiver_key).unwrap();
+        assert!(state.recipients.try_acquire(&receiver_key).is_none());
+        drop(guard);
+        assert!(state.recipients.try_acquire(&receiver_key).is_some());
+    }
 
-        // Initiate a faucet server with the mnemonic associated with the faucet key pair.
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_max_grant_per_request() {
+        let mut rng = ChaChaRng::from_seed([12u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
         let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
-        let faucet_port = pick_unused_port().unwrap();
         let grant_size = RecordAmount::from(1000u64);
-        let num_grants = 5;
         let mut faucet = Faucet {
-            esqs_url: network.query_api.clone(),
-            submit_url: network.submit_api.clone(),
-            address_book_url: network.address_book_api.clone(),
-            mnemonic,
-            dir: faucet_dir.path().to_owned(),
-            port: faucet_port,
             grant_size,
-            num_grants,
-            num_requests,
-            process: None,
+            num_grants: 2,
+            num_requests: 3,
+            extra_args: vec!["--max-grant-per-request".into(), "4500".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
         };
         faucet.start().await;
-        println!("Faucet server initiated.");
-        let client = surf_disco::Client::<FaucetError>::new(
-            format!("http://localhost:{}/api/", faucet_port)
-                .parse()
+        let client = faucet.client();
+        let (receiver, receiver_key, _) = create_receiver(&mut rng, &network).await;
+
+        // Requests above the cap are rejected.
+        let err = client
+            .post::<GrantInfo>("request_fee_assets/amount/6000")
+            .body_binary(&receiver_key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(tide_disco::Error::status(&err), StatusCode::BadRequest);
+
+        // A request within the cap is rounded up to a whole number of grant records, even if that is
+        // more than the default number of grants. The response tells the client what to expect.
+        assert_eq!(
+            client
+                .post::<GrantInfo>("request_fee_assets/amount/2500")
+                .body_binary(&receiver_key)
+                .unwrap()
+                .send()
+                .await
                 .unwrap(),
+            GrantInfo {
+                asset: AssetCode::native(),
+                num_grants: 3,
+                granted: 0,
+                grant_size,
+            }
         );
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * 3u64
+        })
+        .await;
 
-        // Check the status is "available".
+        // If rounding up would take the grant over the cap, the request is rounded down instead.
+        let (_, receiver_key, _) = create_receiver(&mut rng, &network).await;
         assert_eq!(
             client
-                .get::<HealthCheck>("healthcheck")
+                .post::<GrantInfo>("request_fee_assets/amount/4200")
+                .body_binary(&receiver_key)
+                .unwrap()
                 .send()
                 .await
                 .unwrap(),
-            HealthCheck {
-                status: FaucetStatus::Available
+            GrantInfo {
+                asset: AssetCode::native(),
+                num_grants: 4,
+                granted: 0,
+                grant_size,
             }
         );
 
-        // Create receiver keystores.
-        let mut keystores = Vec::new();
-        let mut keys = Vec::new();
-        let mut temp_dirs = Vec::new();
-        for i in 0..num_requests {
-            let receiver_dir = TempDir::new("espresso_keystore_receiver").unwrap();
-            let receiver_mnemonic = KeyTree::random(&mut rng).1;
-            let mut receiver_loader = CreateLoader::exclusive(
-                &mut rng,
-                PathBuf::from(receiver_dir.path()),
-                receiver_mnemonic,
-                Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
-            );
-            let backend = NetworkBackend::new(
-                &UNIVERSAL_PARAM,
-                network.query_api.clone(),
-                network.address_book_api.clone(),
-                network.submit_api.clone(),
-            )
-            .await
-            .unwrap();
-            let mut receiver = EspressoKeystore::new(backend, &mut receiver_loader)
+        // A request without an amount gets the configured number of grants.
+        let (_, receiver_key, _) = create_receiver(&mut rng, &network).await;
+        assert_eq!(
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(&receiver_key)
+                .unwrap()
+                .send()
                 .await
-                .unwrap();
-            let receiver_key = receiver
-                .generate_sending_account("receiver".into(), None)
+                .unwrap(),
+            GrantInfo {
+                asset: AssetCode::native(),
+                num_grants: 2,
+                granted: 0,
+                grant_size,
+            }
+        );
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_per_asset_grant_size() {
+        let mut rng = ChaChaRng::from_seed([2u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+
+        // Give the faucet two custom assets, and configure a different grant size for each.
+        let assets = mint_faucet_assets(&mut rng, &network, &mnemonic, faucet_dir.path(), 2).await;
+        let sizes = [RecordAmount::from(100u64), RecordAmount::from(200u64)];
+        let config_path = faucet_dir.path().join("grant_sizes.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[grant_sizes]\n\"{}\" = {}\n\"{}\" = {}\n",
+                assets[0], sizes[0], assets[1], sizes[1]
+            ),
+        )
+        .unwrap();
+
+        // Use several grants per request and several workers serving concurrent requests, so that
+        // the faucet needs many records of each asset at once, not just the one it was minted.
+        let faucet_port = pick_unused_port().unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let num_grants = 3;
+        let mut faucet = Faucet {
+            port: faucet_port,
+            grant_size,
+            num_grants,
+            num_requests: 2,
+            num_workers: 3,
+            extra_args: vec![
+                "--grant-sizes-path".into(),
+                config_path.display().to_string(),
+            ],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (receiver, key, receiver_dir) = create_receiver(&mut rng, &network).await;
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(&key)
+                .unwrap()
+                .send()
                 .await
                 .unwrap();
-            println!("Receiver keystore {} created.", i);
-
-            temp_dirs.push(receiver_dir);
-            keystores.push(receiver);
-            keys.push(receiver_key);
+            receivers.push((receiver, receiver_dir));
         }
 
-        join_all(keys.iter().map(|key| {
-            let client = &client;
-            async move {
-                // Request native asset for the receiver.
-                client
-                    .post::<()>("request_fee_assets")
-                    .body_binary(&key)
-                    .unwrap()
-                    .send()
-                    .await
-                    .unwrap();
-                println!("Asset transferred.");
+        // Each receiver should get `num_grants` records of each asset, with the configured size,
+        // and the native asset should still use the global grant size.
+        for (receiver, _) in &receivers {
+            retry(|| async {
+                receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * num_grants
+                    && receiver.balance(&assets[0]).await == U256::from(sizes[0]) * num_grants
+                    && receiver.balance(&assets[1]).await == U256::from(sizes[1]) * num_grants
+            })
+            .await;
+            let records = receiver.records().await;
+            // A failed transfer of an additional asset re-queues the request, which would show up
+            // here as extra records.
+            assert_eq!(records.len(), 3 * num_grants);
+            for record in records {
+                let code = record.asset_code();
+                if code == assets[0] {
+                    assert_eq!(record.amount(), sizes[0]);
+                } else if code == assets[1] {
+                    assert_eq!(record.amount(), sizes[1]);
+                } else {
+                    assert_eq!(code, AssetCode::native());
+                    assert_eq!(record.amount(), grant_size);
+                }
             }
-        }))
-        .await;
-
-        if restart {
-            // After submitting all of the requests, wait a random amount of time, and then kill and
-            // restart the faucet, so that it has to reload from storage.
-            let delay = ChaChaRng::from_entropy().gen_range(0..30);
-            tracing::info!("Waiting {} seconds, then killing faucet", delay);
-            sleep(Duration::from_secs(delay)).await;
-            faucet.restart().await;
         }
 
-        // Check the balances for each keystore.
-        join_all(
-            keystores
-                .into_iter()
-                .zip(keys)
-                .enumerate()
-                .map(|(i, (keystore, key))| async move {
-                    retry(|| async {
-                        let balance = keystore.balance(&AssetCode::native()).await;
-                        let desired = U256::from(grant_size) * num_grants;
-                        println!("Keystore {}: balance is {}/{}", i, balance, desired);
-                        if restart {
-                            // It is possible to get an extra record, if we shut down the faucet at
-                            // just the right time.
-                            balance >= desired
-                        } else {
-                            balance == desired
-                        }
-                    })
-                    .await;
+        faucet.stop().await;
+    }
 
-                    // We should have received at least `num_grants` records of `grant_size` each.
-                    let records = keystore.records().await;
-                    if restart {
-                        assert!(
-                            records.len() >= num_grants,
-                            "received {}/{}",
-                            records.len(),
-                            num_grants
-                        );
-                    } else {
-                        assert_eq!(records.len(), num_grants);
-                    }
-                    for record in records {
-                        assert_eq!(*record.asset_definition(), AssetDefinition::native());
-                        assert_eq!(*record.pub_key(), key);
-                        assert_eq!(record.amount(), grant_size);
-                    }
-                })
-                .collect::<Vec<_>>(),
+    #[async_std::test]
+    async fn test_faucet_allowed_assets() {
+        let mut rng = ChaChaRng::from_seed([41u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+
+        // Give the faucet two custom assets and configure grants of both, but only allow one.
+        let assets = mint_faucet_assets(&mut rng, &network, &mnemonic, faucet_dir.path(), 2).await;
+        let config_path = faucet_dir.path().join("grant_sizes.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[grant_sizes]\n\"{}\" = 100\n\"{}\" = 100\n",
+                assets[0], assets[1]
+            ),
         )
+        .unwrap();
+
+        let grant_size = RecordAmount::from(1000u64);
+        let mut faucet = Faucet {
+            grant_size,
+            extra_args: vec![
+                "--grant-sizes-path".into(),
+                config_path.display().to_string(),
+                "--allowed-assets".into(),
+                assets[0].to_string(),
+            ],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+        faucet.start().await;
+        let client = faucet.client();
+
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        // The receiver gets the native asset and the allowed asset. The faucet holds records of the
+        // other asset, but does not dispense it.
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size)
+                && receiver.balance(&assets[0]).await == U256::from(100u64)
+        })
         .await;
+        assert_eq!(receiver.balance(&assets[1]).await, U256::zero());
 
         faucet.stop().await;
     }
 
     #[async_std::test]
     #[traced_test]
-    async fn test_faucet_transfer() {
-        parallel_request(1, false).await;
+    async fn test_faucet_per_asset_fee() {
+        let mut rng = ChaChaRng::from_seed([16u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+
+        // Give the faucet two custom assets, and configure a different fee for each.
+        let assets = mint_faucet_assets(&mut rng, &network, &mnemonic, faucet_dir.path(), 2).await;
+        let fees = [RecordAmount::from(10u64), RecordAmount::from(20u64)];
+        let config_path = faucet_dir.path().join("grant_sizes.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[grant_sizes]\n\"{}\" = 100\n\"{}\" = 100\n[fees]\n\"{}\" = {}\n\"{}\" = {}\n",
+                assets[0], assets[1], assets[0], fees[0], assets[1], fees[1]
+            ),
+        )
+        .unwrap();
+
+        // Run the faucet in process, so we can inspect its balance.
+        let grant_size = RecordAmount::from(1000u64);
+        let fee_size = RecordAmount::from(100u64);
+        let (state, _) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--fee-size",
+                &fee_size.to_string(),
+                "--grant-sizes-path",
+                &config_path.display().to_string(),
+                "--num-grants",
+                "1",
+                "--num-records",
+                "1",
+                "--num-workers",
+                "1",
+            ],
+        )
+        .await;
+        let client = surf_disco::Client::<FaucetError>::new(
+            format!("http://localhost:{}/api/", port).parse().unwrap(),
+        );
+        retry(|| async {
+            matches!(
+                client.get::<HealthCheck>("healthcheck").send().await,
+                Ok(HealthCheck {
+                    status: FaucetStatus::Available
+                })
+            )
+        })
+        .await;
+        let balance = state
+            .keystore
+            .lock()
+            .await
+            .balance(&AssetCode::native())
+            .await;
+
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            receiver.balance(&assets[0]).await == U256::from(100u64)
+                && receiver.balance(&assets[1]).await == U256::from(100u64)
+        })
+        .await;
+
+        // The faucet should have paid the global fee for the native grant, and the configured fee
+        // for each of the other grants.
+        let expected = balance
+            - U256::from(grant_size)
+            - U256::from(fee_size)
+            - U256::from(fees[0])
+            - U256::from(fees[1]);
+        retry(|| async {
+            state
+                .keystore
+                .lock()
+                .await
+                .balance(&AssetCode::native())
+                .await
+                == expected
+        })
+        .await;
     }
 
     #[async_std::test]
     #[traced_test]
-    async fn test_faucet_transfer_restart() {
-        parallel_request(1, true).await;
+    async fn test_faucet_prewarm() {
+        let mut rng = ChaChaRng::from_seed([8u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let grant_size = RecordAmount::from(1000u64);
+
+        // Start a faucet without and then with pre-warming, each in a fresh directory. Only the
+        // second faucet makes grants, so the two do not compete for the records they share.
+        for prewarm in [false, true] {
+            let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+            let grant_size_arg = grant_size.to_string();
+            let mut args = vec![
+                "--grant-size",
+                &grant_size_arg,
+                "--num-grants",
+                "1",
+                "--num-records",
+                "2",
+            ];
+            if prewarm {
+                args.push("--prewarm");
+            }
+            let (state, client) =
+                start_in_process(&mut rng, &network, &mnemonic, faucet_dir.path(), &args).await;
+
+            // The prover is warmed up before the faucet becomes available, and only if asked.
+            assert_eq!(*state.status.read().await, FaucetStatus::Available);
+            assert_eq!(logs_contain("pre-warmed the prover"), prewarm);
+            if !prewarm {
+                continue;
+            }
+
+            // Pre-warming does not use up the records needed for the first grant. We don't compare
+            // how long the first grant takes with and without pre-warming: both faucets run in this
+            // process and break up records before becoming available, so neither grant is the
+            // first proof generated, and the difference would be lost in the noise.
+            let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+            client
+                .post::<GrantInfo>("request_fee_assets")
+                .body_binary(&key)
+                .unwrap()
+                .send()
+                .await
+                .unwrap();
+            retry(|| async {
+                receiver.balance(&AssetCode::native()).await == U256::from(grant_size)
+            })
+            .await;
+        }
     }
 
     #[async_std::test]
     #[traced_test]
-    async fn test_faucet_simultaneous_transfer_restart() {
-        parallel_request(5, true).await;
+    async fn test_faucet_unsatisfiable_num_records() {
+        let mut rng = ChaChaRng::from_seed([3u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+
+        // The genesis faucet record holds 2^32 tokens, so with a grant size of 2^30 the balance can
+        // support at most 4 grant records, far fewer than requested.
+        let grant_size = RecordAmount::from(1u64 << 30);
+        let num_grants = 2;
+        let mut faucet = Faucet {
+            grant_size,
+            num_grants,
+            extra_args: vec!["--num-records".into(), "1000000".into()],
+            ..Faucet::new(&network, mnemonic, faucet_dir.path())
+        };
+
+        // The record breaker should give up on the unsatisfiable target and let the faucet start,
+        // rather than splitting records indefinitely.
+        async_std::future::timeout(Duration::from_secs(20 * 60), faucet.start())
+            .await
+            .expect("faucet did not start with an unsatisfiable number of records");
+
+        // The faucet should still be able to serve requests from the records it did create.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        faucet
+            .client()
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * num_grants
+        })
+        .await;
+
+        faucet.stop().await;
+    }
+
+    #[async_std::test]
+    #[traced_test]
+    async fn test_faucet_exact_denominations() {
+        // Breaking up a record splits it into whole multiples of the denomination.
+        assert_eq!(
+            split_record(
+                RecordAmount::from(11000u64),
+                Some(RecordAmount::from(1100u64))
+            ),
+            (RecordAmount::from(5500u64), RecordAmount::from(5500u64))
+        );
+        assert_eq!(
+            split_record(
+                RecordAmount::from(10000u64),
+                Some(RecordAmount::from(1100u64))
+            ),
+            (RecordAmount::from(4400u64), RecordAmount::from(5600u64))
+        );
+        assert_eq!(
+            split_record(RecordAmount::from(10000u64), None),
+            (RecordAmount::from(5000u64), RecordAmount::from(5000u64))
+        );
+
+        let mut rng = ChaChaRng::from_seed([43u8; 32]);
+        let (network, mnemonic) = faucet_test_network(&mut rng).await;
+        let faucet_dir = TempDir::new("espresso_keystore_faucet").unwrap();
+        let grant_size = RecordAmount::from(1000u64);
+        let denomination = RecordAmount::from(1100u64);
+        let (state, client) = start_in_process(
+            &mut rng,
+            &network,
+            &mnemonic,
+            faucet_dir.path(),
+            &[
+                "--grant-size",
+                &grant_size.to_string(),
+                "--fee-size",
+                "100",
+                "--num-grants",
+                "2",
+                "--num-records",
+                "4",
+                "--num-workers",
+                "1",
+                "--exact-denominations",
+            ],
+        )
+        .await;
+
+        // Every record the faucet broke up is worth a whole number of grants, except for at most
+        // one record holding the remainder.
+        {
+            let keystore = state.keystore.lock().await;
+            let records = spendable_records(&keystore, &AssetCode::native(), grant_size)
+                .await
+                .collect::<Vec<_>>();
+            assert!(records.len() >= opt.num_records);
+            let odd = records
+                .iter()
+                .filter(|record| {
+                    U256::from(record.amount()) % U256::from(denomination) != U256::zero()
+                })
+                .count();
+            assert!(
+                odd <= 1,
+                "{} records are not multiples of {}",
+                odd,
+                denomination
+            );
+        }
+
+        // Every granted record is exactly the grant size.
+        let (receiver, key, _receiver_dir) = create_receiver(&mut rng, &network).await;
+        client
+            .post::<GrantInfo>("request_fee_assets")
+            .body_binary(&key)
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        retry(|| async {
+            receiver.balance(&AssetCode::native()).await == U256::from(grant_size) * 2u64
+        })
+        .await;
+        let records = receiver.records().await;
+        assert_eq!(records.len(), 2);
+        for record in records {
+            assert_eq!(record.asset_code(), AssetCode::native());
+            assert_eq!(record.amount(), grant_size);
+        }
     }
 }
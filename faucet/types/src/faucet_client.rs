@@ -0,0 +1,48 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A typed client for the faucet API.
+
+use crate::{FaucetError, GrantInfo, HealthCheck, StatusReport};
+use jf_cap::keys::UserPubKey;
+use surf_disco::{Client, Url};
+
+/// A client for the faucet API.
+pub struct FaucetClient {
+    client: Client<FaucetError>,
+}
+
+impl FaucetClient {
+    /// Create a client for the faucet API served at `url`, such as `http://localhost:50079/api/`.
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: Client::new(url),
+        }
+    }
+
+    /// Request the faucet's default grant for `pub_key`.
+    pub async fn request_fee_assets(&self, pub_key: &UserPubKey) -> Result<GrantInfo, FaucetError> {
+        self.client
+            .post("request_fee_assets")
+            .body_binary(pub_key)?
+            .send()
+            .await
+    }
+
+    /// Get the status of the faucet.
+    ///
+    /// This succeeds while the faucet is initializing, so it can be used to wait for the faucet to
+    /// become available.
+    pub async fn healthcheck(&self) -> Result<HealthCheck, FaucetError> {
+        self.client.get("healthcheck").send().await
+    }
+
+    /// Get a summary of the internal state of the faucet.
+    ///
+    /// The faucet only serves this report if it was started with `--status-page`; otherwise this
+    /// fails. Use [healthcheck](Self::healthcheck) to get just the status of a faucet which may not
+    /// serve the report.
+    pub async fn request_status(&self) -> Result<StatusReport, FaucetError> {
+        self.client.get("status").send().await
+    }
+}
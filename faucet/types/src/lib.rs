@@ -2,11 +2,16 @@
 // This file is part of the Espresso library.
 
 use atomic_store::PersistenceError;
-use jf_cap::keys::UserPubKey;
+use jf_cap::{
+    keys::UserPubKey,
+    structs::{Amount, AssetCode},
+};
 use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 use tide_disco::{RequestError, StatusCode};
 
+pub mod faucet_client;
+
 #[derive(Debug, Snafu, Serialize, Deserialize)]
 #[snafu(visibility(pub), module(error))]
 pub enum FaucetError {
@@ -22,17 +27,57 @@ pub enum FaucetError {
     #[snafu(display("the queue is full with {} requests, try again later", max_len))]
     QueueFull { max_len: usize },
 
-    #[snafu(display(
-        "there is a pending request with key {}, you can only request once at a time",
-        key
-    ))]
-    AlreadyInQueue { key: UserPubKey },
-
     #[snafu(display("error with persistent storage: {}", msg))]
     Persistence { msg: String },
 
     #[snafu(display("faucet service temporarily unavailable"))]
     Unavailable,
+
+    #[snafu(display("invalid faucet configuration: {}", msg))]
+    Config { msg: String },
+
+    #[snafu(display("request is not authorized"))]
+    Unauthorized,
+
+    #[snafu(display(
+        "invalid request priority {}, expected \"normal\" or \"high\"",
+        priority
+    ))]
+    InvalidPriority { priority: String },
+
+    #[snafu(display(
+        "requested grant of {} is too large, the maximum per request is {}",
+        requested,
+        max
+    ))]
+    GrantTooLarge { requested: u64, max: u64 },
+
+    #[snafu(display("faucet is paused, try again later"))]
+    Paused,
+
+    #[snafu(display("the faucet has dispensed its daily cap of {}, try again later", cap))]
+    DailyCapReached { cap: u64 },
+
+    #[snafu(display("request label is {} bytes, the maximum is {}", len, max))]
+    LabelTooLong { len: usize, max: usize },
+
+    #[snafu(display("cannot grant {} records, the count must be positive", count))]
+    InvalidGrantCount { count: usize },
+
+    #[snafu(display("{}: {}", field, reason))]
+    BadRequest { field: String, reason: String },
+
+    #[snafu(display(
+        "faucet balance is too low to accept new requests, retry after {} seconds",
+        retry_after
+    ))]
+    TemporarilyUnavailable { retry_after: u64 },
+
+    #[snafu(display("faucet is a standby and is not accepting requests"))]
+    Standby,
+
+    #[snafu(display("faucet is not a standby, there is nothing to promote"))]
+    NotStandby,
 }
 
 impl tide_disco::Error for FaucetError {
@@ -45,10 +90,21 @@ impl tide_disco::Error for FaucetError {
             Self::Request { .. } => StatusCode::BadRequest,
             Self::Transfer { .. } => StatusCode::BadRequest,
             Self::Internal { status, .. } => *status,
-            Self::AlreadyInQueue { .. } => StatusCode::TooManyRequests,
             Self::QueueFull { .. } => StatusCode::InternalServerError,
             Self::Persistence { .. } => StatusCode::InternalServerError,
             Self::Unavailable => StatusCode::ServiceUnavailable,
+            Self::Config { .. } => StatusCode::InternalServerError,
+            Self::Unauthorized => StatusCode::Unauthorized,
+            Self::InvalidPriority { .. } => StatusCode::BadRequest,
+            Self::GrantTooLarge { .. } => StatusCode::BadRequest,
+            Self::Paused => StatusCode::ServiceUnavailable,
+            Self::DailyCapReached { .. } => StatusCode::TooManyRequests,
+            Self::LabelTooLong { .. } => StatusCode::BadRequest,
+            Self::InvalidGrantCount { .. } => StatusCode::BadRequest,
+            Self::BadRequest { .. } => StatusCode::BadRequest,
+            Self::TemporarilyUnavailable { .. } => StatusCode::ServiceUnavailable,
+            Self::Standby => StatusCode::ServiceUnavailable,
+            Self::NotStandby => StatusCode::BadRequest,
         }
     }
 }
@@ -66,3 +122,104 @@ impl From<RequestError> for FaucetError {
         Self::Request { source }
     }
 }
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FaucetStatus {
+    Initializing,
+    Available,
+    /// Paused by an operator. Requests are rejected and grants are held until the faucet resumes.
+    Paused,
+    /// A warm standby, tracking the ledger but not processing requests until it is promoted.
+    Standby,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub status: FaucetStatus,
+}
+
+impl tide_disco::healthcheck::HealthCheck for HealthCheck {
+    fn status(&self) -> StatusCode {
+        // The healtcheck should succeed even if the status is [Initializing], otherwise the load
+        // balancer may kill us while we are initializing.
+        StatusCode::Ok
+    }
+}
+
+/// A summary of the internal state of the faucet, for operators.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StatusReport {
+    pub status: FaucetStatus,
+    /// The number of requests which have not yet received all of their grants.
+    pub queue_len: usize,
+    /// The native asset balance of the faucet, in decimal.
+    pub balance: String,
+}
+
+/// Totals of the work done by the faucet since it started, for public dashboards.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StatsReport {
+    /// The number of grant records of the native asset transferred.
+    pub grants: usize,
+    /// The total amount of the native asset granted, in decimal.
+    pub tokens_dispensed: String,
+    /// The number of requests which have not yet received all of their grants.
+    pub queue_len: usize,
+    /// How long the faucet has been running, in seconds.
+    pub uptime_secs: u64,
+    /// The native asset balance of the faucet, in decimal.
+    pub balance: String,
+}
+
+/// The result of rescanning the ledger for the faucet's records.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RescanReport {
+    /// The query service event the scan started from.
+    pub from: usize,
+    /// The native asset balance of the faucet before the scan, in decimal.
+    pub balance_before: String,
+    /// The native asset balance of the faucet after the scan, in decimal.
+    pub balance_after: String,
+}
+
+/// The grant that a request to the faucet will receive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrantInfo {
+    /// The asset being granted.
+    pub asset: AssetCode,
+    /// The number of records the request will be granted in total.
+    pub num_grants: usize,
+    /// The number of records which have already been granted.
+    ///
+    /// This is only nonzero if the request was merged into one which was already in progress.
+    pub granted: usize,
+    /// The amount of `asset` in each granted record.
+    pub grant_size: Amount,
+}
+
+/// A grant made by the faucet, as reported to the grant observer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrantEvent {
+    /// The recipient of the grant.
+    pub pub_key: UserPubKey,
+    /// The asset granted.
+    pub asset: AssetCode,
+    /// The number of records granted.
+    pub grants: usize,
+    /// The amount of `asset` in each granted record.
+    pub grant_size: Amount,
+    /// The label the client attached to the request, if any.
+    pub label: Option<String>,
+    /// The time of the grant, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// An alert posted by the faucet when its queue has stayed full for too long.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueAlert {
+    /// The maximum length of the queue.
+    pub max_len: usize,
+    /// How long the queue has been full, in seconds.
+    pub full_secs: u64,
+}
@@ -5,24 +5,36 @@
 
 use async_std::{
     sync::Arc,
-    task::{sleep, spawn_blocking},
+    task::{sleep, spawn, spawn_blocking},
 };
-use clap::Parser;
+use clap::{Args, Parser};
 use espresso_core::StakingKey;
 use espresso_core::{
     genesis::GenesisNote,
-    state::{ChainVariables, SetMerkleTree, ValidatorState},
+    state::{
+        ChainVariables, ConsensusTime, ElaboratedBlock, ElaboratedTransaction,
+        LedgerStateCommitment, SetMerkleTree, ValidatorState,
+    },
     testing::MultiXfrRecordSpecTransaction,
     testing::{MultiXfrRecordSpec, MultiXfrTestState, TestTxSpec, TxnPrintInfo},
     universal_params::VERIF_CRS,
 };
 use espresso_validator::{validator::*, *};
+use futures::future::join_all;
 use hotshot::types::SignatureKey;
 use hotshot::{traits::State, types::EventType};
 use rand::SeedableRng;
 use rand_chacha::ChaChaRng;
-use std::time::Duration;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempdir::TempDir;
+use tracing::{error, info};
 
 #[derive(Parser)]
 #[command(
@@ -30,39 +42,259 @@ use tracing::info;
     about = "Runs a validator for a given rounds of consensus for testing purposes."
 )]
 struct Options {
-    /// Node with `node_opt.id = 0` will be the transaction submitter.
+    /// Node with `node_opt.id = 0` will be the transaction submitter, unless `--rotate-submitter`
+    /// is given.
     #[command(flatten)]
     node_opt: NodeOpt,
 
+    #[command(flatten)]
+    initial_state: InitialStateOpt,
+
     /// Number of successful transactions to submit.
-    #[arg(long, short)]
-    pub num_txns: u64,
+    #[arg(long, short, required_unless_present = "replay")]
+    pub num_txns: Option<u64>,
+
+    /// Record every block applied by the transaction submitter to this file.
+    ///
+    /// The recording can be replayed with `--replay`. With `--simulate`, the blocks applied by node
+    /// 0 are recorded.
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// Replay a recording made with `--record`, without running consensus.
+    ///
+    /// Each recorded block is applied to the genesis state in turn, and the resulting commitments
+    /// are checked against the recorded ones. The genesis state depends on `--num-nodes` and
+    /// `--secret-key-seed`, which must be the same as in the recorded run.
+    #[arg(long, conflicts_with = "record")]
+    pub replay: Option<PathBuf>,
+
+    /// Run this many nodes in a single process, connected over loopback.
+    ///
+    /// This is a lightweight alternative to running a separate process for each node in local
+    /// tests. The nodes run consensus until `--num-txns` transactions have been committed, and then
+    /// the final commitment of each node is reported. The `--id`, `--num-nodes`, bootstrap and
+    /// port options are ignored, since they are chosen for each simulated node.
+    #[arg(long, conflicts_with = "replay")]
+    pub simulate: Option<usize>,
+
+    /// Append the commitment after each completed round to this file.
+    ///
+    /// Each line contains the round number, the time the round completed in seconds since the Unix
+    /// epoch, and the commitment. With `--simulate`, the rounds completed by the transaction
+    /// submitter are recorded.
+    #[arg(long, conflicts_with = "replay")]
+    pub commitment_log: Option<PathBuf>,
+
+    /// Take turns submitting transactions, instead of having node 0 submit every one.
+    ///
+    /// The transaction for each round is submitted by node `round % num_nodes`. Every node applies
+    /// every block to its own copy of the test state, and generates the same transaction for each
+    /// round as the submitter, so that it is ready when its turn comes. Every node must use this
+    /// option for the run to make progress.
+    #[arg(long, conflicts_with = "replay")]
+    pub rotate_submitter: bool,
 }
 
-fn genesis_for_test(node_opt: &NodeOpt) -> (GenesisNote, MultiXfrTestState) {
-    let mut state = MultiXfrTestState::initialize(
-        GENESIS_SEED,
-        10,
-        10,
-        (
+/// Options for the initial ledger state used by the test transaction generator.
+///
+/// Every node must use the same options, since they determine the genesis block.
+#[derive(Args, Clone, Copy, Debug)]
+struct InitialStateOpt {
+    /// Number of records to mint in the initial state, in addition to the native fee records.
+    ///
+    /// Larger values give the simulation more state to start with, for load testing.
+    #[arg(long, default_value = "3")]
+    pub initial_records: NonZeroUsize,
+
+    /// Number of asset types to define in the initial state, in addition to the native asset.
+    #[arg(long, default_value = "10")]
+    pub initial_assets: u8,
+}
+
+impl InitialStateOpt {
+    /// The specs of the records to mint in the initial state.
+    ///
+    /// The first three records are always the same, so that the default configuration is
+    /// unchanged. Additional records cycle through the asset types and owners.
+    fn record_specs(&self) -> (MultiXfrRecordSpec, Vec<MultiXfrRecordSpec>) {
+        let mut specs = [
             MultiXfrRecordSpec {
                 asset_def_ix: 0,
                 owner_key_ix: 0,
                 asset_amount: 100,
             },
-            vec![
-                MultiXfrRecordSpec {
-                    asset_def_ix: 1,
-                    owner_key_ix: 0,
-                    asset_amount: 50,
-                },
-                MultiXfrRecordSpec {
-                    asset_def_ix: 0,
-                    owner_key_ix: 0,
-                    asset_amount: 70,
-                },
-            ],
-        ),
+            MultiXfrRecordSpec {
+                asset_def_ix: 1,
+                owner_key_ix: 0,
+                asset_amount: 50,
+            },
+            MultiXfrRecordSpec {
+                asset_def_ix: 0,
+                owner_key_ix: 0,
+                asset_amount: 70,
+            },
+        ]
+        .into_iter()
+        .chain((3..).map(|i| MultiXfrRecordSpec {
+            asset_def_ix: (i % (self.initial_assets as usize + 1)) as u8,
+            owner_key_ix: (i % NUM_TEST_KEYS as usize) as u8,
+            asset_amount: 100,
+        }))
+        .take(self.initial_records.get());
+        let first = specs.next().unwrap();
+        (first, specs.collect())
+    }
+}
+
+/// Number of user keys in the test state.
+const NUM_TEST_KEYS: u8 = 10;
+
+/// Number of times the transaction submitter tries to generate and submit a transaction for a
+/// round before giving up.
+const MAX_SUBMIT_ATTEMPTS: usize = 5;
+
+/// A block applied by the transaction submitter.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum LoggedBlock {
+    /// An empty block, applied as is.
+    Empty(ElaboratedBlock),
+    /// A block containing a single generated transaction, which is added to an empty block using
+    /// [MultiXfrTestState::try_add_transaction].
+    Transaction {
+        txn: ElaboratedTransaction,
+        index: usize,
+        kixs: Vec<usize>,
+    },
+}
+
+/// An entry in a recording of the blocks applied by the transaction submitter.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LogEntry {
+    block: LoggedBlock,
+    view_number: ConsensusTime,
+    /// The validator state commitment after applying `block`.
+    commitment: String,
+}
+
+/// A file recording the blocks applied by the transaction submitter.
+struct TransactionLog {
+    file: BufWriter<File>,
+}
+
+impl TransactionLog {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        bincode::serialize_into(&mut self.file, entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        // Flush after each entry, so that the recording is usable even if the run does not finish.
+        self.file.flush()
+    }
+}
+
+/// An append-only file of the commitment after each completed round.
+///
+/// The file is never truncated, so it accumulates the rounds of every run which uses it.
+struct CommitmentLog {
+    file: File,
+}
+
+impl CommitmentLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+        })
+    }
+
+    fn append(&mut self, round: u64, commitment: impl Display) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        writeln!(self.file, "{} {} {}", round, time, commitment)?;
+        // Sync after each round, so that the log survives even if the run does not finish.
+        self.file.sync_data()
+    }
+}
+
+/// Replay the recording at `path` starting from the genesis state.
+///
+/// Returns the commitment after each block, or an error if any of them differs from the recorded
+/// commitment.
+fn replay(
+    node_opt: &NodeOpt,
+    initial_state: &InitialStateOpt,
+    path: &Path,
+) -> io::Result<Vec<String>> {
+    let (_, mut state) = genesis_for_test(node_opt, initial_state);
+    let mut file = BufReader::new(File::open(path)?);
+    let mut commitments = vec![];
+    let mut round = 0;
+    loop {
+        let entry: LogEntry = match bincode::deserialize_from(&mut file) {
+            Ok(entry) => entry,
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                err => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            },
+        };
+        let blk = match entry.block {
+            LoggedBlock::Empty(blk) => blk,
+            LoggedBlock::Transaction { txn, index, kixs } => {
+                let mut blk = state.validator.next_block();
+                state
+                    .try_add_transaction(
+                        &mut blk,
+                        txn,
+                        index,
+                        kixs,
+                        TxnPrintInfo::new_no_time(round, 1),
+                    )
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+                round += 1;
+                blk
+            }
+        };
+        state
+            .validate_and_apply(
+                blk,
+                &entry.view_number,
+                0.0,
+                TxnPrintInfo::new_no_time(round, 1),
+            )
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+        let commitment = state.validator.commit().to_string();
+        if commitment != entry.commitment {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "commitment mismatch after block {}: recorded {}, replayed {}",
+                    commitments.len(),
+                    entry.commitment,
+                    commitment
+                ),
+            ));
+        }
+        commitments.push(commitment);
+    }
+    Ok(commitments)
+}
+
+fn genesis_for_test(
+    node_opt: &NodeOpt,
+    initial_state: &InitialStateOpt,
+) -> (GenesisNote, MultiXfrTestState) {
+    let mut state = MultiXfrTestState::initialize(
+        GENESIS_SEED,
+        NUM_TEST_KEYS,
+        initial_state.initial_assets,
+        initial_state.record_specs(),
     )
     .unwrap();
 
@@ -76,23 +308,31 @@ fn genesis_for_test(node_opt: &NodeOpt) -> (GenesisNote, MultiXfrTestState) {
     let genesis = GenesisNote::new(
         ChainVariables::new(42, VERIF_CRS.clone(), COMMITTEE_SIZE),
         Arc::new(state.records().collect()),
-        initialize_stake_table(
+        initialize_weighted_stake_table(
             known_nodes
                 .into_iter()
                 .map(|key| StakingKey::from_private(&key))
                 .collect(),
+            node_opt.stake_distribution(),
         ),
-    );
+    )
+    .with_commitment_version(node_opt.genesis_commitment_version.unwrap_or_default());
     state.validator = ValidatorState::genesis(genesis.clone());
     (genesis, state)
 }
 
+/// Generate a transaction for `round`.
+///
+/// The state is returned even if generation fails, so that the caller can try again.
 async fn generate_transaction(
     mut state: MultiXfrTestState,
     round: u64,
-) -> (MultiXfrTestState, MultiXfrRecordSpecTransaction) {
+) -> (
+    MultiXfrTestState,
+    Result<MultiXfrRecordSpecTransaction, String>,
+) {
     spawn_blocking(move || {
-        let mut txs = state
+        let res = state
             .generate_transactions(
                 vec![(
                     TestTxSpec::TwoInput {
@@ -106,18 +346,141 @@ async fn generate_transaction(
                 )],
                 TxnPrintInfo::new_no_time(round as usize, 1),
             )
-            .unwrap();
-        (state, txs.remove(0))
+            .map_err(|err| err.to_string())
+            .and_then(|mut txs| {
+                if txs.is_empty() {
+                    Err("no spendable records".to_string())
+                } else {
+                    Ok(txs.remove(0))
+                }
+            });
+        (state, res)
     })
     .await
 }
 
+/// Generate a transaction for `round` and submit it to consensus.
+///
+/// Failures are logged and retried, up to [MAX_SUBMIT_ATTEMPTS] times, so that a single bad
+/// attempt does not abort a long run. A transaction which fails to submit is resubmitted as it is,
+/// rather than regenerated, since generating a transaction advances the state, and nodes mirroring
+/// this one only generate the transaction once.
+async fn submit_transaction(
+    hotshot: &Consensus,
+    mut state: MultiXfrTestState,
+    round: u64,
+) -> (MultiXfrTestState, io::Result<MultiXfrRecordSpecTransaction>) {
+    let mut generated = None;
+    for attempt in 1..=MAX_SUBMIT_ATTEMPTS {
+        let txn = match generated.take() {
+            Some(txn) => txn,
+            None => {
+                let (new_state, res) = generate_transaction(state, round).await;
+                state = new_state;
+                match res {
+                    Ok(txn) => txn,
+                    Err(err) => {
+                        error!(
+                            "round {}: failed to generate transaction (attempt {}/{}): {}",
+                            round + 1,
+                            attempt,
+                            MAX_SUBMIT_ATTEMPTS,
+                            err
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        match hotshot.submit_transaction(txn.transaction.clone()).await {
+            Ok(()) => return (state, Ok(txn)),
+            Err(err) => {
+                error!(
+                    "round {}: failed to submit transaction (attempt {}/{}): {}",
+                    round + 1,
+                    attempt,
+                    MAX_SUBMIT_ATTEMPTS,
+                    err
+                );
+                generated = Some(txn);
+            }
+        }
+    }
+    (
+        state,
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "failed to submit a transaction for round {} after {} attempts",
+                round + 1,
+                MAX_SUBMIT_ATTEMPTS
+            ),
+        )),
+    )
+}
+
+/// Generate the transaction for `round` without submitting it.
+///
+/// This is how a node which is not submitting the transaction for `round` keeps its state in step
+/// with the submitter's. Transaction generation is deterministic given the state, so this yields
+/// the same transaction the submitter generates. The submitter resubmits that transaction if
+/// submitting it fails, so the two stay in step as long as the submitter generates it on its first
+/// attempt.
+async fn mirror_transaction(
+    state: MultiXfrTestState,
+    round: u64,
+) -> (MultiXfrTestState, io::Result<MultiXfrRecordSpecTransaction>) {
+    let (state, res) = generate_transaction(state, round).await;
+    let res = res.map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "round {}: failed to generate transaction: {}",
+                round + 1,
+                err
+            ),
+        )
+    });
+    (state, res)
+}
+
+/// The node which submits the transaction for `round`.
+///
+/// Node 0 submits every transaction, unless `rotate` is set, in which case the nodes take turns in
+/// order of node ID.
+fn submitter(round: u64, num_nodes: usize, rotate: bool) -> usize {
+    if rotate {
+        (round % num_nodes as u64) as usize
+    } else {
+        0
+    }
+}
+
+/// An error applying a decided block to the submitter's state.
+///
+/// The submitter's state can no longer follow consensus after this, so the run is aborted.
+fn apply_error(round: u64, err: impl Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "round {}: failed to apply decided block: {}",
+            round + 1,
+            err
+        ),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn generate_transactions(
     num_txns: u64,
     own_id: usize,
+    num_nodes: usize,
+    rotate: bool,
     mut hotshot: Consensus,
     mut state: MultiXfrTestState,
-) {
+    mut log: Option<TransactionLog>,
+    mut commitment_log: Option<CommitmentLog>,
+) -> io::Result<Option<LedgerStateCommitment>> {
     #[cfg(target_os = "linux")]
     let bytes_per_page = procfs::page_size().unwrap() as u64;
     #[cfg(target_os = "linux")]
@@ -149,16 +512,19 @@ async fn generate_transactions(
         report_mem();
         info!("Commitment: {}", hotshot.get_state().await.commit());
 
-        if own_id == 0 {
-            // If we're the designated transaction submitter (i.e. node 0), submit a transaction and
-            // wait for it to complete.
-            info!("  - Submitting a transaction");
-            let (new_state, mut txn) = generate_transaction(state, round).await;
+        if own_id == 0 || rotate {
+            // If we're the designated transaction submitter (i.e. node 0), or if the submitter
+            // rotates, so that we need to track the state for our turn, generate a transaction and
+            // wait for it to complete. Only the submitter for this round actually submits it.
+            let submitting = submitter(round, num_nodes, rotate) == own_id;
+            let (new_state, res) = if submitting {
+                info!("  - Submitting a transaction");
+                submit_transaction(&hotshot, state, round).await
+            } else {
+                mirror_transaction(state, round).await
+            };
             state = new_state;
-            hotshot
-                .submit_transaction(txn.transaction.clone())
-                .await
-                .unwrap();
+            let mut txn = res?;
             let mut empty_blocks = 0;
             loop {
                 info!("Waiting for HotShot event");
@@ -171,9 +537,10 @@ async fn generate_transactions(
                     info!("decide with {} leaves", leaf_chain.len());
                     if let Some(leaf) = leaf_chain.last() {
                         if leaf.state.block_height > state.validator.block_height + 1 {
-                            panic!(
-                                "missed a block, submitter is behind and cannot build a transaction"
-                            );
+                            return Err(apply_error(
+                                round,
+                                "missed a block, submitter is behind and cannot build a transaction",
+                            ));
                         }
                     }
                     for leaf in leaf_chain.iter().rev() {
@@ -186,7 +553,14 @@ async fn generate_transactions(
                                     0.0,
                                     TxnPrintInfo::new_no_time(round as usize, 1),
                                 )
-                                .unwrap();
+                                .map_err(|err| apply_error(round, err))?;
+                            if let Some(log) = &mut log {
+                                log.append(&LogEntry {
+                                    block: LoggedBlock::Empty(leaf.deltas.clone()),
+                                    view_number: leaf.view_number,
+                                    commitment: state.validator.commit().to_string(),
+                                })?;
+                            }
                             empty_blocks += 1;
                             info!("got empty block ({} since last commit)", empty_blocks);
                             if empty_blocks >= ValidatorState::HISTORY_SIZE {
@@ -195,11 +569,13 @@ async fn generate_transactions(
                                 // proofs, but for testing it doesn't matter and its simpler to just
                                 // build a new transaction.
                                 info!("transaction expired, submitting a new one");
-                                (state, txn) = generate_transaction(state, round).await;
-                                hotshot
-                                    .submit_transaction(txn.transaction.clone())
-                                    .await
-                                    .unwrap();
+                                let (new_state, res) = if submitting {
+                                    submit_transaction(&hotshot, state, round).await
+                                } else {
+                                    mirror_transaction(state, round).await
+                                };
+                                state = new_state;
+                                txn = res?;
                                 empty_blocks = 0;
                             }
                         } else if leaf.deltas.block.0[0].is_genesis() {
@@ -211,16 +587,17 @@ async fn generate_transactions(
                             assert_eq!(leaf.deltas.block.0.len(), 1);
                             assert_eq!(txn.transaction.txn, leaf.deltas.block.0[0]);
                             let mut blk = state.validator.next_block();
-                            let kixs = txn.keys_and_memos.iter().map(|(kix, _)| *kix).collect();
+                            let kixs: Vec<_> =
+                                txn.keys_and_memos.iter().map(|(kix, _)| *kix).collect();
                             state
                                 .try_add_transaction(
                                     &mut blk,
                                     txn.transaction.clone(),
                                     txn.index,
-                                    kixs,
+                                    kixs.clone(),
                                     TxnPrintInfo::new_no_time(round as usize, 1),
                                 )
-                                .unwrap();
+                                .map_err(|err| apply_error(round, err))?;
                             state
                                 .validate_and_apply(
                                     blk,
@@ -228,12 +605,26 @@ async fn generate_transactions(
                                     0.0,
                                     TxnPrintInfo::new_no_time(round as usize, 1),
                                 )
-                                .unwrap();
+                                .map_err(|err| apply_error(round, err))?;
+                            if let Some(log) = &mut log {
+                                log.append(&LogEntry {
+                                    block: LoggedBlock::Transaction {
+                                        txn: txn.transaction.clone(),
+                                        index: txn.index,
+                                        kixs,
+                                    },
+                                    view_number: leaf.view_number,
+                                    commitment: state.validator.commit().to_string(),
+                                })?;
+                            }
                             println!(
                                 "  - Round {} completed. Commitment: {}",
                                 round + 1,
                                 leaf.state.commit()
                             );
+                            if let Some(commitment_log) = &mut commitment_log {
+                                commitment_log.append(round + 1, leaf.state.commit())?;
+                            }
                             round += 1;
                             success = true;
                         }
@@ -265,6 +656,9 @@ async fn generate_transactions(
                             // genesis transaction.
                             let commit = leaf_chain.first().unwrap().state.commit();
                             println!("  - Round {} completed. Commitment: {}", round + 1, commit);
+                            if let Some(commitment_log) = &mut commitment_log {
+                                commitment_log.append(round + 1, commit)?;
+                            }
                             final_commitment = Some(commit);
                             round = (leaf.state.transaction_count - 1) as u64;
                             break;
@@ -292,14 +686,458 @@ async fn generate_transactions(
 
     // Wait for other nodes to catch up.
     sleep(Duration::from_secs(10)).await;
+    Ok(final_commitment)
+}
+
+/// Find a free port on the loopback interface.
+fn unused_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Run `num_nodes` validators in this process until `num_txns` transactions have been committed.
+///
+/// The nodes are connected over loopback using libp2p, with the first few acting as bootstrap nodes.
+/// Each node's ID, ports and store path are chosen by the simulation, and the node does not serve
+/// the query service. All other options, including consensus options and the genesis parameters,
+/// are taken from `base`.
+/// If `commitment_log` is given, the rounds completed by node 0 are appended to it. If `record` is
+/// given, the blocks applied by node 0 are recorded to it. If `rotate` is set, the nodes take turns
+/// submitting transactions. Returns the final commitment reported by each node, in order of node
+/// ID.
+async fn simulate(
+    base: &NodeOpt,
+    initial_state: InitialStateOpt,
+    num_nodes: usize,
+    num_txns: u64,
+    commitment_log: Option<&Path>,
+    record: Option<&Path>,
+    rotate: bool,
+) -> io::Result<Vec<Option<LedgerStateCommitment>>> {
+    let store = TempDir::new("espresso_validator_simulation")?;
+    let bootstrap_nodes = (0..num_nodes.min(MINIMUM_BOOTSTRAP_NODES))
+        .map(|_| Ok(format!("localhost:{}", unused_port()?).parse().unwrap()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let nonbootstrap_base_port = unused_port()? as usize;
+
+    let keys = gen_keys(base.secret_key_seed, num_nodes);
+    let pub_keys = keys
+        .iter()
+        .map(StakingKey::from_private)
+        .collect::<Vec<_>>();
+    let mut nodes = vec![];
+    for (id, priv_key) in keys.into_iter().enumerate() {
+        let node_opt = NodeOpt {
+            id,
+            num_nodes,
+            store_path: Some(store.path().join(id.to_string())),
+            bootstrap_nodes: bootstrap_nodes.clone(),
+            nonbootstrap_base_port,
+            nonbootstrap_port: None,
+            esqs: None,
+            ..base.clone()
+        };
+        node_opt
+            .check()
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        let pub_keys = pub_keys.clone();
+        let commitment_log = match commitment_log {
+            Some(path) if id == 0 => Some(CommitmentLog::open(path)?),
+            _ => None,
+        };
+        let log = match record {
+            Some(path) if id == 0 => Some(TransactionLog::create(path)?),
+            _ => None,
+        };
+        nodes.push(spawn(async move {
+            let (genesis, state) = genesis_for_test(&node_opt, &initial_state);
+            let hotshot = init_validator(
+                ChaChaRng::from_entropy(),
+                &node_opt,
+                priv_key,
+                pub_keys,
+                genesis,
+            )
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::TimedOut, err))?;
+            generate_transactions(
+                num_txns,
+                id,
+                num_nodes,
+                rotate,
+                hotshot,
+                state,
+                log,
+                commitment_log,
+            )
+            .await
+        }));
+    }
+    join_all(nodes).await.into_iter().collect()
 }
 
 #[async_std::main]
 async fn main() -> Result<(), std::io::Error> {
     let options = Options::parse();
+    if let Some(path) = &options.replay {
+        let commitments = replay(&options.node_opt, &options.initial_state, path)?;
+        println!("Replayed {} blocks.", commitments.len());
+        if let Some(commitment) = commitments.last() {
+            println!("Final commitment: {}", commitment);
+        }
+        return Ok(());
+    }
+
+    if let Some(num_nodes) = options.simulate {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_ansi(options.node_opt.colored_logs)
+            .init();
+        let commitments = simulate(
+            &options.node_opt,
+            options.initial_state,
+            num_nodes,
+            options.num_txns.unwrap(),
+            options.commitment_log.as_deref(),
+            options.record.as_deref(),
+            options.rotate_submitter,
+        )
+        .await?;
+        for (id, commitment) in commitments.iter().enumerate() {
+            match commitment {
+                Some(commitment) => println!("Node {} commitment: {}", id, commitment),
+                None => println!("Node {} did not commit any transactions", id),
+            }
+        }
+        if commitments
+            .iter()
+            .any(|commitment| *commitment != commitments[0])
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "simulated nodes did not agree on the final commitment",
+            ));
+        }
+        return Ok(());
+    }
+
     let id = options.node_opt.id;
-    let (genesis, state) = genesis_for_test(&options.node_opt);
+    // Only the transaction submitter applies blocks to its state, so only it has anything to
+    // record.
+    let log = match &options.record {
+        Some(path) if id == 0 => Some(TransactionLog::create(path)?),
+        _ => None,
+    };
+    let commitment_log = match &options.commitment_log {
+        Some(path) => Some(CommitmentLog::open(path)?),
+        None => None,
+    };
+    let (genesis, state) = genesis_for_test(&options.node_opt, &options.initial_state);
+    let num_nodes = options.node_opt.num_nodes;
     let hotshot = init(ChaChaRng::from_entropy(), genesis, options.node_opt).await?;
-    generate_transactions(options.num_txns, id, hotshot, state).await;
+    generate_transactions(
+        options.num_txns.unwrap(),
+        id,
+        num_nodes,
+        options.rotate_submitter,
+        hotshot,
+        state,
+        log,
+        commitment_log,
+    )
+    .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod unit_test {
+    use super::*;
+
+    #[test]
+    fn test_initial_records() {
+        let genesis_records = |initial_records: usize| {
+            let options = Options::try_parse_from([
+                "espresso-validator-testing",
+                "--id",
+                "0",
+                "--num-nodes",
+                "5",
+                "--num-txns",
+                "1",
+                "--initial-records",
+                &initial_records.to_string(),
+            ])
+            .unwrap();
+            let (_, state) = genesis_for_test(&options.node_opt, &options.initial_state);
+            state.record_merkle_tree.num_leaves()
+        };
+
+        // Each initial record spec is minted twice, and each mint creates the minted record and a
+        // fee change record, so each additional spec adds 4 records to the initial record tree.
+        let small = genesis_records(3);
+        let large = genesis_records(6);
+        assert_eq!(large, small + 4 * 3);
+    }
+
+    #[test]
+    fn test_commitment_log() {
+        let dir = TempDir::new("test_commitment_log").unwrap();
+        let path = dir.path().join("commitments.log");
+        let mut log = CommitmentLog::open(&path).unwrap();
+        log.append(1, "a").unwrap();
+        log.append(2, "b").unwrap();
+        drop(log);
+
+        // Reopening the log appends to it, rather than truncating it.
+        let mut log = CommitmentLog::open(&path).unwrap();
+        log.append(3, "c").unwrap();
+        let rounds = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let fields = line.split(' ').collect::<Vec<_>>();
+                (fields[0].to_string(), fields[2].to_string())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rounds,
+            [("1", "a"), ("2", "b"), ("3", "c")]
+                .map(|(round, commitment)| (round.to_string(), commitment.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_submitter_rotation() {
+        // Without rotation, node 0 submits every transaction.
+        assert!((0..10).all(|round| submitter(round, 5, false) == 0));
+
+        // With rotation, every node takes a turn, in order, and then the cycle repeats.
+        let submitters = (0..10)
+            .map(|round| submitter(round, 5, true))
+            .collect::<Vec<_>>();
+        assert_eq!(submitters, [0, 1, 2, 3, 4, 0, 1, 2, 3, 4]);
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_record_replay() {
+        // The fixed-stake configuration needs at least `MINIMUM_NODES` nodes to reach quorum. The
+        // genesis state used by `--replay` depends on `--num-nodes`, so it must match the number of
+        // simulated nodes.
+        let options = Options::try_parse_from([
+            "espresso-validator-testing",
+            "--id",
+            "0",
+            "--num-nodes",
+            &MINIMUM_NODES.to_string(),
+            "--num-txns",
+            "2",
+            "--simulate",
+            &MINIMUM_NODES.to_string(),
+            "--min-propose-time",
+            "1s",
+            "--max-propose-time",
+            "10s",
+        ])
+        .unwrap();
+        let dir = TempDir::new("test_record_replay").unwrap();
+        let path = dir.path().join("transactions.log");
+
+        // Record a simulated run, in which the transaction submitter applies the decided blocks.
+        let commitments = simulate(
+            &options.node_opt,
+            options.initial_state,
+            options.simulate.unwrap(),
+            options.num_txns.unwrap(),
+            None,
+            Some(&path),
+            false,
+        )
+        .await
+        .unwrap();
+        let final_commitment = commitments[0].as_ref().unwrap().to_string();
+
+        // Replaying the recording should reproduce the recorded commitments, ending in the final
+        // commitment of the run.
+        let replayed = replay(&options.node_opt, &options.initial_state, &path).unwrap();
+        assert_eq!(*replayed.last().unwrap(), final_commitment);
+    }
+
+    #[async_std::test]
+    async fn test_simulate() {
+        // The fixed-stake configuration needs at least `MINIMUM_NODES` nodes to reach quorum.
+        let options = Options::try_parse_from([
+            "espresso-validator-testing",
+            "--id",
+            "0",
+            "--num-nodes",
+            &MINIMUM_NODES.to_string(),
+            "--num-txns",
+            "1",
+            "--simulate",
+            &MINIMUM_NODES.to_string(),
+            "--min-propose-time",
+            "1s",
+            "--max-propose-time",
+            "10s",
+        ])
+        .unwrap();
+        let dir = TempDir::new("test_simulate").unwrap();
+        let commitment_log = dir.path().join("commitments.log");
+        let commitments = simulate(
+            &options.node_opt,
+            options.initial_state,
+            options.simulate.unwrap(),
+            1,
+            Some(&commitment_log),
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(commitments.len(), MINIMUM_NODES);
+
+        // Every node decided on the transaction, and they all agree on the resulting state.
+        assert!(commitments[0].is_some());
+        for commitment in &commitments {
+            assert_eq!(*commitment, commitments[0]);
+        }
+
+        // The commitment log has one line for the one completed round.
+        let log = std::fs::read_to_string(&commitment_log).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let fields = lines[0].split(' ').collect::<Vec<_>>();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], "1");
+        fields[1].parse::<u64>().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_simulate_rotate_submitter() {
+        let options = Options::try_parse_from([
+            "espresso-validator-testing",
+            "--id",
+            "0",
+            "--num-nodes",
+            &MINIMUM_NODES.to_string(),
+            "--num-txns",
+            "2",
+            "--simulate",
+            &MINIMUM_NODES.to_string(),
+            "--min-propose-time",
+            "1s",
+            "--max-propose-time",
+            "10s",
+            "--rotate-submitter",
+        ])
+        .unwrap();
+        assert_ne!(
+            submitter(0, MINIMUM_NODES, options.rotate_submitter),
+            submitter(1, MINIMUM_NODES, options.rotate_submitter)
+        );
+
+        // Both rounds complete, with a different node submitting each transaction, and every node
+        // agrees on the resulting state.
+        let commitments = simulate(
+            &options.node_opt,
+            options.initial_state,
+            options.simulate.unwrap(),
+            2,
+            None,
+            None,
+            options.rotate_submitter,
+        )
+        .await
+        .unwrap();
+        assert_eq!(commitments.len(), MINIMUM_NODES);
+        assert!(commitments[0].is_some());
+        for commitment in &commitments {
+            assert_eq!(*commitment, commitments[0]);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_simulate_latency() {
+        let latency = Duration::from_secs(1);
+        let options = Options::try_parse_from([
+            "espresso-validator-testing",
+            "--id",
+            "0",
+            "--num-nodes",
+            &MINIMUM_NODES.to_string(),
+            "--num-txns",
+            "2",
+            "--simulate",
+            &MINIMUM_NODES.to_string(),
+            "--min-propose-time",
+            "1s",
+            "--max-propose-time",
+            "10s",
+            "--sim-latency",
+            "1s",
+        ])
+        .unwrap();
+        assert_eq!(options.node_opt.sim_latency, latency);
+        let dir = TempDir::new("test_simulate_latency").unwrap();
+        let commitment_log = dir.path().join("commitments.log");
+
+        // Both rounds complete despite the latency, and every node agrees on the resulting state.
+        let commitments = simulate(
+            &options.node_opt,
+            options.initial_state,
+            options.simulate.unwrap(),
+            2,
+            Some(&commitment_log),
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(commitments.len(), MINIMUM_NODES);
+        assert!(commitments[0].is_some());
+        for commitment in &commitments {
+            assert_eq!(*commitment, commitments[0]);
+        }
+
+        // A round takes at least two sequential messages, a proposal and the votes on it, so with
+        // the injected latency the second round completes at least two latencies after the first.
+        let log = std::fs::read_to_string(&commitment_log).unwrap();
+        let times = log
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap().parse::<u64>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(times.len(), 2);
+        assert!(times[1] - times[0] >= 2 * latency.as_secs());
+    }
+
+    #[async_std::test]
+    async fn test_generation_failure() {
+        let options = Options::try_parse_from([
+            "espresso-validator-testing",
+            "--id",
+            "0",
+            "--num-nodes",
+            "5",
+            "--num-txns",
+            "1",
+        ])
+        .unwrap();
+        let (_, mut state) = genesis_for_test(&options.node_opt, &options.initial_state);
+
+        // Forget every record except the native fee records, which the generator never spends, so
+        // that there is nothing left to build a transaction from.
+        let num_fee_records = state.fee_records.len();
+        state.owners.truncate(num_fee_records);
+        state.memos.truncate(num_fee_records);
+
+        // Generation fails with an error instead of panicking, and hands back the state so the
+        // submitter can try again.
+        let (state, res) = generate_transaction(state, 0).await;
+        assert!(res.is_err());
+        assert_eq!(state.owners.len(), num_fee_records);
+    }
+}
@@ -12,6 +12,13 @@ use rand_chacha::ChaChaRng;
 #[async_std::main]
 async fn main() -> Result<(), std::io::Error> {
     let node_opt = NodeOpt::parse();
+    if node_opt.print_keys {
+        println!(
+            "{}",
+            staking_keys_json(node_opt.secret_key_seed, node_opt.num_nodes)
+        );
+        return Ok(());
+    }
     let genesis = genesis(&node_opt);
     let hotshot = init(ChaChaRng::from_entropy(), genesis, node_opt).await?;
     run_consensus(hotshot, pending::<()>()).await;
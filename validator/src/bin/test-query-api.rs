@@ -22,7 +22,7 @@ use async_tungstenite::async_std::connect_async;
 use clap::Parser;
 use commit::Committable;
 use espresso_availability_api::query_data::*;
-use espresso_core::ledger::EspressoLedger;
+use espresso_core::{genesis::GenesisNote, ledger::EspressoLedger, state::EspressoTransaction};
 use espresso_esqs::ApiError;
 use espresso_metastate_api::api::NullifierCheck;
 use futures::prelude::*;
@@ -180,6 +180,25 @@ async fn test(opt: &Args) {
         vec![0, num_blocks - 1]
     };
 
+    // Check that the genesis note is the one from the genesis block.
+    let genesis: GenesisNote = get(opt, "/availability/getgenesis").await;
+    let genesis_block: BlockQueryData = get(opt, "/availability/getblock/0").await;
+    assert_eq!(
+        genesis_block.raw_block.block.0,
+        vec![EspressoTransaction::Genesis(genesis.clone())]
+    );
+
+    // Check that the validator set is the stake table from the genesis note.
+    let validators: Vec<ValidatorQueryData> = get(opt, "/availability/validators").await;
+    assert!(!validators.is_empty());
+    assert_eq!(
+        validators
+            .into_iter()
+            .map(|v| (v.staking_key, v.stake))
+            .collect::<Vec<_>>(),
+        genesis.stake_table.into_iter().collect::<Vec<_>>()
+    );
+
     // Check that we can query the 0th block and the last block.
     for ix in test_indices {
         let block = get(opt, format!("/availability/getblock/{}", ix)).await;
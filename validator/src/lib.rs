@@ -10,6 +10,7 @@ use async_std::sync::{Arc, RwLock};
 use async_std::task::spawn;
 use clap::Parser;
 use cld::ClDuration;
+use commit::Committable;
 use dirs::data_local_dir;
 use espresso_core::kv_merkle_tree::KVMerkleTree;
 use espresso_core::reward::{
@@ -20,10 +21,11 @@ use espresso_core::state::{
     amount_to_nonzerou64, EspressoTransaction, EspressoTxnHelperProofs, KVMerkleProof,
 };
 use espresso_core::{
-    genesis::GenesisNote,
+    genesis::{GenesisCommitmentVersion, GenesisNote},
     stake_table::{StakeTableHash, StakingPrivKey},
     state::{
-        ChainVariables, ElaboratedBlock, ElaboratedTransaction, LWPersistence, ValidatorState,
+        ChainVariables, ElaboratedBlock, ElaboratedBlockCommitment, ElaboratedTransaction,
+        LWPersistence, ValidatorState,
     },
     universal_params::VERIF_CRS,
 };
@@ -43,14 +45,13 @@ use hotshot::{
 use hotshot_types::{ExecutionType, HotShotConfig};
 use jf_cap::{
     keys::UserPubKey,
-    structs::{Amount, AssetDefinition, FreezeFlag, RecordOpening},
+    structs::{Amount, AssetDefinition},
 };
 use jf_utils::tagged_blob;
 use libp2p::identity::ed25519::SecretKey;
 use libp2p::{multiaddr, Multiaddr, PeerId};
 use libp2p_networking::network::NetworkNodeType;
 use node_impl::{SignatureKey, ValidatorNodeImpl};
-use rand_chacha::{rand_core::SeedableRng as _, ChaChaRng};
 use snafu::Snafu;
 use static_assertions::const_assert;
 use std::collections::BTreeMap;
@@ -67,6 +68,7 @@ use url::Url;
 
 mod network;
 pub mod node_impl;
+pub use network::HandshakeTimeoutError;
 #[cfg(any(test, feature = "testing"))]
 pub mod testing;
 pub mod validator;
@@ -165,7 +167,7 @@ impl FromStr for Ratio {
 
 /// Options for validator nodes, including node-specific options, consensus options, and other
 /// options that are consistent among nodes.
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 pub struct NodeOpt {
     //
     // 1. Node-specific options.
@@ -284,6 +286,14 @@ pub struct NodeOpt {
     #[arg(long, short, env = "ESPRESSO_VALIDATOR_NUM_NODES")]
     pub num_nodes: usize,
 
+    /// Stake of each node in the genesis stake table, in order of node ID.
+    ///
+    /// If provided, there must be one non-zero amount for each node, and the total stake must be at
+    /// least the committee size. If not provided, each node has the same stake, and at least
+    /// `MINIMUM_NODES` nodes are required.
+    #[arg(long, env = "ESPRESSO_VALIDATOR_STAKE", value_delimiter = ',')]
+    pub stake: Vec<u64>,
+
     /// The base port for the non-bootstrap nodes.
     ///
     /// If specified, the consesnsu port for node `i` will be `nonbootstrap_base_port + i`.
@@ -307,6 +317,18 @@ pub struct NodeOpt {
     #[arg(long, requires = "cdn", env = "ESPRESSO_VALIDATOR_LIBP2P")]
     pub libp2p: bool,
 
+    /// How long to wait for the CDN to signal that all nodes have connected.
+    ///
+    /// If the signal does not arrive in time, the validator fails with an error. If not provided,
+    /// the validator waits indefinitely.
+    #[arg(
+        long,
+        requires = "cdn",
+        env = "ESPRESSO_VALIDATOR_CDN_HANDSHAKE_TIMEOUT",
+        value_parser = parse_duration
+    )]
+    pub cdn_handshake_timeout: Option<Duration>,
+
     /// Minimum time to wait for submitted transactions before proposing a block.
     ///
     /// Increasing this trades off latency for throughput: the rate of new block proposals gets
@@ -381,6 +403,18 @@ pub struct NodeOpt {
     )]
     pub round_start_delay: Duration,
 
+    /// Artificial delay added to every consensus message this node sends.
+    ///
+    /// This lets local runs approximate the latency of a wide area network. The default adds no
+    /// delay.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_SIM_LATENCY",
+        default_value = "0ms",
+        value_parser = parse_duration
+    )]
+    pub sim_latency: Duration,
+
     /// Delay after init before starting consensus.
     #[arg(long, env = "ESPRESSO_VALIDATOR_START_DELAY", default_value = "1ms",
         value_parser = parse_duration)]
@@ -398,6 +432,22 @@ pub struct NodeOpt {
     #[arg(long, env = "ESPRESSO_VALIDATOR_CHAIN_ID", default_value = "0")]
     pub chain_id: u16,
 
+    /// Scheme used to commit to the genesis block (v1 or v2).
+    ///
+    /// New deployments should use v2, which commits to the stake table, and is used if this is not
+    /// given. Deployments created before the stake table was committed to must use v1 to keep their
+    /// genesis block. A node restarting from persisted state refuses to start if this does not
+    /// reproduce its persisted genesis block.
+    #[arg(long, env = "ESPRESSO_VALIDATOR_GENESIS_COMMITMENT_VERSION")]
+    pub genesis_commitment_version: Option<GenesisCommitmentVersion>,
+
+    /// Seed from which the blinding factors of the genesis faucet records are derived.
+    ///
+    /// Nodes must agree on this seed, along with the faucet keys, to agree on the genesis block.
+    /// Defaults to a fixed seed, so that the genesis block is reproducible.
+    #[arg(long, env = "ESPRESSO_VALIDATOR_GENESIS_SEED")]
+    pub genesis_seed: Option<SecretKeySeed>,
+
     /// Public key which should own a faucet record in the genesis block.
     ///
     /// For each given public key, the ledger will be initialized with a record of 2^32 native
@@ -416,6 +466,13 @@ pub struct NodeOpt {
     #[arg(long, env = "ESPRESSO_COLORED_LOGS")]
     pub colored_logs: bool,
 
+    /// Print the public staking key of every node and exit, without starting a node.
+    ///
+    /// The keys are derived from `--secret-key-seed` and `--num-nodes`, and printed as a JSON
+    /// object mapping each node ID to its key, for deployment automation to capture.
+    #[arg(long)]
+    pub print_keys: bool,
+
     #[command(subcommand)]
     pub esqs: Option<full_node::Command>,
 }
@@ -447,12 +504,41 @@ impl NodeOpt {
 }
 
 impl NodeOpt {
+    /// The stake of each node in the genesis stake table, in order of node ID.
+    pub fn stake_distribution(&self) -> Vec<u64> {
+        if self.stake.is_empty() {
+            vec![STAKE_PER_NODE; self.num_nodes]
+        } else {
+            self.stake.clone()
+        }
+    }
+
     pub fn check(&self) -> Result<(), String> {
-        if self.num_nodes < MINIMUM_NODES {
-            return Err(format!(
-                "number of nodes must not be less than {}",
-                MINIMUM_NODES
-            ));
+        if self.stake.is_empty() {
+            if self.num_nodes < MINIMUM_NODES {
+                return Err(format!(
+                    "number of nodes must not be less than {}",
+                    MINIMUM_NODES
+                ));
+            }
+        } else {
+            if self.stake.len() != self.num_nodes {
+                return Err(format!(
+                    "expected stake for {} nodes, but got {}",
+                    self.num_nodes,
+                    self.stake.len()
+                ));
+            }
+            if self.stake.contains(&0) {
+                return Err("stake of each node must be non-zero".into());
+            }
+            let total_stake = self.stake.iter().map(|stake| *stake as u128).sum::<u128>();
+            if total_stake < COMMITTEE_SIZE as u128 {
+                return Err(format!(
+                    "total stake must not be less than the committee size {}",
+                    COMMITTEE_SIZE
+                ));
+            }
         }
         if self.max_propose_time < self.min_propose_time {
             return Err("max propose time must not be less than min propose time".into());
@@ -541,49 +627,56 @@ type Storage = MemoryStorage<ValidatorState>;
 pub type Consensus = HotShotHandle<ValidatorNodeImpl<Network, Storage>>;
 
 pub fn genesis(node_opt: &NodeOpt) -> GenesisNote {
-    let mut rng = ChaChaRng::from_seed(GENESIS_SEED);
-
     // Process the initial native token records for the faucet.
-    let faucet_records = node_opt
-        .faucet_pub_key
-        .clone()
-        .into_iter()
-        .map(|pub_key| {
+    let faucet_records = GenesisNote::seeded_faucet_records(
+        node_opt.genesis_seed.map_or(GENESIS_SEED, Into::into),
+        node_opt.faucet_pub_key.iter().map(|pub_key| {
             // Create the initial grant.
             event!(
                 Level::INFO,
                 "creating initial native token record for {}",
                 pub_key.address()
             );
-            RecordOpening::new(
-                &mut rng,
-                Amount::from(1u64 << 32),
+            (
+                pub_key.clone(),
                 AssetDefinition::native(),
-                pub_key,
-                FreezeFlag::Unfrozen,
+                Amount::from(1u64 << 32),
             )
-        })
-        .collect();
+        }),
+    );
 
     // generate keys
     let known_nodes = gen_keys(node_opt.secret_key_seed, node_opt.num_nodes);
     GenesisNote::new(
         ChainVariables::new(node_opt.chain_id, VERIF_CRS.clone(), COMMITTEE_SIZE),
         Arc::new(faucet_records),
-        initialize_stake_table(
+        initialize_weighted_stake_table(
             known_nodes
                 .into_iter()
                 .map(|key| StakingKey::from_private(&key))
                 .collect(),
+            node_opt.stake_distribution(),
         ),
     )
+    .with_commitment_version(node_opt.genesis_commitment_version.unwrap_or_default())
 }
 
 /// Creates a btreemap for stake table
 pub fn initialize_stake_table(known_nodes: Vec<StakingKey>) -> BTreeMap<StakingKey, Amount> {
+    let stake = vec![STAKE_PER_NODE; known_nodes.len()];
+    initialize_weighted_stake_table(known_nodes, stake)
+}
+
+/// Creates a btreemap for stake table, where `known_nodes[i]` has stake `stake[i]`.
+pub fn initialize_weighted_stake_table(
+    known_nodes: Vec<StakingKey>,
+    stake: Vec<u64>,
+) -> BTreeMap<StakingKey, Amount> {
+    assert_eq!(known_nodes.len(), stake.len());
     known_nodes
         .into_iter()
-        .map(|key| (key, STAKE_PER_NODE.into()))
+        .zip(stake)
+        .map(|(key, stake)| (key, stake.into()))
         .collect()
 }
 
@@ -728,6 +821,16 @@ pub async fn run_consensus<F: Send + Future>(mut consensus: Consensus, kill: F)
     }
 }
 
+/// The public staking keys of all nodes, as a JSON object mapping node ID to key.
+pub fn staking_keys_json(seed: Option<SecretKeySeed>, num_nodes: usize) -> String {
+    let keys = gen_keys(seed, num_nodes)
+        .iter()
+        .map(StakingKey::from_private)
+        .enumerate()
+        .collect::<BTreeMap<_, _>>();
+    serde_json::to_string_pretty(&keys).unwrap()
+}
+
 /// Generate a list of private and public keys for the given number of nodes with a given seed.
 pub fn gen_keys(seed: Option<SecretKeySeed>, num_nodes: usize) -> Vec<StakingPrivKey> {
     (0..num_nodes)
@@ -737,6 +840,11 @@ pub fn gen_keys(seed: Option<SecretKeySeed>, num_nodes: usize) -> Vec<StakingPri
         })
         .collect()
 }
+
+/// Connect this node to the network and start consensus from `genesis`.
+///
+/// Fails if the node uses a CDN and not every node connects to it within
+/// `node_opt.cdn_handshake_timeout`.
 #[allow(clippy::too_many_arguments)]
 pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     rng: R,
@@ -744,7 +852,7 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     priv_key: StakingPrivKey,
     pub_keys: Vec<StakingKey>,
     genesis: GenesisNote,
-) -> Consensus {
+) -> Result<Consensus, HandshakeTimeoutError> {
     debug!("Current node: {}", node_opt.id);
 
     let num_bootstrap = node_opt.bootstrap_nodes.len();
@@ -799,9 +907,15 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     };
 
     let own_network = match node_opt.cdn.clone() {
-        Some(cdn) if !node_opt.libp2p => Network::new_cdn(pub_keys.clone(), cdn, node_opt.id)
-            .await
-            .unwrap(),
+        Some(cdn) if !node_opt.libp2p => {
+            Network::new_cdn(
+                pub_keys.clone(),
+                cdn,
+                node_opt.id,
+                node_opt.cdn_handshake_timeout,
+            )
+            .await?
+        }
         _ => {
             let network = Network::new_p2p(
                 pub_keys[node_opt.id].clone(),
@@ -818,15 +932,20 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
                 // If there is a centralized server, use it as a barrier, so we don't proceed beyond
                 // this point until all nodes have reached this point and connected to the server.
                 // We will still use the libp2p network for consensus itself.
-                Network::new_cdn(pub_keys.clone(), cdn, node_opt.id)
-                    .await
-                    .unwrap();
+                Network::new_cdn(
+                    pub_keys.clone(),
+                    cdn,
+                    node_opt.id,
+                    node_opt.cdn_handshake_timeout,
+                )
+                .await?;
             }
 
             network
         }
     };
 
+    let own_network = own_network.with_latency(node_opt.sim_latency);
     let known_nodes = pub_keys.clone();
 
     debug!("All nodes connected to network");
@@ -854,7 +973,50 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
         ));
     }
 
-    hotshot
+    Ok(hotshot)
+}
+
+/// Check that `stored`, the genesis block persisted by an earlier run of this node with hash
+/// `stored_hash`, is the genesis block built from `genesis`, the genesis this node is configured
+/// with.
+///
+/// The hash is checked separately from the chain, since it depends on the commitment version of the
+/// genesis. The stored block records the version it was created with, but the node builds its
+/// genesis with the version it is configured with, and the two must agree.
+pub fn check_stored_genesis(
+    stored: &ElaboratedBlock,
+    stored_hash: ElaboratedBlockCommitment,
+    genesis: &GenesisNote,
+) -> Result<(), String> {
+    match stored.block.0.first() {
+        Some(EspressoTransaction::Genesis(stored)) => stored
+            .check_chain_id(genesis.chain.chain_id)
+            .map_err(|err| format!("persisted genesis block does not match this node: {}", err))?,
+        _ => return Err("persisted block 0 is not a genesis block".into()),
+    }
+    let hash = ElaboratedBlockCommitment::from(ElaboratedBlock::genesis(genesis.clone()).commit());
+    if hash != stored_hash {
+        return Err(format!(
+            "persisted genesis block has hash {}, but this node's genesis has hash {} with \
+             commitment version {}; check --genesis-commitment-version",
+            stored_hash, hash, genesis.commitment_version
+        ));
+    }
+    Ok(())
+}
+
+/// Load the genesis block persisted in this node's store, if any, and check it against `genesis`.
+pub fn check_persisted_genesis(node_opt: &NodeOpt, genesis: &GenesisNote) -> Result<(), String> {
+    let storage = get_store_dir(node_opt);
+    match QueryData::load_genesis_block(&storage) {
+        Ok(Some(stored)) => check_stored_genesis(&stored.raw_block, stored.block_hash, genesis),
+        Ok(None) => Ok(()),
+        Err(err) => Err(format!(
+            "unable to load persisted genesis block from {}: {}",
+            storage.display(),
+            err
+        )),
+    }
 }
 
 pub fn open_data_source(node_opt: &NodeOpt, consensus: Consensus) -> Arc<RwLock<QueryData>> {
@@ -952,3 +1114,101 @@ async fn collect_reward_daemon<R: CryptoRng + RngCore + Send>(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_weighted_stake_table() {
+        let stake = [100, 1, 20, 5];
+        let node_opt = NodeOpt::parse_from([
+            "--",
+            "--id",
+            "0",
+            "--num-nodes",
+            "4",
+            "--stake",
+            &stake.map(|s| s.to_string()).join(","),
+        ]);
+        node_opt.check().unwrap();
+        assert_eq!(node_opt.stake_distribution(), stake);
+
+        // Each node's key has its configured stake in the genesis stake table, which determines
+        // the stake distribution given to HotShot.
+        let genesis = genesis(&node_opt);
+        let keys = gen_keys(node_opt.secret_key_seed, node_opt.num_nodes);
+        assert_eq!(genesis.stake_table.len(), stake.len());
+        for (key, stake) in keys.iter().zip(stake) {
+            assert_eq!(
+                genesis.stake_table[&StakingKey::from_private(key)],
+                Amount::from(stake)
+            );
+        }
+
+        // Without explicit stake, every node has the same stake.
+        let node_opt = NodeOpt::new(0, MINIMUM_NODES);
+        assert_eq!(
+            node_opt.stake_distribution(),
+            vec![STAKE_PER_NODE; MINIMUM_NODES]
+        );
+
+        // Stake must be given for every node, be non-zero, and add up to the committee size.
+        let mut node_opt = NodeOpt::new(0, 4);
+        node_opt.stake = vec![COMMITTEE_SIZE; 3];
+        node_opt.check().unwrap_err();
+        node_opt.stake = vec![COMMITTEE_SIZE, COMMITTEE_SIZE, COMMITTEE_SIZE, 0];
+        node_opt.check().unwrap_err();
+        node_opt.stake = vec![1; 4];
+        node_opt.check().unwrap_err();
+    }
+
+    #[test]
+    fn test_staking_keys_json() {
+        let node_opt = NodeOpt::parse_from(["--", "--id", "0", "--num-nodes", "7", "--print-keys"]);
+        assert!(node_opt.print_keys);
+        let json = staking_keys_json(node_opt.secret_key_seed, node_opt.num_nodes);
+        let keys: BTreeMap<String, StakingKey> = serde_json::from_str(&json).unwrap();
+
+        // There is one entry per node, keyed by node ID, with that node's key.
+        assert_eq!(keys.len(), 7);
+        for (id, key) in gen_keys(node_opt.secret_key_seed, node_opt.num_nodes)
+            .iter()
+            .enumerate()
+        {
+            assert_eq!(keys[&id.to_string()], StakingKey::from_private(key));
+        }
+    }
+
+    #[test]
+    fn test_check_stored_genesis() {
+        let node_opt = NodeOpt::new(0, MINIMUM_NODES);
+        let genesis = genesis(&node_opt);
+        let stored = |genesis: GenesisNote| {
+            let block = ElaboratedBlock::genesis(genesis);
+            let hash = block.commit().into();
+            (block, hash)
+        };
+        let (block, hash) = stored(genesis.clone());
+        check_stored_genesis(&block, hash, &genesis).unwrap();
+
+        // A node persisted on another chain refuses to start on this one.
+        let other_chain = NodeOpt {
+            chain_id: node_opt.chain_id + 1,
+            ..NodeOpt::new(0, MINIMUM_NODES)
+        };
+        let (block, hash) = stored(super::genesis(&other_chain));
+        check_stored_genesis(&block, hash, &genesis).unwrap_err();
+
+        // A node created with the v1 commitment scheme refuses to start with the default scheme,
+        // even though the persisted block deserializes and is for the same chain.
+        let v1 = NodeOpt {
+            genesis_commitment_version: Some(GenesisCommitmentVersion::V1),
+            ..NodeOpt::new(0, MINIMUM_NODES)
+        };
+        let (block, hash) = stored(super::genesis(&v1));
+        let block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        check_stored_genesis(&block, hash, &genesis).unwrap_err();
+        check_stored_genesis(&block, hash, &super::genesis(&v1)).unwrap();
+    }
+}
@@ -23,20 +23,66 @@ use libp2p_networking::{
     reexport::{Multiaddr, PeerId},
 };
 use serde::{Deserialize, Serialize};
+use snafu::Snafu;
 use std::collections::HashSet;
+use std::future::Future;
 use std::net::ToSocketAddrs;
 use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicBool;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use url::Url;
 
+/// The CDN did not signal the start of the network in time.
+#[derive(Clone, Debug, Snafu)]
+#[snafu(display(
+    "timed out after {:?} waiting for the CDN handshake ({}/{} nodes connected)",
+    timeout,
+    connected,
+    num_nodes
+))]
+pub struct HandshakeTimeoutError {
+    pub timeout: Duration,
+    pub connected: usize,
+    pub num_nodes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct HybridNetwork {
+    transport: Transport,
+    /// Artificial delay added to every message sent, to simulate a slower network.
+    latency: Duration,
+}
+
 #[derive(Clone, Debug)]
-pub enum HybridNetwork {
+enum Transport {
     P2P(Libp2pNetwork<Message<ValidatorState, SignatureKey>, SignatureKey>),
     Cdn(CentralizedServerNetwork<SignatureKey, VRFStakeTableConfig>),
 }
 
+impl From<Transport> for HybridNetwork {
+    fn from(transport: Transport) -> Self {
+        Self {
+            transport,
+            latency: Duration::ZERO,
+        }
+    }
+}
+
 impl HybridNetwork {
+    /// Delay every message sent on this network by `latency`.
+    ///
+    /// This approximates WAN conditions when running nodes locally.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    async fn simulate_latency(&self) {
+        if !self.latency.is_zero() {
+            sleep(self.latency).await;
+        }
+    }
+
     /// Create a new libp2p network.
     pub async fn new_p2p(
         pubkey: StakingKey,
@@ -79,7 +125,7 @@ impl HybridNetwork {
 
         let config = config_builder.build().unwrap();
 
-        Ok(Self::P2P(
+        Ok(Transport::P2P(
             Libp2pNetwork::new(
                 config,
                 pubkey.into(),
@@ -88,15 +134,20 @@ impl HybridNetwork {
                 node_opt.id,
             )
             .await?,
-        ))
+        )
+        .into())
     }
 
     /// Create a new Cdn-based network.
+    ///
+    /// This waits for the CDN to signal that all nodes have connected. If `handshake_timeout` is
+    /// given and the signal does not arrive in time, it fails instead of waiting forever.
     pub async fn new_cdn(
         known_nodes: Vec<StakingKey>,
         server: Url,
         node_id: usize,
-    ) -> Result<Self, NetworkError> {
+        handshake_timeout: Option<Duration>,
+    ) -> Result<Self, HandshakeTimeoutError> {
         let known_nodes = known_nodes
             .into_iter()
             .map(SignatureKey::from)
@@ -115,22 +166,57 @@ impl HybridNetwork {
                 .unwrap(),
             pub_key,
         );
-        while !network.run_ready() {
-            let connected = network.get_connected_client_count().await;
-            tracing::debug!(
-                "waiting for start signal ({}/{} connected)",
-                connected,
-                num_nodes
-            );
-            sleep(Duration::from_secs(1)).await;
+        wait_for_handshake(
+            || network.run_ready(),
+            || network.get_connected_client_count(),
+            num_nodes,
+            handshake_timeout,
+        )
+        .await?;
+        Ok(Transport::Cdn(network).into())
+    }
+}
+
+/// Poll `ready` once a second until it holds, or until `timeout` has passed.
+///
+/// `connected` reports how many of the `num_nodes` nodes have connected so far, for logging and
+/// for the error if the handshake times out.
+pub async fn wait_for_handshake<Fut: Future<Output = usize>>(
+    ready: impl Fn() -> bool,
+    connected: impl Fn() -> Fut,
+    num_nodes: usize,
+    timeout: Option<Duration>,
+) -> Result<(), HandshakeTimeoutError> {
+    let start = Instant::now();
+    while !ready() {
+        let connected = connected().await;
+        tracing::debug!(
+            "waiting for start signal ({}/{} connected)",
+            connected,
+            num_nodes
+        );
+        let mut delay = Duration::from_secs(1);
+        if let Some(timeout) = timeout {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(HandshakeTimeoutError {
+                    timeout,
+                    connected,
+                    num_nodes,
+                });
+            }
+            delay = delay.min(remaining);
         }
-        Ok(Self::Cdn(network))
+        sleep(delay).await;
     }
+    Ok(())
 }
 
+// Methods marked with an attribute naming a method of [HybridNetwork], such as
+// `#[simulate_latency]`, call that method before delegating to the transport.
 macro_rules! impl_networking {
     {
-        $(async fn $name:ident$
+        $($(#[$hook:ident])? async fn $name:ident$
             (<$($type_param:ident $(: $type_constraint:tt)?),*>)?
             (&self $(, $($param:ident : $param_type:ty),* $(,)?)?)
         $(-> $result_type:ty)?;)*
@@ -143,11 +229,12 @@ macro_rules! impl_networking {
                     $(<$($type_param $(: $type_constraint)?),*>)?
                     (&self $(, $($param : $param_type),*)?)
                 $(-> $result_type)? {
-                    match self {
-                        Self::P2P(p2p) =>
+                    $(self.$hook().await;)?
+                    match &self.transport {
+                        Transport::P2P(p2p) =>
                             NetworkingImplementation::<Message<ValidatorState, SignatureKey>, SignatureKey>::
                                 $name(p2p, $($($param),*)?).await,
-                        Self::Cdn(cdn) =>
+                        Transport::Cdn(cdn) =>
                             NetworkingImplementation::<Message<ValidatorState, SignatureKey>, SignatureKey>::
                                 $name(cdn, $($($param),*)?).await,
                     }
@@ -159,10 +246,12 @@ macro_rules! impl_networking {
 
 impl_networking! {
     async fn ready(&self) -> bool;
+    #[simulate_latency]
     async fn broadcast_message(
         &self,
         message: Message<ValidatorState, SignatureKey>,
     ) -> Result<(), NetworkError>;
+    #[simulate_latency]
     async fn message_node(
         &self,
         message: Message<ValidatorState, SignatureKey>,
@@ -186,3 +275,29 @@ impl_networking! {
     ) -> Result<V, NetworkError>;
     async fn notify_of_subsequent_leader(&self, pk: SignatureKey, cancelled: Arc<AtomicBool>);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_handshake_timeout() {
+        // A handshake which is already complete does not wait.
+        wait_for_handshake(|| true, || async { 4 }, 4, Some(Duration::ZERO))
+            .await
+            .unwrap();
+
+        // A stalled handshake fails once the timeout has passed.
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let err = wait_for_handshake(|| false, || async { 3 }, 4, Some(timeout))
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(err.timeout, timeout);
+        assert_eq!(err.connected, 3);
+        assert_eq!(err.num_nodes, 4);
+        assert!(err.to_string().contains("3/4 nodes connected"));
+    }
+}
@@ -221,7 +221,9 @@ pub async fn minimal_test_network(
                 ..NodeOpt::new(i, MINIMUM_NODES)
             };
             let genesis = genesis(&node_opt);
-            let consensus = init_validator(new_rng, &node_opt, priv_key, pub_keys, genesis).await;
+            let consensus = init_validator(new_rng, &node_opt, priv_key, pub_keys, genesis)
+                .await
+                .unwrap();
             let data_source = open_data_source(&node_opt, consensus.clone());
 
             // If applicable, run a query service.
@@ -18,11 +18,37 @@ pub async fn init<R: CryptoRng + RngCore + Send + 'static>(
         eprintln!("{}", msg);
         exit(1);
     }
+    if let Err(err) = genesis.validate_stake_table() {
+        eprintln!("invalid genesis stake table: {}", err);
+        exit(1);
+    }
+    // A node restarting from persisted state must stay on the chain it persisted.
+    if !node_opt.reset_store_state {
+        if let Err(msg) = check_persisted_genesis(&node_opt, &genesis) {
+            eprintln!("{}", msg);
+            exit(1);
+        }
+    }
 
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .with_ansi(node_opt.colored_logs)
         .init();
+    if node_opt.genesis_commitment_version.is_none() {
+        event!(
+            Level::WARN,
+            "no genesis commitment version given, using {}; deployments created before {} must \
+             pass --genesis-commitment-version v1 to keep their genesis block",
+            genesis.commitment_version,
+            genesis.commitment_version
+        );
+    }
+    event!(
+        Level::INFO,
+        "starting from genesis {} with commitment version {}",
+        genesis.commitment_hex(),
+        genesis.commitment_version
+    );
 
     // Initialize the hotshot
     let keys = gen_keys(node_opt.secret_key_seed, node_opt.num_nodes);
@@ -31,7 +57,9 @@ pub async fn init<R: CryptoRng + RngCore + Send + 'static>(
         .into_iter()
         .map(|sk| StakingKey::from_private(&sk))
         .collect();
-    let hotshot = init_validator(rng, &node_opt, priv_key, known_nodes, genesis).await;
+    let hotshot = init_validator(rng, &node_opt, priv_key, known_nodes, genesis)
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::TimedOut, err))?;
     let data_source = open_data_source(&node_opt, hotshot.clone());
 
     // Start an EsQS server if requested.